@@ -0,0 +1,11 @@
+CREATE TABLE person_permissions (
+    id SERIAL PRIMARY KEY,
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+    permission TEXT NOT NULL,
+    is_granted BOOLEAN NOT NULL,
+    UNIQUE (person_id, permission)
+);
@@ -0,0 +1,8 @@
+CREATE TYPE user_role as ENUM ('dev', 'admin', 'prefect', 'participant');
+
+ALTER TABLE people ADD COLUMN permissions user_role NOT NULL DEFAULT 'participant';
+UPDATE people SET permissions = LOWER(r.name)::user_role FROM roles r WHERE r.id = people.role_id AND LOWER(r.name) IN ('dev', 'admin', 'prefect', 'participant');
+
+ALTER TABLE people DROP COLUMN role_id;
+DROP TABLE role_permissions;
+DROP TABLE roles;
@@ -0,0 +1,49 @@
+CREATE TABLE roles (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE role_permissions (
+    role_id INT NOT NULL,
+    CONSTRAINT fk_role_id
+        FOREIGN KEY (role_id)
+        REFERENCES roles(id)
+        ON DELETE CASCADE,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role_id, permission)
+);
+
+-- participant is inserted first so that it gets id 1, which is used as the default below
+INSERT INTO roles (name) VALUES ('Participant'), ('Prefect'), ('Admin'), ('Dev');
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT r.id, p.permission
+FROM roles r
+CROSS JOIN (VALUES ('AddRmSelfToEvent'), ('SeePhotos'), ('SeeBonusPoints')) AS p(permission)
+WHERE r.name IN ('Participant', 'Prefect', 'Admin', 'Dev');
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT r.id, p.permission
+FROM roles r
+CROSS JOIN (VALUES ('EditEvents'), ('ViewPhotoAdders'), ('EditPrefectsOnEvents'), ('EditParticipantsOnEvents'), ('VerifyEvents'), ('AddPhotos'), ('SeePeople')) AS p(permission)
+WHERE r.name IN ('Prefect', 'Admin', 'Dev');
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT r.id, p.permission
+FROM roles r
+CROSS JOIN (VALUES ('ImportCSV'), ('ExportCSV'), ('RunMigrations'), ('EditPeople'), ('AddRewards'), ('GiveBonusPoints')) AS p(permission)
+WHERE r.name IN ('Admin', 'Dev');
+
+INSERT INTO role_permissions (role_id, permission)
+SELECT r.id, 'DevAccess'
+FROM roles r
+WHERE r.name = 'Dev';
+
+ALTER TABLE people ADD COLUMN role_id INT;
+UPDATE people SET role_id = r.id FROM roles r WHERE LOWER(r.name) = people.permissions::TEXT;
+ALTER TABLE people ALTER COLUMN role_id SET NOT NULL;
+ALTER TABLE people ALTER COLUMN role_id SET DEFAULT 1;
+ALTER TABLE people ADD CONSTRAINT fk_role_id FOREIGN KEY (role_id) REFERENCES roles(id) ON DELETE RESTRICT;
+
+ALTER TABLE people DROP COLUMN permissions;
+DROP TYPE user_role;
@@ -0,0 +1,6 @@
+DROP TABLE totp_recovery_codes;
+
+ALTER TABLE roles DROP COLUMN requires_2fa;
+
+ALTER TABLE people DROP COLUMN totp_enabled;
+ALTER TABLE people DROP COLUMN totp_secret;
@@ -0,0 +1,15 @@
+ALTER TABLE people ADD COLUMN totp_secret TEXT;
+ALTER TABLE people ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE roles ADD COLUMN requires_2fa BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE totp_recovery_codes (
+    id SERIAL PRIMARY KEY,
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+    hashed_code TEXT NOT NULL,
+    used_at TIMESTAMP
+);
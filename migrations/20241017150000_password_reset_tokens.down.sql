@@ -0,0 +1,3 @@
+ALTER TABLE people ADD COLUMN password_link_id INT;
+
+DROP TABLE password_reset_tokens;
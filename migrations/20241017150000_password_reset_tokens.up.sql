@@ -0,0 +1,19 @@
+CREATE TABLE password_reset_tokens (
+    id SERIAL PRIMARY KEY,
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+    hashed_token TEXT NOT NULL UNIQUE,
+    requested_by INT,
+    CONSTRAINT fk_requested_by
+        FOREIGN KEY (requested_by)
+        REFERENCES people(id)
+        ON DELETE SET NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP
+);
+
+ALTER TABLE people DROP COLUMN password_link_id;
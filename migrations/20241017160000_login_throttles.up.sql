@@ -0,0 +1,9 @@
+CREATE TABLE login_throttles (
+    id SERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    identifier TEXT NOT NULL,
+    failures INT NOT NULL DEFAULT 0,
+    last_failure TIMESTAMP NOT NULL DEFAULT now(),
+    locked_until TIMESTAMP,
+    UNIQUE (kind, identifier)
+);
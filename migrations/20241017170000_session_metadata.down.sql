@@ -0,0 +1,7 @@
+ALTER TABLE sessions DROP COLUMN user_agent;
+ALTER TABLE sessions DROP COLUMN ip_address;
+ALTER TABLE sessions DROP COLUMN last_seen;
+ALTER TABLE sessions DROP COLUMN created_at;
+ALTER TABLE sessions DROP CONSTRAINT fk_person_id;
+ALTER TABLE sessions DROP COLUMN person_id;
+ALTER TABLE sessions DROP COLUMN handle;
@@ -0,0 +1,10 @@
+ALTER TABLE sessions ADD COLUMN handle SERIAL UNIQUE;
+ALTER TABLE sessions ADD COLUMN person_id INT;
+ALTER TABLE sessions ADD CONSTRAINT fk_person_id
+    FOREIGN KEY (person_id)
+    REFERENCES people(id)
+    ON DELETE CASCADE;
+ALTER TABLE sessions ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT now();
+ALTER TABLE sessions ADD COLUMN last_seen TIMESTAMP NOT NULL DEFAULT now();
+ALTER TABLE sessions ADD COLUMN ip_address TEXT;
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
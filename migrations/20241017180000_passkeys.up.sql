@@ -0,0 +1,15 @@
+CREATE TABLE passkeys (
+    id SERIAL PRIMARY KEY,
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    credential_id BYTEA NOT NULL UNIQUE,
+    passkey JSONB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_used TIMESTAMP
+);
+
+CREATE INDEX passkeys_person_id ON passkeys (person_id);
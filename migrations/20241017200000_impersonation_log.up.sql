@@ -0,0 +1,18 @@
+CREATE TABLE impersonation_log (
+    id SERIAL PRIMARY KEY,
+    impersonator_id INT NOT NULL,
+    CONSTRAINT fk_impersonator_id
+        FOREIGN KEY (impersonator_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+    ip_address TEXT,
+    started_at TIMESTAMP NOT NULL DEFAULT now(),
+    ended_at TIMESTAMP
+);
+
+CREATE INDEX impersonation_log_started_at ON impersonation_log (started_at);
@@ -0,0 +1,14 @@
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    hashed_token TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_used TIMESTAMP
+);
+
+CREATE INDEX api_tokens_person_id ON api_tokens (person_id);
@@ -0,0 +1,3 @@
+ALTER TABLE events DROP CONSTRAINT events_end_after_start;
+ALTER TABLE events DROP COLUMN is_all_day;
+ALTER TABLE events DROP COLUMN end_date;
@@ -0,0 +1,3 @@
+ALTER TABLE events ADD COLUMN end_date TIMESTAMP;
+ALTER TABLE events ADD COLUMN is_all_day BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE events ADD CONSTRAINT events_end_after_start CHECK (end_date IS NULL OR end_date >= date);
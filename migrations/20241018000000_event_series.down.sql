@@ -0,0 +1,7 @@
+DROP INDEX events_series_id;
+ALTER TABLE events DROP COLUMN is_series_override;
+ALTER TABLE events DROP COLUMN series_occurrence;
+ALTER TABLE events DROP CONSTRAINT fk_series_id;
+ALTER TABLE events DROP COLUMN series_id;
+DROP TABLE event_series_exceptions;
+DROP TABLE event_series;
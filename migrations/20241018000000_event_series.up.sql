@@ -0,0 +1,38 @@
+CREATE TABLE event_series (
+    id SERIAL PRIMARY KEY,
+    frequency TEXT NOT NULL CHECK (frequency IN ('daily', 'weekly', 'fortnightly', 'termly')),
+    repeat_until DATE NOT NULL,
+
+    event_name TEXT NOT NULL,
+    location TEXT NOT NULL,
+    teacher TEXT NOT NULL,
+    other_info TEXT,
+    starts_at TIMESTAMP NOT NULL,
+    ends_at TIMESTAMP,
+    is_all_day BOOLEAN NOT NULL DEFAULT false,
+    is_locked BOOLEAN NOT NULL DEFAULT false,
+    extra_points INT NOT NULL DEFAULT 0,
+
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE event_series_exceptions (
+    series_id INT NOT NULL,
+    CONSTRAINT fk_series_id
+        FOREIGN KEY (series_id)
+        REFERENCES event_series(id)
+        ON DELETE CASCADE,
+
+    exception_date DATE NOT NULL,
+    PRIMARY KEY (series_id, exception_date)
+);
+
+ALTER TABLE events ADD COLUMN series_id INT;
+ALTER TABLE events ADD CONSTRAINT fk_series_id
+    FOREIGN KEY (series_id)
+    REFERENCES event_series(id)
+    ON DELETE SET NULL;
+ALTER TABLE events ADD COLUMN series_occurrence TIMESTAMP;
+ALTER TABLE events ADD COLUMN is_series_override BOOLEAN NOT NULL DEFAULT false;
+
+CREATE INDEX events_series_id ON events (series_id, series_occurrence);
@@ -0,0 +1,3 @@
+DROP TABLE event_waitlist;
+ALTER TABLE event_series DROP COLUMN capacity;
+ALTER TABLE events DROP COLUMN capacity;
@@ -0,0 +1,23 @@
+ALTER TABLE events ADD COLUMN capacity INT CHECK (capacity IS NULL OR capacity >= 0);
+ALTER TABLE event_series ADD COLUMN capacity INT CHECK (capacity IS NULL OR capacity >= 0);
+
+CREATE TABLE event_waitlist (
+    id SERIAL PRIMARY KEY,
+
+    event_id INT NOT NULL,
+    CONSTRAINT fk_event_id
+        FOREIGN KEY (event_id)
+        REFERENCES events(id)
+        ON DELETE CASCADE,
+
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+
+    joined_at TIMESTAMP NOT NULL DEFAULT now(),
+    UNIQUE (event_id, person_id)
+);
+
+CREATE INDEX event_waitlist_order ON event_waitlist (event_id, joined_at, id);
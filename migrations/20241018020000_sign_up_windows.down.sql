@@ -0,0 +1,6 @@
+ALTER TABLE events DROP COLUMN auto_locked_at;
+ALTER TABLE events DROP COLUMN locks_at;
+
+ALTER TABLE events DROP CONSTRAINT events_sign_ups_open_before_close;
+ALTER TABLE events DROP COLUMN sign_ups_close_at;
+ALTER TABLE events DROP COLUMN sign_ups_open_at;
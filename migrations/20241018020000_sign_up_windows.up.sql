@@ -0,0 +1,8 @@
+-- NULL means that the defaults from the config get used
+ALTER TABLE events ADD COLUMN sign_ups_open_at TIMESTAMP;
+ALTER TABLE events ADD COLUMN sign_ups_close_at TIMESTAMP;
+ALTER TABLE events ADD CONSTRAINT events_sign_ups_open_before_close CHECK (sign_ups_open_at IS NULL OR sign_ups_close_at IS NULL OR sign_ups_open_at <= sign_ups_close_at);
+
+ALTER TABLE events ADD COLUMN locks_at TIMESTAMP;
+-- set when the scheduler locks an event, so that it doesn't lock it again if someone unlocks it
+ALTER TABLE events ADD COLUMN auto_locked_at TIMESTAMP;
@@ -0,0 +1 @@
+DROP TABLE participant_withdrawals;
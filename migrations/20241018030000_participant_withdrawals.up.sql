@@ -0,0 +1,27 @@
+CREATE TABLE participant_withdrawals (
+    id SERIAL PRIMARY KEY,
+
+    event_id INT NOT NULL,
+    CONSTRAINT fk_event_id
+        FOREIGN KEY (event_id)
+        REFERENCES events(id)
+        ON DELETE CASCADE,
+
+    person_id INT NOT NULL,
+    CONSTRAINT fk_person_id
+        FOREIGN KEY (person_id)
+        REFERENCES people(id)
+        ON DELETE CASCADE,
+
+    -- the same as person_id if they withdrew themselves
+    removed_by INT,
+    CONSTRAINT fk_removed_by
+        FOREIGN KEY (removed_by)
+        REFERENCES people(id)
+        ON DELETE SET NULL,
+
+    was_verified BOOLEAN NOT NULL,
+    withdrawn_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX participant_withdrawals_event ON participant_withdrawals (event_id, withdrawn_at);
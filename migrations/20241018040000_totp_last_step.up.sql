@@ -0,0 +1 @@
+ALTER TABLE people ADD COLUMN totp_last_step BIGINT;
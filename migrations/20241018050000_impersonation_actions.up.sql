@@ -0,0 +1,14 @@
+CREATE TABLE impersonation_actions (
+    id SERIAL PRIMARY KEY,
+    impersonation_id INT NOT NULL,
+    CONSTRAINT fk_impersonation_id
+        FOREIGN KEY (impersonation_id)
+        REFERENCES impersonation_log(id)
+        ON DELETE CASCADE,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    status INT NOT NULL,
+    happened_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX impersonation_actions_impersonation_id ON impersonation_actions (impersonation_id);
@@ -0,0 +1,5 @@
+-- events that were already over before automatic locking existed count as handled, so that it only locks ones that finish from now on
+-- (anything which should be locked has been locked by hand already, and anything which was unlocked on purpose stays unlocked)
+UPDATE events
+SET auto_locked_at = now()
+WHERE auto_locked_at IS NULL AND COALESCE(locks_at, end_date, date) <= now();
@@ -0,0 +1,164 @@
+//! A versioned JSON API under `/api/v1`, for scripts that would otherwise have to scrape pages.
+//!
+//! Requests authenticate with a personal access token from [`crate::auth::api_tokens`] in an `Authorization: Bearer` header, rather than a session cookie. Each handler then checks the same [`PermissionsTarget`]s as the matching HTML route using [`ApiUser::require`].
+
+pub mod bonus_points;
+pub mod events;
+pub mod openapi;
+pub mod people;
+pub mod rewards;
+
+use crate::{
+    auth::{api_tokens::find_api_token_owner, backend::VentAuthBackend, PermissionsTarget},
+    error::VentError,
+    state::{db_objects::AuthorisationBackendPerson, VentState},
+};
+use axum::{
+    extract::FromRequestParts,
+    response::{IntoResponse, Response},
+    routing::MethodRouter,
+    Json, Router,
+};
+use axum_login::{AuthnBackend, AuthzBackend};
+use http::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    request::Parts,
+    HeaderValue, Method, StatusCode,
+};
+use serde::Serialize;
+use snafu::{ensure, Snafu};
+use std::collections::HashSet;
+use utoipa::ToSchema;
+
+#[derive(Snafu, Debug)]
+#[snafu(visibility(pub))]
+pub enum ApiError {
+    #[snafu(display("An API token is needed in the Authorization header, as `Bearer <token>`"))]
+    MissingToken,
+    #[snafu(display("That API token isn't valid - it might have been revoked"))]
+    InvalidToken,
+    #[snafu(display("This needs the {permission:?} permission"))]
+    MissingPermission { permission: PermissionsTarget },
+    #[snafu(display("Couldn't find that {what}"))]
+    NotFound { what: &'static str },
+    #[snafu(display("{reason}"))]
+    BadRequest { reason: String },
+    #[snafu(display("{source}"), context(false))]
+    Vent { source: VentError },
+}
+
+///What gets sent back for any [`ApiError`]
+#[derive(Serialize, ToSchema)]
+pub struct ApiErrorBody {
+    #[schema(example = "Couldn't find that event")]
+    pub error: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let code = match &self {
+            Self::MissingToken | Self::InvalidToken => StatusCode::UNAUTHORIZED,
+            Self::MissingPermission { .. } => StatusCode::FORBIDDEN,
+            Self::NotFound { .. } => StatusCode::NOT_FOUND,
+            Self::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            Self::Vent { source } => source.status_code(),
+        };
+
+        //internal errors can have details about the database in them
+        let message = match &self {
+            Self::Vent { source } => {
+                if code.is_server_error() {
+                    error!(?source, "Error in API request");
+                } else {
+                    debug!(?source, "Error in API request");
+                }
+                code.canonical_reason().unwrap_or("Error").to_string()
+            }
+            _ => self.to_string(),
+        };
+
+        let mut response = (code, Json(ApiErrorBody { error: message })).into_response();
+        if code == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+///The person whose token was used for an API request, with all of their permissions
+pub struct ApiUser {
+    pub user: AuthorisationBackendPerson,
+    permissions: HashSet<PermissionsTarget>,
+}
+
+impl ApiUser {
+    pub fn has_perm(&self, permission: PermissionsTarget) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    pub fn require(&self, permission: PermissionsTarget) -> Result<(), ApiError> {
+        ensure!(
+            self.has_perm(permission),
+            MissingPermissionSnafu { permission }
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<VentState> for ApiUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &VentState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(token) = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+        else {
+            return MissingTokenSnafu.fail();
+        };
+
+        let Some(person_id) = find_api_token_owner(state, token).await? else {
+            warn!("Invalid API token used");
+            return InvalidTokenSnafu.fail();
+        };
+
+        let backend = VentAuthBackend::new(state.clone());
+        let Some(user) = backend.get_user(&person_id).await? else {
+            return InvalidTokenSnafu.fail();
+        };
+        let permissions = backend.get_all_permissions(&user).await?;
+
+        Ok(Self { user, permissions })
+    }
+}
+
+///A route under `/api/v1` - its path, the method it's for, and the handler. [`router`] gets built from these, so that the tests can check them against the [`openapi`] document.
+pub type ApiRoute = (&'static str, Method, MethodRouter<VentState>);
+
+fn v1_routes() -> Vec<ApiRoute> {
+    [
+        events::routes(),
+        people::routes(),
+        bonus_points::routes(),
+        rewards::routes(),
+    ]
+    .concat()
+}
+
+pub fn router() -> Router<VentState> {
+    let v1 = v1_routes()
+        .into_iter()
+        .fold(Router::new(), |router, (path, _, method_router)| {
+            router.route(path, method_router)
+        });
+
+    Router::new().merge(openapi::router()).nest("/api/v1", v1)
+}
@@ -0,0 +1,177 @@
+//! Bonus points, and who they were given to.
+
+use crate::{
+    api::{ApiError, ApiRoute, ApiUser, BadRequestSnafu},
+    auth::PermissionsTarget,
+    error::{SqlxAction, SqlxSnafu},
+    state::{webhooks::WebhookEventKind, VentState},
+};
+use axum::{
+    extract::State,
+    http::{Method, StatusCode},
+    routing::{get, post},
+    Json,
+};
+use chrono::{NaiveDateTime, Utc};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiBonusPoint {
+    pub id: i32,
+    pub point_date: NaiveDateTime,
+    pub staff_member_id: Option<i32>,
+    pub num_points: i32,
+    pub reason: String,
+    pub participant_ids: Vec<i32>,
+}
+
+///`GET` method for every bonus point, newest first
+///
+/// Needs `SeeBonusPoints`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/bonus_points",
+    tag = "bonus_points",
+    responses(
+        (status = 200, description = "Every bonus point", body = [ApiBonusPoint]),
+        (status = 403, description = "The token can't see bonus points", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn get_bonus_points(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+) -> Result<Json<Vec<ApiBonusPoint>>, ApiError> {
+    api_user.require(PermissionsTarget::SeeBonusPoints)?;
+
+    Ok(Json(
+        sqlx::query_as!(
+            ApiBonusPoint,
+            r#"
+SELECT bp.id, bp.point_date, bp.staff_member_id, bp.num_points, bp.reason,
+       COALESCE(array_agg(pbp.participant_id) FILTER (WHERE pbp.participant_id IS NOT NULL), '{}') AS "participant_ids!"
+FROM bonus_points bp
+LEFT JOIN participant_bonus_points pbp ON pbp.bonus_point_id = bp.id
+GROUP BY bp.id
+ORDER BY bp.point_date DESC"#
+        )
+        .fetch_all(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::GettingBonusPoints,
+        })?,
+    ))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewBonusPoint {
+    reason: String,
+    num_points: i32,
+    #[serde(default)]
+    participant_ids: Vec<i32>,
+}
+
+///`POST` method to give a bonus point, with the token's owner as the staff member
+///
+/// Needs `GiveBonusPoints`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/bonus_points",
+    tag = "bonus_points",
+    request_body = NewBonusPoint,
+    responses(
+        (status = 201, description = "The bonus point was given", body = ApiBonusPoint),
+        (status = 400, description = "There was no reason", body = crate::api::ApiErrorBody),
+        (status = 403, description = "The token can't give bonus points", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn post_give_bonus_point(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+    Json(NewBonusPoint {
+        reason,
+        num_points,
+        participant_ids,
+    }): Json<NewBonusPoint>,
+) -> Result<(StatusCode, Json<ApiBonusPoint>), ApiError> {
+    api_user.require(PermissionsTarget::GiveBonusPoints)?;
+
+    let reason = reason.trim().to_string();
+    if reason.is_empty() {
+        return BadRequestSnafu {
+            reason: "Bonus points need a reason",
+        }
+        .fail();
+    }
+
+    let point_date = Utc::now().naive_utc();
+    let staff_member_id = api_user.user.id;
+    let participant_ids = participant_ids.into_iter().unique().collect_vec();
+
+    let id = sqlx::query!(
+        r#"
+INSERT INTO public.bonus_points (point_date, staff_member_id, num_points, reason)
+VALUES ($1, $2, $3, $4)
+RETURNING id
+        "#,
+        point_date,
+        staff_member_id,
+        num_points,
+        reason
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingBonusPoint,
+    })?
+    .id;
+
+    for participant_id in &participant_ids {
+        debug!(%participant_id, bonus_point_id = %id, "Adding person to bonus point from API");
+        sqlx::query!(
+            "INSERT INTO public.participant_bonus_points (participant_id, bonus_point_id) VALUES ($1, $2)",
+            participant_id,
+            id,
+        )
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::AddingParticipantToBonusPoint {
+                person: (*participant_id).into(),
+                bonus_point_id: id,
+            },
+        })?;
+    }
+
+    state.update_events()?;
+
+    let bonus_point = ApiBonusPoint {
+        id,
+        point_date,
+        staff_member_id: Some(staff_member_id),
+        num_points,
+        reason,
+        participant_ids,
+    };
+
+    if !bonus_point.participant_ids.is_empty() {
+        state
+            .fire_webhook(WebhookEventKind::BonusPointGiven, &bonus_point)
+            .await;
+    }
+
+    Ok((StatusCode::CREATED, Json(bonus_point)))
+}
+
+pub fn routes() -> Vec<ApiRoute> {
+    vec![
+        ("/bonus_points", Method::GET, get(get_bonus_points)),
+        ("/bonus_points", Method::POST, post(post_give_bonus_point)),
+    ]
+}
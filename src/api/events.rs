@@ -0,0 +1,491 @@
+//! Events, and who is taking part in them.
+
+use crate::{
+    api::{people::ApiPersonSummary, ApiError, ApiRoute, ApiUser, BadRequestSnafu, NotFoundSnafu},
+    auth::PermissionsTarget,
+    error::{SqlxAction, SqlxSnafu},
+    state::{
+        db_objects::DbEvent,
+        sign_ups::{SignUpStatus, SignUpWindow},
+        waitlist::{leave_waitlist, sign_up, SignUpOutcome},
+        webhooks::WebhookEventKind,
+        withdrawals::remove_participant,
+        VentState,
+    },
+};
+use axum::{
+    extract::{Path, State},
+    http::{Method, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json,
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiEvent {
+    pub id: i32,
+    pub name: String,
+    pub date: NaiveDateTime,
+    pub location: String,
+    pub teacher: String,
+    pub other_info: Option<String>,
+    pub is_locked: bool,
+    pub extra_points: i32,
+    ///When the event finishes - for all-day events, this is the last day of it
+    pub end_date: Option<NaiveDateTime>,
+    pub is_all_day: bool,
+    ///The series that this is an occurrence of, if it repeats
+    pub series_id: Option<i32>,
+    ///How many people can take part before everyone else goes on the waitlist
+    pub capacity: Option<i32>,
+    ///When people can start signing up, if it isn't the default
+    pub sign_ups_open_at: Option<NaiveDateTime>,
+    ///When people can't sign up any more, if it isn't the default
+    pub sign_ups_close_at: Option<NaiveDateTime>,
+    ///When the event gets locked, if it isn't the default
+    pub locks_at: Option<NaiveDateTime>,
+}
+
+impl From<DbEvent> for ApiEvent {
+    fn from(event: DbEvent) -> Self {
+        Self {
+            id: event.id,
+            name: event.event_name,
+            date: event.date,
+            location: event.location,
+            teacher: event.teacher,
+            other_info: event.other_info,
+            is_locked: event.is_locked,
+            extra_points: event.extra_points,
+            end_date: event.end_date,
+            is_all_day: event.is_all_day,
+            series_id: event.series_id,
+            capacity: event.capacity,
+            sign_ups_open_at: event.sign_ups_open_at,
+            sign_ups_close_at: event.sign_ups_close_at,
+            locks_at: event.locks_at,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiParticipant {
+    #[serde(flatten)]
+    pub person: ApiPersonSummary,
+    pub is_verified: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiEventDetails {
+    #[serde(flatten)]
+    pub event: ApiEvent,
+    pub prefects: Vec<ApiPersonSummary>,
+    pub participants: Vec<ApiParticipant>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiParticipation {
+    pub event_id: i32,
+    pub person_id: i32,
+    pub is_verified: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiWaitlistEntry {
+    pub event_id: i32,
+    pub person_id: i32,
+    ///Where they are in the queue, starting at 1
+    pub position: i64,
+}
+
+async fn get_event(state: &VentState, event_id: i32) -> Result<DbEvent, ApiError> {
+    sqlx::query_as!(DbEvent, "SELECT * FROM events WHERE id = $1", event_id)
+        .fetch_optional(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::GettingEvent(event_id),
+        })?
+        .context(NotFoundSnafu { what: "event" })
+}
+
+///Checks that someone can add or remove `person_id` to or from an event, in the same way as the HTML routes.
+///
+/// People who can edit participants can change anyone on any unlocked event, and everyone else can only change themselves while sign-ups are open.
+fn check_can_change_participant(
+    state: &VentState,
+    api_user: &ApiUser,
+    event: &DbEvent,
+    person_id: i32,
+) -> Result<(), ApiError> {
+    if event.is_locked {
+        return BadRequestSnafu {
+            reason: "That event is locked",
+        }
+        .fail();
+    }
+
+    if api_user.has_perm(PermissionsTarget::EditParticipantsOnEvents) {
+        return Ok(());
+    }
+
+    api_user.require(PermissionsTarget::AddRmSelfToEvent)?;
+    if person_id != api_user.user.id {
+        return api_user.require(PermissionsTarget::EditParticipantsOnEvents);
+    }
+    match SignUpWindow::for_event(&state.settings.sign_ups, event).status() {
+        SignUpStatus::Open => {}
+        SignUpStatus::NotOpenYet => {
+            return BadRequestSnafu {
+                reason: "Sign-ups for that event haven't opened yet",
+            }
+            .fail()
+        }
+        SignUpStatus::Closed => {
+            return BadRequestSnafu {
+                reason: "Sign-ups for that event have closed",
+            }
+            .fail()
+        }
+    }
+
+    Ok(())
+}
+
+///`GET` method for every event, newest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/events",
+    tag = "events",
+    responses((status = 200, description = "Every event", body = [ApiEvent])),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn get_events(
+    _api_user: ApiUser,
+    State(state): State<VentState>,
+) -> Result<Json<Vec<ApiEvent>>, ApiError> {
+    Ok(Json(
+        sqlx::query_as!(DbEvent, "SELECT * FROM events ORDER BY date DESC")
+            .fetch_all(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::FindingAllEvents,
+            })?
+            .into_iter()
+            .map(ApiEvent::from)
+            .collect(),
+    ))
+}
+
+///`GET` method for one event, with its prefects and participants
+#[utoipa::path(
+    get,
+    path = "/api/v1/events/{id}",
+    tag = "events",
+    params(("id" = i32, Path, description = "The event's ID")),
+    responses(
+        (status = 200, description = "The event", body = ApiEventDetails),
+        (status = 404, description = "There's no event with that ID", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn get_event_details(
+    _api_user: ApiUser,
+    State(state): State<VentState>,
+    Path(event_id): Path<i32>,
+) -> Result<Json<ApiEventDetails>, ApiError> {
+    let event = get_event(&state, event_id).await?;
+
+    let prefects = sqlx::query_as!(
+        ApiPersonSummary,
+        r#"
+SELECT p.id, p.first_name, p.surname, p.form
+FROM people p
+INNER JOIN prefect_events pe ON pe.prefect_id = p.id
+WHERE pe.event_id = $1
+ORDER BY p.form, p.surname"#,
+        event_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingParticipantsOrPrefectsAtEvents {
+            event_id: Some(event_id),
+        },
+    })?;
+
+    let participants = sqlx::query!(
+        r#"
+SELECT p.id, p.first_name, p.surname, p.form, pe.is_verified
+FROM people p
+INNER JOIN participant_events pe ON pe.participant_id = p.id
+WHERE pe.event_id = $1
+ORDER BY p.form, p.surname"#,
+        event_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingParticipantsOrPrefectsAtEvents {
+            event_id: Some(event_id),
+        },
+    })?
+    .into_iter()
+    .map(|rec| ApiParticipant {
+        person: ApiPersonSummary {
+            id: rec.id,
+            first_name: rec.first_name,
+            surname: rec.surname,
+            form: rec.form,
+        },
+        is_verified: rec.is_verified,
+    })
+    .collect();
+
+    Ok(Json(ApiEventDetails {
+        event: event.into(),
+        prefects,
+        participants,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddParticipant {
+    ///Defaults to whoever owns the token
+    person_id: Option<i32>,
+}
+
+///`POST` method to add someone to an event - returns `201` if they were added, `202` if it was full so they went on the waitlist, or `200` if they were already there
+///
+/// Needs `AddRmSelfToEvent` to add yourself while sign-ups are open, or `EditParticipantsOnEvents` to add anyone until the event is locked.
+#[utoipa::path(
+    post,
+    path = "/api/v1/events/{id}/participants",
+    tag = "events",
+    params(("id" = i32, Path, description = "The event's ID")),
+    request_body = AddParticipant,
+    responses(
+        (status = 201, description = "They were added", body = ApiParticipation),
+        (status = 202, description = "The event was full, so they're on the waitlist", body = ApiWaitlistEntry),
+        (status = 200, description = "They were already on the event", body = ApiParticipation),
+        (status = 400, description = "The event is locked or sign-ups aren't open", body = crate::api::ApiErrorBody),
+        (status = 403, description = "The token can't add that person", body = crate::api::ApiErrorBody),
+        (status = 404, description = "There's no event with that ID", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn post_add_participant(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+    Path(event_id): Path<i32>,
+    Json(AddParticipant { person_id }): Json<AddParticipant>,
+) -> Result<Response, ApiError> {
+    let person_id = person_id.unwrap_or(api_user.user.id);
+    let event = get_event(&state, event_id).await?;
+    check_can_change_participant(&state, &api_user, &event, person_id)?;
+
+    debug!(%person_id, %event_id, "Adding participant to event from API");
+
+    match sign_up(&mut *state.get_connection().await?, event_id, person_id).await? {
+        SignUpOutcome::AlreadyIn => {
+            let existing = sqlx::query!(
+                "SELECT is_verified FROM participant_events WHERE participant_id = $1 AND event_id = $2",
+                person_id,
+                event_id
+            )
+            .fetch_one(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::FindingParticipantOrPrefect {
+                    person: person_id.into(),
+                    event_id,
+                },
+            })?;
+
+            Ok((
+                StatusCode::OK,
+                Json(ApiParticipation {
+                    event_id,
+                    person_id,
+                    is_verified: existing.is_verified,
+                }),
+            )
+                .into_response())
+        }
+        SignUpOutcome::Waitlisted { position } => Ok((
+            StatusCode::ACCEPTED,
+            Json(ApiWaitlistEntry {
+                event_id,
+                person_id,
+                position,
+            }),
+        )
+            .into_response()),
+        SignUpOutcome::Added => {
+            state.update_events()?;
+
+            let participation = ApiParticipation {
+                event_id,
+                person_id,
+                is_verified: false,
+            };
+            state
+                .fire_webhook(WebhookEventKind::ParticipantAdded, &participation)
+                .await;
+
+            Ok((StatusCode::CREATED, Json(participation)).into_response())
+        }
+    }
+}
+
+///`DELETE` method to take someone off an event or its waitlist - if that frees up a place, the next person on the waitlist gets it
+///
+/// Needs the same permissions as adding them. Taking someone off an event gets recorded in its withdrawals, rather than forgetting that they were on it.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/events/{id}/participants/{person_id}",
+    tag = "events",
+    params(
+        ("id" = i32, Path, description = "The event's ID"),
+        ("person_id" = i32, Path, description = "The participant's ID"),
+    ),
+    responses(
+        (status = 204, description = "They were taken off the event or its waitlist"),
+        (status = 400, description = "The event is locked or sign-ups aren't open", body = crate::api::ApiErrorBody),
+        (status = 403, description = "The token can't remove that person", body = crate::api::ApiErrorBody),
+        (status = 404, description = "They weren't on the event or its waitlist", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn delete_participant(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+    Path((event_id, person_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, ApiError> {
+    let event = get_event(&state, event_id).await?;
+    check_can_change_participant(&state, &api_user, &event, person_id)?;
+
+    let removed = remove_participant(
+        &mut *state.get_connection().await?,
+        event_id,
+        person_id,
+        api_user.user.id,
+    )
+    .await?;
+
+    if !removed {
+        if leave_waitlist(&mut *state.get_connection().await?, event_id, person_id).await? {
+            return Ok(StatusCode::NO_CONTENT);
+        }
+
+        return NotFoundSnafu {
+            what: "participant",
+        }
+        .fail();
+    }
+
+    state.fill_places(event_id).await?;
+    state.update_events()?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Verification {
+    is_verified: bool,
+}
+
+///`POST` method to verify or unverify someone's participation
+///
+/// Needs `VerifyEvents`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/events/{id}/participants/{person_id}/verification",
+    tag = "events",
+    params(
+        ("id" = i32, Path, description = "The event's ID"),
+        ("person_id" = i32, Path, description = "The participant's ID"),
+    ),
+    request_body = Verification,
+    responses(
+        (status = 200, description = "Their participation was updated", body = ApiParticipation),
+        (status = 403, description = "The token can't verify events", body = crate::api::ApiErrorBody),
+        (status = 404, description = "They weren't on the event", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn post_verification(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+    Path((event_id, person_id)): Path<(i32, i32)>,
+    Json(Verification { is_verified }): Json<Verification>,
+) -> Result<Json<ApiParticipation>, ApiError> {
+    api_user.require(PermissionsTarget::VerifyEvents)?;
+
+    let was_verified = sqlx::query!(
+        r#"
+WITH old AS (SELECT is_verified FROM participant_events WHERE event_id = $1 AND participant_id = $2)
+UPDATE participant_events SET is_verified = $3
+WHERE event_id = $1 AND participant_id = $2
+RETURNING (SELECT is_verified FROM old) AS "was_verified!""#,
+        event_id,
+        person_id,
+        is_verified
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingParticipantOrPrefect {
+            person: person_id.into(),
+            event_id,
+        },
+    })?
+    .context(NotFoundSnafu {
+        what: "participant",
+    })?
+    .was_verified;
+
+    let participation = ApiParticipation {
+        event_id,
+        person_id,
+        is_verified,
+    };
+
+    if is_verified && !was_verified {
+        state
+            .fire_webhook(WebhookEventKind::ParticipantVerified, &participation)
+            .await;
+    }
+
+    Ok(Json(participation))
+}
+
+pub fn routes() -> Vec<ApiRoute> {
+    vec![
+        ("/events", Method::GET, get(get_events)),
+        ("/events/:id", Method::GET, get(get_event_details)),
+        (
+            "/events/:id/participants",
+            Method::POST,
+            post(post_add_participant),
+        ),
+        (
+            "/events/:id/participants/:person_id",
+            Method::DELETE,
+            delete(delete_participant),
+        ),
+        (
+            "/events/:id/participants/:person_id/verification",
+            Method::POST,
+            post(post_verification),
+        ),
+    ]
+}
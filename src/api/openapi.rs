@@ -0,0 +1,384 @@
+//! An [OpenAPI 3](https://spec.openapis.org/oas/v3.0.3) document for the API, generated from the `#[utoipa::path]` attributes on the handlers and the types they take and return, so that it stays in step with them.
+//!
+//! It gets served as JSON on `/api/openapi.json` for generating clients, and rendered into a page on `/api/docs`. The HTML forms that take [`FormEvent`], [`FormPerson`] and [`FormBonusPoint`] are in there too, under `forms`.
+
+//the `OpenApi` derive uses `for_each` in what it generates
+#![allow(clippy::needless_for_each)]
+
+use crate::{
+    api::{bonus_points, events, people, rewards, ApiErrorBody},
+    auth::{backend::Auth, get_auth_object},
+    error::{SerdeJsonAction, SerdeJsonSnafu, VentError},
+    routes::{
+        add_event, add_person, give_bonus_point, rewards::Reward, FormBonusPoint, FormEvent,
+        FormPerson,
+    },
+    state::{event_series::SeriesFrequency, VentState},
+};
+use axum::{extract::State, response::IntoResponse, routing::get, Json, Router};
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::Value;
+use snafu::ResultExt;
+use tower_sessions::Session;
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        OpenApi as OpenApiDocument,
+    },
+    Modify, OpenApi,
+};
+
+///The name of the session cookie, from `tower_sessions`
+const SESSION_COOKIE: &str = "id";
+///The HTTP methods that can be under a path in the document, in the order they're shown
+const METHODS: &[&str] = &["get", "post", "put", "patch", "delete"];
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Vent",
+        description = "Everything that the JSON API under `/api/v1` can do. Make a token on your profile, and send it as `Authorization: Bearer <token>` - each request can do whatever you could do on the site."
+    ),
+    paths(
+        events::get_events,
+        events::get_event_details,
+        events::post_add_participant,
+        events::delete_participant,
+        events::post_verification,
+        people::get_me,
+        people::get_people,
+        people::get_person,
+        bonus_points::get_bonus_points,
+        bonus_points::post_give_bonus_point,
+        rewards::get_rewards,
+        rewards::get_rewards_received,
+        rewards::post_reward_received,
+        add_event::post_add_event_form,
+        add_person::post_add_person,
+        give_bonus_point::post_give_bonus_points_form,
+    ),
+    components(schemas(
+        ApiErrorBody,
+        events::ApiEvent,
+        events::ApiEventDetails,
+        events::ApiParticipant,
+        events::ApiParticipation,
+        events::ApiWaitlistEntry,
+        events::AddParticipant,
+        events::Verification,
+        people::ApiPersonSummary,
+        people::ApiPersonEvent,
+        people::ApiPersonDetails,
+        bonus_points::ApiBonusPoint,
+        bonus_points::NewBonusPoint,
+        rewards::ApiRewardReceived,
+        Reward,
+        FormEvent,
+        FormPerson,
+        FormBonusPoint,
+        SeriesFrequency,
+    )),
+    modifiers(&SecuritySchemes),
+    tags(
+        (name = "events", description = "Events, and who is taking part in them"),
+        (name = "people", description = "People, and what they've done"),
+        (name = "bonus_points", description = "Bonus points, and who they were given to"),
+        (name = "rewards", description = "Rewards, and who has received them"),
+        (name = "forms", description = "The forms behind some of the site's pages. These need a logged in session and a `csrf_token` field rather than an API token."),
+    )
+)]
+struct ApiDoc;
+
+///Adds the two ways of authenticating - API tokens and session cookies
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut OpenApiDocument) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+            components.add_security_scheme(
+                "session",
+                SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(SESSION_COOKIE))),
+            );
+        }
+    }
+}
+
+static DOCUMENT: Lazy<OpenApiDocument> = Lazy::new(ApiDoc::openapi);
+
+#[derive(Serialize)]
+struct DocResponse {
+    status: String,
+    description: String,
+    body: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DocOperation {
+    method: String,
+    path: String,
+    tag: String,
+    summary: String,
+    description: Option<String>,
+    request_body: Option<String>,
+    responses: Vec<DocResponse>,
+}
+
+#[derive(Serialize)]
+struct DocProperty {
+    name: String,
+    kind: String,
+    is_required: bool,
+    description: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DocSchema {
+    name: String,
+    ///Other schemas that this one has all of the properties of
+    includes: Vec<String>,
+    properties: Vec<DocProperty>,
+}
+
+fn get_str<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.get(key).and_then(Value::as_str)
+}
+
+///Gets the name of a schema from a reference to it, like `#/components/schemas/ApiEvent`
+fn schema_name(reference: &str) -> String {
+    reference
+        .rsplit('/')
+        .next()
+        .unwrap_or(reference)
+        .to_string()
+}
+
+///Gets a short description of a schema's type, like `ApiEvent[]` or `string (date-time)`
+fn describe_schema(schema: &Value) -> String {
+    if let Some(reference) = get_str(schema, "$ref") {
+        return schema_name(reference);
+    }
+    if let Some(all_of) = schema.get("allOf").and_then(Value::as_array) {
+        return all_of.iter().map(describe_schema).join(" + ");
+    }
+
+    match get_str(schema, "type") {
+        Some("array") => format!(
+            "{}[]",
+            schema
+                .get("items")
+                .map_or_else(|| "any".to_string(), describe_schema)
+        ),
+        Some(kind) => match get_str(schema, "format") {
+            Some(format) => format!("{kind} ({format})"),
+            None => kind.to_string(),
+        },
+        None => "object".to_string(),
+    }
+}
+
+///Describes the first schema in a `content` object, along with its content type
+fn describe_content(content: Option<&Value>) -> Option<String> {
+    let (content_type, media) = content?.as_object()?.iter().next()?;
+    let schema = media
+        .get("schema")
+        .map_or_else(|| "any".to_string(), describe_schema);
+    Some(format!("{schema} as {content_type}"))
+}
+
+fn collect_properties(
+    schema: &Value,
+    includes: &mut Vec<String>,
+    properties: &mut Vec<DocProperty>,
+) {
+    if let Some(reference) = get_str(schema, "$ref") {
+        includes.push(schema_name(reference));
+        return;
+    }
+    if let Some(all_of) = schema.get("allOf").and_then(Value::as_array) {
+        for part in all_of {
+            collect_properties(part, includes, properties);
+        }
+        return;
+    }
+
+    let required = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|required| required.iter().filter_map(Value::as_str).collect_vec())
+        .unwrap_or_default();
+
+    for (name, property) in schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+    {
+        properties.push(DocProperty {
+            name: name.clone(),
+            kind: describe_schema(property),
+            is_required: required.contains(&name.as_str()),
+            description: get_str(property, "description").map(ToString::to_string),
+        });
+    }
+}
+
+fn get_operations(document: &Value) -> Vec<DocOperation> {
+    let mut operations = vec![];
+
+    for (path, item) in document
+        .get("paths")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+    {
+        for method in METHODS {
+            let Some(operation) = item.get(*method) else {
+                continue;
+            };
+
+            let responses = operation
+                .get("responses")
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+                .map(|(status, response)| DocResponse {
+                    status: status.clone(),
+                    description: get_str(response, "description")
+                        .unwrap_or_default()
+                        .to_string(),
+                    body: describe_content(response.get("content")),
+                })
+                .collect();
+
+            operations.push(DocOperation {
+                method: method.to_uppercase(),
+                path: path.clone(),
+                tag: operation
+                    .get("tags")
+                    .and_then(|tags| tags.get(0))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                summary: get_str(operation, "summary")
+                    .unwrap_or_default()
+                    .to_string(),
+                description: get_str(operation, "description").map(ToString::to_string),
+                request_body: describe_content(
+                    operation
+                        .get("requestBody")
+                        .and_then(|body| body.get("content")),
+                ),
+                responses,
+            });
+        }
+    }
+
+    operations
+}
+
+fn get_schemas(document: &Value) -> Vec<DocSchema> {
+    document
+        .get("components")
+        .and_then(|components| components.get("schemas"))
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(name, schema)| {
+            let mut includes = vec![];
+            let mut properties = vec![];
+            collect_properties(schema, &mut includes, &mut properties);
+
+            DocSchema {
+                name: name.clone(),
+                includes,
+                properties,
+            }
+        })
+        .collect()
+}
+
+///`GET` method for the document, as JSON
+#[axum::debug_handler]
+async fn get_openapi_json() -> impl IntoResponse {
+    Json(&*DOCUMENT)
+}
+
+///`GET` method for a readable version of the document
+#[axum::debug_handler]
+async fn get_api_docs(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let aa = get_auth_object(auth, &session).await?;
+
+    let document = serde_json::to_value(&*DOCUMENT).context(SerdeJsonSnafu {
+        action: SerdeJsonAction::OpenApiDocument,
+    })?;
+
+    state
+        .compile(
+            "www/api_docs.liquid",
+            liquid::object!({
+                "auth": aa,
+                "version": DOCUMENT.info.version.clone(),
+                "operations": get_operations(&document),
+                "schemas": get_schemas(&document),
+            }),
+            Some("API Docs".to_string()),
+        )
+        .await
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/api/openapi.json", get(get_openapi_json))
+        .route("/api/docs", get(get_api_docs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::v1_routes;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn document_matches_router() {
+        //axum has `/:id` where OpenAPI has `/{id}`
+        let routed: BTreeSet<(String, String)> = v1_routes()
+            .into_iter()
+            .map(|(path, method, _)| {
+                let path = path
+                    .split('/')
+                    .map(|segment| match segment.strip_prefix(':') {
+                        Some(parameter) => format!("{{{parameter}}}"),
+                        None => segment.to_string(),
+                    })
+                    .join("/");
+                (format!("/api/v1{path}"), method.as_str().to_lowercase())
+            })
+            .collect();
+
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let documented: BTreeSet<(String, String)> = document["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .filter(|(path, _)| path.starts_with("/api/"))
+            .flat_map(|(path, item)| {
+                METHODS
+                    .iter()
+                    .filter(|method| item.get(**method).is_some())
+                    .map(|method| (path.clone(), (*method).to_string()))
+            })
+            .collect();
+
+        assert_eq!(routed, documented);
+    }
+}
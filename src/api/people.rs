@@ -0,0 +1,233 @@
+//! People, and what they've done.
+
+use crate::{
+    api::{ApiError, ApiRoute, ApiUser, NotFoundSnafu},
+    auth::PermissionsTarget,
+    error::{SqlxAction, SqlxSnafu},
+    routes::rewards::Reward,
+    state::VentState,
+};
+use axum::{
+    extract::{Path, State},
+    http::Method,
+    routing::get,
+    Json,
+};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use snafu::{OptionExt, ResultExt};
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiPersonSummary {
+    pub id: i32,
+    pub first_name: String,
+    pub surname: String,
+    pub form: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiPersonEvent {
+    pub id: i32,
+    pub name: String,
+    pub date: NaiveDateTime,
+    pub is_verified: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiPersonDetails {
+    #[serde(flatten)]
+    pub person: ApiPersonSummary,
+    pub username: String,
+    pub was_first_entry: bool,
+    pub events_participated: Vec<ApiPersonEvent>,
+    pub events_supervised: Vec<ApiPersonEvent>,
+    ///Verified events plus bonus points, like on the profile page
+    pub points: i64,
+    pub rewards: Vec<Reward>,
+}
+
+async fn get_person_details(
+    state: &VentState,
+    person_id: i32,
+) -> Result<ApiPersonDetails, ApiError> {
+    let person = sqlx::query!(
+        "SELECT id, first_name, surname, form, username, was_first_entry FROM people WHERE id = $1",
+        person_id
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(person_id.into()),
+    })?
+    .context(NotFoundSnafu { what: "person" })?;
+
+    let events_participated = sqlx::query_as!(
+        ApiPersonEvent,
+        r#"
+SELECT e.id, e.event_name AS name, e.date, pe.is_verified
+FROM events e
+INNER JOIN participant_events pe ON pe.event_id = e.id
+WHERE pe.participant_id = $1
+ORDER BY e.date DESC"#,
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingEventsOnPeople {
+            person: person_id.into(),
+        },
+    })?;
+
+    let events_supervised = sqlx::query_as!(
+        ApiPersonEvent,
+        r#"
+SELECT e.id, e.event_name AS name, e.date, true AS "is_verified!"
+FROM events e
+INNER JOIN prefect_events pe ON pe.event_id = e.id
+WHERE pe.prefect_id = $1
+ORDER BY e.date DESC"#,
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingEventsOnPeople {
+            person: person_id.into(),
+        },
+    })?;
+
+    let bonus_points = sqlx::query!(
+        r#"
+SELECT COALESCE(SUM(bp.num_points), 0) AS "total!"
+FROM participant_bonus_points pbp
+INNER JOIN bonus_points bp ON bp.id = pbp.bonus_point_id
+WHERE pbp.participant_id = $1"#,
+        person_id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingBonusPoints,
+    })?
+    .total;
+
+    let rewards = sqlx::query_as!(
+        Reward,
+        "SELECT name, first_entry_pts, second_entry_pts, id FROM rewards_received rr INNER JOIN rewards r ON r.id = rr.reward_id AND rr.person_id = $1",
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingRewardsReceived(Some(person_id.into())),
+    })?;
+
+    let verified_events = events_participated
+        .iter()
+        .filter(|event| event.is_verified)
+        .count();
+
+    Ok(ApiPersonDetails {
+        person: ApiPersonSummary {
+            id: person.id,
+            first_name: person.first_name,
+            surname: person.surname,
+            form: person.form,
+        },
+        username: person.username,
+        was_first_entry: person.was_first_entry,
+        events_participated,
+        events_supervised,
+        points: i64::try_from(verified_events)
+            .unwrap_or(i64::MAX)
+            .saturating_add(bonus_points),
+        rewards,
+    })
+}
+
+///`GET` method for whoever owns the token
+#[utoipa::path(
+    get,
+    path = "/api/v1/me",
+    tag = "people",
+    responses((status = 200, description = "Whoever owns the token", body = ApiPersonDetails)),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn get_me(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+) -> Result<Json<ApiPersonDetails>, ApiError> {
+    Ok(Json(get_person_details(&state, api_user.user.id).await?))
+}
+
+///`GET` method for everyone, sorted by form then surname
+///
+/// Needs `SeePeople`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/people",
+    tag = "people",
+    responses(
+        (status = 200, description = "Everyone", body = [ApiPersonSummary]),
+        (status = 403, description = "The token can't see people", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn get_people(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+) -> Result<Json<Vec<ApiPersonSummary>>, ApiError> {
+    api_user.require(PermissionsTarget::SeePeople)?;
+
+    Ok(Json(
+        sqlx::query_as!(
+            ApiPersonSummary,
+            "SELECT id, first_name, surname, form FROM people ORDER BY form, surname"
+        )
+        .fetch_all(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingPeople,
+        })?,
+    ))
+}
+
+///`GET` method for one person - anyone can see themselves
+///
+/// Needs `SeePeople` for anyone else.
+#[utoipa::path(
+    get,
+    path = "/api/v1/people/{id}",
+    tag = "people",
+    params(("id" = i32, Path, description = "The person's ID")),
+    responses(
+        (status = 200, description = "The person", body = ApiPersonDetails),
+        (status = 403, description = "The token can't see other people", body = crate::api::ApiErrorBody),
+        (status = 404, description = "There's no person with that ID", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn get_person(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+    Path(person_id): Path<i32>,
+) -> Result<Json<ApiPersonDetails>, ApiError> {
+    if person_id != api_user.user.id {
+        api_user.require(PermissionsTarget::SeePeople)?;
+    }
+
+    Ok(Json(get_person_details(&state, person_id).await?))
+}
+
+pub fn routes() -> Vec<ApiRoute> {
+    vec![
+        ("/me", Method::GET, get(get_me)),
+        ("/people", Method::GET, get(get_people)),
+        ("/people/:id", Method::GET, get(get_person)),
+    ]
+}
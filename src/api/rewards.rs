@@ -0,0 +1,152 @@
+//! Rewards, and who has received them.
+
+use crate::{
+    api::{ApiError, ApiRoute, ApiUser},
+    auth::PermissionsTarget,
+    error::{SqlxAction, SqlxSnafu},
+    routes::rewards::Reward,
+    state::{webhooks::WebhookEventKind, VentState},
+};
+use axum::{
+    extract::State,
+    http::{Method, StatusCode},
+    routing::{get, post},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use utoipa::ToSchema;
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ApiRewardReceived {
+    pub reward_id: i32,
+    pub person_id: i32,
+}
+
+///`GET` method for every reward
+#[utoipa::path(
+    get,
+    path = "/api/v1/rewards",
+    tag = "rewards",
+    responses((status = 200, description = "Every reward", body = [Reward])),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn get_rewards(
+    _api_user: ApiUser,
+    State(state): State<VentState>,
+) -> Result<Json<Vec<Reward>>, ApiError> {
+    Ok(Json(
+        sqlx::query_as!(Reward, "SELECT * FROM rewards ORDER BY id")
+            .fetch_all(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::GettingRewards,
+            })?,
+    ))
+}
+
+///`GET` method for every reward that has been given out
+///
+/// Needs `AddRewards`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/rewards/received",
+    tag = "rewards",
+    responses(
+        (status = 200, description = "Every reward given out", body = [ApiRewardReceived]),
+        (status = 403, description = "The token can't see rewards", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn get_rewards_received(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+) -> Result<Json<Vec<ApiRewardReceived>>, ApiError> {
+    api_user.require(PermissionsTarget::AddRewards)?;
+
+    Ok(Json(
+        sqlx::query_as!(
+            ApiRewardReceived,
+            "SELECT reward_id, person_id FROM rewards_received ORDER BY person_id, reward_id"
+        )
+        .fetch_all(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::GettingRewardsReceived(None),
+        })?,
+    ))
+}
+
+///`POST` method to mark a reward as given - returns `201` if it's new, or `200` if they already had it
+///
+/// Needs `AddRewards`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/rewards/received",
+    tag = "rewards",
+    request_body = ApiRewardReceived,
+    responses(
+        (status = 201, description = "The reward was given", body = ApiRewardReceived),
+        (status = 200, description = "They already had the reward", body = ApiRewardReceived),
+        (status = 403, description = "The token can't give rewards", body = crate::api::ApiErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+#[axum::debug_handler]
+async fn post_reward_received(
+    api_user: ApiUser,
+    State(state): State<VentState>,
+    Json(received): Json<ApiRewardReceived>,
+) -> Result<(StatusCode, Json<ApiRewardReceived>), ApiError> {
+    api_user.require(PermissionsTarget::AddRewards)?;
+    let ApiRewardReceived {
+        reward_id,
+        person_id,
+    } = received;
+
+    if sqlx::query!(
+        "SELECT reward_id FROM rewards_received WHERE reward_id = $1 AND person_id = $2",
+        reward_id,
+        person_id
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingRewardsReceived(Some(person_id.into())),
+    })?
+    .is_some()
+    {
+        return Ok((StatusCode::OK, Json(received)));
+    }
+
+    sqlx::query!(
+        "INSERT INTO rewards_received (reward_id, person_id) VALUES ($1, $2)",
+        reward_id,
+        person_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingReward,
+    })?;
+
+    state
+        .fire_webhook(WebhookEventKind::RewardAwarded, &received)
+        .await;
+
+    Ok((StatusCode::CREATED, Json(received)))
+}
+
+pub fn routes() -> Vec<ApiRoute> {
+    vec![
+        ("/rewards", Method::GET, get(get_rewards)),
+        ("/rewards/received", Method::GET, get(get_rewards_received)),
+        (
+            "/rewards/received",
+            Method::POST,
+            post(post_reward_received),
+        ),
+    ]
+}
@@ -1,38 +1,93 @@
-#![allow(clippy::match_same_arms)]
-
 pub mod add_password;
+pub mod api_tokens;
 pub mod backend;
-pub mod cloudflare_turnstile;
+pub mod captcha;
+pub mod csrf;
+pub mod impersonation;
 pub mod login;
+pub mod magic_link;
+pub mod oidc;
+pub mod passkeys;
+pub mod password_hash;
+pub mod password_policy;
 pub mod pg_session;
+pub mod rate_limit;
+pub mod remote_ip;
+pub mod sessions;
+pub mod two_factor;
 
-use crate::{auth::backend::Auth, error::VentError};
+use crate::{
+    auth::{backend::Auth, impersonation::get_impersonation},
+    error::{SqlxAction, SqlxSnafu, VentError},
+};
 use axum_login::AuthzBackend;
 use heck::AsSnakeCase;
 use itertools::Itertools;
 use liquid::{model::Value, Object};
 use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use sqlx::{pool::PoolConnection, PgConnection, Postgres};
 use std::collections::HashSet;
 use strum::IntoEnumIterator;
+use tower_sessions::Session;
 
-#[derive(
-    sqlx::Type, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize, Debug,
-)]
-#[sqlx(type_name = "user_role", rename_all = "lowercase")]
-pub enum PermissionsRole {
-    Participant,
-    Prefect,
-    Admin,
-    Dev,
+///A role from the `roles` table - which [`PermissionsTarget`]s each role has are stored in `role_permissions`, and edited by devs on `/roles`
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PermissionsRole {
+    pub id: i32,
+    pub name: String,
+    pub requires_2fa: bool,
 }
 
-impl PermissionsRole {
-    pub fn can(self) -> HashSet<PermissionsTarget> {
-        PermissionsTarget::iter().filter(|x| x.can(self)).collect()
-    }
+pub async fn get_roles(
+    mut conn: PoolConnection<Postgres>,
+) -> Result<Vec<PermissionsRole>, VentError> {
+    sqlx::query_as!(
+        PermissionsRole,
+        "SELECT id, name, requires_2fa FROM roles ORDER BY id"
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingRoles,
+    })
+}
+
+pub async fn get_role_permissions(
+    mut conn: PoolConnection<Postgres>,
+    role_id: i32,
+) -> Result<HashSet<PermissionsTarget>, VentError> {
+    Ok(sqlx::query!(
+        "SELECT permission FROM role_permissions WHERE role_id = $1",
+        role_id
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingRolePermissions(role_id),
+    })?
+    .into_iter()
+    .filter_map(|rec| match rec.permission.parse() {
+        Ok(x) => Some(x),
+        Err(_e) => {
+            warn!(?rec.permission, %role_id, "Unknown role permission in DB");
+            None
+        }
+    })
+    .collect())
 }
 
-#[derive(strum::EnumIter, strum::IntoStaticStr, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(
+    strum::EnumIter,
+    strum::IntoStaticStr,
+    strum::EnumString,
+    Copy,
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    Hash,
+)]
 pub enum PermissionsTarget {
     DevAccess,
     ImportCSV,
@@ -53,31 +108,91 @@ pub enum PermissionsTarget {
     SeeBonusPoints,
 }
 
-impl PermissionsTarget {
-    pub fn can(self, role: PermissionsRole) -> bool {
-        match self {
-            PermissionsTarget::DevAccess => role >= PermissionsRole::Dev,
-            PermissionsTarget::ImportCSV => role >= PermissionsRole::Admin,
-            PermissionsTarget::ExportCSV => role >= PermissionsRole::Admin,
-            PermissionsTarget::RunMigrations => role >= PermissionsRole::Admin,
-            PermissionsTarget::EditPeople => role >= PermissionsRole::Admin,
-            PermissionsTarget::AddRewards => role >= PermissionsRole::Admin,
-            PermissionsTarget::EditEvents => role >= PermissionsRole::Prefect,
-            PermissionsTarget::ViewPhotoAdders => role >= PermissionsRole::Prefect,
-            PermissionsTarget::EditPrefectsOnEvents => role >= PermissionsRole::Prefect,
-            PermissionsTarget::EditParticipantsOnEvents => role >= PermissionsRole::Prefect,
-            PermissionsTarget::VerifyEvents => role >= PermissionsRole::Prefect,
-            PermissionsTarget::AddRmSelfToEvent => role >= PermissionsRole::Participant,
-            PermissionsTarget::SeePhotos => role >= PermissionsRole::Participant,
-            PermissionsTarget::AddPhotos => role >= PermissionsRole::Prefect,
-            PermissionsTarget::SeePeople => role >= PermissionsRole::Prefect,
-            PermissionsTarget::GiveBonusPoints => role >= PermissionsRole::Admin,
-            PermissionsTarget::SeeBonusPoints => role >= PermissionsRole::Participant,
+///Permissions that have been granted or revoked for one specific person, on top of what their [`PermissionsRole`] gives them
+#[derive(Debug, Default, Clone)]
+pub struct IndividualPermissions {
+    pub granted: HashSet<PermissionsTarget>,
+    pub denied: HashSet<PermissionsTarget>,
+}
+
+impl IndividualPermissions {
+    ///Applies the grants and denials to the permissions from a role. Denials win over grants.
+    pub fn apply(&self, mut from_role: HashSet<PermissionsTarget>) -> HashSet<PermissionsTarget> {
+        from_role.extend(self.granted.iter().copied());
+        from_role.retain(|x| !self.denied.contains(x));
+        from_role
+    }
+
+    ///Whether a permission has been granted (`Some(true)`), denied (`Some(false)`) or left to their role (`None`)
+    pub fn get(&self, target: PermissionsTarget) -> Option<bool> {
+        if self.granted.contains(&target) {
+            Some(true)
+        } else if self.denied.contains(&target) {
+            Some(false)
+        } else {
+            None
         }
     }
 }
 
-pub async fn get_auth_object(auth: Auth) -> Result<Object, VentError> {
+pub async fn get_individual_permissions(
+    mut conn: PoolConnection<Postgres>,
+    person_id: i32,
+) -> Result<IndividualPermissions, VentError> {
+    let mut individual = IndividualPermissions::default();
+
+    for rec in sqlx::query!(
+        "SELECT permission, is_granted FROM person_permissions WHERE person_id = $1",
+        person_id
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingIndividualPermissions(person_id.into()),
+    })? {
+        let Ok(permission) = rec.permission.parse::<PermissionsTarget>() else {
+            warn!(?rec.permission, %person_id, "Unknown individual permission in DB");
+            continue;
+        };
+
+        if rec.is_granted {
+            individual.granted.insert(permission);
+        } else {
+            individual.denied.insert(permission);
+        }
+    }
+
+    Ok(individual)
+}
+
+///Gets everyone who has a permission, from either their role or their individual permissions - the same as checking [`VentAuthBackend::get_all_permissions`](axum_login::AuthzBackend::get_all_permissions) for each person, but in one query.
+pub async fn get_people_with_permission(
+    conn: &mut PgConnection,
+    permission: PermissionsTarget,
+) -> Result<HashSet<i32>, VentError> {
+    let name: &'static str = permission.into();
+
+    Ok(sqlx::query!(
+        r#"
+SELECT p.id
+FROM people p
+WHERE COALESCE(
+    (SELECT pp.is_granted FROM person_permissions pp WHERE pp.person_id = p.id AND pp.permission = $1),
+    EXISTS(SELECT 1 FROM role_permissions rp WHERE rp.role_id = p.role_id AND rp.permission = $1)
+)"#,
+        name
+    )
+    .fetch_all(conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPeople,
+    })?
+    .into_iter()
+    .map(|rec| rec.id)
+    .collect())
+}
+
+pub async fn get_auth_object(auth: Auth, session: &Session) -> Result<Object, VentError> {
     let iter = PermissionsTarget::iter().map(|x| {
         let pre_snake_case: &'static str = x.into();
         (
@@ -102,7 +217,13 @@ pub async fn get_auth_object(auth: Auth) -> Result<Object, VentError> {
                 perms.insert(snake, Value::Scalar(allowed.contains(&variant).into()));
             }
 
-            Ok(liquid::object!({"is_logged_in": true, "permissions": perms, "user": x}))
+            let impersonator = get_impersonation(session)
+                .await?
+                .map(|impersonation| impersonation.impersonator_name);
+
+            Ok(
+                liquid::object!({"is_logged_in": true, "permissions": perms, "user": x, "impersonator": impersonator}),
+            )
         }
         None => {
             let mut perms = Object::new();
@@ -110,7 +231,9 @@ pub async fn get_auth_object(auth: Auth) -> Result<Object, VentError> {
                 perms.insert(snake, Value::Scalar(false.into()));
             }
 
-            Ok(liquid::object!({"is_logged_in": false, "permissions": perms}))
+            Ok(
+                liquid::object!({"is_logged_in": false, "permissions": perms, "impersonator": None::<String>}),
+            )
         }
     }
 }
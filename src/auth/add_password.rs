@@ -1,35 +1,152 @@
 use crate::{
     auth::{
         backend::{Auth, VentAuthBackend},
-        cloudflare_turnstile::{verify_turnstile, GrabCFRemoteIP},
-        get_auth_object, PermissionsTarget,
+        get_auth_object,
+        login::login_or_second_factor,
+        password_hash::hash_password,
+        password_policy::password_problems,
+        rate_limit::{is_locked_out, record_failure, Attempter},
+        remote_ip::RemoteIp,
+        two_factor::get_two_factor_state,
+        PermissionsTarget,
     },
     error::{SqlxAction, SqlxSnafu, VentError},
-    state::{db_objects::DbPerson, mail::EmailToSend, VentState},
+    state::{
+        db_objects::{AuthorisationBackendPerson, DbPerson},
+        mail::{EmailKind, EmailToSend},
+        VentState,
+    },
 };
 use axum::{
     extract::{Path, Query, State},
     response::{IntoResponse, Redirect},
-    routing::get,
+    routing::{get, post},
     Form, Router,
 };
 use axum_login::permission_required;
-use bcrypt::{hash, DEFAULT_COST};
-use itertools::Itertools;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use rand::{thread_rng, Rng};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use snafu::ResultExt;
-use sqlx::{pool::PoolConnection, Postgres};
+use sqlx::{pool::PoolConnection, Connection, Postgres};
 use std::time::Duration;
 use tokio::time::sleep;
+use tower_sessions::Session;
+
+///How long a password reset link works for
+pub const RESET_TOKEN_LIFETIME_HOURS: i32 = 48;
+
+///Reset tokens are random enough that they don't need a slow hash like Argon2 - just enough that the database doesn't hold working links.
+fn hash_reset_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+enum ResetTokenStatus {
+    Valid,
+    Expired,
+    Missing,
+}
+
+impl ResetTokenStatus {
+    fn failure_redirect(&self) -> Option<&'static str> {
+        match self {
+            Self::Valid => None,
+            Self::Expired => Some("/login_failure/expired_link"),
+            Self::Missing => Some("/login_failure/failed_numbers"),
+        }
+    }
+}
+
+async fn check_reset_token(
+    state: &VentState,
+    person_id: i32,
+    token: &str,
+) -> Result<ResetTokenStatus, VentError> {
+    let hashed_token = hash_reset_token(token);
+
+    let Some(rec) = sqlx::query!(
+        r#"
+SELECT used_at IS NULL AND expires_at > now() AS "is_current!"
+FROM password_reset_tokens
+WHERE person_id = $1 AND hashed_token = $2"#,
+        person_id,
+        hashed_token
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPasswordResetTokens,
+    })?
+    else {
+        return Ok(ResetTokenStatus::Missing);
+    };
+
+    Ok(if rec.is_current {
+        ResetTokenStatus::Valid
+    } else {
+        ResetTokenStatus::Expired
+    })
+}
+
+///Uses up a reset token, returning whether it was still valid. This is one statement, so two requests with the same token can't both use it.
+async fn consume_reset_token(
+    state: &VentState,
+    person_id: i32,
+    token: &str,
+) -> Result<bool, VentError> {
+    let hashed_token = hash_reset_token(token);
+
+    Ok(sqlx::query!(
+        r#"
+UPDATE password_reset_tokens
+SET used_at = now()
+WHERE person_id = $1 AND hashed_token = $2 AND used_at IS NULL AND expires_at > now()
+RETURNING person_id"#,
+        person_id,
+        hashed_token
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPasswordResetTokens(person_id.into()),
+    })?
+    .is_some())
+}
+
+///Checks a reset token with the same throttling as logging in, and returns where to redirect to if it didn't work
+async fn check_reset_token_throttled(
+    state: &VentState,
+    person_id: i32,
+    token: &str,
+    remote_ip: &RemoteIp,
+) -> Result<Option<&'static str>, VentError> {
+    let attempters = [
+        Attempter::Ip(remote_ip.to_string()),
+        Attempter::for_person(state, person_id).await?,
+    ];
+    if is_locked_out(state, &attempters).await? {
+        return Ok(Some("/login_failure/locked_out"));
+    }
+
+    let failure = check_reset_token(state, person_id, token)
+        .await?
+        .failure_redirect();
+    if failure.is_some() {
+        record_failure(state, &attempters).await?;
+    }
+
+    Ok(failure)
+}
 
 //tried to use an Option<Path<_>>, but didn't work
 #[axum::debug_handler]
 async fn get_blank_add_password(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
     state
         .compile(
             "www/add_password.liquid",
@@ -44,30 +161,21 @@ async fn get_blank_add_password(
 
 #[derive(Debug, Deserialize)]
 struct Link {
-    code: i32,
+    code: String,
 }
 
 #[axum::debug_handler]
 async fn get_add_password(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
     Path(id): Path<i32>,
-    Query(Link { code: link_thingie }): Query<Link>,
+    remote_ip: RemoteIp,
+    Query(Link { code: token }): Query<Link>,
 ) -> Result<impl IntoResponse, VentError> {
-    let correct_code = sqlx::query!("SELECT password_link_id FROM people WHERE id = $1", id)
-        .fetch_one(&mut *state.get_connection().await?)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::FindingPerson(id.into()),
-        })?
-        .password_link_id;
-
-    let Some(correct_code) = correct_code else {
-        return Ok(Redirect::to("/login_failure/no_numbers").into_response());
-    };
-    if correct_code != link_thingie {
-        return Ok(Redirect::to("/login_failure/failed_numbers").into_response());
-    };
+    if let Some(failure) = check_reset_token_throttled(&state, id, &token, &remote_ip).await? {
+        return Ok(Redirect::to(failure).into_response());
+    }
 
     if sqlx::query!("SELECT hashed_password FROM people WHERE id = $1", id)
         .fetch_one(&mut *state.get_connection().await?)
@@ -84,15 +192,18 @@ async fn get_add_password(
     let person = sqlx::query_as!(
         DbPerson,
         r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
 FROM people 
 WHERE id = $1"#,
         id
     )
     .fetch_one(&mut *state.get_connection().await?)
-    .await.context(SqlxSnafu { action: SqlxAction::FindingPerson(id.into()) })?;
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(id.into()),
+    })?;
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     Ok(state
         .compile(
@@ -101,7 +212,8 @@ WHERE id = $1"#,
                 "is_authing_user": true,
                 "person": person,
                 "auth": aa,
-                "link_id": link_thingie
+                "token": token,
+                "password_problems": Vec::<String>::new(),
             }),
             None,
         )
@@ -113,25 +225,27 @@ WHERE id = $1"#,
 struct AddPasswordForm {
     pub id: i32,
     pub unhashed_password: String,
-    pub password_link_id: i32,
-    #[serde(rename = "cf-turnstile-response")]
-    pub cf_turnstile_response: String,
+    pub token: String,
+    ///Each captcha provider sends its response under a different name
+    #[serde(default, alias = "cf-turnstile-response", alias = "h-captcha-response")]
+    pub captcha_response: String,
 }
 
 #[axum::debug_handler]
 async fn post_add_password(
     mut auth: Auth,
+    session: Session,
     State(state): State<VentState>,
-    remote_ip: GrabCFRemoteIP,
+    remote_ip: RemoteIp,
     Form(AddPasswordForm {
         id,
         unhashed_password,
-        password_link_id,
-        cf_turnstile_response,
+        token,
+        captcha_response,
     }): Form<AddPasswordForm>,
 ) -> Result<impl IntoResponse, VentError> {
-    if !verify_turnstile(cf_turnstile_response, remote_ip).await? {
-        return Ok(Redirect::to("/login_failure/failed_turnstile"));
+    if !state.captcha.verify(&captcha_response, &remote_ip).await? {
+        return Ok(Redirect::to("/login_failure/failed_captcha").into_response());
     }
 
     if sqlx::query!("SELECT hashed_password FROM people WHERE id = $1", id)
@@ -143,100 +257,175 @@ async fn post_add_password(
         .hashed_password
         .is_some()
     {
-        return Ok(Redirect::to("/login_failure/password_already_set"));
+        return Ok(Redirect::to("/login_failure/password_already_set").into_response());
     }
 
-    let expected = sqlx::query!("SELECT password_link_id FROM people WHERE id = $1", id)
-        .fetch_one(&mut *state.get_connection().await?)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::FindingPerson(id.into()),
-        })?
-        .password_link_id;
-    let Some(expected) = expected else {
-        return Ok(Redirect::to("/login_failure/no_numbers"));
-    };
-    if expected != password_link_id {
-        return Ok(Redirect::to("/login_failure/failed_numbers"));
-    };
+    if let Some(failure) = check_reset_token_throttled(&state, id, &token, &remote_ip).await? {
+        return Ok(Redirect::to(failure).into_response());
+    }
+
+    let person = sqlx::query_as!(
+        DbPerson,
+        r#"
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
+FROM people
+WHERE id = $1"#,
+        id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(id.into()),
+    })?;
+
+    let problems = password_problems(
+        &state.settings.password_policy,
+        &unhashed_password,
+        &[&person.first_name, &person.surname, &person.username],
+    );
+    if !problems.is_empty() {
+        //the token hasn't been used up yet, so they can just try again
+        let aa = get_auth_object(auth, &session).await?;
+        return Ok(state
+            .compile(
+                "www/add_password.liquid",
+                liquid::object!({
+                    "is_authing_user": true,
+                    "person": person,
+                    "auth": aa,
+                    "token": token,
+                    "password_problems": problems,
+                }),
+                None,
+            )
+            .await?
+            .into_response());
+    }
 
     //from here, we assume we're all good
 
-    let hashed = hash(&unhashed_password, DEFAULT_COST)?;
+    //checking the token above doesn't use it up, so if another request got there first then this one has to stop
+    if !consume_reset_token(&state, id, &token).await? {
+        warn!(%id, "Password reset token was used by another request");
+        return Ok(Redirect::to("/login_failure/expired_link").into_response());
+    }
+
+    //any other tokens sent to this person are now pointless too
+    sqlx::query!(
+        "UPDATE password_reset_tokens SET used_at = now() WHERE person_id = $1 AND used_at IS NULL",
+        id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPasswordResetTokens(id.into()),
+    })?;
+
+    let hashed = hash_password(&state.settings.password_hashing, &unhashed_password).await?;
     let person: DbPerson = sqlx::query_as!(
         DbPerson,
         r#"
 UPDATE people
 SET hashed_password = $1
 WHERE id = $2
-RETURNING id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+RETURNING id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
     "#,
         hashed,
         id
     )
     .fetch_one(&mut *state.get_connection().await?)
-    .await.context(SqlxSnafu { action: SqlxAction::UpdatingPerson(id.into()) })?;
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(id.into()),
+    })?;
 
-    auth.login(&(person.into())).await?;
+    //2FA doesn't get reset with the password, so still needs checking here
+    let mut user: AuthorisationBackendPerson = person.into();
+    user.two_factor = get_two_factor_state(state.get_connection().await?, id).await?;
+    let next = login_or_second_factor(&mut auth, &session, &state, user, None).await?;
 
-    Ok(Redirect::to("/"))
+    Ok(Redirect::to(&next).into_response())
 }
 
+///Makes a new reset token for someone (replacing any they already had), removes their password, and gets the email with the link ready to go.
+///
+/// `requested_by` is whoever asked for the reset - it's `None` if it came from the person trying to log in without a password.
 pub async fn get_email_to_be_sent_for_reset_password(
     mut connection: PoolConnection<Postgres>,
     user_id: i32,
+    requested_by: Option<i32>,
 ) -> Result<EmailToSend, VentError> {
-    let current_ids =
-        sqlx::query!(r#"SELECT password_link_id FROM people WHERE password_link_id <> NULL"#)
-            .fetch_all(&mut *connection)
-            .await
-            .context(SqlxSnafu {
-                action: SqlxAction::FindingPerson(user_id.into()),
-            })?
-            .into_iter()
-            .map(|x| x.password_link_id.unwrap()) //we check for null above so fine
-            .collect_vec();
-
-    let id: i32 = {
-        let mut rng = thread_rng();
-        let mut tester = rng.gen::<u16>();
-        while current_ids.contains(&(tester.into())) {
-            tester = rng.gen::<u16>();
-        }
-        tester
-    }
-    .into(); //ensure always positive
+    let token = URL_SAFE_NO_PAD.encode(thread_rng().gen::<[u8; 32]>());
+    let hashed_token = hash_reset_token(&token);
+
+    //the old tokens and password only go if the new token gets saved
+    let mut transaction = connection.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
 
     sqlx::query!(
-        "UPDATE people SET password_link_id = $1, hashed_password = NULL WHERE id = $2",
-        id,
+        "DELETE FROM password_reset_tokens WHERE person_id = $1 AND used_at IS NULL",
         user_id
     )
-    .execute(&mut *connection)
+    .execute(&mut *transaction)
     .await
     .context(SqlxSnafu {
-        action: SqlxAction::UpdatingPerson(id.into()),
+        action: SqlxAction::UpdatingPasswordResetTokens(user_id.into()),
+    })?;
+
+    sqlx::query!(
+        r#"
+INSERT INTO password_reset_tokens (person_id, hashed_token, requested_by, expires_at)
+VALUES ($1, $2, $3, now() + make_interval(hours => $4))"#,
+        user_id,
+        hashed_token,
+        requested_by,
+        RESET_TOKEN_LIFETIME_HOURS
+    )
+    .execute(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingPasswordResetToken(user_id.into()),
+    })?;
+
+    sqlx::query!(
+        "UPDATE people SET hashed_password = NULL WHERE id = $1",
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(user_id.into()),
     })?;
 
     let person = sqlx::query!(
         "SELECT username, first_name, surname FROM people WHERE id = $1",
         user_id
     )
-    .fetch_one(&mut *connection)
+    .fetch_one(&mut *transaction)
     .await
     .context(SqlxSnafu {
-        action: SqlxAction::FindingPerson(id.into()),
+        action: SqlxAction::FindingPerson(user_id.into()),
+    })?;
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
     })?;
 
     Ok(EmailToSend {
         to_username: person.username,
         to_id: user_id,
         to_fullname: format!("{} {}", person.first_name, person.surname),
-        unique_id: id,
+        kind: EmailKind::AddPassword { token },
     })
 }
 
-pub async fn spam_password_emails(State(state): State<VentState>) -> Result<Redirect, VentError> {
+pub async fn spam_password_emails(
+    auth: Auth,
+    State(state): State<VentState>,
+) -> Result<Redirect, VentError> {
+    let requested_by = auth.user.map(|user| user.id);
+
     let ids: Vec<_> = sqlx::query!("SELECT id FROM people WHERE hashed_password IS NULL")
         .fetch_all(&mut *state.get_connection().await?)
         .await
@@ -248,7 +437,7 @@ pub async fn spam_password_emails(State(state): State<VentState>) -> Result<Redi
         for id in ids {
             let id = id.id;
             sleep(Duration::from_secs(60 * 5)).await;
-            if let Err(e) = state.reset_password(id).await {
+            if let Err(e) = state.reset_password(id, requested_by).await {
                 error!(?e, ?id, "Error resetting password");
             }
         }
@@ -257,9 +446,96 @@ pub async fn spam_password_emails(State(state): State<VentState>) -> Result<Redi
     Ok(Redirect::to("/"))
 }
 
+///`GET` method to see all of the reset links that could still be used
+#[axum::debug_handler]
+async fn get_password_reset_tokens(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    #[derive(Serialize)]
+    struct OutstandingToken {
+        id: i32,
+        person_id: i32,
+        person_name: String,
+        requested_by: Option<String>,
+        created_at: String,
+        expires_at: String,
+    }
+
+    let tokens = sqlx::query!(
+        r#"
+SELECT t.id, t.person_id, t.created_at, t.expires_at,
+       p.first_name, p.surname, r.username AS "requested_by?"
+FROM password_reset_tokens t
+INNER JOIN people p ON p.id = t.person_id
+LEFT JOIN people r ON r.id = t.requested_by
+WHERE t.used_at IS NULL AND t.expires_at > now()
+ORDER BY t.created_at DESC"#
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPasswordResetTokens,
+    })?
+    .into_iter()
+    .map(|rec| OutstandingToken {
+        id: rec.id,
+        person_id: rec.person_id,
+        person_name: format!("{} {}", rec.first_name, rec.surname),
+        requested_by: rec.requested_by,
+        created_at: rec
+            .created_at
+            .format(&state.settings.niche.date_time_format)
+            .to_string(),
+        expires_at: rec
+            .expires_at
+            .format(&state.settings.niche.date_time_format)
+            .to_string(),
+    })
+    .collect::<Vec<_>>();
+
+    let aa = get_auth_object(auth, &session).await?;
+    state
+        .compile(
+            "www/password_reset_tokens.liquid",
+            liquid::object!({ "auth": aa, "tokens": tokens }),
+            Some("Password Reset Links".into()),
+        )
+        .await
+}
+
+#[derive(Deserialize)]
+struct RevokeToken {
+    id: i32,
+}
+
+///`POST` method to stop a reset link from working
+#[axum::debug_handler]
+async fn post_revoke_password_reset_token(
+    State(state): State<VentState>,
+    Form(RevokeToken { id }): Form<RevokeToken>,
+) -> Result<impl IntoResponse, VentError> {
+    debug!(%id, "Revoking password reset token");
+
+    sqlx::query!("DELETE FROM password_reset_tokens WHERE id = $1", id)
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::RemovingPasswordResetToken(id),
+        })?;
+
+    Ok(Redirect::to("/password_reset_tokens"))
+}
+
 pub fn router() -> Router<VentState> {
     Router::new()
-        .route("/all_passwords", get(spam_password_emails))
+        .route("/all_passwords", post(spam_password_emails))
+        .route("/password_reset_tokens", get(get_password_reset_tokens))
+        .route(
+            "/revoke_password_reset_token",
+            post(post_revoke_password_reset_token),
+        )
         .route_layer(permission_required!(
             VentAuthBackend,
             login_url = "/login",
@@ -0,0 +1,174 @@
+//! Personal access tokens for the JSON API in [`crate::api`].
+//!
+//! People make and revoke these on their profile. The token itself is only shown once when it's made - like reset tokens, only a hash of it is stored.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        get_auth_object,
+    },
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::VentState,
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    routing::post,
+    Form, Router,
+};
+use axum_login::login_required;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use tower_sessions::Session;
+
+///Goes at the start of every token, so they're easy to spot if they get leaked
+const TOKEN_PREFIX: &str = "vent_";
+///The longest name that a token can have
+const MAX_NAME_LEN: usize = 100;
+
+#[derive(Serialize)]
+pub struct ApiTokenInfo {
+    id: i32,
+    name: String,
+    created_at: String,
+    last_used: Option<String>,
+}
+
+fn hash_api_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+///Gets someone's tokens, without the tokens themselves
+pub async fn get_api_tokens(
+    state: &VentState,
+    person_id: i32,
+) -> Result<Vec<ApiTokenInfo>, VentError> {
+    Ok(sqlx::query!(
+        "SELECT id, name, created_at, last_used FROM api_tokens WHERE person_id = $1 ORDER BY created_at",
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingApiTokens(person_id.into()),
+    })?
+    .into_iter()
+    .map(|rec| ApiTokenInfo {
+        id: rec.id,
+        name: rec.name,
+        created_at: rec
+            .created_at
+            .to_env_string(&state.settings.niche.date_time_format),
+        last_used: rec
+            .last_used
+            .map(|last_used| last_used.to_env_string(&state.settings.niche.date_time_format)),
+    })
+    .collect())
+}
+
+///Finds who a token belongs to, and marks it as used
+pub async fn find_api_token_owner(
+    state: &VentState,
+    token: &str,
+) -> Result<Option<i32>, VentError> {
+    Ok(sqlx::query!(
+        "UPDATE api_tokens SET last_used = now() WHERE hashed_token = $1 RETURNING person_id",
+        hash_api_token(token)
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingApiTokenOwner,
+    })?
+    .map(|rec| rec.person_id))
+}
+
+#[derive(Deserialize)]
+struct NewApiToken {
+    name: String,
+}
+
+///`POST` method to make a new token - this is the only time that the token gets shown
+#[axum::debug_handler]
+async fn post_create_api_token(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Form(NewApiToken { name }): Form<NewApiToken>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.as_ref().unwrap().id;
+    let name: String = name.trim().chars().take(MAX_NAME_LEN).collect();
+    if name.is_empty() {
+        return Ok(Redirect::to("/edit_user").into_response());
+    }
+
+    let token = format!(
+        "{TOKEN_PREFIX}{}",
+        URL_SAFE_NO_PAD.encode(thread_rng().gen::<[u8; 32]>())
+    );
+
+    sqlx::query!(
+        "INSERT INTO api_tokens (person_id, name, hashed_token) VALUES ($1, $2, $3)",
+        current_id,
+        name,
+        hash_api_token(&token)
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingApiToken(current_id.into()),
+    })?;
+
+    info!(%current_id, "Made API token");
+
+    let aa = get_auth_object(auth, &session).await?;
+    Ok(state
+        .compile(
+            "www/api_token_created.liquid",
+            liquid::object!({ "auth": aa, "name": name, "token": token }),
+            Some("New API Token".to_string()),
+        )
+        .await?
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct ApiTokenId {
+    id: i32,
+}
+
+///`POST` method to revoke one of your own tokens
+#[axum::debug_handler]
+async fn post_revoke_api_token(
+    auth: Auth,
+    State(state): State<VentState>,
+    Form(ApiTokenId { id }): Form<ApiTokenId>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.as_ref().unwrap().id;
+
+    info!(%current_id, %id, "Revoking API token");
+
+    sqlx::query!(
+        "DELETE FROM api_tokens WHERE id = $1 AND person_id = $2",
+        id,
+        current_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingApiToken(id),
+    })?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/api_tokens/create", post(post_create_api_token))
+        .route("/api_tokens/revoke", post(post_revoke_api_token))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+}
@@ -1,5 +1,12 @@
 use crate::{
-    auth::{login::LoginCreds, PermissionsTarget},
+    auth::{
+        get_individual_permissions, get_role_permissions,
+        login::LoginCreds,
+        password_hash::{hash_password, verify_password, PasswordCheck},
+        rate_limit::{is_locked_out, Attempter},
+        two_factor::{get_two_factor_state, verify_second_factor},
+        PermissionsTarget,
+    },
     error::{LoginFailureReason, SqlxAction, SqlxSnafu, VentError},
     state::{
         db_objects::{AuthorisationBackendPerson, DbPerson},
@@ -7,7 +14,6 @@ use crate::{
     },
 };
 use axum_login::{AuthSession, AuthnBackend, AuthzBackend, UserId};
-use bcrypt::verify;
 use snafu::ResultExt;
 use std::collections::HashSet;
 
@@ -32,15 +38,17 @@ impl AuthnBackend for VentAuthBackend {
 
     async fn authenticate(
         &self,
-        LoginCreds {
-            username,
-            unhashed_password,
-        }: Self::Credentials,
+        creds: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
-        let db_user = sqlx::query_as!(
+        match creds {
+            LoginCreds::Password {
+                username,
+                unhashed_password,
+            } => {
+                let db_user = sqlx::query_as!(
             DbPerson,
             r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
 FROM people
 WHERE LOWER(username) = LOWER($1)
         "#,
@@ -49,38 +57,113 @@ WHERE LOWER(username) = LOWER($1)
             .fetch_optional(&mut *self.state.get_connection().await?)
             .await.context(SqlxSnafu {action: SqlxAction::FindingPerson(username.into())})?;
 
-        let Some(db_user) = db_user else {
-            return Ok(None);
-        };
-        let Some(hashed_password) = &db_user.hashed_password else {
-            self.state.reset_password(db_user.id).await?;
-            return Err(VentError::LoginFailure {
-                reason: LoginFailureReason::PasswordIsNotSet,
-            });
-        };
+                let Some(db_user) = db_user else {
+                    return Ok(None);
+                };
+                if is_locked_out(&self.state, &[Attempter::username(&db_user.username)]).await? {
+                    return Err(VentError::LoginFailure {
+                        reason: LoginFailureReason::LockedOut,
+                    });
+                }
+                let Some(hashed_password) = &db_user.hashed_password else {
+                    self.state.reset_password(db_user.id, None).await?;
+                    return Err(VentError::LoginFailure {
+                        reason: LoginFailureReason::PasswordIsNotSet,
+                    });
+                };
+
+                let settings = &self.state.settings.password_hashing;
+                if let PasswordCheck::Correct { needs_rehash } = verify_password(settings, &unhashed_password, hashed_password).await? {
+                    //this changes the session auth hash, so it logs them out of any other sessions - but only once
+                    if needs_rehash {
+                        info!(id = %db_user.id, "Upgrading password hash");
+                        let rehashed = hash_password(settings, &unhashed_password).await?;
+                        sqlx::query!(
+                            "UPDATE people SET hashed_password = $1 WHERE id = $2",
+                            rehashed,
+                            db_user.id
+                        )
+                        .execute(&mut *self.state.get_connection().await?)
+                        .await
+                        .context(SqlxSnafu {
+                            action: SqlxAction::UpdatingPerson(db_user.id.into()),
+                        })?;
+                    }
 
-        if verify(unhashed_password, hashed_password)? {
-            Ok(Some(db_user.into()))
-        } else {
-            Err(VentError::LoginFailure {
-                reason: LoginFailureReason::IncorrectPassword,
-            })
+                    self.get_user(&db_user.id).await
+                } else {
+                    Err(VentError::LoginFailure {
+                        reason: LoginFailureReason::IncorrectPassword,
+                    })
+                }
+            }
+            LoginCreds::SecondFactor { user_id, code } => {
+                if is_locked_out(&self.state, &[Attempter::for_person(&self.state, user_id).await?]).await? {
+                    return Err(VentError::LoginFailure {
+                        reason: LoginFailureReason::LockedOut,
+                    });
+                }
+
+                if verify_second_factor(&self.state, user_id, &code).await? {
+                    self.get_user(&user_id).await
+                } else {
+                    Err(VentError::LoginFailure {
+                        reason: LoginFailureReason::IncorrectSecondFactor,
+                    })
+                }
+            }
+            LoginCreds::SingleSignOn { username } => {
+                let Some(rec) = sqlx::query!(
+                    "SELECT id, username FROM people WHERE LOWER(username) = LOWER($1)",
+                    username
+                )
+                .fetch_optional(&mut *self.state.get_connection().await?)
+                .await
+                .context(SqlxSnafu {
+                    action: SqlxAction::FindingPerson(username.into()),
+                })?
+                else {
+                    return Ok(None);
+                };
+                if is_locked_out(&self.state, &[Attempter::username(&rec.username)]).await? {
+                    return Err(VentError::LoginFailure {
+                        reason: LoginFailureReason::LockedOut,
+                    });
+                }
+
+                self.get_user(&rec.id).await
+            }
+            LoginCreds::Passkey { user_id } | LoginCreds::MagicLink { user_id } => {
+                if is_locked_out(&self.state, &[Attempter::for_person(&self.state, user_id).await?]).await? {
+                    return Err(VentError::LoginFailure {
+                        reason: LoginFailureReason::LockedOut,
+                    });
+                }
+
+                self.get_user(&user_id).await
+            }
         }
     }
 
     async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
-        sqlx::query_as!(
+        let Some(db_user) = sqlx::query_as!(
             DbPerson,
             r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
 FROM people
 WHERE id = $1
         "#,
             user_id
             )
             .fetch_optional(&mut *self.state.get_connection().await?)
-            .await.context(SqlxSnafu {action: SqlxAction::FindingPerson((*user_id).into())})
-            .map(|x| x.map(Into::into))
+            .await.context(SqlxSnafu {action: SqlxAction::FindingPerson((*user_id).into())})? else {
+            return Ok(None);
+        };
+
+        let mut user: AuthorisationBackendPerson = db_user.into();
+        user.two_factor = get_two_factor_state(self.state.get_connection().await?, user.id).await?;
+
+        Ok(Some(user))
     }
 }
 
@@ -90,16 +173,28 @@ impl AuthzBackend for VentAuthBackend {
 
     async fn get_user_permissions(
         &self,
-        _user: &Self::User,
+        user: &Self::User,
     ) -> Result<HashSet<Self::Permission>, Self::Error> {
-        //TODO: individual permissions for things like photos?
-        Ok(HashSet::new())
+        Ok(
+            get_individual_permissions(self.state.get_connection().await?, user.id)
+                .await?
+                .granted,
+        )
     }
 
     async fn get_group_permissions(
         &self,
         user: &Self::User,
     ) -> Result<HashSet<Self::Permission>, Self::Error> {
-        Ok(user.permissions.can())
+        get_role_permissions(self.state.get_connection().await?, user.role_id).await
+    }
+
+    ///Overriden from the default as individual permissions can also take away permissions that the role would give
+    async fn get_all_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        let individual = get_individual_permissions(self.state.get_connection().await?, user.id).await?;
+        Ok(individual.apply(self.get_group_permissions(user).await?))
     }
 }
@@ -0,0 +1,221 @@
+//! Captchas for the login and add password forms, behind the [`CaptchaProvider`] trait so that which one gets used can be picked in the `captcha` section of the config.
+
+mod hcaptcha;
+mod proof_of_work;
+mod turnstile;
+
+use crate::{
+    auth::remote_ip::RemoteIp,
+    cfg::CaptchaSettings,
+    error::{ReqwestAction, ReqwestSnafu, SerdeJsonAction, VentError},
+};
+use reqwest::Client;
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::{
+    collections::HashMap,
+    env::var,
+    fmt::{Debug, Display, Formatter},
+    sync::Arc,
+};
+
+pub use hcaptcha::HCaptcha;
+pub use proof_of_work::ProofOfWork;
+pub use turnstile::Turnstile;
+
+#[async_trait::async_trait]
+pub trait CaptchaProvider: Debug + Send + Sync {
+    ///Everything that `partials/captcha.liquid` needs to show the captcha - this gets called on every page render, so it can make new challenges.
+    ///
+    /// It must have a `kind`, and can have a `script_src` for the header.
+    fn widget(&self) -> liquid::Object;
+
+    ///Origins that the captcha loads scripts, frames or styles from, and talks to - these get added to the `Content-Security-Policy`.
+    fn content_security_origins(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    ///Checks the response that came back with the form - returns whether or not it passed
+    async fn verify(&self, response: &str, remote_ip: &RemoteIp) -> Result<bool, VentError>;
+}
+
+///For intranet deployments (or testing offline) where a captcha isn't wanted
+#[derive(Debug)]
+pub struct Disabled;
+
+#[async_trait::async_trait]
+impl CaptchaProvider for Disabled {
+    fn widget(&self) -> liquid::Object {
+        liquid::object!({ "kind": "disabled" })
+    }
+
+    async fn verify(&self, _response: &str, _remote_ip: &RemoteIp) -> Result<bool, VentError> {
+        Ok(true)
+    }
+}
+
+pub fn captcha_provider(settings: &CaptchaSettings) -> Arc<dyn CaptchaProvider> {
+    match settings {
+        CaptchaSettings::Turnstile {
+            site_key,
+            secret_key,
+        } => Arc::new(Turnstile::new(site_key.clone(), secret_key.clone())),
+        CaptchaSettings::HCaptcha {
+            site_key,
+            secret_key,
+        } => Arc::new(HCaptcha::new(site_key.clone(), secret_key.clone())),
+        CaptchaSettings::ProofOfWork { difficulty, secret } => {
+            Arc::new(ProofOfWork::new(*difficulty, secret.clone()))
+        }
+        CaptchaSettings::Disabled => {
+            warn!("Captchas are disabled");
+            Arc::new(Disabled)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CommonHeaders {
+    CaptchaSecret,
+    CaptchaResponse,
+    RemoteIP,
+    ContentType,
+}
+
+impl Display for CommonHeaders {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommonHeaders::CaptchaSecret => write!(f, "secret"),
+            CommonHeaders::CaptchaResponse => write!(f, "response"),
+            CommonHeaders::RemoteIP => write!(f, "remoteip"),
+            CommonHeaders::ContentType => Display::fmt(&http::header::CONTENT_TYPE, f),
+        }
+    }
+}
+
+///What comes back from a `siteverify` endpoint - Turnstile and hCaptcha both use the same shape
+#[derive(Deserialize, Debug)]
+struct SiteverifyResponse {
+    success: bool,
+    challenge_ts: Option<String>,
+    hostname: Option<String>,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+///Checks a response against a `siteverify` endpoint, which both Turnstile and hCaptcha have
+async fn siteverify(
+    url: &str,
+    secret_key: &str,
+    response: &str,
+    remote_ip: &RemoteIp,
+    action: ReqwestAction,
+) -> Result<bool, VentError> {
+    if cfg!(debug_assertions) || var("IS_LOCALHOST").is_ok() {
+        return Ok(true);
+    }
+
+    let remote_ip = remote_ip.to_string();
+    let mut body = HashMap::new();
+    body.insert(CommonHeaders::CaptchaSecret.to_string(), secret_key);
+    body.insert(CommonHeaders::CaptchaResponse.to_string(), response);
+    body.insert(CommonHeaders::RemoteIP.to_string(), &remote_ip);
+
+    debug!(?remote_ip, "Checking captcha response");
+
+    let post_response = Client::new()
+        .post(url)
+        .form(&body)
+        .send()
+        .await
+        .context(ReqwestSnafu { action })?
+        .error_for_status()
+        .with_context(|e| ReqwestSnafu {
+            action: ReqwestAction::RErrorForStatus(e.status()),
+        })?
+        .json::<SiteverifyResponse>()
+        .await
+        .context(ReqwestSnafu {
+            action: ReqwestAction::ConvertToJson(SerdeJsonAction::CaptchaSiteverifyResponse),
+        })?;
+
+    debug!(?post_response.hostname, ?post_response.challenge_ts, "Got captcha response");
+
+    if post_response.success {
+        return Ok(true);
+    }
+
+    if !post_response.error_codes.is_empty() {
+        error!(?post_response.error_codes, "Captcha Response Error");
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use liquid::ValueView;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::net::Ipv4Addr;
+
+    const REMOTE_IP: RemoteIp = RemoteIp(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    #[test]
+    fn disabled_has_to_be_explicit() {
+        let settings: CaptchaSettings =
+            serde_json::from_value(json!({ "provider": "disabled" })).unwrap();
+        assert!(matches!(settings, CaptchaSettings::Disabled));
+    }
+
+    ///Finds a counter for the challenge in a proof of work widget, like the browser does
+    fn solve(widget: &liquid::Object) -> String {
+        let challenge = widget["challenge"].to_kstr().to_string();
+        let difficulty = widget["difficulty"]
+            .as_scalar()
+            .and_then(|difficulty| difficulty.to_integer())
+            .unwrap();
+
+        (0..u64::MAX)
+            .map(|counter| format!("{challenge}:{counter}"))
+            .find(|response| {
+                let digest = Sha256::digest(response.as_bytes());
+                u128::from_be_bytes(digest[..16].try_into().unwrap()).leading_zeros()
+                    >= u32::try_from(difficulty).unwrap()
+            })
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn proof_of_work_only_works_once() {
+        let captcha = captcha_provider(&CaptchaSettings::ProofOfWork {
+            difficulty: 8,
+            secret: None,
+        });
+        let response = solve(&captcha.widget());
+
+        assert!(captcha.verify(&response, &REMOTE_IP).await.unwrap());
+        assert!(!captcha.verify(&response, &REMOTE_IP).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn proof_of_work_rejects_bad_responses() {
+        let captcha = captcha_provider(&CaptchaSettings::ProofOfWork {
+            difficulty: 8,
+            secret: None,
+        });
+        let response = solve(&captcha.widget());
+
+        let other_instance = captcha_provider(&CaptchaSettings::ProofOfWork {
+            difficulty: 8,
+            secret: None,
+        });
+        assert!(!other_instance.verify(&response, &REMOTE_IP).await.unwrap());
+
+        let easier = response.replacen(".8.", ".0.", 1);
+        assert!(!captcha.verify(&easier, &REMOTE_IP).await.unwrap());
+
+        assert!(!captcha.verify("", &REMOTE_IP).await.unwrap());
+    }
+}
@@ -0,0 +1,47 @@
+use super::{siteverify, CaptchaProvider};
+use crate::{
+    auth::remote_ip::RemoteIp,
+    error::{ReqwestAction, VentError},
+};
+
+///[hCaptcha](https://docs.hcaptcha.com/)
+#[derive(Debug)]
+pub struct HCaptcha {
+    site_key: String,
+    secret_key: String,
+}
+
+impl HCaptcha {
+    pub fn new(site_key: String, secret_key: String) -> Self {
+        Self {
+            site_key,
+            secret_key,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CaptchaProvider for HCaptcha {
+    fn widget(&self) -> liquid::Object {
+        liquid::object!({
+            "kind": "hcaptcha",
+            "script_src": "https://js.hcaptcha.com/1/api.js",
+            "site_key": self.site_key.clone(),
+        })
+    }
+
+    fn content_security_origins(&self) -> &'static [&'static str] {
+        &["https://hcaptcha.com", "https://*.hcaptcha.com"]
+    }
+
+    async fn verify(&self, response: &str, remote_ip: &RemoteIp) -> Result<bool, VentError> {
+        siteverify(
+            "https://api.hcaptcha.com/siteverify",
+            &self.secret_key,
+            response,
+            remote_ip,
+            ReqwestAction::HCaptcha,
+        )
+        .await
+    }
+}
@@ -0,0 +1,146 @@
+//! A self-hosted proof-of-work captcha, for when sending people's details to a third party isn't wanted.
+//!
+//! Each render gets a challenge signed with an HMAC so nothing needs storing, and the browser has to find a counter where the SHA-256 of `{challenge}:{counter}` starts with `difficulty` zero bits. Used challenges are remembered until they'd have expired anyway, so each one only works once.
+
+use super::CaptchaProvider;
+use crate::{auth::remote_ip::RemoteIp, error::VentError};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use moka::future::Cache;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::{Debug, Formatter},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+///How long someone has to solve a challenge and submit the form
+const CHALLENGE_LIFETIME_SECS: u64 = 10 * 60;
+
+pub struct ProofOfWork {
+    difficulty: u32,
+    key: Vec<u8>,
+    used: Cache<String, ()>,
+}
+
+impl Debug for ProofOfWork {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProofOfWork")
+            .field("difficulty", &self.difficulty)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProofOfWork {
+    ///If there's no `secret`, a random one gets made - which is fine for one instance, but replicas need to share one.
+    pub fn new(difficulty: u32, secret: Option<String>) -> Self {
+        let key = secret.map_or_else(
+            || thread_rng().gen::<[u8; 32]>().to_vec(),
+            String::into_bytes,
+        );
+
+        Self {
+            difficulty,
+            key,
+            used: Cache::builder()
+                .time_to_live(Duration::from_secs(CHALLENGE_LIFETIME_SECS))
+                .build(),
+        }
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("HMACs can use keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn is_signature_valid(&self, payload: &str, signature: &str) -> bool {
+        let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+            return false;
+        };
+        self.mac(payload).verify_slice(&signature).is_ok()
+    }
+}
+
+fn unix_now() -> Result<u64, VentError> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut count = 0;
+    for byte in bytes {
+        count += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    count
+}
+
+#[async_trait::async_trait]
+impl CaptchaProvider for ProofOfWork {
+    fn widget(&self) -> liquid::Object {
+        let issued = unix_now().unwrap_or_default();
+        let nonce: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let payload = format!("{issued}.{nonce}.{}", self.difficulty);
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+
+        liquid::object!({
+            "kind": "proof_of_work",
+            "challenge": format!("{payload}.{signature}"),
+            "difficulty": self.difficulty,
+        })
+    }
+
+    async fn verify(&self, response: &str, _remote_ip: &RemoteIp) -> Result<bool, VentError> {
+        let Some((challenge, counter)) = response.rsplit_once(':') else {
+            return Ok(false);
+        };
+        let Some((payload, signature)) = challenge.rsplit_once('.') else {
+            return Ok(false);
+        };
+        if !self.is_signature_valid(payload, signature) {
+            warn!("Proof of work challenge with a bad signature");
+            return Ok(false);
+        }
+
+        let mut parts = payload.split('.');
+        let (Some(Ok(issued)), Some(_nonce), Some(Ok(difficulty))) = (
+            parts.next().map(str::parse::<u64>),
+            parts.next(),
+            parts.next().map(str::parse::<u32>),
+        ) else {
+            return Ok(false);
+        };
+
+        if unix_now()?.saturating_sub(issued) > CHALLENGE_LIFETIME_SECS {
+            debug!("Proof of work challenge expired");
+            return Ok(false);
+        }
+
+        let digest = Sha256::digest(format!("{challenge}:{counter}").as_bytes());
+        if leading_zero_bits(&digest) < difficulty {
+            return Ok(false);
+        }
+
+        if !self
+            .used
+            .entry(challenge.to_string())
+            .or_insert(())
+            .await
+            .is_fresh()
+        {
+            warn!("Proof of work challenge reused");
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,47 @@
+use super::{siteverify, CaptchaProvider};
+use crate::{
+    auth::remote_ip::RemoteIp,
+    error::{ReqwestAction, VentError},
+};
+
+///[Cloudflare Turnstile](https://developers.cloudflare.com/turnstile/)
+#[derive(Debug)]
+pub struct Turnstile {
+    site_key: String,
+    secret_key: String,
+}
+
+impl Turnstile {
+    pub fn new(site_key: String, secret_key: String) -> Self {
+        Self {
+            site_key,
+            secret_key,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CaptchaProvider for Turnstile {
+    fn widget(&self) -> liquid::Object {
+        liquid::object!({
+            "kind": "turnstile",
+            "script_src": "https://challenges.cloudflare.com/turnstile/v0/api.js",
+            "site_key": self.site_key.clone(),
+        })
+    }
+
+    fn content_security_origins(&self) -> &'static [&'static str] {
+        &["https://challenges.cloudflare.com"]
+    }
+
+    async fn verify(&self, response: &str, remote_ip: &RemoteIp) -> Result<bool, VentError> {
+        siteverify(
+            "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            &self.secret_key,
+            response,
+            remote_ip,
+            ReqwestAction::CloudflareTurntile,
+        )
+        .await
+    }
+}
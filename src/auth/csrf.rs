@@ -0,0 +1,139 @@
+//! Protection against [cross-site request forgery](https://owasp.org/www-community/attacks/csrf), using a random token per session.
+//!
+//! [`VentState::compile`](crate::state::VentState::compile) puts the token into every page as `csrf_token`, and `partials/csrf.liquid` adds it to forms. Then, [`verify_csrf_token`] rejects any request that could change something without the right token in the form body, the `csrf_token` query parameter (for multipart forms, where the body isn't read here), or the `X-CSRF-Token` header.
+//!
+//! Tokens only get made when a page is rendered, so requests that never see a form (eg. healthchecks) don't get sessions.
+//!
+//! The JSON API under `/api/` is skipped, as it only ever uses bearer tokens and never looks at the session cookie.
+
+use crate::error::{TowerSessionsSnafu, VentError};
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::sync::{Arc, Mutex};
+use tower_sessions::Session;
+use url::form_urlencoded;
+
+///Session key for the CSRF token
+const CSRF_TOKEN_KEY: &str = "csrf_token";
+///Name of the form field and query parameter that the token gets sent back in
+const CSRF_FIELD: &str = "csrf_token";
+///Header that the token can be sent back in
+const CSRF_HEADER: &str = "X-CSRF-Token";
+///The largest form body that gets read to look for the token
+const MAX_FORM_BYTES: usize = 1024 * 1024 * 10;
+///Paths that don't use session cookies, so can't be forged
+const EXEMPT_PREFIXES: &[&str] = &["/api/"];
+
+tokio::task_local! {
+    ///The token for the session of the request currently being handled, if there is one yet
+    static CURRENT_TOKEN: Arc<Mutex<Option<String>>>;
+}
+
+///Gets the CSRF token for the current request's session, making one if it doesn't have one yet.
+///
+/// Returns `None` outside of [`verify_csrf_token`], eg. in background tasks.
+pub fn current_csrf_token() -> Option<String> {
+    CURRENT_TOKEN
+        .try_with(|token| {
+            token
+                .lock()
+                .expect("CSRF token mutex poisoned")
+                .get_or_insert_with(|| URL_SAFE_NO_PAD.encode(thread_rng().gen::<[u8; 32]>()))
+                .clone()
+        })
+        .ok()
+}
+
+///Compares tokens by their hashes, so the comparison doesn't leak how much of the token was right
+fn tokens_match(expected: &str, provided: &str) -> bool {
+    Sha256::digest(expected.as_bytes()) == Sha256::digest(provided.as_bytes())
+}
+
+fn find_field(encoded: &[u8]) -> Option<String> {
+    form_urlencoded::parse(encoded)
+        .find(|(key, _)| key == CSRF_FIELD)
+        .map(|(_, value)| value.into_owned())
+}
+
+///Middleware that checks CSRF tokens on anything that isn't a `GET`, `HEAD`, `OPTIONS` or `TRACE` (outside of the API), and makes the token available to [`current_csrf_token`].
+pub async fn verify_csrf_token(
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Result<Response, VentError> {
+    let existing: Option<String> = session
+        .get(CSRF_TOKEN_KEY)
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    let is_exempt = EXEMPT_PREFIXES
+        .iter()
+        .any(|prefix| request.uri().path().starts_with(prefix));
+
+    let request = if request.method().is_safe() || is_exempt {
+        request
+    } else {
+        let (parts, body) = request.into_parts();
+
+        let mut provided = parts
+            .headers
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string)
+            .or_else(|| {
+                parts
+                    .uri
+                    .query()
+                    .and_then(|query| find_field(query.as_bytes()))
+            });
+
+        let is_form = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|content_type| {
+                content_type.starts_with("application/x-www-form-urlencoded")
+            });
+
+        let body = if provided.is_none() && is_form {
+            let bytes = to_bytes(body, MAX_FORM_BYTES).await?;
+            provided = find_field(&bytes);
+            Body::from(bytes)
+        } else {
+            body
+        };
+
+        let is_valid = match (&existing, &provided) {
+            (Some(expected), Some(provided)) => tokens_match(expected, provided),
+            _ => false,
+        };
+        if !is_valid {
+            warn!(method = %parts.method, path = %parts.uri.path(), has_token = provided.is_some(), "Rejecting request with a bad CSRF token");
+            return Err(VentError::CsrfTokenMismatch);
+        }
+
+        Request::from_parts(parts, body)
+    };
+
+    let token = Arc::new(Mutex::new(existing.clone()));
+    let response = CURRENT_TOKEN.scope(token.clone(), next.run(request)).await;
+
+    let token = token.lock().expect("CSRF token mutex poisoned").clone();
+    if let Some(token) = token.filter(|token| Some(token) != existing.as_ref()) {
+        session
+            .insert(CSRF_TOKEN_KEY, token)
+            .await
+            .context(TowerSessionsSnafu)?;
+    }
+
+    Ok(response)
+}
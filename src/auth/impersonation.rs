@@ -0,0 +1,366 @@
+//! Letting devs see the site as someone else does, for support.
+//!
+//! Starting logs the dev in as the other person, and keeps who they really are in the session so that [`get_auth_object`] can show a banner with a way back. Every start and stop goes into `impersonation_log`, which devs can see on `/impersonation_log`.
+//!
+//! Whilst impersonating, [`block_account_changes`] stops anything that would change the other person's login details, and [`record_impersonated_changes`] puts everything else that could change something into `impersonation_actions`, so it's clear who really did it.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        get_auth_object,
+        remote_ip::RemoteIp,
+        PermissionsTarget,
+    },
+    error::{SqlxAction, SqlxSnafu, TowerSessionsSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::VentState,
+};
+use axum::{
+    extract::{Path, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Router,
+};
+use axum_login::{login_required, permission_required, AuthnBackend};
+use http::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use tower_sessions::Session;
+
+///Session key for who is really logged in whilst impersonating
+const IMPERSONATION_KEY: &str = "impersonation";
+
+///Paths that can be looked at but not changed whilst impersonating, as they change how someone logs in
+const BLOCKED_PREFIXES: &[&str] = &[
+    "/2fa/",
+    "/passkeys/",
+    "/sessions/",
+    "/change_password",
+    "/api_tokens/",
+    "/edit_user",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Impersonation {
+    pub impersonator_id: i32,
+    pub impersonator_name: String,
+    ///The row in `impersonation_log` for this impersonation
+    log_id: i32,
+}
+
+///Gets who is really logged in, if they're impersonating someone
+pub async fn get_impersonation(session: &Session) -> Result<Option<Impersonation>, VentError> {
+    session
+        .get(IMPERSONATION_KEY)
+        .await
+        .context(TowerSessionsSnafu)
+}
+
+///Marks an impersonation as over in the log, eg. when someone logs out without stopping first
+pub async fn end_impersonation(
+    state: &VentState,
+    session: &Session,
+) -> Result<Option<Impersonation>, VentError> {
+    let Some(impersonation) = session
+        .remove::<Impersonation>(IMPERSONATION_KEY)
+        .await
+        .context(TowerSessionsSnafu)?
+    else {
+        return Ok(None);
+    };
+
+    sqlx::query!(
+        "UPDATE impersonation_log SET ended_at = now() WHERE id = $1 AND ended_at IS NULL",
+        impersonation.log_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingImpersonationLog(impersonation.log_id),
+    })?;
+
+    Ok(Some(impersonation))
+}
+
+///Whether a request would change someone's login details, and so can't be made whilst impersonating them
+fn is_account_change(method: &Method, path: &str) -> bool {
+    !method.is_safe()
+        && BLOCKED_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+}
+
+///Middleware that stops impersonating devs from changing the profile, passwords, 2FA, passkeys, sessions or API tokens of the person they're impersonating.
+///
+/// They can still see those pages, including `/edit_user` when [`require_two_factor_enrolment`](crate::auth::two_factor::require_two_factor_enrolment) sends them there.
+pub async fn block_account_changes(
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Result<Response, VentError> {
+    let path = request.uri().path();
+    if is_account_change(request.method(), path) && get_impersonation(&session).await?.is_some() {
+        warn!(%path, "Blocked account change whilst impersonating");
+        return Ok((
+            StatusCode::FORBIDDEN,
+            "You can't change someone's login details whilst viewing the site as them.",
+        )
+            .into_response());
+    }
+
+    Ok(next.run(request).await)
+}
+
+///Middleware that records anything that could change something whilst impersonating (ie. anything other than `GET`, `HEAD`, `OPTIONS` or `TRACE`) against the impersonation.
+///
+/// Only the path is kept, as query strings can have CSRF tokens in them.
+pub async fn record_impersonated_changes(
+    session: Session,
+    State(state): State<VentState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, VentError> {
+    if request.method().is_safe() {
+        return Ok(next.run(request).await);
+    }
+    let Some(impersonation) = get_impersonation(&session).await? else {
+        return Ok(next.run(request).await);
+    };
+
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let response = next.run(request).await;
+
+    info!(impersonator_id = %impersonation.impersonator_id, %method, %path, "Change whilst impersonating");
+    if let Err(e) = sqlx::query!(
+        r#"
+INSERT INTO impersonation_actions (impersonation_id, method, path, status)
+VALUES ($1, $2, $3, $4)"#,
+        impersonation.log_id,
+        method,
+        path,
+        i32::from(response.status().as_u16())
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingImpersonationAction(impersonation.log_id),
+    }) {
+        error!(?e, "Error recording change whilst impersonating");
+    }
+
+    Ok(response)
+}
+
+///`POST` method to start acting as someone else
+#[axum::debug_handler]
+async fn post_start_impersonating(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: RemoteIp,
+    Path(person_id): Path<i32>,
+) -> Result<impl IntoResponse, VentError> {
+    let impersonator = auth.user.clone().unwrap();
+    if impersonator.id == person_id || get_impersonation(&session).await?.is_some() {
+        return Ok(Redirect::to(&format!("/edit_person/{person_id}")));
+    }
+
+    let Some(person) = auth.backend.get_user(&person_id).await? else {
+        return Ok(Redirect::to("/show_people"));
+    };
+
+    let log_id = sqlx::query!(
+        r#"
+INSERT INTO impersonation_log (impersonator_id, person_id, ip_address)
+VALUES ($1, $2, $3)
+RETURNING id"#,
+        impersonator.id,
+        person_id,
+        remote_ip.to_string()
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingImpersonationLog,
+    })?
+    .id;
+
+    info!(impersonator_id = %impersonator.id, %person_id, "Starting impersonation");
+
+    auth.login(&person).await?;
+    session
+        .insert(
+            IMPERSONATION_KEY,
+            Impersonation {
+                impersonator_id: impersonator.id,
+                impersonator_name: format!("{} {}", impersonator.first_name, impersonator.surname),
+                log_id,
+            },
+        )
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    Ok(Redirect::to("/"))
+}
+
+///`POST` method to go back to being yourself
+#[axum::debug_handler]
+async fn post_stop_impersonating(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let person_id = auth.user.as_ref().unwrap().id;
+    let Some(impersonation) = end_impersonation(&state, &session).await? else {
+        return Ok(Redirect::to("/"));
+    };
+
+    info!(impersonator_id = %impersonation.impersonator_id, %person_id, "Stopping impersonation");
+
+    if let Some(impersonator) = auth
+        .backend
+        .get_user(&impersonation.impersonator_id)
+        .await?
+    {
+        auth.login(&impersonator).await?;
+        Ok(Redirect::to(&format!("/edit_person/{person_id}")))
+    } else {
+        auth.logout().await?;
+        Ok(Redirect::to("/login"))
+    }
+}
+
+#[derive(Serialize)]
+struct LoggedAction {
+    method: String,
+    path: String,
+    status: i32,
+    happened_at: String,
+}
+
+#[derive(Serialize)]
+struct LogEntry {
+    impersonator: String,
+    person: String,
+    person_id: i32,
+    ip_address: Option<String>,
+    started_at: String,
+    ended_at: Option<String>,
+    actions: Vec<LoggedAction>,
+}
+
+///`GET` method for the most recent impersonations
+#[axum::debug_handler]
+async fn get_impersonation_log(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let aa = get_auth_object(auth, &session).await?;
+
+    let mut actions: HashMap<i32, Vec<LoggedAction>> = HashMap::new();
+    for rec in sqlx::query!(
+        r#"
+SELECT impersonation_id, method, path, status, happened_at
+FROM impersonation_actions
+WHERE impersonation_id IN (SELECT id FROM impersonation_log ORDER BY started_at DESC LIMIT 250)
+ORDER BY happened_at"#
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingImpersonationActions,
+    })? {
+        actions
+            .entry(rec.impersonation_id)
+            .or_default()
+            .push(LoggedAction {
+                method: rec.method,
+                path: rec.path,
+                status: rec.status,
+                happened_at: rec
+                    .happened_at
+                    .to_env_string(&state.settings.niche.date_time_format),
+            });
+    }
+
+    let entries: Vec<LogEntry> = sqlx::query!(
+        r#"
+SELECT il.id, il.person_id, il.ip_address, il.started_at, il.ended_at,
+       i.first_name AS impersonator_first_name, i.surname AS impersonator_surname,
+       p.first_name AS person_first_name, p.surname AS person_surname
+FROM impersonation_log il
+INNER JOIN people i ON i.id = il.impersonator_id
+INNER JOIN people p ON p.id = il.person_id
+ORDER BY il.started_at DESC
+LIMIT 250"#
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingImpersonationLog,
+    })?
+    .into_iter()
+    .map(|rec| LogEntry {
+        impersonator: format!(
+            "{} {}",
+            rec.impersonator_first_name, rec.impersonator_surname
+        ),
+        person: format!("{} {}", rec.person_first_name, rec.person_surname),
+        person_id: rec.person_id,
+        ip_address: rec.ip_address,
+        started_at: rec
+            .started_at
+            .to_env_string(&state.settings.niche.date_time_format),
+        ended_at: rec
+            .ended_at
+            .map(|ended_at| ended_at.to_env_string(&state.settings.niche.date_time_format)),
+        actions: actions.remove(&rec.id).unwrap_or_default(),
+    })
+    .collect();
+
+    state
+        .compile(
+            "www/impersonation_log.liquid",
+            liquid::object!({ "auth": aa, "entries": entries }),
+            Some("Impersonation Log".to_string()),
+        )
+        .await
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/impersonate/:id", post(post_start_impersonating))
+        .route("/impersonation_log", get(get_impersonation_log))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::DevAccess
+        ))
+        .route("/impersonate/stop", post(post_stop_impersonating))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_pages_can_be_seen_but_not_changed() {
+        assert!(!is_account_change(&Method::GET, "/edit_user"));
+        assert!(!is_account_change(&Method::HEAD, "/edit_user"));
+        assert!(is_account_change(&Method::POST, "/edit_user"));
+        assert!(is_account_change(&Method::POST, "/2fa/start"));
+        assert!(is_account_change(&Method::POST, "/change_password"));
+    }
+
+    #[test]
+    fn other_changes_are_not_blocked() {
+        assert!(!is_account_change(&Method::POST, "/add_event"));
+        assert!(!is_account_change(&Method::POST, "/impersonate/stop"));
+        assert!(!is_account_change(&Method::GET, "/"));
+    }
+}
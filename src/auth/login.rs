@@ -1,11 +1,15 @@
 use crate::{
     auth::{
         backend::{Auth, VentAuthBackend},
-        cloudflare_turnstile::{verify_turnstile, GrabCFRemoteIP},
         get_auth_object,
+        impersonation::end_impersonation,
+        password_hash::hash_password,
+        rate_limit::{clear_failures, is_locked_out, record_failure, Attempter},
+        remote_ip::RemoteIp,
+        two_factor::{PENDING_2FA_NEXT_KEY, PENDING_2FA_USER_KEY},
     },
-    error::{ALError, LoginFailureReason, SqlxAction, SqlxSnafu, VentError},
-    state::VentState,
+    error::{ALError, LoginFailureReason, SqlxAction, SqlxSnafu, TowerSessionsSnafu, VentError},
+    state::{db_objects::AuthorisationBackendPerson, VentState},
 };
 use axum::{
     extract::{Path, State},
@@ -15,29 +19,35 @@ use axum::{
 };
 use axum::extract::Query;
 use axum_login::login_required;
-use bcrypt::{hash, DEFAULT_COST};
 use http::StatusCode;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
+use tower_sessions::Session;
 
 #[derive(Debug, Deserialize)]
 pub struct NextUrl {
-    next: Option<String>
+    pub next: Option<String>
 }
 
 #[derive(Deserialize)]
 pub struct LoginForm {
     pub username: String,
     pub unhashed_password: String,
-    #[serde(rename = "cf-turnstile-response")]
-    pub cf_turnstile_response: String,
+    ///Each captcha provider sends its response under a different name
+    #[serde(
+        default,
+        alias = "cf-turnstile-response",
+        alias = "h-captcha-response"
+    )]
+    pub captcha_response: String,
     next: Option<String>,
 }
 
 #[axum::debug_handler]
 pub async fn get_login(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
     Query(NextUrl {next}): Query<NextUrl>,
 ) -> Result<impl IntoResponse, VentError> {
@@ -70,12 +80,12 @@ pub async fn get_login(
 
         println!("Created admin user with password {password:?}");
 
-        let hashed = hash(&password, DEFAULT_COST)?;
+        let hashed = hash_password(&state.settings.password_hashing, &password).await?;
         sqlx::query!(
             r#"
 INSERT INTO public.people
-(permissions, first_name, surname, username, form, hashed_password)
-VALUES('dev', 'Admin', 'Admin', $1, 'Staff', $2);
+(role_id, first_name, surname, username, form, hashed_password)
+VALUES((SELECT role_id FROM role_permissions WHERE permission = 'DevAccess' LIMIT 1), 'Admin', 'Admin', $1, 'Staff', $2);
         "#,
             USERNAME,
             hashed
@@ -87,14 +97,14 @@ VALUES('dev', 'Admin', 'Admin', $1, 'Staff', $2);
         })?;
     }
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
     let next = match next {
         Some(x) => liquid::object!({"next_exists": true, "next": x}),
         None => liquid::object!({"next_exists": false}),
     };
     state.compile(
         "www/login.liquid",
-        liquid::object!({ "auth": aa, "tech_support_person": state.settings.tech_support_person.clone(), "next": next}),
+        liquid::object!({ "auth": aa, "tech_support_person": state.settings.tech_support_person.clone(), "next": next, "oidc_provider_name": state.settings.oidc.as_ref().map(|oidc| oidc.provider_name.clone()), "magic_link_enabled": state.settings.magic_link.is_some() }),
         None
     )
     .await
@@ -112,18 +122,37 @@ pub enum FailureReason {
     FailedNumbers,
     #[serde(rename = "password_already_set")]
     PasswordAlreadySet,
-    #[serde(rename = "failed_turnstile")]
-    FailedTurnstile,
+    #[serde(rename = "failed_captcha", alias = "failed_turnstile")]
+    FailedCaptcha,
+    #[serde(rename = "failed_2fa")]
+    FailedSecondFactor,
+    #[serde(rename = "failed_sso")]
+    FailedSingleSignOn,
+    #[serde(rename = "expired_link")]
+    ExpiredLink,
+    #[serde(rename = "locked_out")]
+    LockedOut,
+    #[serde(rename = "failed_passkey", alias = "no_passkeys")]
+    FailedPasskey,
+    #[serde(rename = "link_not_allowed")]
+    LinkNotAllowed,
 }
 
 impl FailureReason {
     pub fn status_code(self) -> StatusCode {
         match self {
-            Self::NoNumbers | Self::PasswordAlreadySet => StatusCode::BAD_REQUEST,
-            Self::UserNotFound => StatusCode::NOT_FOUND,
-            Self::FailedTurnstile | Self::FailedNumbers | Self::BadPassword => {
-                StatusCode::FORBIDDEN
+            Self::NoNumbers | Self::PasswordAlreadySet | Self::ExpiredLink => {
+                StatusCode::BAD_REQUEST
             }
+            Self::UserNotFound => StatusCode::NOT_FOUND,
+            Self::LockedOut => StatusCode::TOO_MANY_REQUESTS,
+            Self::FailedCaptcha
+            | Self::FailedNumbers
+            | Self::BadPassword
+            | Self::FailedSecondFactor
+            | Self::FailedSingleSignOn
+            | Self::FailedPasskey
+            | Self::LinkNotAllowed => StatusCode::FORBIDDEN,
         }
     }
 }
@@ -131,14 +160,15 @@ impl FailureReason {
 #[axum::debug_handler]
 pub async fn get_login_failure(
     auth: Auth,
+    session: Session,
     Path(was_password_related): Path<FailureReason>,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
     let html = state
         .compile(
             "www/failed_auth.liquid",
-            liquid::object!({ "auth": aa, "was_password_related": was_password_related }),
+            liquid::object!({ "auth": aa, "was_password_related": was_password_related, "tech_support_person": state.settings.tech_support_person.clone() }),
             None,
         )
         .await?;
@@ -147,47 +177,102 @@ pub async fn get_login_failure(
 }
 
 #[derive(Clone)]
-pub struct LoginCreds {
-    pub username: String,
-    pub unhashed_password: String,
+pub enum LoginCreds {
+    Password {
+        username: String,
+        unhashed_password: String,
+    },
+    ///Only constructed from the ID in the session after someone has already got their password right
+    SecondFactor { user_id: i32, code: String },
+    ///Only constructed after the OIDC provider has vouched for someone with this username
+    SingleSignOn { username: String },
+    ///Only constructed after one of this person's passkeys has signed a challenge
+    Passkey { user_id: i32 },
+    ///Only constructed after someone has used a login link that was emailed to them
+    MagicLink { user_id: i32 },
+}
+
+///Logs someone in after they've got their password right, unless they have 2FA turned on - then they get sent to `/login_2fa` to finish logging in.
+///
+/// Returns where to redirect to.
+pub async fn login_or_second_factor(
+    auth: &mut Auth,
+    session: &Session,
+    state: &VentState,
+    user: AuthorisationBackendPerson,
+    next: Option<String>,
+) -> Result<String, VentError> {
+    if user.two_factor.enabled {
+        session
+            .insert(PENDING_2FA_USER_KEY, user.id)
+            .await
+            .context(TowerSessionsSnafu)?;
+        if let Some(next) = next {
+            session
+                .insert(PENDING_2FA_NEXT_KEY, next)
+                .await
+                .context(TowerSessionsSnafu)?;
+        }
+
+        return Ok("/login_2fa".to_string());
+    }
+
+    clear_failures(state, &Attempter::username(&user.username)).await?;
+    auth.login(&user).await?;
+    Ok(next.unwrap_or_else(|| "/".to_string()))
 }
 
 #[axum::debug_handler]
 pub async fn post_login(
     mut auth: Auth,
-    remote_ip: GrabCFRemoteIP,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: RemoteIp,
     Form(LoginForm {
         username,
         unhashed_password,
-        cf_turnstile_response,
+        captcha_response,
         next
     }): Form<LoginForm>,
 ) -> Result<impl IntoResponse, VentError> {
-    if !verify_turnstile(cf_turnstile_response, remote_ip).await? {
-        return Ok(Redirect::to("/login_failure/failed_turnstile"));
+    let ip = Attempter::Ip(remote_ip.to_string());
+    if is_locked_out(&state, std::slice::from_ref(&ip)).await? {
+        return Ok(Redirect::to("/login_failure/locked_out"));
+    }
+
+    if !state.captcha.verify(&captcha_response, &remote_ip).await? {
+        return Ok(Redirect::to("/login_failure/failed_captcha"));
     }
 
+    let attempters = [ip, Attempter::username(&username)];
+
     Ok(Redirect::to(&
         match auth
-            .authenticate(LoginCreds {
+            .authenticate(LoginCreds::Password {
                 username: username.clone(),
                 unhashed_password,
             })
             .await
         {
-            Ok(Some(x)) => {
-                auth.login(&x).await?;
-                next.unwrap_or_else(|| "/".to_string())
+            Ok(Some(x)) => login_or_second_factor(&mut auth, &session, &state, x, next).await?,
+            Ok(None) => {
+                record_failure(&state, &attempters).await?;
+                "/login_failure/user_not_found".to_string()
             }
-            Ok(None) => "/login_failure/user_not_found".to_string(),
             Err(error) => {
                 if let ALError::Backend(VentError::LoginFailure { reason }) = error {
                     match reason {
                         LoginFailureReason::PasswordIsNotSet => "/add_password",
                         LoginFailureReason::IncorrectPassword => {
-                            error!(username = ? username, "Wrong password for trying to login");
+                            error!(username = ? username, %remote_ip, "Wrong password for trying to login");
+                            record_failure(&state, &attempters).await?;
                             "/login_failure/bad_password"
                         }
+                        LoginFailureReason::IncorrectSecondFactor => "/login_failure/failed_2fa",
+                        LoginFailureReason::LockedOut => {
+                            warn!(username = ? username, %remote_ip, "Locked out user tried to login");
+                            "/login_failure/locked_out"
+                        }
                     }.to_string()
                 } else {
                     return Err(error.into());
@@ -198,7 +283,12 @@ pub async fn post_login(
 }
 
 #[axum::debug_handler]
-pub async fn get_logout(mut auth: Auth) -> Result<impl IntoResponse, VentError> {
+pub async fn get_logout(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    end_impersonation(&state, &session).await?;
     auth.logout().await?;
     Ok(Redirect::to("/"))
 }
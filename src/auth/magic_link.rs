@@ -0,0 +1,278 @@
+//! Logging in with a one-time link sent by email, for roles that have it turned on in the `magic_link` section of the config.
+//!
+//! Someone's role gets checked again when they use a link, in case it has changed (or been taken out of the config) since it was sent.
+//!
+//! Opening the link shows a page with a button, rather than logging in straight away - otherwise email scanners that open links would use them up. Like passwords, these still need the second factor if someone has 2FA turned on.
+
+use crate::{
+    auth::{
+        backend::Auth,
+        get_auth_object,
+        login::{login_or_second_factor, LoginCreds},
+        rate_limit::{is_locked_out, record_failure, Attempter},
+        remote_ip::RemoteIp,
+    },
+    error::{ALError, LoginFailureReason, SqlxAction, SqlxSnafu, VentError},
+    state::{
+        mail::{EmailKind, EmailToSend},
+        VentState,
+    },
+};
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Redirect},
+    routing::get,
+    Form, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use tower_sessions::Session;
+
+///Like reset tokens, these are random enough that a fast hash is fine
+fn hash_magic_link_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+///`GET` method for the page to ask for a login link
+#[axum::debug_handler]
+async fn get_magic_link(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    if state.settings.magic_link.is_none() {
+        return Ok(Redirect::to("/login").into_response());
+    }
+
+    let aa = get_auth_object(auth, &session).await?;
+    Ok(state
+        .compile(
+            "www/magic_link.liquid",
+            liquid::object!({ "auth": aa, "stage": "ask" }),
+            None,
+        )
+        .await?
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct MagicLinkRequest {
+    username: String,
+    ///Each captcha provider sends its response under a different name
+    #[serde(default, alias = "cf-turnstile-response", alias = "h-captcha-response")]
+    captcha_response: String,
+}
+
+///`POST` method to send a login link - this always says that one got sent, so it can't be used to find out who has an account.
+#[axum::debug_handler]
+async fn post_magic_link(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: RemoteIp,
+    Form(MagicLinkRequest {
+        username,
+        captcha_response,
+    }): Form<MagicLinkRequest>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(settings) = &state.settings.magic_link else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+
+    let ip = Attempter::Ip(remote_ip.to_string());
+    if is_locked_out(&state, &[ip.clone(), Attempter::username(&username)]).await? {
+        return Ok(Redirect::to("/login_failure/locked_out").into_response());
+    }
+    if !state.captcha.verify(&captcha_response, &remote_ip).await? {
+        return Ok(Redirect::to("/login_failure/failed_captcha").into_response());
+    }
+
+    let person = sqlx::query!(
+        r#"
+SELECT p.id, p.username, p.first_name, p.surname, r.name AS role_name,
+       EXISTS (
+           SELECT 1 FROM magic_link_tokens mlt
+           WHERE mlt.person_id = p.id AND mlt.created_at > now() - INTERVAL '1 minute'
+       ) AS "recently_sent!"
+FROM people p
+INNER JOIN roles r ON r.id = p.role_id
+WHERE LOWER(p.username) = LOWER($1)"#,
+        username
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(username.clone().into()),
+    })?;
+
+    match person {
+        None => {
+            record_failure(&state, &[ip]).await?;
+        }
+        Some(person) if !settings.is_allowed_for(&person.role_name) => {
+            debug!(%username, role = %person.role_name, "Role can't use login links");
+        }
+        Some(person) if person.recently_sent => {
+            debug!(%username, "Already sent a login link recently");
+        }
+        Some(person) => {
+            let token = URL_SAFE_NO_PAD.encode(thread_rng().gen::<[u8; 32]>());
+
+            sqlx::query!(
+                "DELETE FROM magic_link_tokens WHERE person_id = $1 AND used_at IS NULL",
+                person.id
+            )
+            .execute(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::UpdatingMagicLinkTokens(person.id.into()),
+            })?;
+
+            sqlx::query!(
+                r#"
+INSERT INTO magic_link_tokens (person_id, hashed_token, expires_at)
+VALUES ($1, $2, now() + make_interval(mins => $3))"#,
+                person.id,
+                hash_magic_link_token(&token),
+                settings.lifetime_minutes
+            )
+            .execute(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::AddingMagicLinkToken(person.id.into()),
+            })?;
+
+            info!(person_id = %person.id, "Sending login link");
+            state.send_email(EmailToSend {
+                to_username: person.username,
+                to_id: person.id,
+                to_fullname: format!("{} {}", person.first_name, person.surname),
+                kind: EmailKind::MagicLink {
+                    token,
+                    lifetime_minutes: settings.lifetime_minutes,
+                },
+            });
+        }
+    }
+
+    let aa = get_auth_object(auth, &session).await?;
+    Ok(state
+        .compile(
+            "www/magic_link.liquid",
+            liquid::object!({ "auth": aa, "stage": "sent" }),
+            None,
+        )
+        .await?
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct MagicLinkToken {
+    token: String,
+}
+
+///`GET` method for the link in the email - this just shows a button to actually log in
+#[axum::debug_handler]
+async fn get_magic_link_login(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Query(MagicLinkToken { token }): Query<MagicLinkToken>,
+) -> Result<impl IntoResponse, VentError> {
+    let aa = get_auth_object(auth, &session).await?;
+    state
+        .compile(
+            "www/magic_link.liquid",
+            liquid::object!({ "auth": aa, "stage": "confirm", "token": token }),
+            None,
+        )
+        .await
+}
+
+///`POST` method that uses up the token and logs someone in
+#[axum::debug_handler]
+async fn post_magic_link_login(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: RemoteIp,
+    Form(MagicLinkToken { token }): Form<MagicLinkToken>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(settings) = &state.settings.magic_link else {
+        return Ok(Redirect::to("/login"));
+    };
+
+    let ip = Attempter::Ip(remote_ip.to_string());
+    if is_locked_out(&state, std::slice::from_ref(&ip)).await? {
+        return Ok(Redirect::to("/login_failure/locked_out"));
+    }
+
+    let hashed_token = hash_magic_link_token(&token);
+
+    let Some(link) = sqlx::query!(
+        r#"
+UPDATE magic_link_tokens mlt
+SET used_at = now()
+FROM people p
+INNER JOIN roles r ON r.id = p.role_id
+WHERE mlt.hashed_token = $1 AND mlt.used_at IS NULL AND mlt.expires_at > now() AND p.id = mlt.person_id
+RETURNING mlt.person_id, r.name AS role_name"#,
+        hashed_token
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingMagicLinkTokens,
+    })?
+    else {
+        let exists = sqlx::query!(
+            "SELECT id FROM magic_link_tokens WHERE hashed_token = $1",
+            hashed_token
+        )
+        .fetch_optional(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingMagicLinkTokens,
+        })?
+        .is_some();
+
+        return Ok(Redirect::to(if exists {
+            "/login_failure/expired_link"
+        } else {
+            record_failure(&state, &[ip]).await?;
+            "/login_failure/failed_numbers"
+        }));
+    };
+
+    let user_id = link.person_id;
+    if !settings.is_allowed_for(&link.role_name) {
+        warn!(%user_id, role = %link.role_name, "Role can't use login links any more");
+        return Ok(Redirect::to("/login_failure/link_not_allowed"));
+    }
+
+    let user = match auth.authenticate(LoginCreds::MagicLink { user_id }).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(Redirect::to("/login_failure/user_not_found")),
+        Err(ALError::Backend(VentError::LoginFailure {
+            reason: LoginFailureReason::LockedOut,
+        })) => return Ok(Redirect::to("/login_failure/locked_out")),
+        Err(e) => return Err(e.into()),
+    };
+
+    info!(%user_id, "Logged in with a login link");
+    let next = login_or_second_factor(&mut auth, &session, &state, user, None).await?;
+
+    Ok(Redirect::to(&next))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/magic_link", get(get_magic_link).post(post_magic_link))
+        .route(
+            "/magic_link/login",
+            get(get_magic_link_login).post(post_magic_link_login),
+        )
+}
@@ -0,0 +1,523 @@
+//! [OpenID Connect](https://openid.net/specs/openid-connect-core-1_0.html) single sign-on, using the authorization code flow with PKCE.
+//!
+//! The provider is set in the `oidc` section of the config. When someone comes back from the provider, their `preferred_username` claim (or failing that, their email) gets matched against `people.username`. Their email has to have been verified by the provider. Password login still works alongside this.
+
+use crate::{
+    auth::{
+        backend::Auth,
+        login::{login_or_second_factor, LoginCreds, NextUrl},
+    },
+    cfg::OidcSettings,
+    error::{
+        ReqwestAction, ReqwestSnafu, SerdeJsonAction, SerdeJsonSnafu, TowerSessionsSnafu, VentError,
+    },
+    state::VentState,
+};
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Redirect},
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+use std::{sync::Arc, time::Duration};
+use tower_sessions::Session;
+use url::Url;
+
+///Session key for the [`PendingOidcLogin`] whilst someone is off at the provider
+const PENDING_OIDC_KEY: &str = "pending_oidc_login";
+
+///How long the discovery document gets kept before asking the provider for it again
+const DISCOVERY_TTL_SECS: u64 = 60 * 60;
+
+///Discovery documents by issuer URL, so logging in doesn't need an extra request to the provider every time
+static DISCOVERY_CACHE: Lazy<Cache<String, Arc<ProviderMetadata>>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(DISCOVERY_TTL_SECS))
+        .build()
+});
+
+///The bits of the [discovery document](https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata) that we need
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingOidcLogin {
+    state: String,
+    nonce: String,
+    pkce_verifier: String,
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    aud: serde_json::Value,
+    ///When the token stops being valid, in seconds since the Unix epoch
+    exp: i64,
+    nonce: Option<String>,
+    #[serde(flatten)]
+    identity: IdentityClaims,
+}
+
+#[derive(Deserialize)]
+struct IdentityClaims {
+    preferred_username: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+impl IdentityClaims {
+    ///All of the usernames this could match, in the order they should be tried
+    fn candidate_usernames(self, username_domain: &str) -> Vec<String> {
+        let mut candidates = vec![];
+        candidates.extend(self.preferred_username);
+        if let Some(email) = self.email {
+            if let Some((local, domain)) = email.rsplit_once('@') {
+                if domain.eq_ignore_ascii_case(username_domain) {
+                    candidates.push(local.to_string());
+                }
+            }
+            candidates.push(email);
+        }
+        candidates
+    }
+
+    fn is_empty(&self) -> bool {
+        self.preferred_username.is_none() && self.email.is_none()
+    }
+
+    ///Whether the provider has checked that the person owns their email - without this, anyone could make an account at the provider with someone else's email and log in as them
+    fn is_verified(&self) -> bool {
+        self.email_verified == Some(true)
+    }
+}
+
+fn random_string(len: usize) -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn redirect_uri(state: &VentState) -> String {
+    format!(
+        "{}/oidc/callback",
+        state.settings.brand.domain.trim_end_matches('/')
+    )
+}
+
+async fn discover(settings: &OidcSettings) -> Result<Arc<ProviderMetadata>, VentError> {
+    if let Some(metadata) = DISCOVERY_CACHE.get(&settings.issuer_url).await {
+        return Ok(metadata);
+    }
+
+    let metadata: Arc<ProviderMetadata> = Arc::new(
+        Client::new()
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                settings.issuer_url.trim_end_matches('/')
+            ))
+            .send()
+            .await
+            .context(ReqwestSnafu {
+                action: ReqwestAction::OidcDiscovery,
+            })?
+            .error_for_status()
+            .with_context(|e| ReqwestSnafu {
+                action: ReqwestAction::RErrorForStatus(e.status()),
+            })?
+            .json()
+            .await
+            .context(ReqwestSnafu {
+                action: ReqwestAction::OidcDiscovery,
+            })?,
+    );
+
+    DISCOVERY_CACHE
+        .insert(settings.issuer_url.clone(), metadata.clone())
+        .await;
+    Ok(metadata)
+}
+
+///Swaps the code that the provider sent someone back with for their tokens
+async fn exchange_code(
+    metadata: &ProviderMetadata,
+    settings: &OidcSettings,
+    redirect_uri: &str,
+    code: &str,
+    pkce_verifier: &str,
+) -> Result<TokenResponse, VentError> {
+    Client::new()
+        .post(&metadata.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", settings.client_id.as_str()),
+            ("client_secret", settings.client_secret.as_str()),
+            ("code_verifier", pkce_verifier),
+        ])
+        .send()
+        .await
+        .context(ReqwestSnafu {
+            action: ReqwestAction::OidcTokenExchange,
+        })?
+        .error_for_status()
+        .with_context(|e| ReqwestSnafu {
+            action: ReqwestAction::RErrorForStatus(e.status()),
+        })?
+        .json()
+        .await
+        .context(ReqwestSnafu {
+            action: ReqwestAction::OidcTokenExchange,
+        })
+}
+
+///Reads the claims out of an ID token.
+///
+/// The signature isn't checked, which is fine as we got the token straight from the token endpoint over TLS (see [the spec](https://openid.net/specs/openid-connect-core-1_0.html#IDTokenValidation)), but the issuer, audience, expiry and nonce are. `now` is in seconds since the Unix epoch.
+fn read_id_token(
+    id_token: &str,
+    metadata: &ProviderMetadata,
+    settings: &OidcSettings,
+    expected_nonce: &str,
+    now: i64,
+) -> Result<Option<IdentityClaims>, VentError> {
+    let Some(payload) = id_token.split('.').nth(1) else {
+        warn!("Malformed ID token");
+        return Ok(None);
+    };
+    let claims: IdTokenClaims =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?).context(SerdeJsonSnafu {
+            action: SerdeJsonAction::OidcIdToken,
+        })?;
+
+    let audience_matches = match &claims.aud {
+        serde_json::Value::String(aud) => aud == &settings.client_id,
+        serde_json::Value::Array(auds) => auds
+            .iter()
+            .any(|aud| aud.as_str() == Some(settings.client_id.as_str())),
+        _ => false,
+    };
+
+    if claims.iss != metadata.issuer
+        || !audience_matches
+        || claims.exp <= now
+        || claims.nonce.as_deref() != Some(expected_nonce)
+    {
+        warn!(?claims.iss, ?claims.aud, ?claims.exp, "ID token failed validation");
+        return Ok(None);
+    }
+
+    Ok(Some(claims.identity))
+}
+
+///`GET` method that sends someone off to the OIDC provider
+#[axum::debug_handler]
+async fn get_oidc_login(
+    session: Session,
+    State(state): State<VentState>,
+    Query(NextUrl { next }): Query<NextUrl>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(settings) = &state.settings.oidc else {
+        return Ok(Redirect::to("/login"));
+    };
+
+    let metadata = discover(settings).await?;
+
+    let pending = PendingOidcLogin {
+        state: random_string(32),
+        nonce: random_string(32),
+        pkce_verifier: random_string(64),
+        next,
+    };
+    let pkce_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(pending.pkce_verifier.as_bytes()));
+
+    let url = Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", settings.client_id.as_str()),
+            ("redirect_uri", redirect_uri(&state).as_str()),
+            ("scope", "openid profile email"),
+            ("state", pending.state.as_str()),
+            ("nonce", pending.nonce.as_str()),
+            ("code_challenge", pkce_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )?;
+
+    session
+        .insert(PENDING_OIDC_KEY, pending)
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    Ok(Redirect::to(url.as_str()))
+}
+
+#[derive(Deserialize)]
+struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+///`GET` method that the OIDC provider sends people back to
+#[axum::debug_handler]
+async fn get_oidc_callback(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Query(params): Query<CallbackParams>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(settings) = &state.settings.oidc else {
+        return Ok(Redirect::to("/login"));
+    };
+
+    let Some(pending) = session
+        .remove::<PendingOidcLogin>(PENDING_OIDC_KEY)
+        .await
+        .context(TowerSessionsSnafu)?
+    else {
+        warn!("OIDC callback without a pending login");
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    };
+
+    if let Some(error) = params.error {
+        warn!(?error, ?params.error_description, "OIDC provider returned an error");
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    }
+    let (Some(code), Some(returned_state)) = (params.code, params.state) else {
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    };
+    if returned_state != pending.state {
+        warn!("OIDC state didn't match");
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    }
+
+    let metadata = discover(settings).await?;
+    let redirect_uri = redirect_uri(&state);
+
+    let tokens = exchange_code(
+        &metadata,
+        settings,
+        &redirect_uri,
+        &code,
+        &pending.pkce_verifier,
+    )
+    .await?;
+
+    let Some(mut identity) = read_id_token(
+        &tokens.id_token,
+        &metadata,
+        settings,
+        &pending.nonce,
+        Utc::now().timestamp(),
+    )?
+    else {
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    };
+
+    //some providers only put the profile claims in the userinfo response
+    if identity.is_empty() {
+        if let Some(userinfo_endpoint) = &metadata.userinfo_endpoint {
+            identity = Client::new()
+                .get(userinfo_endpoint)
+                .bearer_auth(&tokens.access_token)
+                .send()
+                .await
+                .context(ReqwestSnafu {
+                    action: ReqwestAction::OidcUserInfo,
+                })?
+                .error_for_status()
+                .with_context(|e| ReqwestSnafu {
+                    action: ReqwestAction::RErrorForStatus(e.status()),
+                })?
+                .json()
+                .await
+                .context(ReqwestSnafu {
+                    action: ReqwestAction::OidcUserInfo,
+                })?;
+        }
+    }
+
+    if !identity.is_verified() {
+        warn!(?identity.email, "OIDC email hasn't been verified by the provider");
+        return Ok(Redirect::to("/login_failure/failed_sso"));
+    }
+
+    for username in identity.candidate_usernames(&state.settings.mail.username_domain) {
+        match auth
+            .authenticate(LoginCreds::SingleSignOn {
+                username: username.clone(),
+            })
+            .await
+        {
+            Ok(Some(user)) => {
+                info!(%username, "Logged in with OIDC");
+                let next =
+                    login_or_second_factor(&mut auth, &session, &state, user, pending.next).await?;
+                return Ok(Redirect::to(&next));
+            }
+            Ok(None) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    warn!("Nobody matched the OIDC claims");
+    Ok(Redirect::to("/login_failure/user_not_found"))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/oidc/login", get(get_oidc_login))
+        .route("/oidc/callback", get(get_oidc_callback))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    const NONCE: &str = "nonce";
+    const NOW: i64 = 1_700_000_000;
+
+    ///Starts a local OIDC provider whose ID tokens have `claims` on top of some valid defaults, returning the settings to use it and how many times it has been asked for its discovery document
+    async fn mock_provider(claims: serde_json::Value) -> (OidcSettings, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let discoveries = Arc::new(AtomicUsize::new(0));
+
+        let mut all_claims = json!({
+            "iss": issuer,
+            "aud": "vent",
+            "exp": NOW + 60,
+            "nonce": NONCE,
+            "preferred_username": "student",
+            "email": "student@school.example",
+            "email_verified": true,
+        });
+        all_claims
+            .as_object_mut()
+            .unwrap()
+            .extend(claims.as_object().unwrap().clone());
+        let id_token = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(all_claims.to_string())
+        );
+
+        let metadata = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+        });
+        let counter = discoveries.clone();
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let metadata = metadata.clone();
+                    async move { Json(metadata) }
+                }),
+            )
+            .route(
+                "/token",
+                post(move || {
+                    let id_token = id_token.clone();
+                    async move { Json(json!({ "access_token": "access", "id_token": id_token })) }
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let settings = OidcSettings {
+            issuer_url: issuer,
+            client_id: "vent".into(),
+            client_secret: "secret".into(),
+            provider_name: "Mock".into(),
+        };
+        (settings, discoveries)
+    }
+
+    async fn log_in(claims: serde_json::Value) -> Option<IdentityClaims> {
+        let (settings, _) = mock_provider(claims).await;
+        let metadata = discover(&settings).await.unwrap();
+        let tokens = exchange_code(
+            &metadata,
+            &settings,
+            "http://vent/oidc/callback",
+            "code",
+            "verifier",
+        )
+        .await
+        .unwrap();
+        read_id_token(&tokens.id_token, &metadata, &settings, NONCE, NOW).unwrap()
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_mock_provider() {
+        let identity = log_in(json!({})).await.expect("valid token was rejected");
+        assert!(identity.is_verified());
+        assert_eq!(
+            identity.candidate_usernames("school.example"),
+            vec!["student", "student", "student@school.example"]
+        );
+    }
+
+    #[tokio::test]
+    async fn caches_discovery() {
+        let (settings, discoveries) = mock_provider(json!({})).await;
+        discover(&settings).await.unwrap();
+        discover(&settings).await.unwrap();
+        assert_eq!(discoveries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_tokens() {
+        assert!(log_in(json!({ "exp": NOW - 1 })).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_nonce_or_audience() {
+        assert!(log_in(json!({ "nonce": "other" })).await.is_none());
+        assert!(log_in(json!({ "aud": ["someone-else"] })).await.is_none());
+        assert!(log_in(json!({ "aud": ["someone-else", "vent"] }))
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn unverified_emails_are_not_trusted() {
+        let unverified = log_in(json!({ "email_verified": false })).await.unwrap();
+        assert!(!unverified.is_verified());
+
+        let missing = log_in(json!({ "email_verified": null })).await.unwrap();
+        assert!(!missing.is_verified());
+    }
+}
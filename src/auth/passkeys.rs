@@ -0,0 +1,554 @@
+//! [WebAuthn](https://www.w3.org/TR/webauthn-3/) passkeys, so people can log in without a password.
+//!
+//! Passkeys get registered from `edit_self`, and then used on `/login` after typing in a username. The browser side is in `partials/passkeys.liquid`, which talks to the JSON endpoints here. The ceremony state lives in the session between the start and finish requests.
+//!
+//! Passkeys always require user verification (eg. a PIN or fingerprint), so logging in with one skips TOTP.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        login::LoginCreds,
+        rate_limit::{clear_failures, is_locked_out, record_failure, Attempter},
+        remote_ip::RemoteIp,
+    },
+    cfg::Settings,
+    error::{ALError, LoginFailureReason, SqlxAction, SqlxSnafu, TowerSessionsSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::VentState,
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    routing::post,
+    Form, Json, Router,
+};
+use axum_login::login_required;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use snafu::ResultExt;
+use sqlx::types::Json as SqlxJson;
+use tower_sessions::Session;
+use webauthn_rs::{
+    prelude::{
+        Passkey, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential,
+        RegisterPublicKeyCredential, RequestChallengeResponse, Url, Uuid,
+    },
+    Webauthn, WebauthnBuilder,
+};
+
+///Session key for the [`PasskeyRegistration`] whilst someone's authenticator is making a passkey
+const PENDING_REGISTRATION_KEY: &str = "pending_passkey_registration";
+///Session key for the [`PendingPasskeyLogin`] whilst someone's authenticator is signing the challenge
+const PENDING_LOGIN_KEY: &str = "pending_passkey_login";
+
+///Makes the relying party from `brand.domain` - panics if it isn't a URL with a host.
+pub fn build_webauthn(settings: &Settings) -> Webauthn {
+    let origin = Url::parse(&settings.brand.domain).expect("brand.domain must be a URL");
+    let rp_id = origin
+        .host_str()
+        .expect("brand.domain must have a host")
+        .to_string();
+
+    WebauthnBuilder::new(&rp_id, &origin)
+        .expect("unable to use brand.domain for WebAuthn")
+        .rp_name(&settings.brand.instance_name)
+        .build()
+        .expect("unable to build WebAuthn relying party")
+}
+
+///Passkeys need a stable handle for each user that isn't personal information - the database ID is fine for that.
+fn user_handle(person_id: i32) -> Uuid {
+    Uuid::from_u128(person_id as u128)
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingPasskeyLogin {
+    user_id: i32,
+    state: PasskeyAuthentication,
+    next: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PasskeyInfo {
+    id: i32,
+    name: String,
+    created_at: String,
+    last_used: Option<String>,
+}
+
+struct StoredPasskey {
+    id: i32,
+    passkey: Passkey,
+}
+
+async fn get_stored_passkeys(
+    state: &VentState,
+    person_id: i32,
+) -> Result<Vec<StoredPasskey>, VentError> {
+    Ok(sqlx::query!(
+        r#"SELECT id, passkey AS "passkey: SqlxJson<Passkey>" FROM passkeys WHERE person_id = $1"#,
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPasskeys(person_id.into()),
+    })?
+    .into_iter()
+    .map(|rec| StoredPasskey {
+        id: rec.id,
+        passkey: rec.passkey.0,
+    })
+    .collect())
+}
+
+///Gets the passkeys someone has registered, for showing on `edit_self` and `edit_person`
+pub async fn get_passkeys(
+    state: &VentState,
+    person_id: i32,
+) -> Result<Vec<PasskeyInfo>, VentError> {
+    Ok(sqlx::query!(
+        "SELECT id, name, created_at, last_used FROM passkeys WHERE person_id = $1 ORDER BY created_at",
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPasskeys(person_id.into()),
+    })?
+    .into_iter()
+    .map(|rec| PasskeyInfo {
+        id: rec.id,
+        name: rec.name,
+        created_at: rec
+            .created_at
+            .to_env_string(&state.settings.niche.date_time_format),
+        last_used: rec
+            .last_used
+            .map(|last_used| last_used.to_env_string(&state.settings.niche.date_time_format)),
+    })
+    .collect())
+}
+
+///Removes one of someone's passkeys - the person ID is checked so people can only remove their own
+pub async fn remove_passkey(
+    state: &VentState,
+    person_id: i32,
+    passkey_id: i32,
+) -> Result<(), VentError> {
+    sqlx::query!(
+        "DELETE FROM passkeys WHERE id = $1 AND person_id = $2",
+        passkey_id,
+        person_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingPasskey(passkey_id),
+    })?;
+
+    Ok(())
+}
+
+///`POST` method that starts registering a passkey, returning the options for `navigator.credentials.create`
+#[axum::debug_handler]
+async fn post_start_registration(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let user = auth.user.as_ref().unwrap();
+
+    let existing = get_stored_passkeys(&state, user.id)
+        .await?
+        .into_iter()
+        .map(|stored| stored.passkey.cred_id().clone())
+        .collect();
+
+    let (challenge, registration) = state.webauthn.start_passkey_registration(
+        user_handle(user.id),
+        &user.username,
+        &format!("{} {}", user.first_name, user.surname),
+        Some(existing),
+    )?;
+
+    session
+        .insert(PENDING_REGISTRATION_KEY, registration)
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    Ok(Json(challenge))
+}
+
+#[derive(Deserialize)]
+struct FinishRegistration {
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+///`POST` method that checks and saves the new passkey
+#[axum::debug_handler]
+async fn post_finish_registration(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Json(FinishRegistration { name, credential }): Json<FinishRegistration>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.as_ref().unwrap().id;
+
+    let Some(registration) = session
+        .remove::<PasskeyRegistration>(PENDING_REGISTRATION_KEY)
+        .await
+        .context(TowerSessionsSnafu)?
+    else {
+        return Ok(Json(
+            json!({ "error": "No passkey registration was in progress." }),
+        ));
+    };
+
+    let passkey = match state
+        .webauthn
+        .finish_passkey_registration(&credential, &registration)
+    {
+        Ok(passkey) => passkey,
+        Err(e) => {
+            warn!(?e, %current_id, "Failed to register passkey");
+            return Ok(Json(
+                json!({ "error": "Your device's passkey couldn't be verified." }),
+            ));
+        }
+    };
+
+    let name = match name.trim() {
+        "" => "Passkey".to_string(),
+        name => name.to_string(),
+    };
+
+    let credential_id: &[u8] = passkey.cred_id().as_ref();
+    sqlx::query!(
+        "INSERT INTO passkeys (person_id, name, credential_id, passkey) VALUES ($1, $2, $3, $4)",
+        current_id,
+        name,
+        credential_id,
+        SqlxJson(&passkey) as _
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingPasskey(current_id.into()),
+    })?;
+
+    info!(%current_id, "Registered passkey");
+
+    Ok(Json(json!({ "redirect": "/edit_user" })))
+}
+
+#[derive(Deserialize)]
+struct PasskeyId {
+    passkey_id: i32,
+}
+
+///`POST` method for someone to remove one of their own passkeys
+#[axum::debug_handler]
+async fn post_remove_passkey(
+    auth: Auth,
+    State(state): State<VentState>,
+    Form(PasskeyId { passkey_id }): Form<PasskeyId>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.unwrap().id;
+    remove_passkey(&state, current_id, passkey_id).await?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+///Makes the challenge for whoever was found with a username, and their passkeys.
+///
+/// Nobody having that username and them not having any passkeys both give `None`, so that starting a login can't be used to find out who has an account.
+fn start_authentication(
+    webauthn: &Webauthn,
+    found: Option<(i32, Vec<Passkey>)>,
+) -> Result<Option<(i32, RequestChallengeResponse, PasskeyAuthentication)>, VentError> {
+    let Some((user_id, passkeys)) = found.filter(|(_, passkeys)| !passkeys.is_empty()) else {
+        return Ok(None);
+    };
+
+    let (challenge, authentication) = webauthn.start_passkey_authentication(&passkeys)?;
+    Ok(Some((user_id, challenge, authentication)))
+}
+
+#[derive(Deserialize)]
+struct StartLogin {
+    username: String,
+    next: Option<String>,
+}
+
+///`POST` method that starts logging in with a passkey, returning the options for `navigator.credentials.get`, or where to go if that won't work.
+#[axum::debug_handler]
+async fn post_start_login(
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: RemoteIp,
+    Json(StartLogin { username, next }): Json<StartLogin>,
+) -> Result<impl IntoResponse, VentError> {
+    let ip = Attempter::Ip(remote_ip.to_string());
+    if is_locked_out(&state, &[ip.clone(), Attempter::username(&username)]).await? {
+        return Ok(Json(json!({ "redirect": "/login_failure/locked_out" })));
+    }
+
+    let user_id = sqlx::query!(
+        "SELECT id FROM people WHERE LOWER(username) = LOWER($1)",
+        username
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(username.clone().into()),
+    })?
+    .map(|rec| rec.id);
+
+    let found = match user_id {
+        Some(user_id) => Some((
+            user_id,
+            get_stored_passkeys(&state, user_id)
+                .await?
+                .into_iter()
+                .map(|stored| stored.passkey)
+                .collect(),
+        )),
+        None => None,
+    };
+
+    let Some((user_id, challenge, authentication)) = start_authentication(&state.webauthn, found)?
+    else {
+        record_failure(&state, &[ip]).await?;
+        return Ok(Json(json!({ "redirect": "/login_failure/failed_passkey" })));
+    };
+
+    session
+        .insert(
+            PENDING_LOGIN_KEY,
+            PendingPasskeyLogin {
+                user_id,
+                state: authentication,
+                next,
+            },
+        )
+        .await
+        .context(TowerSessionsSnafu)?;
+
+    Ok(Json(json!({ "challenge": challenge })))
+}
+
+///`POST` method that checks the signed challenge and logs someone in, returning where to go next
+#[axum::debug_handler]
+async fn post_finish_login(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: RemoteIp,
+    Json(credential): Json<PublicKeyCredential>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(PendingPasskeyLogin {
+        user_id,
+        state: authentication,
+        next,
+    }) = session
+        .remove::<PendingPasskeyLogin>(PENDING_LOGIN_KEY)
+        .await
+        .context(TowerSessionsSnafu)?
+    else {
+        return Ok(Json(json!({ "redirect": "/login_failure/failed_passkey" })));
+    };
+
+    let result = match state
+        .webauthn
+        .finish_passkey_authentication(&credential, &authentication)
+    {
+        Ok(result) => result,
+        Err(e) => {
+            warn!(?e, %user_id, %remote_ip, "Failed passkey login");
+            record_failure(
+                &state,
+                &[
+                    Attempter::Ip(remote_ip.to_string()),
+                    Attempter::for_person(&state, user_id).await?,
+                ],
+            )
+            .await?;
+            return Ok(Json(json!({ "redirect": "/login_failure/failed_passkey" })));
+        }
+    };
+
+    //keep the signature counter up to date, so cloned authenticators can be spotted
+    for StoredPasskey { id, mut passkey } in get_stored_passkeys(&state, user_id).await? {
+        if passkey.cred_id() != result.cred_id() {
+            continue;
+        }
+
+        let counter_changed = passkey.update_credential(&result).unwrap_or(false);
+        sqlx::query!(
+            r#"
+UPDATE passkeys
+SET last_used = now(), passkey = CASE WHEN $2 THEN $3 ELSE passkey END
+WHERE id = $1"#,
+            id,
+            counter_changed,
+            SqlxJson(&passkey) as _
+        )
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingPasskey(id),
+        })?;
+    }
+
+    let user = match auth.authenticate(LoginCreds::Passkey { user_id }).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Ok(Json(json!({ "redirect": "/login_failure/failed_passkey" }))),
+        Err(ALError::Backend(VentError::LoginFailure {
+            reason: LoginFailureReason::LockedOut,
+        })) => return Ok(Json(json!({ "redirect": "/login_failure/locked_out" }))),
+        Err(e) => return Err(e.into()),
+    };
+
+    info!(%user_id, "Logged in with passkey");
+    clear_failures(&state, &Attempter::username(&user.username)).await?;
+    auth.login(&user).await?;
+
+    Ok(Json(
+        json!({ "redirect": next.unwrap_or_else(|| "/".to_string()) }),
+    ))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/passkeys/register/start", post(post_start_registration))
+        .route("/passkeys/register/finish", post(post_finish_registration))
+        .route("/passkeys/remove", post(post_remove_passkey))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+        .route("/passkeys/login/start", post(post_start_login))
+        .route("/passkeys/login/finish", post(post_finish_login))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+    const ORIGIN: &str = "https://vent.example";
+
+    fn webauthn() -> Webauthn {
+        WebauthnBuilder::new("vent.example", &Url::parse(ORIGIN).unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    fn authenticator() -> WebauthnAuthenticator<SoftPasskey> {
+        //passkeys always need user verification, which a soft token can't really do
+        WebauthnAuthenticator::new(SoftPasskey::new(true))
+    }
+
+    fn register(
+        webauthn: &Webauthn,
+        authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+        person_id: i32,
+    ) -> Passkey {
+        let (challenge, registration) = webauthn
+            .start_passkey_registration(user_handle(person_id), "username", "Display Name", None)
+            .unwrap();
+        let credential = authenticator
+            .do_registration(Url::parse(ORIGIN).unwrap(), challenge)
+            .unwrap();
+
+        webauthn
+            .finish_passkey_registration(&credential, &registration)
+            .unwrap()
+    }
+
+    fn sign(
+        authenticator: &mut WebauthnAuthenticator<SoftPasskey>,
+        challenge: RequestChallengeResponse,
+    ) -> PublicKeyCredential {
+        authenticator
+            .do_authentication(Url::parse(ORIGIN).unwrap(), challenge)
+            .unwrap()
+    }
+
+    #[test]
+    fn unknown_usernames_look_like_no_passkeys() {
+        let webauthn = webauthn();
+
+        assert!(start_authentication(&webauthn, None).unwrap().is_none());
+        assert!(start_authentication(&webauthn, Some((1, vec![])))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn registered_passkeys_can_log_in() {
+        let webauthn = webauthn();
+        let mut authenticator = authenticator();
+        let mut passkey = register(&webauthn, &mut authenticator, 1);
+
+        let (user_id, challenge, authentication) =
+            start_authentication(&webauthn, Some((1, vec![passkey.clone()])))
+                .unwrap()
+                .unwrap();
+        assert_eq!(user_id, 1);
+
+        let credential = sign(&mut authenticator, challenge);
+        let result = webauthn
+            .finish_passkey_authentication(&credential, &authentication)
+            .unwrap();
+
+        assert!(result.user_verified());
+        assert_eq!(result.cred_id(), passkey.cred_id());
+        assert_eq!(passkey.update_credential(&result), Some(true));
+    }
+
+    #[test]
+    fn replayed_responses_are_rejected() {
+        let webauthn = webauthn();
+        let mut authenticator = authenticator();
+        let passkey = register(&webauthn, &mut authenticator, 1);
+
+        let (_, challenge, authentication) =
+            start_authentication(&webauthn, Some((1, vec![passkey.clone()])))
+                .unwrap()
+                .unwrap();
+        let credential = sign(&mut authenticator, challenge);
+        webauthn
+            .finish_passkey_authentication(&credential, &authentication)
+            .unwrap();
+
+        let (_, _, next_authentication) = start_authentication(&webauthn, Some((1, vec![passkey])))
+            .unwrap()
+            .unwrap();
+        assert!(webauthn
+            .finish_passkey_authentication(&credential, &next_authentication)
+            .is_err());
+    }
+
+    #[test]
+    fn other_peoples_passkeys_are_rejected() {
+        let webauthn = webauthn();
+        let mut ours = authenticator();
+        let mut theirs = authenticator();
+        let our_passkey = register(&webauthn, &mut ours, 1);
+        let their_passkey = register(&webauthn, &mut theirs, 2);
+
+        let (_, _, authentication) = start_authentication(&webauthn, Some((1, vec![our_passkey])))
+            .unwrap()
+            .unwrap();
+        let (_, their_challenge, _) =
+            start_authentication(&webauthn, Some((2, vec![their_passkey])))
+                .unwrap()
+                .unwrap();
+        let credential = sign(&mut theirs, their_challenge);
+
+        assert!(webauthn
+            .finish_passkey_authentication(&credential, &authentication)
+            .is_err());
+    }
+}
@@ -0,0 +1,110 @@
+//! Hashing and checking passwords.
+//!
+//! New passwords get hashed with Argon2id, using the parameters from the `password_hashing` section of the config. Older bcrypt hashes (which start with `$2`) still work, and [`verify_password`] says when a hash should be replaced so that they get upgraded as people log in.
+//!
+//! Both of these are deliberately slow, so they run on the blocking thread pool rather than holding up other requests.
+
+use crate::{
+    cfg::PasswordHashSettings,
+    error::{JoinSnafu, ThreadReason, VentError},
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use snafu::ResultExt;
+use tokio::task::spawn_blocking;
+
+///What happened when checking a password against a hash
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PasswordCheck {
+    Incorrect,
+    ///`needs_rehash` is set if the hash is bcrypt, or Argon2 with different parameters to the config
+    Correct {
+        needs_rehash: bool,
+    },
+}
+
+fn argon2(settings: &PasswordHashSettings) -> Result<Argon2<'static>, VentError> {
+    let params = Params::new(
+        settings.memory_kib,
+        settings.iterations,
+        settings.parallelism,
+        None,
+    )?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+///Hashes a new password with Argon2id
+pub async fn hash_password(
+    settings: &PasswordHashSettings,
+    password: &str,
+) -> Result<String, VentError> {
+    let settings = settings.clone();
+    let password = password.to_string();
+
+    spawn_blocking(move || hash_password_blocking(&settings, &password))
+        .await
+        .context(JoinSnafu {
+            title: ThreadReason::HashingPassword,
+        })?
+}
+
+fn hash_password_blocking(
+    settings: &PasswordHashSettings,
+    password: &str,
+) -> Result<String, VentError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(argon2(settings)?
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+///Checks a password against either an Argon2 or a bcrypt hash
+pub async fn verify_password(
+    settings: &PasswordHashSettings,
+    password: &str,
+    hashed_password: &str,
+) -> Result<PasswordCheck, VentError> {
+    let settings = settings.clone();
+    let password = password.to_string();
+    let hashed_password = hashed_password.to_string();
+
+    spawn_blocking(move || verify_password_blocking(&settings, &password, &hashed_password))
+        .await
+        .context(JoinSnafu {
+            title: ThreadReason::CheckingPassword,
+        })?
+}
+
+fn verify_password_blocking(
+    settings: &PasswordHashSettings,
+    password: &str,
+    hashed_password: &str,
+) -> Result<PasswordCheck, VentError> {
+    if !hashed_password.starts_with("$argon2") {
+        return Ok(if bcrypt::verify(password, hashed_password)? {
+            PasswordCheck::Correct { needs_rehash: true }
+        } else {
+            PasswordCheck::Incorrect
+        });
+    }
+
+    let parsed = PasswordHash::new(hashed_password)?;
+    let hasher = argon2(settings)?;
+    match hasher.verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => {}
+        Err(argon2::password_hash::Error::Password) => return Ok(PasswordCheck::Incorrect),
+        Err(e) => return Err(e.into()),
+    }
+
+    let wanted = hasher.params();
+    let needs_rehash = parsed.algorithm != Algorithm::Argon2id.ident()
+        || Params::try_from(&parsed).map_or(true, |params| {
+            params.m_cost() != wanted.m_cost()
+                || params.t_cost() != wanted.t_cost()
+                || params.p_cost() != wanted.p_cost()
+        });
+
+    Ok(PasswordCheck::Correct { needs_rehash })
+}
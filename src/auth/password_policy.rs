@@ -0,0 +1,37 @@
+//! Checking new passwords against the `password_policy` section of the config, using [zxcvbn](https://github.com/dropbox/zxcvbn) to guess how easy they'd be to crack.
+
+use crate::cfg::PasswordPolicySettings;
+use zxcvbn::zxcvbn;
+
+///Checks a new password, returning everything wrong with it for the person to fix - if it's empty, the password is fine.
+///
+/// `user_inputs` should be things like their name and username, which make for easy guesses.
+pub fn password_problems(
+    policy: &PasswordPolicySettings,
+    password: &str,
+    user_inputs: &[&str],
+) -> Vec<String> {
+    let mut problems = vec![];
+
+    if password.chars().count() < policy.min_length {
+        problems.push(format!(
+            "Passwords need to be at least {} characters long.",
+            policy.min_length
+        ));
+    }
+
+    let entropy = zxcvbn(password, user_inputs);
+    if (entropy.score() as u8) < policy.min_score {
+        let before = problems.len();
+        if let Some(feedback) = entropy.feedback() {
+            problems.extend(feedback.warning().map(|warning| warning.to_string()));
+            problems.extend(feedback.suggestions().iter().map(ToString::to_string));
+        }
+        //zxcvbn doesn't always say why
+        if problems.len() == before {
+            problems.push("That password is too easy to guess.".to_string());
+        }
+    }
+
+    problems
+}
@@ -1,4 +1,6 @@
-use crate::error::{ComponentRangeSnafu, SerdeJsonAction, SerdeJsonSnafu, SqlxAction, SqlxSnafu};
+use crate::error::{
+    ComponentRangeSnafu, SerdeJsonAction, SerdeJsonSnafu, SqlxAction, SqlxSnafu, VentError,
+};
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
 use serde_json::from_slice;
 use snafu::ResultExt;
@@ -19,17 +21,25 @@ impl PostgresStore {
     pub fn new(pool: Pool<Postgres>) -> Self {
         Self { pool }
     }
+
+    ///Deletes all of the expired sessions, returning how many there were
+    pub async fn delete_expired_sessions(&self) -> Result<u64, VentError> {
+        Ok(
+            sqlx::query!("DELETE FROM public.sessions where expiry_date < (now())")
+                .execute(&self.pool)
+                .await
+                .context(SqlxSnafu {
+                    action: SqlxAction::DeletingOldSessions,
+                })?
+                .rows_affected(),
+        )
+    }
 }
 
 #[async_trait]
 impl ExpiredDeletion for PostgresStore {
     async fn delete_expired(&self) -> Result<(), SSError> {
-        sqlx::query!("DELETE FROM public.sessions where expiry_date < (now())")
-            .execute(&self.pool)
-            .await
-            .context(SqlxSnafu {
-                action: SqlxAction::DeletingOldSessions,
-            })?;
+        self.delete_expired_sessions().await?;
         Ok(())
     }
 }
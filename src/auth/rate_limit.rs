@@ -0,0 +1,186 @@
+//! Tracking failed login attempts per-IP and per-username, with exponential backoff.
+//!
+//! The first few failures are free, and then every failure after that locks out that IP or username for twice as long as the last one (up to a maximum). Failures are forgotten after a day without any, or when someone logs in successfully.
+//!
+//! IPs get far more free failures than usernames, as lots of people can share one - a school's network, or a reverse proxy that isn't in `trusted_proxies`.
+
+use crate::{
+    error::{SqlxAction, SqlxSnafu, VentError},
+    state::VentState,
+};
+use chrono::NaiveDateTime;
+use snafu::ResultExt;
+use sqlx::{Pool, Postgres};
+
+///How many failures in a row are allowed for a username before it gets locked out
+const FREE_USERNAME_ATTEMPTS: i32 = 5;
+///How many failures in a row are allowed from an IP before it gets locked out
+const FREE_IP_ATTEMPTS: i32 = 50;
+///How long the first lockout is
+const BASE_LOCKOUT_SECS: f64 = 30.0;
+///The longest a lockout can be
+const MAX_LOCKOUT_SECS: f64 = 60.0 * 60.0;
+
+///Something that could be trying to log in - stored in `login_throttles` as a `kind` and an `identifier`.
+#[derive(Debug, Clone)]
+pub enum Attempter {
+    Ip(String),
+    Username(String),
+}
+
+impl Attempter {
+    pub fn username(username: &str) -> Self {
+        Self::Username(username.to_lowercase())
+    }
+
+    ///Gets the [`Attempter::Username`] for the person with that ID
+    pub async fn for_person(state: &VentState, person_id: i32) -> Result<Self, VentError> {
+        let username = sqlx::query!("SELECT username FROM people WHERE id = $1", person_id)
+            .fetch_one(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::FindingPerson(person_id.into()),
+            })?
+            .username;
+
+        Ok(Self::username(&username))
+    }
+
+    fn free_attempts(&self) -> i32 {
+        match self {
+            Self::Ip(_) => FREE_IP_ATTEMPTS,
+            Self::Username(_) => FREE_USERNAME_ATTEMPTS,
+        }
+    }
+
+    fn parts(&self) -> (&'static str, &str) {
+        match self {
+            Self::Ip(ip) => ("ip", ip),
+            Self::Username(username) => ("username", username),
+        }
+    }
+}
+
+///Gets when an attempter is locked out until, if they're currently locked out
+pub async fn locked_until(
+    state: &VentState,
+    attempter: &Attempter,
+) -> Result<Option<NaiveDateTime>, VentError> {
+    let (kind, identifier) = attempter.parts();
+
+    Ok(sqlx::query!(
+        r#"
+SELECT locked_until AS "locked_until!"
+FROM login_throttles
+WHERE kind = $1 AND identifier = $2 AND locked_until > now()"#,
+        kind,
+        identifier
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingLoginThrottles,
+    })?
+    .map(|rec| rec.locked_until))
+}
+
+///Whether any of the attempters are currently locked out
+pub async fn is_locked_out(state: &VentState, attempters: &[Attempter]) -> Result<bool, VentError> {
+    for attempter in attempters {
+        if locked_until(state, attempter).await?.is_some() {
+            debug!(?attempter, "Locked out");
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+///Records a failed attempt against all of the attempters, locking them out if they've had too many
+pub async fn record_failure(state: &VentState, attempters: &[Attempter]) -> Result<(), VentError> {
+    for attempter in attempters {
+        let (kind, identifier) = attempter.parts();
+
+        let failures = sqlx::query!(
+            r#"
+INSERT INTO login_throttles (kind, identifier, failures, last_failure)
+VALUES ($1, $2, 1, now())
+ON CONFLICT (kind, identifier) DO UPDATE SET
+    failures = CASE
+        WHEN login_throttles.last_failure < now() - INTERVAL '1 day' THEN 1
+        ELSE login_throttles.failures + 1
+    END,
+    last_failure = now()
+RETURNING failures"#,
+            kind,
+            identifier
+        )
+        .fetch_one(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingLoginThrottles,
+        })?
+        .failures;
+
+        let free_attempts = attempter.free_attempts();
+        if failures <= free_attempts {
+            continue;
+        }
+
+        let lockout_secs =
+            (BASE_LOCKOUT_SECS * 2_f64.powi(failures - free_attempts - 1)).min(MAX_LOCKOUT_SECS);
+        warn!(?attempter, %failures, %lockout_secs, "Locking out after failed logins");
+
+        sqlx::query!(
+            r#"
+UPDATE login_throttles
+SET locked_until = now() + make_interval(secs => $3)
+WHERE kind = $1 AND identifier = $2"#,
+            kind,
+            identifier,
+            lockout_secs
+        )
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingLoginThrottles,
+        })?;
+    }
+
+    Ok(())
+}
+
+///Forgets all of the failures (and any lockout) for an attempter - used when someone logs in successfully, or an admin unlocks them.
+pub async fn clear_failures(state: &VentState, attempter: &Attempter) -> Result<(), VentError> {
+    let (kind, identifier) = attempter.parts();
+
+    sqlx::query!(
+        "DELETE FROM login_throttles WHERE kind = $1 AND identifier = $2",
+        kind,
+        identifier
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingLoginThrottles,
+    })?;
+
+    Ok(())
+}
+
+///Deletes every throttle which has been forgotten (a day without failures) and isn't locked out, returning how many there were.
+///
+/// This includes usernames which don't belong to anyone, which would otherwise pile up forever.
+pub async fn delete_stale_throttles(pool: &Pool<Postgres>) -> Result<u64, VentError> {
+    Ok(sqlx::query!(
+        r#"
+DELETE FROM login_throttles
+WHERE last_failure < now() - INTERVAL '1 day' AND (locked_until IS NULL OR locked_until < now())"#
+    )
+    .execute(pool)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::DeletingOldLoginThrottles,
+    })?
+    .rows_affected())
+}
@@ -0,0 +1,166 @@
+//! Working out the IP address of whoever sent a request, taking into account any reverse proxies in front of Vent.
+//!
+//! Forwarding headers are only believed if the connection comes from one of the `trusted_proxies` in the config, and only the one `forwarded_header` that those proxies set is looked at - a client could send any of the others itself. Then, the chain of addresses in the headers is walked backwards, skipping over any more trusted proxies, and the first address that isn't trusted is the client.
+
+use crate::{cfg::ForwardedHeader, error::VentError, state::VentState, VentConnection};
+use axum::extract::{ConnectInfo, FromRequestParts};
+use http::{request::Parts, HeaderMap};
+use ipnet::IpNet;
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, SocketAddr},
+};
+
+///The IP address of the client that sent the request - use this anywhere that needs to know who someone is (eg. rate limiting, logs or captchas).
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteIp(pub IpAddr);
+
+impl Display for RemoteIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+///Parses one address from a forwarding header, which might have a port, or be in square brackets if it's IPv6
+fn parse_forwarded_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim().trim_matches('"');
+
+    address
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| address.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            address
+                .strip_prefix('[')
+                .and_then(|address| address.strip_suffix(']'))
+                .and_then(|address| address.parse().ok())
+        })
+}
+
+///Gets the chain of addresses from the forwarding header, with the client first and the closest proxy last.
+///
+/// Addresses that can't be parsed (eg. `unknown` or obfuscated identifiers from [RFC 7239](https://datatracker.ietf.org/doc/html/rfc7239)) are `None`.
+fn forwarded_chain(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    let values = headers
+        .get_all(header.name())
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+
+    if header == ForwardedHeader::Forwarded {
+        values
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_forwarded_address(value))
+            })
+            .collect()
+    } else {
+        values.map(parse_forwarded_address).collect()
+    }
+}
+
+///Works out the client's address from the address of the connection and the headers
+pub fn client_ip(
+    peer: IpAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+    forwarded_header: ForwardedHeader,
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let mut client = peer;
+    for hop in forwarded_chain(headers, forwarded_header).into_iter().rev() {
+        let Some(hop) = hop else {
+            //the last trusted proxy couldn't say who it was talking to
+            break;
+        };
+
+        client = hop;
+        if !is_trusted(&hop) {
+            break;
+        }
+    }
+
+    client
+}
+
+#[async_trait]
+impl FromRequestParts<VentState> for RemoteIp {
+    type Rejection = VentError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &VentState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(ConnectInfo(connection)) = parts.extensions.get::<ConnectInfo<VentConnection>>()
+        else {
+            error!("Failed to get Remote IP");
+            return Err(VentError::MissingRemoteIp);
+        };
+
+        Ok(Self(client_ip(
+            connection.remote_addr.ip(),
+            &parts.headers,
+            &state.settings.trusted_proxies,
+            state.settings.forwarded_header,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    const PROXY: &str = "10.0.0.1";
+    const CLIENT: &str = "203.0.113.7";
+    const SPOOFED: &str = "198.51.100.1";
+
+    fn client(headers: &[(&'static str, &str)], forwarded_header: ForwardedHeader) -> IpAddr {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+
+        client_ip(
+            PROXY.parse().unwrap(),
+            &map,
+            &["10.0.0.0/8".parse().unwrap()],
+            forwarded_header,
+        )
+    }
+
+    #[test]
+    fn only_the_configured_header_is_believed() {
+        let headers = [
+            ("Forwarded", &*format!("for={SPOOFED}")),
+            ("X-Forwarded-For", CLIENT),
+            ("X-Real-IP", SPOOFED),
+            ("CF-Connecting-IP", SPOOFED),
+        ];
+
+        assert_eq!(
+            client(&headers, ForwardedHeader::XForwardedFor),
+            CLIENT.parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            client(&headers, ForwardedHeader::Forwarded),
+            SPOOFED.parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_header_is_the_proxy() {
+        assert_eq!(
+            client(&[("X-Real-IP", CLIENT)], ForwardedHeader::XForwardedFor),
+            PROXY.parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Letting people see where they're logged in, and log out their other sessions.
+//!
+//! [`record_session_metadata`] keeps the extra columns on the `sessions` table up to date, which [`PostgresStore`](crate::auth::pg_session::PostgresStore) doesn't know about. Sessions are referred to by their `handle` rather than their ID, as the ID is what goes in the cookie.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        impersonation::get_impersonation,
+        remote_ip::RemoteIp,
+    },
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::VentState,
+};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::post,
+    Form, Router,
+};
+use axum_login::login_required;
+use http::header::USER_AGENT;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use tower_sessions::Session;
+
+///The longest user agent that gets stored
+const MAX_USER_AGENT_LEN: usize = 512;
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    handle: i32,
+    created_at: String,
+    last_seen: String,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    is_current: bool,
+}
+
+///Middleware that records who each session belongs to, and when and where it was last used.
+///
+/// To avoid writing on every request, `last_seen` only gets updated once a minute.
+pub async fn record_session_metadata(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: RemoteIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let (Some(user), Some(session_id)) = (&auth.user, session.id()) {
+        //devs viewing the site as someone else shouldn't show up in that person's sessions
+        let person_id = match get_impersonation(&session).await {
+            Ok(Some(impersonation)) => impersonation.impersonator_id,
+            Ok(None) => user.id,
+            Err(e) => {
+                error!(?e, "Error checking for impersonation");
+                user.id
+            }
+        };
+
+        let user_agent = request
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(|user_agent| {
+                user_agent
+                    .chars()
+                    .take(MAX_USER_AGENT_LEN)
+                    .collect::<String>()
+            });
+
+        let result = async {
+            sqlx::query!(
+                r#"
+UPDATE sessions
+SET person_id = $1, last_seen = now(), ip_address = $2, user_agent = $3
+WHERE id = $4 AND (
+    person_id IS DISTINCT FROM $1
+    OR ip_address IS DISTINCT FROM $2
+    OR last_seen < now() - INTERVAL '1 minute'
+)"#,
+                person_id,
+                remote_ip.to_string(),
+                user_agent,
+                session_id.to_string()
+            )
+            .execute(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::UpdatingSessionMetadata(session_id),
+            })
+        }
+        .await;
+
+        if let Err(e) = result {
+            error!(?e, "Error recording session metadata");
+        }
+    }
+
+    next.run(request).await
+}
+
+///Gets all of the current sessions for a person, marking which one is being used to make this request
+pub async fn get_sessions(
+    state: &VentState,
+    person_id: i32,
+    session: &Session,
+) -> Result<Vec<SessionInfo>, VentError> {
+    let current_id = session.id().map(|id| id.to_string());
+
+    Ok(sqlx::query!(
+        r#"
+SELECT id, handle, created_at, last_seen, ip_address, user_agent
+FROM sessions
+WHERE person_id = $1 AND expiry_date > now()
+ORDER BY last_seen DESC"#,
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingSessionsForPerson(person_id.into()),
+    })?
+    .into_iter()
+    .map(|rec| SessionInfo {
+        handle: rec.handle,
+        created_at: rec
+            .created_at
+            .to_env_string(&state.settings.niche.date_time_format),
+        last_seen: rec
+            .last_seen
+            .to_env_string(&state.settings.niche.date_time_format),
+        ip_address: rec.ip_address,
+        user_agent: rec.user_agent,
+        is_current: current_id.as_ref() == Some(&rec.id),
+    })
+    .collect())
+}
+
+///Counts how many sessions someone currently has
+pub async fn count_sessions(state: &VentState, person_id: i32) -> Result<i64, VentError> {
+    Ok(sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM sessions WHERE person_id = $1 AND expiry_date > now()"#,
+        person_id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingSessionsForPerson(person_id.into()),
+    })?
+    .count)
+}
+
+///Logs someone out of every session they have - used by admins for compromised accounts
+pub async fn remove_all_sessions(state: &VentState, person_id: i32) -> Result<(), VentError> {
+    sqlx::query!("DELETE FROM sessions WHERE person_id = $1", person_id)
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::RemovingSessionsForPerson(person_id.into()),
+        })?;
+
+    Ok(())
+}
+
+///Logs someone out of every session apart from the one making this request
+pub async fn remove_other_sessions(
+    state: &VentState,
+    person_id: i32,
+    session: &Session,
+) -> Result<(), VentError> {
+    let session_id = session
+        .id()
+        .map(|id| id.to_string())
+        .unwrap_or_default();
+
+    sqlx::query!(
+        "DELETE FROM sessions WHERE person_id = $1 AND id <> $2",
+        person_id,
+        session_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingSessionsForPerson(person_id.into()),
+    })?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct RevokeSession {
+    handle: i32,
+}
+
+///`POST` method for someone to log out one of their own other sessions
+#[axum::debug_handler]
+async fn post_revoke_session(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Form(RevokeSession { handle }): Form<RevokeSession>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.as_ref().unwrap().id;
+    let session_id = session
+        .id()
+        .map(|id| id.to_string())
+        .unwrap_or_default();
+
+    debug!(%current_id, %handle, "Revoking session");
+
+    sqlx::query!(
+        "DELETE FROM sessions WHERE handle = $1 AND person_id = $2 AND id <> $3",
+        handle,
+        current_id,
+        session_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingSessionsForPerson(current_id.into()),
+    })?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+///`POST` method for someone to log out everywhere apart from here
+#[axum::debug_handler]
+async fn post_revoke_other_sessions(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.as_ref().unwrap().id;
+
+    info!(%current_id, "Revoking all other sessions");
+
+    remove_other_sessions(&state, current_id, &session).await?;
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/sessions/revoke", post(post_revoke_session))
+        .route("/sessions/revoke_others", post(post_revoke_other_sessions))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+}
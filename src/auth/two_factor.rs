@@ -0,0 +1,564 @@
+//! TOTP ([RFC 6238](https://datatracker.ietf.org/doc/html/rfc6238)) two-factor authentication.
+//!
+//! Users enrol from `edit_self` by scanning a QR code and confirming a code, at which point they get a set of single-use recovery codes. After that, logging in with a password puts the user's ID into the session, and they get sent to `/login_2fa` to finish logging in.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        get_auth_object,
+        login::LoginCreds,
+        rate_limit::{clear_failures, is_locked_out, record_failure, Attempter},
+        remote_ip::RemoteIp,
+    },
+    error::{ALError, LoginFailureReason, SqlxAction, SqlxSnafu, TowerSessionsSnafu, VentError},
+    state::VentState,
+};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_login::login_required;
+use bcrypt::{hash, verify, DEFAULT_COST};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use sqlx::{pool::PoolConnection, Postgres};
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tower_sessions::Session;
+
+///Session key for the ID of someone who has put in the right password, but hasn't yet put in their TOTP code
+pub const PENDING_2FA_USER_KEY: &str = "pending_2fa_user_id";
+///Session key for where to send someone after they've put in their TOTP code
+pub const PENDING_2FA_NEXT_KEY: &str = "pending_2fa_next";
+
+const NUM_RECOVERY_CODES: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct TwoFactorState {
+    ///Whether or not the person has finished enrolling in 2FA
+    pub enabled: bool,
+    ///Whether or not the person's role requires them to use 2FA
+    pub required: bool,
+}
+
+pub async fn get_two_factor_state(
+    mut conn: PoolConnection<Postgres>,
+    person_id: i32,
+) -> Result<TwoFactorState, VentError> {
+    let rec = sqlx::query!(
+        r#"
+SELECT p.totp_enabled, r.requires_2fa
+FROM people p
+INNER JOIN roles r ON r.id = p.role_id
+WHERE p.id = $1"#,
+        person_id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(person_id.into()),
+    })?;
+
+    Ok(TwoFactorState {
+        enabled: rec.totp_enabled,
+        required: rec.requires_2fa,
+    })
+}
+
+fn build_totp(secret: &str, username: String, instance_name: &str) -> Result<TOTP, VentError> {
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        Secret::Encoded(secret.to_string()).to_bytes()?,
+        Some(instance_name.to_string()),
+        username,
+    )?)
+}
+
+///Works out which time step a code is for, allowing for the same clock skew as [`TOTP::check`]
+fn matching_step(totp: &TOTP, code: &str, now: u64) -> Option<u64> {
+    let mut exact = totp.clone();
+    exact.skew = 0;
+
+    let current = now / totp.step;
+    let skew = u64::from(totp.skew);
+    (current.saturating_sub(skew)..=current + skew).find(|step| exact.check(code, step * totp.step))
+}
+
+///Checks just the TOTP code for a person, ignoring recovery codes. Each time step only works once, so a code can't be used again whilst it is still valid.
+async fn verify_totp_code(
+    state: &VentState,
+    person_id: i32,
+    code: &str,
+) -> Result<bool, VentError> {
+    let rec = sqlx::query!(
+        "SELECT username, totp_secret FROM people WHERE id = $1",
+        person_id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(person_id.into()),
+    })?;
+
+    let Some(secret) = rec.totp_secret else {
+        return Ok(false);
+    };
+
+    let totp = build_totp(&secret, rec.username, &state.settings.brand.instance_name)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let Some(step) = matching_step(&totp, code.trim(), now) else {
+        return Ok(false);
+    };
+
+    //only moving forwards means two requests with the same code can't both get through
+    let is_unused = sqlx::query!(
+        r#"
+UPDATE people
+SET totp_last_step = $2
+WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+RETURNING id"#,
+        person_id,
+        i64::try_from(step).unwrap_or(i64::MAX)
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(person_id.into()),
+    })?
+    .is_some();
+
+    if !is_unused {
+        warn!(%person_id, "TOTP code was used again");
+    }
+
+    Ok(is_unused)
+}
+
+///Checks a TOTP code, and if that doesn't work, then checks it against the unused recovery codes (marking it as used if it matches)
+pub async fn verify_second_factor(
+    state: &VentState,
+    person_id: i32,
+    code: &str,
+) -> Result<bool, VentError> {
+    if verify_totp_code(state, person_id, code).await? {
+        return Ok(true);
+    }
+
+    let code = code.trim().to_lowercase();
+    for rec in sqlx::query!(
+        "SELECT id, hashed_code FROM totp_recovery_codes WHERE person_id = $1 AND used_at IS NULL",
+        person_id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingRecoveryCodes(person_id.into()),
+    })? {
+        if verify(&code, &rec.hashed_code)? {
+            //only one login gets to use it up, even if two happen at once
+            let was_unused = sqlx::query!(
+                "UPDATE totp_recovery_codes SET used_at = now() WHERE id = $1 AND used_at IS NULL RETURNING id",
+                rec.id
+            )
+            .fetch_optional(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::UpdatingRecoveryCodes(person_id.into()),
+            })?
+            .is_some();
+
+            if was_unused {
+                info!(%person_id, "Recovery code used");
+            } else {
+                warn!(%person_id, "Recovery code was used by another login at the same time");
+            }
+
+            return Ok(was_unused);
+        }
+    }
+
+    Ok(false)
+}
+
+///Replaces all of a person's recovery codes, and returns the new ones in plaintext so they can be shown once
+async fn regenerate_recovery_codes(
+    state: &VentState,
+    person_id: i32,
+) -> Result<Vec<String>, VentError> {
+    const OPTIONS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    const HALF_LEN: usize = 5;
+
+    let codes = {
+        let mut rng = thread_rng();
+        (0..NUM_RECOVERY_CODES)
+            .map(|_| {
+                let mut half = || {
+                    (0..HALF_LEN)
+                        .map(|_| char::from(OPTIONS[rng.gen_range(0..OPTIONS.len())]))
+                        .collect::<String>()
+                };
+                let first = half();
+                format!("{first}-{}", half())
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let hashed_codes = codes
+        .iter()
+        .map(|code| hash(code, DEFAULT_COST))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    //if anything fails part way through, they keep their old codes rather than ending up with only some of the new ones
+    let mut transaction = state.begin_transaction().await?;
+
+    sqlx::query!(
+        "DELETE FROM totp_recovery_codes WHERE person_id = $1",
+        person_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingRecoveryCodes(person_id.into()),
+    })?;
+
+    for hashed in hashed_codes {
+        sqlx::query!(
+            "INSERT INTO totp_recovery_codes (person_id, hashed_code) VALUES ($1, $2)",
+            person_id,
+            hashed
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingRecoveryCodes(person_id.into()),
+        })?;
+    }
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(codes)
+}
+
+///Gets the object for the 2FA section of `edit_self` - including the QR code if enrolment has been started but not finished.
+pub async fn get_two_factor_object(
+    state: &VentState,
+    person_id: i32,
+) -> Result<liquid::Object, VentError> {
+    let rec = sqlx::query!(
+        r#"
+SELECT p.username, p.totp_secret, p.totp_enabled, r.requires_2fa,
+       (SELECT COUNT(*) FROM totp_recovery_codes trc WHERE trc.person_id = p.id AND trc.used_at IS NULL) AS "recovery_codes_left!"
+FROM people p
+INNER JOIN roles r ON r.id = p.role_id
+WHERE p.id = $1"#,
+        person_id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(person_id.into()),
+    })?;
+
+    Ok(match rec.totp_secret {
+        Some(secret) if !rec.totp_enabled => {
+            let qr = build_totp(&secret, rec.username, &state.settings.brand.instance_name)?
+                .get_qr_base64()
+                .map_err(|reason| VentError::TotpQr { reason })?;
+
+            liquid::object!({
+                "enabled": false,
+                "pending": true,
+                "required": rec.requires_2fa,
+                "secret": secret,
+                "qr": qr,
+            })
+        }
+        _ => liquid::object!({
+            "enabled": rec.totp_enabled,
+            "pending": false,
+            "required": rec.requires_2fa,
+            "recovery_codes_left": rec.recovery_codes_left,
+        }),
+    })
+}
+
+///`POST` method to start enrolling - makes a new secret, which then gets shown on `edit_self` until it's confirmed.
+///
+/// This doesn't do anything if 2FA is already turned on, as otherwise it could be turned off without a code - it has to go through `/2fa/disable` first.
+#[axum::debug_handler]
+async fn post_start_enrolment(
+    auth: Auth,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.unwrap().id;
+
+    let secret = Secret::generate_secret().to_encoded().to_string();
+
+    let started = sqlx::query!(
+        r#"
+UPDATE people
+SET totp_secret = $1, totp_enabled = false, totp_last_step = NULL
+WHERE id = $2 AND NOT totp_enabled
+RETURNING id"#,
+        secret,
+        current_id
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(current_id.into()),
+    })?
+    .is_some();
+
+    if !started {
+        warn!(%current_id, "Tried to start enrolling in 2FA whilst it was already on");
+    }
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+#[derive(Deserialize)]
+struct TwoFactorCode {
+    code: String,
+}
+
+async fn show_recovery_codes(
+    auth: Auth,
+    session: &Session,
+    state: &VentState,
+    codes: Vec<String>,
+) -> Result<Response, VentError> {
+    let aa = get_auth_object(auth, session).await?;
+    Ok(state
+        .compile(
+            "www/recovery_codes.liquid",
+            liquid::object!({ "auth": aa, "codes": codes }),
+            Some("Recovery Codes".into()),
+        )
+        .await?
+        .into_response())
+}
+
+///`POST` method to finish enrolling - checks the code from the authenticator app, and then shows the recovery codes.
+#[axum::debug_handler]
+async fn post_confirm_enrolment(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Form(TwoFactorCode { code }): Form<TwoFactorCode>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.as_ref().unwrap().id;
+
+    if !verify_totp_code(&state, current_id, &code).await? {
+        warn!(%current_id, "Wrong code when confirming 2FA");
+        return Ok(Redirect::to("/login_failure/failed_2fa").into_response());
+    }
+
+    sqlx::query!(
+        "UPDATE people SET totp_enabled = true WHERE id = $1",
+        current_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(current_id.into()),
+    })?;
+
+    info!(%current_id, "Enrolled in 2FA");
+
+    let codes = regenerate_recovery_codes(&state, current_id).await?;
+    show_recovery_codes(auth, &session, &state, codes).await
+}
+
+///`POST` method to get a new set of recovery codes, which needs a current TOTP code.
+#[axum::debug_handler]
+async fn post_regenerate_recovery_codes(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Form(TwoFactorCode { code }): Form<TwoFactorCode>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.as_ref().unwrap().id;
+
+    if !verify_totp_code(&state, current_id, &code).await? {
+        return Ok(Redirect::to("/login_failure/failed_2fa").into_response());
+    }
+
+    let codes = regenerate_recovery_codes(&state, current_id).await?;
+    show_recovery_codes(auth, &session, &state, codes).await
+}
+
+///`POST` method to turn off 2FA. If the person's role requires 2FA, they'll have to enrol again before doing anything else.
+#[axum::debug_handler]
+async fn post_disable(
+    auth: Auth,
+    State(state): State<VentState>,
+    Form(TwoFactorCode { code }): Form<TwoFactorCode>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.unwrap().id;
+
+    if !verify_second_factor(&state, current_id, &code).await? {
+        return Ok(Redirect::to("/login_failure/failed_2fa"));
+    }
+
+    sqlx::query!(
+        "UPDATE people SET totp_secret = NULL, totp_enabled = false, totp_last_step = NULL WHERE id = $1",
+        current_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(current_id.into()),
+    })?;
+    sqlx::query!(
+        "DELETE FROM totp_recovery_codes WHERE person_id = $1",
+        current_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingRecoveryCodes(current_id.into()),
+    })?;
+
+    info!(%current_id, "Disabled 2FA");
+
+    Ok(Redirect::to("/edit_user"))
+}
+
+///`GET` method for the second step of logging in
+#[axum::debug_handler]
+async fn get_login_second_factor(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    if session
+        .get::<i32>(PENDING_2FA_USER_KEY)
+        .await
+        .context(TowerSessionsSnafu)?
+        .is_none()
+    {
+        return Ok(Redirect::to("/login").into_response());
+    }
+
+    let aa = get_auth_object(auth, &session).await?;
+    Ok(state
+        .compile(
+            "www/login_2fa.liquid",
+            liquid::object!({ "auth": aa }),
+            Some("Two-Factor Authentication".into()),
+        )
+        .await?
+        .into_response())
+}
+
+///`POST` method for the second step of logging in - the user ID comes from the session, so this can only be reached after getting the password right.
+#[axum::debug_handler]
+async fn post_login_second_factor(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    remote_ip: RemoteIp,
+    Form(TwoFactorCode { code }): Form<TwoFactorCode>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(user_id) = session
+        .get::<i32>(PENDING_2FA_USER_KEY)
+        .await
+        .context(TowerSessionsSnafu)?
+    else {
+        return Ok(Redirect::to("/login"));
+    };
+
+    let ip = Attempter::Ip(remote_ip.to_string());
+    if is_locked_out(&state, std::slice::from_ref(&ip)).await? {
+        return Ok(Redirect::to("/login_failure/locked_out"));
+    }
+
+    match auth
+        .authenticate(LoginCreds::SecondFactor { user_id, code })
+        .await
+    {
+        Ok(Some(user)) => {
+            let next = session
+                .remove::<String>(PENDING_2FA_NEXT_KEY)
+                .await
+                .context(TowerSessionsSnafu)?;
+            session
+                .remove::<i32>(PENDING_2FA_USER_KEY)
+                .await
+                .context(TowerSessionsSnafu)?;
+
+            clear_failures(&state, &Attempter::username(&user.username)).await?;
+            auth.login(&user).await?;
+            Ok(Redirect::to(&next.unwrap_or_else(|| "/".to_string())))
+        }
+        Ok(None) => Ok(Redirect::to("/login_failure/user_not_found")),
+        Err(ALError::Backend(VentError::LoginFailure {
+            reason: LoginFailureReason::IncorrectSecondFactor,
+        })) => {
+            error!(%user_id, %remote_ip, "Wrong 2FA code for trying to login");
+            record_failure(&state, &[ip, Attempter::for_person(&state, user_id).await?]).await?;
+            Ok(Redirect::to("/login_failure/failed_2fa"))
+        }
+        Err(ALError::Backend(VentError::LoginFailure {
+            reason: LoginFailureReason::LockedOut,
+        })) => {
+            warn!(%user_id, %remote_ip, "Locked out user tried to put in a 2FA code");
+            Ok(Redirect::to("/login_failure/locked_out"))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+///Middleware that stops anyone whose role requires 2FA from doing anything other than enrolling (or logging out) until they've enrolled.
+pub async fn require_two_factor_enrolment(auth: Auth, request: Request, next: Next) -> Response {
+    const ALLOWED_PREFIXES: &[&str] = &[
+        "/edit_user",
+        "/2fa/",
+        "/logout",
+        "/impersonate/",
+        "/healthcheck",
+        "/favicon.ico",
+        "/manifest.json",
+        "/sw.js",
+        "/offline.html",
+        "/robots.txt",
+    ];
+
+    if let Some(user) = &auth.user {
+        let path = request.uri().path();
+        if user.two_factor.required
+            && !user.two_factor.enabled
+            && !ALLOWED_PREFIXES
+                .iter()
+                .any(|prefix| path.starts_with(prefix))
+        {
+            debug!(user_id=%user.id, %path, "Redirecting to enrol in 2FA");
+            return Redirect::to("/edit_user").into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/2fa/start", post(post_start_enrolment))
+        .route("/2fa/confirm", post(post_confirm_enrolment))
+        .route("/2fa/recovery_codes", post(post_regenerate_recovery_codes))
+        .route("/2fa/disable", post(post_disable))
+        .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
+        .route(
+            "/login_2fa",
+            get(get_login_second_factor).post(post_login_second_factor),
+        )
+}
@@ -1,7 +1,9 @@
 use config::{Config, ConfigError, File};
-use serde::Deserialize;
-use std::path::PathBuf;
+use ipnet::IpNet;
+use serde::{de::Error as _, Deserialize, Deserializer};
+use std::{env::var, path::PathBuf};
 use tokio::task::spawn_blocking;
+use url::{Host, Url};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct BrandSettings {
@@ -10,6 +12,22 @@ pub struct BrandSettings {
     pub google_analytics: Option<String>,
 }
 
+impl BrandSettings {
+    ///Whether the site is served over HTTPS, so cookies can be `Secure` and HSTS can be sent
+    pub fn is_https(&self) -> bool {
+        Url::parse(&self.domain).is_ok_and(|url| url.scheme() == "https")
+    }
+
+    ///The domain for the session cookie - browsers won't accept one for `localhost` or an IP address, so those get left as host-only cookies
+    pub fn cookie_domain(&self) -> Option<String> {
+        let url = Url::parse(&self.domain).ok()?;
+        match url.host()? {
+            Host::Domain(domain) if domain.contains('.') => Some(domain.to_string()),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct NicheSettings {
     pub date_time_format: String,
@@ -23,6 +41,60 @@ pub struct Settings {
     pub mail: MailSettings,
     pub timezone_id: String,
     pub tech_support_person: String,
+    pub oidc: Option<OidcSettings>,
+    pub magic_link: Option<MagicLinkSettings>,
+    ///Filled in from the environment by [`Settings::new`] if it isn't in the config
+    pub captcha: CaptchaSettings,
+    ///Reverse proxies whose forwarding headers can be believed - see [`crate::auth::remote_ip`].
+    ///
+    /// Defaults to none, so behind a proxy that isn't listed here every request looks like it comes from the proxy, and login failures from anyone count towards the same IP lockout.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    ///Which header the trusted proxies put the client's address in - any others are ignored
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
+    ///How often expired sessions, stale login throttles and old webhook deliveries get deleted from the database - see [`crate::state::maintenance`]
+    #[serde(default = "default_cleanup_interval_secs")]
+    pub cleanup_interval_secs: u64,
+    #[serde(default)]
+    pub security_headers: SecurityHeaderSettings,
+    #[serde(default)]
+    pub password_policy: PasswordPolicySettings,
+    #[serde(default)]
+    pub password_hashing: PasswordHashSettings,
+    #[serde(default)]
+    pub webhooks: WebhookSettings,
+    #[serde(default)]
+    pub sign_ups: SignUpSettings,
+}
+
+fn default_cleanup_interval_secs() -> u64 {
+    60 * 60
+}
+
+///A header that reverse proxies use to say who they're forwarding for - see [`crate::auth::remote_ip`].
+///
+/// Defaults to Cloudflare's header, as that's the only one that Vent used to look at.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    ///[RFC 7239](https://datatracker.ietf.org/doc/html/rfc7239)
+    Forwarded,
+    XForwardedFor,
+    XRealIp,
+    #[default]
+    CfConnectingIp,
+}
+
+impl ForwardedHeader {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Forwarded => "Forwarded",
+            Self::XForwardedFor => "X-Forwarded-For",
+            Self::XRealIp => "X-Real-IP",
+            Self::CfConnectingIp => "CF-Connecting-IP",
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -33,6 +105,167 @@ pub struct MailSettings {
     pub username_domain: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct MagicLinkSettings {
+    ///Names of the roles that can log in with emailed links
+    pub roles: Vec<String>,
+    #[serde(
+        default = "default_magic_link_lifetime_minutes",
+        deserialize_with = "deserialize_magic_link_lifetime_minutes"
+    )]
+    pub lifetime_minutes: i32,
+}
+
+fn default_magic_link_lifetime_minutes() -> i32 {
+    15
+}
+
+///Postgres's `make_interval` takes the minutes as an `i32`, so anything bigger is a config error rather than getting cut off when a link is made
+fn deserialize_magic_link_lifetime_minutes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<i32, D::Error> {
+    let minutes = i64::deserialize(deserializer)?;
+    i32::try_from(minutes)
+        .ok()
+        .filter(|minutes| *minutes > 0)
+        .ok_or_else(|| {
+            D::Error::custom(format!(
+                "magic_link.lifetime_minutes has to be between 1 and {}, not {minutes}",
+                i32::MAX
+            ))
+        })
+}
+
+impl MagicLinkSettings {
+    pub fn is_allowed_for(&self, role_name: &str) -> bool {
+        self.roles
+            .iter()
+            .any(|role| role.eq_ignore_ascii_case(role_name))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcSettings {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub provider_name: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum CaptchaSettings {
+    Turnstile {
+        site_key: String,
+        secret_key: String,
+    },
+    #[serde(rename = "hcaptcha")]
+    HCaptcha {
+        site_key: String,
+        secret_key: String,
+    },
+    ProofOfWork {
+        #[serde(default = "default_proof_of_work_difficulty")]
+        difficulty: u32,
+        secret: Option<String>,
+    },
+    Disabled,
+}
+
+fn default_proof_of_work_difficulty() -> u32 {
+    16
+}
+
+///Before captchas were configurable, Turnstile was always used with keys from the environment - so keep doing that if there isn't a `captcha` section in the config.
+///
+/// Captchas can only be turned off with `provider = "disabled"`, so missing keys are an error rather than quietly turning them off. `var` gets environment variables, so that tests don't have to change the real environment.
+fn with_captcha_from_env(
+    config: Config,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Config, ConfigError> {
+    if config.get_table("captcha").is_ok() {
+        return Ok(config);
+    }
+
+    let (Some(site_key), Some(secret_key)) = (var("CFT_SITEKEY"), var("CFT_SECRETKEY")) else {
+        return Err(ConfigError::Message("no captcha configured - set a `provider` in the `captcha` section of the config (which can be \"disabled\"), or set `CFT_SITEKEY` and `CFT_SECRETKEY` for Turnstile".into()));
+    };
+
+    Config::builder()
+        .add_source(config)
+        .set_override("captcha.provider", "turnstile")?
+        .set_override("captcha.site_key", site_key)?
+        .set_override("captcha.secret_key", secret_key)?
+        .build()
+}
+
+///Headers added to every response - see [`crate::security_headers`]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SecurityHeaderSettings {
+    ///Replaces the generated `Content-Security-Policy` entirely - any `{csp_nonce}` gets swapped for the nonce that the inline scripts have
+    pub content_security_policy: Option<String>,
+    ///Extra sources allowed for everything in the generated `Content-Security-Policy`
+    pub extra_csp_sources: Vec<String>,
+    ///How long browsers should remember to only use HTTPS - only sent if `brand.domain` is HTTPS
+    pub hsts_max_age_secs: u64,
+    pub frame_options: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+}
+
+impl Default for SecurityHeaderSettings {
+    fn default() -> Self {
+        Self {
+            content_security_policy: None,
+            extra_csp_sources: vec![],
+            hsts_max_age_secs: 60 * 60 * 24 * 365,
+            frame_options: "DENY".into(),
+            referrer_policy: "strict-origin-when-cross-origin".into(),
+            permissions_policy: "camera=(), microphone=(), geolocation=(), payment=(), usb=()"
+                .into(),
+        }
+    }
+}
+
+///What new passwords have to meet - see [`crate::auth::password_policy`]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicySettings {
+    pub min_length: usize,
+    ///The lowest [zxcvbn](https://github.com/dropbox/zxcvbn) score allowed, from 0 (anything goes) to 4
+    pub min_score: u8,
+}
+
+impl Default for PasswordPolicySettings {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_score: 2,
+        }
+    }
+}
+
+///Argon2id parameters for new password hashes - see [`crate::auth::password_hash`]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PasswordHashSettings {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashSettings {
+    ///The [OWASP recommendation](https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#argon2id)
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
 impl Settings {
     pub async fn new() -> Result<Self, ConfigError> {
         let builder = Config::builder()
@@ -45,9 +278,133 @@ impl Settings {
             builder
                 .add_source(File::from(PathBuf::from("config.toml")))
                 .build()
+                .and_then(|config| with_captcha_from_env(config, |key| var(key).ok()))
                 .and_then(Config::try_deserialize)
         })
         .await
         .expect("unable to join spawn_blocking thread")
     }
 }
+
+///How outgoing webhooks get sent - see [`crate::state::webhooks`]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhookSettings {
+    ///How many times to try each delivery before giving up on it
+    pub max_attempts: i32,
+    ///How long to wait after the first failed attempt - this doubles after each one
+    pub initial_backoff_secs: u32,
+    ///The longest to wait between attempts
+    pub max_backoff_secs: u32,
+    ///How long to wait for an endpoint to respond
+    pub timeout_secs: u64,
+    ///How often to look for deliveries that are due to be retried
+    pub poll_interval_secs: u64,
+    ///How long finished deliveries are kept for the webhooks page
+    pub retention_days: i32,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff_secs: 30,
+            max_backoff_secs: 6 * 60 * 60,
+            timeout_secs: 10,
+            poll_interval_secs: 30,
+            retention_days: 30,
+        }
+    }
+}
+
+///The defaults for when people can sign up to events, and when events get locked - see [`crate::state::sign_ups`]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SignUpSettings {
+    ///How long before an event sign-ups open - if it's not set, they open as soon as the event is added
+    pub opens_hours_before: Option<u32>,
+    ///How long before an event sign-ups close
+    pub closes_minutes_before: u32,
+    ///Whether events get locked automatically after they finish
+    pub auto_lock: bool,
+    ///How long after an event finishes it gets locked
+    pub lock_hours_after: u32,
+    ///How often to look for events that need locking
+    pub lock_check_interval_secs: u64,
+}
+
+impl Default for SignUpSettings {
+    ///Before this was configurable, sign-ups always closed an hour before events
+    fn default() -> Self {
+        Self {
+            opens_hours_before: None,
+            closes_minutes_before: 60,
+            auto_lock: true,
+            lock_hours_after: 24,
+            lock_check_interval_secs: 5 * 60,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    fn config(toml: &str) -> Config {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
+    }
+
+    fn captcha(config: &Config) -> CaptchaSettings {
+        config.get("captcha").unwrap()
+    }
+
+    #[test]
+    fn configured_captchas_ignore_the_environment() {
+        let config = with_captcha_from_env(config("[captcha]\nprovider = \"disabled\""), |_| {
+            Some("key".into())
+        })
+        .unwrap();
+        assert!(matches!(captcha(&config), CaptchaSettings::Disabled));
+    }
+
+    #[test]
+    fn environment_keys_use_turnstile() {
+        let config = with_captcha_from_env(config(""), |key| Some(format!("{key}!"))).unwrap();
+        assert!(matches!(
+            captcha(&config),
+            CaptchaSettings::Turnstile { site_key, secret_key }
+                if site_key == "CFT_SITEKEY!" && secret_key == "CFT_SECRETKEY!"
+        ));
+    }
+
+    #[test]
+    fn magic_link_lifetimes_have_to_fit_in_postgres() {
+        let lifetime = |minutes: &str| {
+            config(&format!(
+                "[magic_link]\nroles = [\"Participant\"]\nlifetime_minutes = {minutes}"
+            ))
+            .get::<MagicLinkSettings>("magic_link")
+            .map(|settings| settings.lifetime_minutes)
+        };
+
+        assert_eq!(lifetime("60").unwrap(), 60);
+        assert!(lifetime("0").is_err());
+        assert!(lifetime("2147483648")
+            .unwrap_err()
+            .to_string()
+            .contains("has to be between 1 and 2147483647"));
+    }
+
+    #[test]
+    fn missing_environment_does_not_disable() {
+        let error = with_captcha_from_env(config(""), |key| {
+            (key == "CFT_SITEKEY").then(|| "key".into())
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("no captcha configured"));
+    }
+}
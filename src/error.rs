@@ -1,6 +1,7 @@
 use crate::{
-    auth::{backend::VentAuthBackend, cloudflare_turnstile::CommonHeaders},
+    auth::{backend::VentAuthBackend, captcha::CommonHeaders},
     image_format::ImageFormat,
+    security_headers::current_csp_nonce,
 };
 use axum::{
     http::StatusCode,
@@ -36,6 +37,8 @@ pub enum HttpAction {
 pub enum LoginFailureReason {
     PasswordIsNotSet,
     IncorrectPassword,
+    IncorrectSecondFactor,
+    LockedOut,
 }
 
 #[derive(Debug)]
@@ -51,6 +54,7 @@ pub enum LettreAction {
 #[derive(Debug)]
 pub enum WhatToParse {
     PartOfAPerson(PersonField),
+    PartOfAnEvent(EventField),
     IdForRecord,
 }
 
@@ -65,7 +69,7 @@ pub enum PersonField {
     FirstName,
     Surname,
     Form,
-    IsPrefect,
+    Role,
     Username,
     WasFirstEntry,
 }
@@ -77,6 +81,9 @@ pub enum EventField {
     Date,
     Name,
     Time,
+    EndDate,
+    EndTime,
+    IsAllDay,
 }
 
 #[derive(Debug)]
@@ -140,6 +147,10 @@ pub enum IOAction {
 #[derive(Debug)]
 pub enum ReqwestAction {
     CloudflareTurntile,
+    HCaptcha,
+    OidcDiscovery,
+    OidcTokenExchange,
+    OidcUserInfo,
     RErrorForStatus(Option<reqwest::StatusCode>),
     // HErrorForStatus(Option<http::StatusCode>),
     ConvertToJson(SerdeJsonAction),
@@ -154,9 +165,12 @@ pub enum ConvertingWhatToString {
 
 #[derive(Debug)]
 pub enum SerdeJsonAction {
-    CloudflareTurnstileResponse,
+    CaptchaSiteverifyResponse,
+    OidcIdToken,
     ParsingLogFile,
     SessionSerde,
+    OpenApiDocument,
+    WebhookPayload,
 }
 
 #[derive(Debug)]
@@ -170,6 +184,8 @@ pub enum LiquidAction {
 pub enum ThreadReason {
     LiquidCompiler,
     FindingExistingFilesWithWalkDir,
+    HashingPassword,
+    CheckingPassword,
 }
 
 #[derive(Debug)]
@@ -206,12 +222,58 @@ pub enum SqlxAction {
     },
     AddingPerson,
     RemovingPerson(DatabaseIDMethod),
+    FindingIndividualPermissions(DatabaseIDMethod),
+    UpdatingIndividualPermissions(DatabaseIDMethod),
+
+    FindingRecoveryCodes(DatabaseIDMethod),
+    UpdatingRecoveryCodes(DatabaseIDMethod),
+
+    FindingPasswordResetTokens,
+    AddingPasswordResetToken(DatabaseIDMethod),
+    UpdatingPasswordResetTokens(DatabaseIDMethod),
+    RemovingPasswordResetToken(i32),
+
+    FindingLoginThrottles,
+    UpdatingLoginThrottles,
+    DeletingOldLoginThrottles,
+
+    FindingPasskeys(DatabaseIDMethod),
+    AddingPasskey(DatabaseIDMethod),
+    UpdatingPasskey(i32),
+    RemovingPasskey(i32),
+
+    FindingMagicLinkTokens,
+    AddingMagicLinkToken(DatabaseIDMethod),
+    UpdatingMagicLinkTokens(DatabaseIDMethod),
+
+    FindingImpersonationLog,
+    AddingImpersonationLog,
+    UpdatingImpersonationLog(i32),
+    AddingImpersonationAction(i32),
+    FindingImpersonationActions,
+
+    FindingApiTokens(DatabaseIDMethod),
+    FindingApiTokenOwner,
+    AddingApiToken(DatabaseIDMethod),
+    RemovingApiToken(i32),
+
+    GettingRoles,
+    GettingRolePermissions(i32),
+    AddingRole,
+    UpdatingRole(i32),
+    RemovingRole(i32),
 
     GettingEvent(i32),
     UpdatingEvent(i32),
     FindingAllEvents,
     RemovingEvent(i32),
     AddingEvent,
+    LockingDueEvents,
+
+    FindingEventSeries(i32),
+    AddingEventSeries,
+    UpdatingEventSeries(i32),
+    RemovingEventSeries(i32),
 
     FindingParticipantOrPrefect {
         person: DatabaseIDMethod,
@@ -225,6 +287,11 @@ pub enum SqlxAction {
         person: DatabaseIDMethod,
         event_id: i32,
     },
+    RemovingParticipantOrPrefect {
+        person: DatabaseIDMethod,
+        event_id: i32,
+    },
+    FindingWithdrawals(i32),
     FindingParticipantsOrPrefectsAtEvents {
         event_id: Option<i32>,
     },
@@ -235,6 +302,17 @@ pub enum SqlxAction {
         event_id: i32,
     },
 
+    FindingWaitlist(i32),
+    AddingToWaitlist {
+        person: DatabaseIDMethod,
+        event_id: i32,
+    },
+    RemovingFromWaitlist {
+        person: DatabaseIDMethod,
+        event_id: i32,
+    },
+    PromotingFromWaitlist(i32),
+
     RemovingPrefectOrPrefectFromEventByRI {
         relation_id: i32,
     },
@@ -267,12 +345,27 @@ pub enum SqlxAction {
     RemovingSession(Id),
     AddingSession,
     FindingSession(Id),
+    UpdatingSessionMetadata(Id),
+    FindingSessionsForPerson(DatabaseIDMethod),
+    RemovingSessionsForPerson(DatabaseIDMethod),
 
     AcquiringConnection,
+    StartingTransaction,
+    CommittingTransaction,
 
     GettingRewards,
     GettingRewardsReceived(Option<DatabaseIDMethod>),
     AddingReward,
+
+    FindingWebhooks,
+    FindingWebhook(i32),
+    AddingWebhook,
+    UpdatingWebhook(i32),
+    RemovingWebhook(i32),
+    QueueingWebhookDeliveries,
+    FindingWebhookDeliveries(Option<i32>),
+    UpdatingWebhookDelivery(i32),
+    DeletingOldWebhookDeliveries,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -352,13 +445,14 @@ pub enum VentError {
     Xlsx { source: rust_xlsxwriter::XlsxError },
     #[snafu(display("Error with Encrypting: {source}"), context(false))]
     Bcrypt { source: bcrypt::BcryptError },
+    #[snafu(display("Error with hashing a password: {source}"), context(false))]
+    PasswordHash {
+        source: argon2::password_hash::Error,
+    },
+    #[snafu(display("Invalid Argon2 parameters: {source}"), context(false))]
+    Argon2 { source: argon2::Error },
     #[snafu(display("Error converting {what:?} to string"))]
     ToStr { what: ConvertingWhatToString },
-    #[snafu(display("Error converting {header:?} to string due to {source}"))]
-    HeaderToStr {
-        source: http::header::ToStrError,
-        header: CommonHeaders,
-    },
     #[snafu(display("Error reqwest-ing: {source} whilst trying to {action:?}"))]
     Reqwest {
         source: reqwest::Error,
@@ -405,6 +499,22 @@ pub enum VentError {
         source: time::error::ComponentRange,
         naive: NaiveDateTime,
     },
+    #[snafu(display("Error with TOTP: {source}"), context(false))]
+    TotpUrl { source: totp_rs::TotpUrlError },
+    #[snafu(display("Error parsing TOTP secret: {source:?}"), context(false))]
+    TotpSecret { source: totp_rs::SecretParseError },
+    #[snafu(display("Error decoding base64: {source}"), context(false))]
+    Base64 { source: base64::DecodeError },
+    #[snafu(display("Error parsing URL: {source}"), context(false))]
+    Url { source: url::ParseError },
+    #[snafu(display("Error with WebAuthn: {source}"), context(false))]
+    Webauthn {
+        source: webauthn_rs::prelude::WebauthnError,
+    },
+    #[snafu(display("Error reading request body: {source}"), context(false))]
+    AxumBody { source: axum::Error },
+    #[snafu(display("Error with system time: {source}"), context(false))]
+    SystemTime { source: std::time::SystemTimeError },
     #[snafu(display("Failure with S3 due to {source}"))]
     S3Error {
         source: S3Error,
@@ -420,10 +530,32 @@ pub enum VentError {
     MalformedCSV {
         was_trying_to_get: TryingToGetFromCSV,
     },
-    #[snafu(display("Missing Cloudflare IP in headers"))]
-    MissingCFIP,
+    #[snafu(display("Unable to work out the IP address of the request"))]
+    MissingRemoteIp,
+    #[snafu(display("Missing or incorrect CSRF token - try going back and reloading the page"))]
+    CsrfTokenMismatch,
     #[snafu(display("Failure to login due to {reason:?}"))]
     LoginFailure { reason: LoginFailureReason },
+    #[snafu(display("Error creating TOTP QR code: {reason}"))]
+    TotpQr { reason: String },
+    #[snafu(display("Event can't end at {ends} before it starts at {starts}"))]
+    EventEndsBeforeStart {
+        starts: NaiveDateTime,
+        ends: NaiveDateTime,
+    },
+    #[snafu(display("Repeating events need a day to repeat until, which can't be before they start"))]
+    SeriesNeedsEndDate,
+    #[snafu(display("Repeating events can't have more than {max} occurrences - try an earlier end date"))]
+    TooManyOccurrences { max: usize },
+    #[snafu(display("Termly events can't start on the {day}th, since some months don't have one - try the 28th or earlier"))]
+    TermlyStartsTooLate { day: u32 },
+    #[snafu(display("Sign-ups can't close at {closes} before they open at {opens}"))]
+    SignUpsCloseBeforeOpen {
+        opens: NaiveDateTime,
+        closes: NaiveDateTime,
+    },
+    #[snafu(display("There isn't a role called {name:?}"))]
+    UnknownRole { name: String },
 }
 
 impl From<ALError> for VentError {
@@ -448,7 +580,8 @@ pub fn get_error_page(error_code: StatusCode, content: VentError) -> (StatusCode
         Html(format!(
             include_str!("../www/server_error.html"),
             error = content,
-            code = error_code
+            code = error_code,
+            nonce = current_csp_nonce().unwrap_or_default()
         )),
     )
 }
@@ -463,13 +596,21 @@ pub async fn not_found_fallback(uri: Uri) -> (StatusCode, Html<String>) {
     )
 }
 
-impl IntoResponse for VentError {
-    fn into_response(self) -> axum::response::Response {
-        let code = match &self {
+impl VentError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
             VentError::Sqlx {
                 source: _,
                 action: trying_to_do,
-            } if !matches!(trying_to_do, SqlxAction::AcquiringConnection) => StatusCode::NOT_FOUND,
+            } if !matches!(
+                trying_to_do,
+                SqlxAction::AcquiringConnection
+                    | SqlxAction::StartingTransaction
+                    | SqlxAction::CommittingTransaction
+            ) =>
+            {
+                StatusCode::NOT_FOUND
+            }
             VentError::ParseInt { .. }
             | VentError::ParseBool { .. }
             | VentError::ParseTime { .. }
@@ -478,12 +619,24 @@ impl IntoResponse for VentError {
             | VentError::Image { .. }
             | VentError::NoImageExtension { .. }
             | VentError::MalformedCSV { .. }
-            | VentError::MissingCFIP
-            | VentError::LoginFailure { .. } => StatusCode::BAD_REQUEST,
+            | VentError::MissingRemoteIp
+            | VentError::LoginFailure { .. }
+            | VentError::EventEndsBeforeStart { .. }
+            | VentError::SeriesNeedsEndDate
+            | VentError::TooManyOccurrences { .. }
+            | VentError::TermlyStartsTooLate { .. }
+            | VentError::SignUpsCloseBeforeOpen { .. }
+            | VentError::UnknownRole { .. } => StatusCode::BAD_REQUEST,
             VentError::PageNotFound { .. } => StatusCode::NOT_FOUND,
+            VentError::CsrfTokenMismatch => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+        }
+    }
+}
 
+impl IntoResponse for VentError {
+    fn into_response(self) -> axum::response::Response {
+        let code = self.status_code();
         get_error_page(code, self).into_response()
     }
 }
@@ -8,31 +8,48 @@
     clippy::too_many_lines
 )]
 
+mod api;
 mod auth;
 mod cfg;
 mod error;
 mod image_format;
 mod liquid_utils;
 mod routes;
+mod security_headers;
 mod state;
 
 pub use http;
 
 use crate::{
-    auth::{add_password, backend::VentAuthBackend, login, pg_session::PostgresStore},
+    auth::{
+        add_password,
+        api_tokens,
+        backend::VentAuthBackend,
+        csrf::verify_csrf_token,
+        impersonation::{self, block_account_changes, record_impersonated_changes},
+        login,
+        magic_link,
+        oidc,
+        passkeys,
+        pg_session::PostgresStore,
+        remote_ip::client_ip,
+        sessions::{self, record_session_metadata},
+        two_factor::{self, require_two_factor_enrolment},
+    },
     error::not_found_fallback,
     liquid_utils::partials,
+    security_headers::{add_security_headers, SecurityHeaders},
     routes::{
         add_event, add_people_to_event, add_person, calendar::get_calendar_feed, csv_import_export,
         edit_person, edit_self, eoy_migration, give_bonus_point, images, index::get_index, public,
-        rewards, show_bonus_points, show_events, show_people, spreadsheets::get_spreadsheet,
-        update_bonus_point, update_events,
+        rewards, roles, show_bonus_points, show_events, show_people, spreadsheets::get_spreadsheet,
+        update_bonus_point, update_events, webhooks,
     },
     state::VentState,
 };
-use axum::{extract::{DefaultBodyLimit}, response::IntoResponse, routing::get, Router};
+use axum::{extract::{ConnectInfo, DefaultBodyLimit, Request}, response::IntoResponse, routing::get, Router};
 use axum_login::{
-    tower_sessions::{Expiry, SessionManagerLayer},
+    tower_sessions::{cookie::SameSite, Expiry, SessionManagerLayer},
     AuthManagerLayerBuilder,
 };
 use http::StatusCode;
@@ -138,15 +155,38 @@ async fn main() {
         .await
         .expect("cannot run migrations.");
 
-    let session_layer = SessionManagerLayer::new(PostgresStore::new(pool.clone()))
-        .with_secure(false)
-        .with_expiry(Expiry::OnInactivity(Duration::days(14)));
+    let state = VentState::new(pool.clone()).await;
 
-    let state = VentState::new(pool).await;
+    //lax rather than strict, so that the cookie still gets sent when coming back from an OIDC provider
+    let mut session_layer = SessionManagerLayer::new(PostgresStore::new(pool))
+        .with_secure(state.settings.brand.is_https())
+        .with_same_site(SameSite::Lax)
+        .with_expiry(Expiry::OnInactivity(Duration::days(14)));
+    if let Some(domain) = state.settings.brand.cookie_domain() {
+        session_layer = session_layer.with_domain(domain);
+    }
 
     let auth_layer =
         AuthManagerLayerBuilder::new(VentAuthBackend::new(state.clone()), session_layer).build();
 
+    let trusted_proxies = state.settings.trusted_proxies.clone();
+    let forwarded_header = state.settings.forwarded_header;
+    let trace_layer = TraceLayer::new_for_http().make_span_with(move |request: &Request| {
+        let client_ip = request
+            .extensions()
+            .get::<ConnectInfo<VentConnection>>()
+            .map(|ConnectInfo(connection)| {
+                client_ip(
+                    connection.remote_addr.ip(),
+                    request.headers(),
+                    &trusted_proxies,
+                    forwarded_header,
+                )
+            });
+
+        info_span!("request", method = %request.method(), uri = %request.uri(), ?client_ip)
+    });
+
     let router = Router::new()
         .route("/healthcheck", get(healthcheck))
         .route("/ical", get(get_calendar_feed))
@@ -155,10 +195,19 @@ async fn main() {
         .merge(public::router())
         .merge(add_password::router())
         .merge(login::router())
+        .merge(two_factor::router())
+        .merge(oidc::router())
+        .merge(passkeys::router())
+        .merge(magic_link::router())
+        .merge(impersonation::router())
+        .merge(sessions::router())
+        .merge(api_tokens::router())
+        .merge(api::router())
         .merge(partials::router())
         .merge(csv_import_export::router())
         .merge(edit_self::router())
         .merge(rewards::router())
+        .merge(roles::router())
         .merge(add_event::router())
         .merge(add_people_to_event::router())
         .merge(add_person::router())
@@ -171,11 +220,27 @@ async fn main() {
         .merge(give_bonus_point::router())
         .merge(update_bonus_point::router())
         .merge(show_bonus_points::router())
+        .merge(webhooks::router())
         .merge(state::router())
         .fallback(not_found_fallback)
-        .layer(TraceLayer::new_for_http())
+        .layer(trace_layer)
         .layer(DefaultBodyLimit::max(1024 * 1024 * 50)) //50MB i think
+        .layer(axum::middleware::from_fn(require_two_factor_enrolment))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            record_session_metadata,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            record_impersonated_changes,
+        ))
+        .layer(axum::middleware::from_fn(block_account_changes))
+        .layer(axum::middleware::from_fn(verify_csrf_token))
         .layer(auth_layer)
+        .layer(axum::middleware::from_fn_with_state(
+            SecurityHeaders::new(&state.settings, state.captcha.as_ref()),
+            add_security_headers,
+        ))
         .layer(ConcurrencyLimitLayer::new(512)) //limit to 512 inflight reqs
         .with_state(state.clone());
 
@@ -11,40 +11,216 @@ pub mod images;
 pub mod index;
 pub mod public;
 pub mod rewards;
+pub mod roles;
 pub mod show_bonus_points;
 pub mod show_events;
 pub mod show_people;
 pub mod spreadsheets;
 pub mod update_bonus_point;
 pub mod update_events;
+pub mod webhooks;
 
-use crate::auth::PermissionsRole;
+use crate::{
+    error::{
+        EncodeStep, EventEndsBeforeStartSnafu, ParseTimeSnafu, SeriesNeedsEndDateSnafu,
+        SignUpsCloseBeforeOpenSnafu, TermlyStartsTooLateSnafu, VentError,
+    },
+    state::event_series::SeriesFrequency,
+};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime};
 use serde::Deserialize;
+use snafu::{ensure, OptionExt, ResultExt};
+use utoipa::ToSchema;
 
 ///Struct to hold the event that comes back from the [`add_event`] form
 ///
 /// NB: when going into a [`DbEvent`], the ID will be -1,
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct FormEvent {
     pub name: String,
+    ///In the format `%Y-%m-%dT%H:%M`, like from a `datetime-local` input
+    #[schema(example = "2024-10-17T18:30")]
     pub date: String,
     pub location: String,
     pub teacher: String,
     pub info: String,
     pub is_locked: bool,
     pub victory_points: i32,
+    ///In the same format as `date` - leave it empty if the event doesn't have a set end. For all-day events, this is the last day of it.
+    #[serde(default)]
+    #[schema(example = "2024-10-19T16:00")]
+    pub end_date: Option<String>,
+    ///Whether the event lasts all day, ignoring the times in `date` and `end_date`
+    #[serde(default)]
+    pub is_all_day: bool,
+    ///How often the event happens - leave it empty if it only happens once
+    #[serde(default)]
+    pub repeats: Option<SeriesFrequency>,
+    ///The last day that a repeating event can happen on, as `%Y-%m-%d`
+    #[serde(default)]
+    #[schema(example = "2025-07-18")]
+    pub repeat_until: Option<String>,
+    ///Days that a repeating event doesn't happen on, as `%Y-%m-%d` separated by commas
+    #[serde(default)]
+    #[schema(example = "2024-10-28,2024-10-30")]
+    pub repeat_except: Option<String>,
+    ///When updating an occurrence of a repeating event, whether to change every one after it too
+    #[serde(default)]
+    pub apply_to_future: bool,
+    ///How many people can sign up before everyone else goes on a waitlist - leave it empty for no limit
+    #[serde(default)]
+    pub capacity: Option<u16>,
+    ///When people can start signing up, in the same format as `date` - leave it empty to use the default. Repeating events always use the defaults.
+    #[serde(default)]
+    pub sign_ups_open_at: Option<String>,
+    ///When people can't sign up any more, in the same format as `date` - leave it empty to use the default
+    #[serde(default)]
+    pub sign_ups_close_at: Option<String>,
+    ///When the event gets locked, in the same format as `date` - leave it empty to use the default
+    #[serde(default)]
+    pub locks_at: Option<String>,
 }
 
-#[derive(Deserialize)]
+///When sign-ups for a [`FormEvent`] open and close, and when it gets locked - anything that is `None` uses the default
+#[allow(clippy::struct_field_names)] //they're named after their columns
+pub struct SignUpTimes {
+    pub sign_ups_open_at: Option<NaiveDateTime>,
+    pub sign_ups_close_at: Option<NaiveDateTime>,
+    pub locks_at: Option<NaiveDateTime>,
+}
+
+///How a [`FormEvent`] repeats
+pub struct EventRepeats {
+    pub frequency: SeriesFrequency,
+    pub repeat_until: NaiveDate,
+    pub exceptions: Vec<NaiveDate>,
+}
+
+///Parses the start and end of a [`FormEvent`], making sure that it doesn't end before it starts
+///
+/// For all-day events, the times get set to midnight.
+pub fn parse_event_times(
+    date: String,
+    end_date: Option<String>,
+    is_all_day: bool,
+    how_got_in: EncodeStep,
+) -> Result<(NaiveDateTime, Option<NaiveDateTime>), VentError> {
+    let parse = |original: String| {
+        NaiveDateTime::parse_from_str(&original, "%Y-%m-%dT%H:%M")
+            .context(ParseTimeSnafu {
+                original,
+                how_got_in,
+            })
+            .map(|date| {
+                if is_all_day {
+                    date.date().and_time(NaiveTime::MIN)
+                } else {
+                    date
+                }
+            })
+    };
+
+    let starts = parse(date)?;
+    let ends = end_date
+        .filter(|end_date| !end_date.is_empty())
+        .map(parse)
+        .transpose()?;
+
+    if let Some(ends) = ends {
+        ensure!(ends >= starts, EventEndsBeforeStartSnafu { starts, ends });
+    }
+
+    Ok((starts, ends))
+}
+
+///Parses the sign-up times of a [`FormEvent`], making sure that sign-ups don't close before they open
+pub fn parse_sign_up_times(
+    sign_ups_open_at: Option<String>,
+    sign_ups_close_at: Option<String>,
+    locks_at: Option<String>,
+    how_got_in: EncodeStep,
+) -> Result<SignUpTimes, VentError> {
+    let parse = |original: Option<String>| {
+        original
+            .filter(|original| !original.is_empty())
+            .map(|original| {
+                NaiveDateTime::parse_from_str(&original, "%Y-%m-%dT%H:%M").context(ParseTimeSnafu {
+                    original,
+                    how_got_in,
+                })
+            })
+            .transpose()
+    };
+
+    let sign_ups_open_at = parse(sign_ups_open_at)?;
+    let sign_ups_close_at = parse(sign_ups_close_at)?;
+    if let (Some(opens), Some(closes)) = (sign_ups_open_at, sign_ups_close_at) {
+        ensure!(
+            closes >= opens,
+            SignUpsCloseBeforeOpenSnafu { opens, closes }
+        );
+    }
+
+    Ok(SignUpTimes {
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at: parse(locks_at)?,
+    })
+}
+
+///Parses how a [`FormEvent`] that starts at `starts` repeats, if it does
+pub fn parse_event_repeats(
+    starts: NaiveDateTime,
+    repeats: Option<SeriesFrequency>,
+    repeat_until: Option<&str>,
+    repeat_except: Option<&str>,
+    how_got_in: EncodeStep,
+) -> Result<Option<EventRepeats>, VentError> {
+    let Some(frequency) = repeats else {
+        return Ok(None);
+    };
+    let parse = |original: &str| {
+        NaiveDate::parse_from_str(original.trim(), "%Y-%m-%d").context(ParseTimeSnafu {
+            original,
+            how_got_in,
+        })
+    };
+
+    let repeat_until = parse(
+        repeat_until
+            .filter(|repeat_until| !repeat_until.is_empty())
+            .context(SeriesNeedsEndDateSnafu)?,
+    )?;
+    ensure!(repeat_until >= starts.date(), SeriesNeedsEndDateSnafu);
+    ensure!(
+        frequency.can_start_on(starts.date()),
+        TermlyStartsTooLateSnafu { day: starts.day() }
+    );
+
+    let exceptions = repeat_except
+        .unwrap_or_default()
+        .split(',')
+        .filter(|date| !date.trim().is_empty())
+        .map(parse)
+        .collect::<Result<_, _>>()?;
+
+    Ok(Some(EventRepeats {
+        frequency,
+        repeat_until,
+        exceptions,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
 pub struct FormPerson {
     pub first_name: String,
     pub surname: String,
     pub username: String,
     pub form: Option<String>,
-    pub permissions: PermissionsRole,
+    pub role_id: i32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct FormBonusPoint {
     pub user_id: i32,
     pub reason: String,
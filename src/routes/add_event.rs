@@ -5,13 +5,23 @@
 //! It serves a simple form, and handles post requests to add that event to the DB.
 
 use crate::{
+    api::events::ApiEvent,
     auth::{
         backend::{Auth, VentAuthBackend},
         get_auth_object, PermissionsTarget,
     },
-    error::{EncodeStep, ParseTimeSnafu, SqlxAction, SqlxSnafu, VentError},
-    routes::FormEvent,
-    state::VentState,
+    error::{EncodeStep, SqlxAction, SqlxSnafu, VentError},
+    routes::{
+        parse_event_repeats, parse_event_times, parse_sign_up_times, EventRepeats, FormEvent,
+        SignUpTimes,
+    },
+    state::{
+        db_objects::DbEvent,
+        event_series::{create_series, SeriesTemplate},
+        sign_ups::SignUpDefaults,
+        webhooks::WebhookEventKind,
+        VentState,
+    },
 };
 use axum::{
     extract::State,
@@ -21,27 +31,39 @@ use axum::{
 };
 use axum_extra::extract::Form;
 use axum_login::permission_required;
-use chrono::NaiveDateTime;
 use snafu::ResultExt;
+use tower_sessions::Session;
 
 ///`GET` method for the `add_event` form - just compiles and returns the liquid `www/add_event.liquid`
 #[axum::debug_handler]
 async fn get_add_event_form(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     state
         .compile(
             "www/add_event.liquid",
-            liquid::object!({"auth": aa}),
+            liquid::object!({
+                "auth": aa,
+                "sign_up_defaults": SignUpDefaults::from(&state.settings.sign_ups),
+            }),
             Some("New House Event".to_string()),
         )
         .await
 }
 
-///`POST` method to add an event from a form to the database. Redirects back to the [`get_add_event_form`]
+///`POST` method to add an event from a form to the database. Redirects to the new event, or the first occurrence of it if it repeats
+#[utoipa::path(
+    post,
+    path = "/add_event",
+    tag = "forms",
+    request_body(content = FormEvent, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 303, description = "Redirects to the new event's page, or the first occurrence's page if it repeats")),
+    security(("session" = []))
+)]
 #[axum::debug_handler]
 async fn post_add_event_form(
     State(state): State<VentState>,
@@ -53,37 +75,113 @@ async fn post_add_event_form(
         info,
         is_locked,
         victory_points: _,
+        end_date,
+        is_all_day,
+        repeats,
+        repeat_until,
+        repeat_except,
+        apply_to_future: _,
+        capacity,
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at,
     }): Form<FormEvent>,
 ) -> Result<impl IntoResponse, VentError> {
-    let date = NaiveDateTime::parse_from_str(&date, "%Y-%m-%dT%H:%M").context(ParseTimeSnafu {
-        original: date,
-        how_got_in: EncodeStep::Encode,
-    })?;
+    let (date, end_date) = parse_event_times(date, end_date, is_all_day, EncodeStep::Encode)?;
+    let capacity = capacity.map(i32::from);
+    let SignUpTimes {
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at,
+    } = parse_sign_up_times(
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at,
+        EncodeStep::Encode,
+    )?;
+
+    if let Some(EventRepeats {
+        frequency,
+        repeat_until,
+        exceptions,
+    }) = parse_event_repeats(
+        date,
+        repeats,
+        repeat_until.as_deref(),
+        repeat_except.as_deref(),
+        EncodeStep::Encode,
+    )? {
+        debug!(?frequency, ?repeat_until, "Adding event series");
+
+        let template = SeriesTemplate {
+            frequency,
+            repeat_until,
+            event_name: name,
+            location,
+            teacher,
+            other_info: Some(info),
+            starts_at: date,
+            ends_at: end_date,
+            is_all_day,
+            is_locked,
+            extra_points: 0,
+            capacity,
+        };
+        let mut transaction = state.begin_transaction().await?;
+        let events = create_series(&mut transaction, &template, &exceptions, None).await?;
+        transaction.commit().await.context(SqlxSnafu {
+            action: SqlxAction::CommittingTransaction,
+        })?;
+        let first_id = events.first().map(|event| event.id);
+
+        state.update_events()?;
+        for event in events {
+            state
+                .fire_webhook(WebhookEventKind::EventCreated, ApiEvent::from(event))
+                .await;
+        }
+
+        //every occurrence could have been an exception
+        return Ok(Redirect::to(&first_id.map_or_else(
+            || "/show_events".to_string(),
+            |id| format!("/update_event/{id}"),
+        )));
+    }
 
     debug!("Fetching ID for update event");
 
-    let id = sqlx::query!(
+    let event = sqlx::query_as!(
+        DbEvent,
         r#"
 INSERT INTO public.events
-(event_name, "date", "location", teacher, other_info, is_locked)
-VALUES($1, $2, $3, $4, $5, $6)
-RETURNING id
+(event_name, "date", "location", teacher, other_info, is_locked, end_date, is_all_day, capacity, sign_ups_open_at, sign_ups_close_at, locks_at)
+VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+RETURNING *
         "#,
         name,
         date,
         location,
         teacher,
         info,
-        is_locked
+        is_locked,
+        end_date,
+        is_all_day,
+        capacity,
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at
     )
     .fetch_one(&mut *state.get_connection().await?) //add the event to the db
     .await
     .context(SqlxSnafu {
         action: SqlxAction::AddingEvent,
-    })?
-    .id;
+    })?;
+    let id = event.id;
 
     state.update_events()?;
+    state
+        .fire_webhook(WebhookEventKind::EventCreated, ApiEvent::from(event))
+        .await;
 
     Ok(Redirect::to(&format!("/update_event/{id}"))) //redirect to the relevant update event page for that event
 }
@@ -1,12 +1,19 @@
-//! Module that publishes 2 `POST` methods that deal with adding prefects and participants to events based off of path parameters. This is a fair bit easier than an invisible form.
+//! Module that publishes the `POST` methods that deal with adding prefects and participants to events, and participants taking themselves off them again, based off of path parameters. This is a fair bit easier than an invisible form.
 
 use crate::{
+    api::events::ApiParticipation,
     auth::{
         backend::{Auth, VentAuthBackend},
-        PermissionsRole, PermissionsTarget,
+        PermissionsTarget,
     },
     error::{SqlxAction, SqlxSnafu, VentError},
-    state::VentState,
+    state::{
+        sign_ups::{SignUpStatus, SignUpWindow},
+        waitlist::{leave_waitlist, sign_up, SignUpOutcome},
+        webhooks::WebhookEventKind,
+        withdrawals::remove_participant,
+        VentState,
+    },
 };
 use axum::{
     extract::State,
@@ -15,8 +22,7 @@ use axum::{
     Router,
 };
 use axum_extra::extract::Form;
-use axum_login::{login_required, permission_required};
-use chrono::Utc;
+use axum_login::{permission_required, AuthzBackend};
 use serde::Deserialize;
 use snafu::ResultExt;
 
@@ -80,6 +86,32 @@ async fn post_add_prefect_to_event(
     Ok(Redirect::to(&format!("/update_event/{event_id}"))) //redirect back to the update event page
 }
 
+///Gets whether an event is locked, and when people can sign up to it
+async fn get_lock_and_window(
+    state: &VentState,
+    event_id: i32,
+) -> Result<(bool, SignUpWindow), VentError> {
+    let record = sqlx::query!(
+        "SELECT date, is_locked, sign_ups_open_at, sign_ups_close_at FROM events WHERE id = $1",
+        event_id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingEvent(event_id),
+    })?;
+
+    Ok((
+        record.is_locked,
+        SignUpWindow::new(
+            &state.settings.sign_ups,
+            record.date,
+            record.sign_ups_open_at,
+            record.sign_ups_close_at,
+        ),
+    ))
+}
+
 ///`POST` method that adds a participant
 #[axum::debug_handler]
 async fn post_add_participant_to_event(
@@ -91,78 +123,59 @@ async fn post_add_participant_to_event(
     }): Form<AddPerson>,
 ) -> Result<impl IntoResponse, VentError> {
     let current_user = auth.user.expect("need to be logged in to add participants");
+    let can_edit_others = auth
+        .backend
+        .has_perm(&current_user, PermissionsTarget::EditParticipantsOnEvents)
+        .await?;
 
-    let event_date = {
-        let record = sqlx::query!("SELECT date,is_locked FROM events WHERE id = $1", event_id)
-            .fetch_one(&mut *state.get_connection().await?)
-            .await
-            .context(SqlxSnafu {
-                action: SqlxAction::GettingEvent(event_id),
-            })?;
-
-        if record.is_locked {
-            warn!("Student {person_ids:?} tried to add to {event_id}, but event locked.");
-            return Ok(Redirect::to(&format!("/update_event/{event_id}")));
-        }
-
-        record.date
-    };
+    let (is_locked, window) = get_lock_and_window(&state, event_id).await?;
+    if is_locked {
+        warn!("Student {person_ids:?} tried to add to {event_id}, but event locked.");
+        return Ok(Redirect::to(&format!("/update_event/{event_id}")));
+    }
 
-    if event_date < (Utc::now() + chrono::Duration::hours(1)).naive_local()
-        && current_user.permissions < PermissionsRole::Prefect
-    {
-        warn!("Student {person_ids:?} tried to add to {event_id}, but event out of date.");
+    let status = window.status();
+    if status != SignUpStatus::Open && !can_edit_others {
+        warn!(
+            ?status,
+            "Student {person_ids:?} tried to add to {event_id}, but sign-ups aren't open."
+        );
         return Ok(Redirect::to(&format!("/update_event/{event_id}")));
     }
 
     for participant_id in person_ids {
-        if sqlx::query!(
-            r#"
-    SELECT * FROM public.participant_events
-    WHERE participant_id = $1
-    AND event_id = $2"#,
+        if !can_edit_others && current_user.id != participant_id {
+            warn!(?participant_id, perp=?current_user.id, "Participant did POST magic to get other participant, but failed.");
+            continue;
+        }
+
+        let outcome = sign_up(
+            &mut *state.get_connection().await?,
+            event_id,
             participant_id,
-            event_id
         )
-        .fetch_optional(&mut *state.get_connection().await?)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::FindingParticipantOrPrefect {
-                person: participant_id.into(),
-                event_id,
-            },
-        })?
-        .is_none()
-        //if we can't find anything assoiated with this participant and this event
-        {
-            if current_user.permissions < PermissionsRole::Prefect
-                && current_user.id != participant_id
-            {
-                warn!(?participant_id, perp=?current_user.id, "Participant did POST magic to get other participant, but failed.");
-                continue;
-            }
+        .await?;
+        match outcome {
+            SignUpOutcome::Added => {
+                debug!(%participant_id, %event_id, "Added participant to event");
 
-            debug!(%participant_id, %event_id, "Adding participant to event");
-            //then we add the participant to the event
-            sqlx::query!(
-                r#"
-    INSERT INTO public.participant_events
-    (participant_id, event_id, is_verified)
-    VALUES($1, $2, false);            
-                "#,
-                participant_id,
-                event_id
-            )
-            .execute(&mut *state.get_connection().await?)
-            .await
-            .context(SqlxSnafu {
-                action: SqlxAction::AddingParticipantOrPrefect {
-                    person: participant_id.into(),
-                    event_id,
-                },
-            })?;
-        } else {
-            warn!(%participant_id, %event_id, "Participant already in event.");
+                state
+                    .fire_webhook(
+                        WebhookEventKind::ParticipantAdded,
+                        ApiParticipation {
+                            event_id,
+                            person_id: participant_id,
+                            is_verified: false,
+                        },
+                    )
+                    .await;
+            }
+            SignUpOutcome::Waitlisted { position } => {
+                debug!(%participant_id, %event_id, %position, "Event full, so participant is on the waitlist");
+            }
+            SignUpOutcome::AlreadyIn => {
+                warn!(%participant_id, %event_id, "Participant already in event.");
+            }
         }
     }
 
@@ -171,6 +184,86 @@ async fn post_add_participant_to_event(
     Ok(Redirect::to(&format!("/update_event/{event_id}"))) //then back to the update event page
 }
 
+#[derive(Deserialize)]
+struct Withdraw {
+    event_id: i32,
+}
+
+///`POST` method for participants to take themselves off an event, as long as it isn't locked and sign-ups are still open
+#[axum::debug_handler]
+async fn post_withdraw_from_event(
+    auth: Auth,
+    State(state): State<VentState>,
+    Form(Withdraw { event_id }): Form<Withdraw>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_user = auth.user.expect("need to be logged in to withdraw");
+    let can_edit_others = auth
+        .backend
+        .has_perm(&current_user, PermissionsTarget::EditParticipantsOnEvents)
+        .await?;
+
+    let (is_locked, window) = get_lock_and_window(&state, event_id).await?;
+    if is_locked {
+        warn!(person_id=?current_user.id, %event_id, "Tried to withdraw, but event locked.");
+        return Ok(Redirect::to(&format!("/update_event/{event_id}")));
+    }
+
+    let status = window.status();
+    if status != SignUpStatus::Open && !can_edit_others {
+        warn!(person_id=?current_user.id, %event_id, ?status, "Tried to withdraw, but sign-ups aren't open.");
+        return Ok(Redirect::to(&format!("/update_event/{event_id}")));
+    }
+
+    if remove_participant(
+        &mut *state.get_connection().await?,
+        event_id,
+        current_user.id,
+        current_user.id,
+    )
+    .await?
+    {
+        debug!(person_id=%current_user.id, %event_id, "Participant withdrew from event");
+
+        state.fill_places(event_id).await?;
+        state.update_events()?;
+    } else {
+        warn!(person_id=%current_user.id, %event_id, "Tried to withdraw from an event that they weren't on");
+    }
+
+    Ok(Redirect::to(&format!("/update_event/{event_id}")))
+}
+
+#[derive(Deserialize)]
+struct LeaveWaitlist {
+    event_id: i32,
+    person_id: i32,
+}
+
+///`POST` method to take someone off an event's waitlist - people can take themselves off, and anyone who can edit participants can take off anyone
+#[axum::debug_handler]
+async fn post_leave_waitlist(
+    auth: Auth,
+    State(state): State<VentState>,
+    Form(LeaveWaitlist {
+        event_id,
+        person_id,
+    }): Form<LeaveWaitlist>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_user = auth.user.expect("need to be logged in to leave waitlists");
+    let can_edit_others = auth
+        .backend
+        .has_perm(&current_user, PermissionsTarget::EditParticipantsOnEvents)
+        .await?;
+
+    if !can_edit_others && current_user.id != person_id {
+        warn!(?person_id, perp=?current_user.id, "Participant did POST magic to take someone else off a waitlist, but failed.");
+    } else if !leave_waitlist(&mut *state.get_connection().await?, event_id, person_id).await? {
+        warn!(%person_id, %event_id, "Tried to leave a waitlist that they weren't on");
+    }
+
+    Ok(Redirect::to(&format!("/update_event/{event_id}")))
+}
+
 pub fn router() -> Router<VentState> {
     Router::new()
         .route("/add_prefect", post(post_add_prefect_to_event))
@@ -180,5 +273,11 @@ pub fn router() -> Router<VentState> {
             PermissionsTarget::EditPrefectsOnEvents
         ))
         .route("/add_participant", post(post_add_participant_to_event))
-        .route_layer(login_required!(VentAuthBackend)) //TODO: actually use `PermissionsTarget::AddRmSelfToEvent`
+        .route("/withdraw_from_event", post(post_withdraw_from_event))
+        .route("/leave_waitlist", post(post_leave_waitlist))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::AddRmSelfToEvent
+        ))
 }
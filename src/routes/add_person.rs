@@ -3,7 +3,7 @@
 use crate::{
     auth::{
         backend::{Auth, VentAuthBackend},
-        get_auth_object, PermissionsTarget,
+        get_auth_object, get_roles, PermissionsTarget,
     },
     error::{SqlxAction, SqlxSnafu, VentError},
     routes::FormPerson,
@@ -17,24 +17,36 @@ use axum::{
 };
 use axum_login::permission_required;
 use snafu::ResultExt;
+use tower_sessions::Session;
 
 ///`GET` function to display the add person form
 #[axum::debug_handler]
 async fn get_add_person(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
+    let roles = get_roles(state.get_connection().await?).await?;
 
     state
         .compile(
             "www/add_person.liquid",
-            liquid::object!({"auth": aa}),
+            liquid::object!({"auth": aa, "roles": roles}),
             Some("New Person".into()),
         )
         .await
 }
 
+///`POST` method to add a person from the form. Redirects back to the form.
+#[utoipa::path(
+    post,
+    path = "/add_person",
+    tag = "forms",
+    request_body(content = FormPerson, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 303, description = "Redirects back to the form")),
+    security(("session" = []))
+)]
 #[axum::debug_handler]
 async fn post_add_person(
     State(state): State<VentState>,
@@ -43,17 +55,17 @@ async fn post_add_person(
         surname,
         username,
         form,
-        permissions,
+        role_id,
     }): Form<FormPerson>,
 ) -> Result<impl IntoResponse, VentError> {
     info!("Inserting new person into DB");
     sqlx::query!(
         r#"
 INSERT INTO public.people
-(permissions, first_name, surname, username, form)
+(role_id, first_name, surname, username, form)
 VALUES($1, $2, $3, $4, $5);    
     "#,
-        permissions as _,
+        role_id,
         first_name,
         surname,
         username,
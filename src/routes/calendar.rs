@@ -1,15 +1,25 @@
 //! Module that publishes an iCalendar file in a GET method
 
 use crate::{
+    auth::{get_people_with_permission, PermissionsTarget},
     error::{SqlxAction, SqlxSnafu, VentError},
-    state::{db_objects::DbEvent, VentState},
+    state::{
+        db_objects::{DbEvent, DbEventSeries},
+        event_series::occurrence_slots,
+        VentState,
+    },
 };
 use axum::{extract::State, response::IntoResponse};
 
-use icalendar::{Calendar, CalendarDateTime, Component, Event, EventLike};
+use chrono::{Days, NaiveDateTime};
+use icalendar::{Calendar, CalendarDateTime, Component, Event, EventLike, Property};
 use snafu::ResultExt;
 use sqlx::{pool::PoolConnection, Pool, Postgres};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::{
     broadcast::{error::TryRecvError, Receiver as BroadcastReceiver},
     mpsc::{unbounded_channel, UnboundedSender},
@@ -23,16 +33,112 @@ pub async fn get_calendar_feed(
     Ok(state.get_calendar().await)
 }
 
+///Makes the UID that a series and all of its occurrences share
+fn series_uid(series_id: i32, domain: &str) -> String {
+    format!("series-{series_id}@{domain}")
+}
+
+///Sets when an event starts and ends - all-day events use dates, and everything else uses times in `tzid`
+fn set_times(
+    ical_event: &mut Event,
+    starts: NaiveDateTime,
+    ends: Option<NaiveDateTime>,
+    is_all_day: bool,
+    tzid: &str,
+) {
+    if is_all_day {
+        //the end of all-day events in iCal is the day after the last one
+        let last_day = ends.unwrap_or(starts).date();
+        ical_event
+            .starts(starts.date())
+            .ends(last_day + Days::new(1));
+    } else {
+        ical_event.starts(CalendarDateTime::WithTimezone {
+            date_time: starts,
+            tzid: tzid.to_string(),
+        });
+        if let Some(ends) = ends {
+            ical_event.ends(CalendarDateTime::WithTimezone {
+                date_time: ends,
+                tzid: tzid.to_string(),
+            });
+        }
+    }
+}
+
+///Makes a property that points at an occurrence of a series, like `EXDATE` or `RECURRENCE-ID`
+fn occurrence_property(
+    key: &str,
+    occurrence: NaiveDateTime,
+    is_all_day: bool,
+    tzid: &str,
+) -> Property {
+    if is_all_day {
+        Property::new(key, occurrence.format("%Y%m%d").to_string().as_str())
+            .add_parameter("VALUE", "DATE")
+            .done()
+    } else {
+        Property::new(key, occurrence.format("%Y%m%dT%H%M%S").to_string().as_str())
+            .add_parameter("TZID", tzid)
+            .done()
+    }
+}
+
+///Makes one repeating entry for a series, with an `EXDATE` for each occurrence that isn't in `present`
+fn series_event(
+    series: &DbEventSeries,
+    present: &HashSet<NaiveDateTime>,
+    tzid: &str,
+    domain: &str,
+) -> Result<Event, VentError> {
+    let slots = occurrence_slots(series.frequency(), series.starts_at, series.repeat_until)?;
+
+    let mut ical_event = Event::new();
+    ical_event
+        .uid(series_uid(series.id, domain).as_str())
+        .summary(&series.event_name)
+        .location(&series.location)
+        .add_property(
+            "RRULE",
+            format!("{};COUNT={}", series.frequency().rrule(), slots.len()).as_str(),
+        );
+    set_times(
+        &mut ical_event,
+        series.starts_at,
+        series.ends_at,
+        series.is_all_day,
+        tzid,
+    );
+    for slot in slots.into_iter().filter(|slot| !present.contains(slot)) {
+        ical_event.append_multi_property(occurrence_property(
+            "EXDATE",
+            slot,
+            series.is_all_day,
+            tzid,
+        ));
+    }
+
+    Ok(ical_event.done())
+}
+
 pub async fn get_events(
     mut conn: PoolConnection<Postgres>,
     tzid: String,
     calendar_title: &str,
+    domain: &str,
 ) -> Result<Calendar, VentError> {
     let mut prefect_events: HashMap<i32, Vec<String>> = HashMap::new();
 
+    let prefect_ids =
+        get_people_with_permission(&mut conn, PermissionsTarget::EditParticipantsOnEvents)
+            .await?
+            .into_iter()
+            .collect::<Vec<_>>();
     let prefects = sqlx::query!(
         r#"
-    SELECT id, first_name, surname FROM people p WHERE p.permissions != 'participant'"#
+    SELECT id, first_name, surname FROM people p
+    WHERE p.id = ANY($1)"#,
+        &prefect_ids
     )
     .fetch_all(&mut *conn)
     .await
@@ -60,7 +166,44 @@ pub async fn get_events(
 
     debug!(?prefect_events, "Worked out PEs");
 
+    let events = sqlx::query_as!(DbEvent, r#"SELECT * FROM events"#)
+        .fetch_all(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingAllEvents,
+        })?;
+    let series = sqlx::query_as!(DbEventSeries, r#"SELECT * FROM event_series"#)
+        .fetch_all(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingAllEvents,
+        })?
+        .into_iter()
+        .map(|series| (series.id, series))
+        .collect::<HashMap<_, _>>();
+
+    let mut present_occurrences: HashMap<i32, HashSet<NaiveDateTime>> = HashMap::new();
+    for event in &events {
+        if let (Some(series_id), Some(occurrence)) = (event.series_id, event.series_occurrence) {
+            present_occurrences
+                .entry(series_id)
+                .or_default()
+                .insert(occurrence);
+        }
+    }
+
     let mut calendar = Calendar::new();
+
+    //each series is one entry that repeats, with the occurrences that have been cancelled taken out
+    for (series_id, present) in &present_occurrences {
+        let Some(series) = series.get(series_id) else {
+            continue;
+        };
+        debug!(?series.event_name, ?series.starts_at, "Adding series to calendar");
+
+        calendar.push(series_event(series, present, &tzid, domain)?);
+    }
+
     for DbEvent {
         id: _,
         event_name,
@@ -71,12 +214,17 @@ pub async fn get_events(
         zip_file: _,
         is_locked: _,
         extra_points: _,
-    } in sqlx::query_as!(DbEvent, r#"SELECT * FROM events"#)
-        .fetch_all(&mut *conn)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::FindingAllEvents,
-        })?
+        end_date,
+        is_all_day,
+        series_id,
+        series_occurrence,
+        is_series_override,
+        capacity: _,
+        sign_ups_open_at: _,
+        sign_ups_close_at: _,
+        locks_at: _,
+        auto_locked_at: _,
+    } in events
     {
         /* let other_info = other_info.unwrap_or_default();
         let prefects = prefect_events
@@ -84,17 +232,32 @@ pub async fn get_events(
             .map(|x| x.join(", "))
             .unwrap_or_default(); */
 
-        debug!(?event_name, ?date, "Adding event to calendar");
+        let series = series_id.and_then(|series_id| series.get(&series_id));
+        if series.is_some() && !is_series_override {
+            //already covered by the series' RRULE
+            continue;
+        }
+
+        debug!(?event_name, ?date, ?end_date, "Adding event to calendar");
+
+        let mut ical_event = Event::new();
+        ical_event.summary(&event_name).location(&location);
+        set_times(&mut ical_event, date, end_date, is_all_day, &tzid);
+
+        //occurrences that have been changed on their own replace that occurrence of the series
+        if let (Some(series), Some(occurrence)) = (series, series_occurrence) {
+            ical_event
+                .uid(series_uid(series.id, domain).as_str())
+                .append_property(occurrence_property(
+                    "RECURRENCE-ID",
+                    occurrence,
+                    series.is_all_day,
+                    &tzid,
+                ));
+        }
 
         calendar.push(
-            Event::new()
-                .summary(&event_name)
-                .starts(CalendarDateTime::WithTimezone {
-                    date_time: date,
-                    tzid: tzid.clone(),
-                })
-                .ends(date)
-                .location(&location)
+            ical_event
                 /* .description(&format!(
                                     r#"
                 Teacher: {teacher}
@@ -114,6 +277,7 @@ pub fn update_calendar_thread(
     mut stop_rx: BroadcastReceiver<()>,
     tzid: String,
     instance_name: &impl AsRef<str>,
+    domain: String,
     calendar: Arc<RwLock<Calendar>>,
 ) -> UnboundedSender<()> {
     let instance_name = instance_name.as_ref();
@@ -129,14 +293,16 @@ pub fn update_calendar_thread(
 
             if let Ok(()) = update_rx.try_recv() {
                 match pool.acquire().await {
-                    Ok(conn) => match get_events(conn, tzid.clone(), &calendar_title).await {
-                        Ok(x) => {
-                            *calendar.write().await = x;
+                    Ok(conn) => {
+                        match get_events(conn, tzid.clone(), &calendar_title, &domain).await {
+                            Ok(x) => {
+                                *calendar.write().await = x;
+                            }
+                            Err(e) => {
+                                error!(?e, "Error updating calendar!!!");
+                            }
                         }
-                        Err(e) => {
-                            error!(?e, "Error updating calendar!!!");
-                        }
-                    },
+                    }
                     Err(e) => error!(?e, "Error getting connection to update calendar"),
                 }
             }
@@ -147,3 +313,93 @@ pub fn update_calendar_thread(
 
     update_tx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use itertools::Itertools;
+
+    fn at(year: i32, month: u32, day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(18, 30, 0)
+            .unwrap()
+    }
+
+    fn series(frequency: &str, starts_at: NaiveDateTime, repeat_until: NaiveDate) -> DbEventSeries {
+        DbEventSeries {
+            id: 7,
+            frequency: frequency.into(),
+            repeat_until,
+            event_name: "Chess Club".into(),
+            location: "Library".into(),
+            teacher: "Mr Smith".into(),
+            other_info: None,
+            starts_at,
+            ends_at: Some(starts_at + chrono::Duration::hours(1)),
+            is_all_day: false,
+            is_locked: false,
+            extra_points: 0,
+            created_at: starts_at,
+            capacity: None,
+        }
+    }
+
+    ///Gets the lines of an event as it would be in the feed
+    fn lines(event: Event) -> Vec<String> {
+        let mut calendar = Calendar::new();
+        calendar.push(event);
+        calendar
+            .done()
+            .to_string()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn missing_occurrences_are_excluded() {
+        let series = series("weekly", at(2025, 1, 6), at(2025, 1, 27).date());
+        let present = [at(2025, 1, 6), at(2025, 1, 20), at(2025, 1, 27)]
+            .into_iter()
+            .collect();
+
+        let lines = lines(series_event(&series, &present, "Europe/London", "example.com").unwrap());
+        assert!(lines.contains(&"UID:series-7@example.com".to_string()));
+        assert!(lines.contains(&"RRULE:FREQ=WEEKLY;COUNT=4".to_string()));
+        assert!(lines.contains(&"DTSTART;TZID=Europe/London:20250106T183000".to_string()));
+        let exdates = lines
+            .iter()
+            .filter(|line| line.starts_with("EXDATE"))
+            .collect_vec();
+        assert_eq!(exdates, ["EXDATE;TZID=Europe/London:20250113T183000"]);
+    }
+
+    #[test]
+    fn termly_series_repeat_every_four_months() {
+        let series = series("termly", at(2025, 1, 28), at(2026, 1, 31).date());
+        let present = [at(2025, 1, 28), at(2025, 9, 28), at(2026, 1, 28)]
+            .into_iter()
+            .collect();
+
+        let lines = lines(series_event(&series, &present, "Europe/London", "example.com").unwrap());
+        assert!(lines.contains(&"RRULE:FREQ=MONTHLY;INTERVAL=4;COUNT=4".to_string()));
+        let exdates = lines
+            .iter()
+            .filter(|line| line.starts_with("EXDATE"))
+            .collect_vec();
+        assert_eq!(exdates, ["EXDATE;TZID=Europe/London:20250528T183000"]);
+    }
+
+    #[test]
+    fn all_day_series_use_dates() {
+        let mut series = series("daily", at(2025, 1, 6), at(2025, 1, 8).date());
+        series.is_all_day = true;
+        let present = [at(2025, 1, 6), at(2025, 1, 8)].into_iter().collect();
+
+        let lines = lines(series_event(&series, &present, "Europe/London", "example.com").unwrap());
+        assert!(lines.contains(&"DTSTART;VALUE=DATE:20250106".to_string()));
+        assert!(lines.contains(&"EXDATE;VALUE=DATE:20250107".to_string()));
+    }
+}
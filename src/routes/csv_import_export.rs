@@ -1,14 +1,15 @@
 use crate::{
     auth::{
         backend::{Auth, VentAuthBackend},
-        get_auth_object, PermissionsRole, PermissionsTarget,
+        get_auth_object, get_roles, PermissionsTarget,
     },
     error::{
-        EncodeStep, EventField, MalformedCSVSnafu, ParseBoolSnafu,
-        ParseTimeSnafu, PersonField, SqlxAction, SqlxSnafu, TryingToGetFromCSV, VentError,
-        WhatToParse,
+        EncodeStep, EventEndsBeforeStartSnafu, EventField, MalformedCSVSnafu, ParseBoolSnafu,
+        ParseTimeSnafu, PersonField, SqlxAction, SqlxSnafu, TryingToGetFromCSV, UnknownRoleSnafu,
+        VentError, WhatToParse,
     },
-    state::VentState,
+    api::events::ApiEvent,
+    state::{db_objects::DbEvent, webhooks::WebhookEventKind, VentState},
 };
 use axum::{
     extract::{Multipart, State},
@@ -21,16 +22,18 @@ use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
 use csv_async::{AsyncReaderBuilder};
 use futures::stream::StreamExt;
 use serde::Deserialize;
-use snafu::{OptionExt, ResultExt};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::HashMap;
 use crate::routes::public::serve_bytes_with_mime;
+use tower_sessions::Session;
 
 #[axum::debug_handler]
 pub async fn get_import_export_csv(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     state
         .compile(
@@ -88,6 +91,13 @@ pub async fn post_import_people_from_csv(
         .map(|r| (r.surname, r.id))
         .collect();
 
+    //roles can be renamed, so they get matched without caring about case
+    let role_ids: HashMap<String, i32> = get_roles(state.get_connection().await?)
+        .await?
+        .into_iter()
+        .map(|role| (role.name.to_lowercase(), role.id))
+        .collect();
+
     //possibility of baby data races here, but not too important
 
     while let Some(record) = csv_reader.next().await.transpose()? {
@@ -101,16 +111,9 @@ pub async fn post_import_people_from_csv(
         let form = record.get(2).context(MalformedCSVSnafu {
             was_trying_to_get: PersonField::Form,
         })?;
-        let is_prefect: bool = record
-            .get(3)
-            .context(MalformedCSVSnafu {
-                was_trying_to_get: PersonField::IsPrefect,
-            })?
-            .parse()
-            .context(ParseBoolSnafu {
-                trying_to_parse: WhatToParse::PartOfAPerson(PersonField::IsPrefect),
-                how_got_in: EncodeStep::Decode,
-            })?;
+        let role = record.get(3).context(MalformedCSVSnafu {
+            was_trying_to_get: PersonField::Role,
+        })?;
         let username = record.get(4).context(MalformedCSVSnafu {
             was_trying_to_get: PersonField::Username,
         })?;
@@ -132,17 +135,21 @@ pub async fn post_import_people_from_csv(
             }
         }
 
-        let perms = if is_prefect {
-            PermissionsRole::Prefect
-        } else {
-            PermissionsRole::Participant
+        //older CSVs have an `is_prefect` column instead, which maps to the roles that it used to mean
+        let role_name = match role.trim().parse::<bool>() {
+            Ok(true) => "Prefect",
+            Ok(false) => "Participant",
+            Err(_) => role.trim(),
+        };
+        let Some(&role_id) = role_ids.get(&role_name.to_lowercase()) else {
+            return UnknownRoleSnafu { name: role_name }.fail();
         };
 
         if let Some(needs_to_update) = needs_to_update {
             debug!("Updating");
             sqlx::query!(
-                "UPDATE people SET permissions = $1, username = $2 WHERE id = $3",
-                perms as _,
+                "UPDATE people SET role_id = $1, username = $2 WHERE id = $3",
+                role_id,
                 username,
                 needs_to_update
             )
@@ -155,13 +162,13 @@ pub async fn post_import_people_from_csv(
             debug!("Creating");
             sqlx::query!(
                     r#"INSERT INTO public.people
-            (first_name, surname, form, hashed_password, permissions, username, password_link_id, was_first_entry)
-            VALUES($1, $2, $3, NULL, $4, $5, NULL, $6);
+            (first_name, surname, form, hashed_password, role_id, username, was_first_entry)
+            VALUES($1, $2, $3, NULL, $4, $5, $6);
             "#,
                     first_name,
                     surname,
                     form,
-                    perms as _,
+                    role_id,
                     username,
                     was_first_entry
                 )
@@ -210,34 +217,90 @@ pub async fn post_import_events_from_csv(
         let name = record.get(1).context(MalformedCSVSnafu {
             was_trying_to_get: TryingToGetFromCSV::Event(EventField::Name),
         })?;
-        let time = {
-            let str = record.get(2).context(MalformedCSVSnafu {
-                was_trying_to_get: TryingToGetFromCSV::Event(EventField::Time),
-            })?;
-
+        //the end and all-day columns are optional, so older CSVs still work
+        let optional = |index: usize| record.get(index).filter(|str| !str.trim().is_empty());
+        let parse_time = |str: &str| {
             NaiveTime::parse_from_str(str, "%R").context(ParseTimeSnafu {
                 original: str.to_string(),
                 how_got_in: EncodeStep::Decode,
-            })?
+            })
+        };
+
+        let is_all_day = optional(7)
+            .map(|str| {
+                str.parse().context(ParseBoolSnafu {
+                    trying_to_parse: WhatToParse::PartOfAnEvent(EventField::IsAllDay),
+                    how_got_in: EncodeStep::Decode,
+                })
+            })
+            .transpose()?
+            .unwrap_or(false);
+        let time = if is_all_day {
+            NaiveTime::MIN
+        } else {
+            parse_time(record.get(2).context(MalformedCSVSnafu {
+                was_trying_to_get: TryingToGetFromCSV::Event(EventField::Time),
+            })?)?
         };
         let date_time = NaiveDateTime::new(date, time);
 
+        let end_date_time = match (optional(5), optional(6)) {
+            (None, None) => None,
+            (end_date, end_time) => {
+                let end_date = end_date
+                    .map(|str| {
+                        NaiveDate::parse_from_str(str, "%d-%m-%Y").context(ParseTimeSnafu {
+                            original: str.to_string(),
+                            how_got_in: EncodeStep::Decode,
+                        })
+                    })
+                    .transpose()?
+                    .unwrap_or(date);
+                let end_time = if is_all_day {
+                    NaiveTime::MIN
+                } else {
+                    parse_time(end_time.context(MalformedCSVSnafu {
+                        was_trying_to_get: TryingToGetFromCSV::Event(EventField::EndTime),
+                    })?)?
+                };
+
+                Some(NaiveDateTime::new(end_date, end_time))
+            }
+        };
+        if let Some(ends) = end_date_time {
+            ensure!(
+                ends >= date_time,
+                EventEndsBeforeStartSnafu {
+                    starts: date_time,
+                    ends
+                }
+            );
+        }
+
         debug!(?name, ?date, ?location, "Creating new event");
 
-        sqlx::query!(
+        let event = sqlx::query_as!(
+            DbEvent,
             r#"
-INSERT INTO events (event_name, date, location, teacher) 
-VALUES ($1, $2, $3, $4)"#,
+INSERT INTO events (event_name, date, location, teacher, end_date, is_all_day) 
+VALUES ($1, $2, $3, $4, $5, $6)
+RETURNING *"#,
             name,
             date_time,
             location,
-            teacher
+            teacher,
+            end_date_time,
+            is_all_day
         )
-        .execute(&mut *state.get_connection().await?)
+        .fetch_one(&mut *state.get_connection().await?)
         .await
         .context(SqlxSnafu {
             action: SqlxAction::AddingEvent,
         })?;
+
+        state
+            .fire_webhook(WebhookEventKind::EventCreated, ApiEvent::from(event))
+            .await;
     }
 
     state.update_events()?;
@@ -250,7 +313,16 @@ pub async fn export_events_to_csv(
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
     let mut csv_writer = csv::Writer::from_writer(vec![]);
-    csv_writer.write_record(&["date", "name", "time", "location", "teacher"]).unwrap();
+    csv_writer.write_record([
+        "date",
+        "name",
+        "time",
+        "location",
+        "teacher",
+        "end_date",
+        "end_time",
+        "is_all_day",
+    ]).unwrap();
 
     #[derive(Deserialize)]
     struct SmolEvent {
@@ -258,6 +330,8 @@ pub async fn export_events_to_csv(
         pub date: NaiveDateTime,
         pub location: String,
         pub teacher: String,
+        pub end_date: Option<NaiveDateTime>,
+        pub is_all_day: bool,
     }
 
     for SmolEvent {
@@ -265,9 +339,11 @@ pub async fn export_events_to_csv(
         date,
         location,
         teacher,
+        end_date,
+        is_all_day,
     } in sqlx::query_as!(
         SmolEvent,
-        r#"SELECT event_name, date, location, teacher FROM events"#
+        r#"SELECT event_name, date, location, teacher, end_date, is_all_day FROM events"#
     )
     .fetch_all(&mut *state.get_connection().await?)
     .await
@@ -280,6 +356,14 @@ pub async fn export_events_to_csv(
             date.format("%H:%M").to_string(),
             location,
             teacher,
+            end_date
+                .map(|end_date| end_date.format("%d-%m-%Y").to_string())
+                .unwrap_or_default(),
+            end_date
+                .filter(|_| !is_all_day)
+                .map(|end_date| end_date.format("%H:%M").to_string())
+                .unwrap_or_default(),
+            is_all_day.to_string(),
         ]).unwrap();
     }
     
@@ -295,7 +379,7 @@ pub async fn export_people_to_csv(
         "first_name",
         "surname",
         "form",
-        "is_prefect",
+        "role",
         "username",
         "was_first_entry",
     ]).unwrap();
@@ -305,7 +389,7 @@ pub async fn export_people_to_csv(
         pub first_name: String,
         pub surname: String,
         pub form: String,
-        pub permissions: PermissionsRole,
+        pub role: String,
         pub username: String,
         pub was_first_entry: bool,
     }
@@ -314,12 +398,15 @@ pub async fn export_people_to_csv(
         first_name,
         surname,
         form,
-        permissions,
+        role,
         username,
         was_first_entry
     } in sqlx::query_as!(
         SmolPerson,
-        r#"SELECT first_name, surname, form, permissions as "permissions: _", username, was_first_entry FROM people"#
+        r#"
+SELECT p.first_name, p.surname, p.form, p.username, p.was_first_entry, r.name AS role
+FROM people p
+INNER JOIN roles r ON r.id = p.role_id"#
     )
     .fetch_all(&mut *state.get_connection().await?)
     .await.context(SqlxSnafu { action: SqlxAction::FindingPeople })?
@@ -328,7 +415,7 @@ pub async fn export_people_to_csv(
             first_name,
             surname,
             form,
-            (permissions >= PermissionsRole::Prefect).to_string(),
+            role,
             username,
             was_first_entry.to_string()
         ]).unwrap();
@@ -1,7 +1,11 @@
 use crate::{
     auth::{
         backend::{Auth, VentAuthBackend},
-        get_auth_object, PermissionsRole, PermissionsTarget,
+        get_auth_object, get_individual_permissions, get_role_permissions, get_roles,
+        passkeys::{get_passkeys, remove_passkey},
+        rate_limit::{clear_failures, locked_until, Attempter},
+        sessions::{count_sessions, remove_all_sessions},
+        PermissionsTarget,
     },
     error::{DatabaseIDMethod, SqlxAction, SqlxSnafu, VentError},
     liquid_utils::CustomFormat,
@@ -10,30 +14,38 @@ use crate::{
 };
 use axum::{
     extract::{Path, State},
+    http::StatusCode,
     response::{IntoResponse, Redirect},
     routing::{get, post},
     Form, Router,
 };
-use axum_login::permission_required;
+use axum_login::{permission_required, AuthzBackend};
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
+use std::collections::HashMap;
+use strum::IntoEnumIterator;
+use tower_sessions::Session;
 
 #[axum::debug_handler]
 async fn get_edit_person(
     auth: Auth,
+    session: Session,
     Path(id): Path<i32>,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
     #[derive(Serialize)]
     struct SmolPerson {
         pub id: i32,
-        pub permissions: PermissionsRole,
+        pub role_id: i32,
+        pub role_name: String,
         pub first_name: String,
         pub surname: String,
         pub username: String,
         pub password_is_set: bool,
         pub form: String,
         pub was_first_entry: bool,
+        pub locked_until: Option<String>,
+        pub active_sessions: i64,
     }
 
     debug!("Getting relevant person");
@@ -41,23 +53,41 @@ async fn get_edit_person(
     let person = sqlx::query_as!(
         DbPerson,
         r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
 FROM people WHERE id = $1
         "#,
         id
     )
-        .fetch_one(&mut *state.get_connection().await?)
-        .await.context(SqlxSnafu { action: SqlxAction::FindingPerson(id.into()) })?;
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(id.into()),
+    })?;
+
+    let roles = get_roles(state.get_connection().await?).await?;
+    let role_name = roles
+        .iter()
+        .find(|role| role.id == person.role_id)
+        .map(|role| role.name.clone())
+        .unwrap_or_default();
+
+    let locked_until = locked_until(&state, &Attempter::username(&person.username))
+        .await?
+        .map(|until| until.to_env_string(&state.settings.niche.date_time_format));
+    let active_sessions = count_sessions(&state, person.id).await?;
 
     let person = SmolPerson {
         id: person.id,
-        permissions: person.permissions,
+        role_id: person.role_id,
+        role_name,
         first_name: person.first_name,
         surname: person.surname,
         username: person.username,
         form: person.form,
         password_is_set: person.hashed_password.is_some(),
         was_first_entry: person.was_first_entry,
+        locked_until,
+        active_sessions,
     };
 
     debug!("Getting events supervised");
@@ -170,11 +200,45 @@ ON pe.event_id = e.id AND pe.participant_id = $1 AND pe.is_verified"#,
     let pts = event_pts + bonus_pts;
     let rewards = sqlx::query_as!(Reward, "select name, first_entry_pts, second_entry_pts, id FROM rewards_received rr inner join rewards r on r.id = rr.reward_id and rr.person_id = $1", person.id).fetch_all(&mut *state.get_connection().await?).await.context(SqlxSnafu { action: SqlxAction::FindingPerson(person.id.into()) })?;
 
+    #[derive(Serialize)]
+    struct IndividualPermission {
+        name: &'static str,
+        role_has: bool,
+        state: &'static str,
+        can_change: bool,
+    }
+
+    debug!("Getting individual permissions");
+
+    let individual = get_individual_permissions(state.get_connection().await?, person.id).await?;
+    let role_permissions =
+        get_role_permissions(state.get_connection().await?, person.role_id).await?;
+    let current_user = auth
+        .user
+        .as_ref()
+        .expect("need to be logged in to see people");
+    let own_permissions = auth.backend.get_all_permissions(current_user).await?;
+    let is_self = current_user.id == person.id;
+    let individual_permissions = PermissionsTarget::iter()
+        .map(|target| IndividualPermission {
+            name: target.into(),
+            role_has: role_permissions.contains(&target),
+            state: match individual.get(target) {
+                Some(true) => "grant",
+                Some(false) => "deny",
+                None => "default",
+            },
+            can_change: !is_self && own_permissions.contains(&target),
+        })
+        .collect::<Vec<_>>();
+
     debug!("Compiling");
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
+
+    let passkeys = get_passkeys(&state, id).await?;
 
-    state.compile("www/edit_person.liquid", liquid::object!({ "person": person, "supervised": events_supervised, "participated": events_participated, "pts": pts, "event_pts": event_pts, "bonus_points": bonus_points, "bonus_pts": bonus_pts, "rewards": rewards, "auth": aa, "imgs": photos, "n_imgs": photos.len() }), Some(format!("Edit {} {}", person.first_name, person.surname))).await
+    state.compile("www/edit_person.liquid", liquid::object!({ "person": person, "supervised": events_supervised, "participated": events_participated, "pts": pts, "event_pts": event_pts, "bonus_points": bonus_points, "bonus_pts": bonus_pts, "rewards": rewards, "auth": aa, "imgs": photos, "n_imgs": photos.len(), "individual_permissions": individual_permissions, "roles": roles, "passkeys": passkeys }), Some(format!("Edit {} {}", person.first_name, person.surname))).await
 }
 
 #[axum::debug_handler]
@@ -186,14 +250,14 @@ async fn post_edit_person(
         surname,
         form,
         username,
-        permissions,
+        role_id,
     }): Form<FormPerson>,
 ) -> Result<impl IntoResponse, VentError> {
     debug!("Editing person");
     sqlx::query!(
         r#"
 UPDATE public.people
-SET permissions=$6, first_name=$2, surname=$3, form=$4, username=$5
+SET role_id=$6, first_name=$2, surname=$3, form=$4, username=$5
 WHERE id=$1
         "#,
         id,
@@ -201,7 +265,7 @@ WHERE id=$1
         surname,
         form,
         username,
-        permissions as _
+        role_id
     )
     .execute(&mut *state.get_connection().await?)
     .await
@@ -212,6 +276,93 @@ WHERE id=$1
     Ok(Redirect::to(&format!("/edit_person/{id}")))
 }
 
+///`POST` method to set the individual permissions for a person - the form has one field per [`PermissionsTarget`], with a value of `default`, `grant` or `deny`.
+///
+/// Nobody can change their own permissions, and people can only change permissions that they have themselves - anything else has to stay how it was, so it can be left out of the form.
+#[axum::debug_handler]
+async fn post_edit_individual_permissions(
+    auth: Auth,
+    Path(id): Path<i32>,
+    State(state): State<VentState>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_user = auth
+        .user
+        .as_ref()
+        .expect("need to be logged in to edit permissions");
+    if current_user.id == id {
+        warn!(%id, "Tried to change their own individual permissions");
+        return Ok((
+            StatusCode::FORBIDDEN,
+            "You can't change your own permissions.",
+        )
+            .into_response());
+    }
+
+    let own_permissions = auth.backend.get_all_permissions(current_user).await?;
+    let existing = get_individual_permissions(state.get_connection().await?, id).await?;
+
+    let mut wanted = vec![];
+    for target in PermissionsTarget::iter() {
+        let name: &'static str = target.into();
+        let can_change = own_permissions.contains(&target);
+        let current = existing.get(target);
+        let requested = match form.get(name).map(String::as_str) {
+            Some("grant") => Some(true),
+            Some("deny") => Some(false),
+            None if !can_change => current,
+            _ => None,
+        };
+
+        if requested != current && !can_change {
+            warn!(%id, ?target, perp=%current_user.id, "Tried to change a permission they don't have");
+            return Ok((
+                StatusCode::FORBIDDEN,
+                "You can only change permissions that you have yourself.",
+            )
+                .into_response());
+        }
+
+        if let Some(is_granted) = requested {
+            wanted.push((name, is_granted));
+        }
+    }
+
+    debug!(%id, "Clearing individual permissions");
+
+    //if anything fails part way through, they keep the permissions they had before
+    let mut transaction = state.begin_transaction().await?;
+
+    sqlx::query!("DELETE FROM person_permissions WHERE person_id = $1", id)
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingIndividualPermissions(id.into()),
+        })?;
+
+    for (name, is_granted) in wanted {
+        debug!(%id, %name, %is_granted, "Adding individual permission");
+
+        sqlx::query!(
+            "INSERT INTO person_permissions (person_id, permission, is_granted) VALUES ($1, $2, $3)",
+            id,
+            name,
+            is_granted
+        )
+        .execute(&mut *transaction)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingIndividualPermissions(id.into()),
+        })?;
+    }
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(Redirect::to(&format!("/edit_person/{id}")).into_response())
+}
+
 #[derive(Deserialize)]
 struct PasswordReset {
     id: i32,
@@ -223,17 +374,64 @@ async fn post_reset_password(
     State(state): State<VentState>,
     Form(PasswordReset { id }): Form<PasswordReset>,
 ) -> Result<impl IntoResponse, VentError> {
+    let requested_by = auth.user.as_ref().map(|x| x.id);
+
     debug!("Logging out.");
 
-    if auth.user.as_ref().is_some_and(|x| x.id == id) {
+    if requested_by == Some(id) {
         auth.logout().await?;
     }
 
     debug!("Sending password reset");
-    state.reset_password(id).await?;
+    state.reset_password(id, requested_by).await?;
     Ok(Redirect::to("/"))
 }
 
+///`POST` method to let someone who's been locked out after too many failed logins try again straight away
+#[axum::debug_handler]
+async fn post_unlock_person(
+    Path(id): Path<i32>,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%id, "Unlocking person");
+
+    clear_failures(&state, &Attempter::for_person(&state, id).await?).await?;
+
+    Ok(Redirect::to(&format!("/edit_person/{id}")))
+}
+
+///`POST` method to log someone out of every session they have, eg. if their account might have been compromised
+#[axum::debug_handler]
+async fn post_logout_everywhere(
+    Path(id): Path<i32>,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%id, "Logging person out everywhere");
+
+    remove_all_sessions(&state, id).await?;
+
+    Ok(Redirect::to(&format!("/edit_person/{id}")))
+}
+
+#[derive(Deserialize)]
+struct PasskeyId {
+    passkey_id: i32,
+}
+
+///`POST` method to take away one of someone's passkeys, eg. if they've lost the device it was on
+#[axum::debug_handler]
+async fn post_revoke_passkey(
+    Path(id): Path<i32>,
+    State(state): State<VentState>,
+    Form(PasskeyId { passkey_id }): Form<PasskeyId>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%id, %passkey_id, "Revoking passkey");
+
+    remove_passkey(&state, id, passkey_id).await?;
+
+    Ok(Redirect::to(&format!("/edit_person/{id}")))
+}
+
 pub fn router() -> Router<VentState> {
     Router::new()
         .route("/edit_person/:id", post(post_edit_person))
@@ -243,6 +441,19 @@ pub fn router() -> Router<VentState> {
             PermissionsTarget::SeePeople
         ))
         .route("/reset_password", post(post_reset_password))
+        .route(
+            "/edit_person/:id/permissions",
+            post(post_edit_individual_permissions),
+        )
+        .route("/edit_person/:id/unlock", post(post_unlock_person))
+        .route(
+            "/edit_person/:id/logout_everywhere",
+            post(post_logout_everywhere),
+        )
+        .route(
+            "/edit_person/:id/passkeys/revoke",
+            post(post_revoke_passkey),
+        )
         .route_layer(permission_required!(
             VentAuthBackend,
             login_url = "/login",
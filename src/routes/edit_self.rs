@@ -1,28 +1,38 @@
 use crate::{
     auth::{
+        api_tokens::get_api_tokens,
         backend::{Auth, VentAuthBackend},
-        get_auth_object, PermissionsRole,
+        get_auth_object,
+        passkeys::get_passkeys,
+        password_hash::{hash_password, verify_password, PasswordCheck},
+        password_policy::password_problems,
+        rate_limit::{is_locked_out, record_failure, Attempter},
+        sessions::{get_sessions, remove_other_sessions},
+        two_factor::{get_two_factor_object, get_two_factor_state},
     },
     error::{DatabaseIDMethod, SqlxAction, SqlxSnafu, VentError},
     liquid_utils::CustomFormat,
     routes::rewards::Reward,
-    state::{db_objects::DbPerson, VentState},
+    state::{
+        db_objects::{AuthorisationBackendPerson, DbPerson},
+        VentState,
+    },
 };
 use axum::{
     extract::State,
     response::{IntoResponse, Redirect},
-    routing::get,
+    routing::{get, post},
     Form, Router,
 };
 use axum_login::login_required;
-use bcrypt::{hash, DEFAULT_COST};
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
+use tower_sessions::Session;
 
 #[derive(Serialize)]
 struct SmolPerson {
     pub id: i32,
-    pub permissions: PermissionsRole,
+    pub role_id: i32,
     pub first_name: String,
     pub surname: String,
     pub username: String,
@@ -59,26 +69,40 @@ struct BonusPoint {
 #[axum::debug_handler]
 pub async fn get_edit_user(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
-    let aa = get_auth_object(auth.clone()).await?;
-    let current_id = auth.user.unwrap().id;
+    render_edit_user(auth, &session, &state, vec![]).await
+}
+
+///Renders someone's own profile page, with anything wrong with the new password they just tried
+async fn render_edit_user(
+    auth: Auth,
+    session: &Session,
+    state: &VentState,
+    password_problems: Vec<String>,
+) -> Result<impl IntoResponse, VentError> {
+    let aa = get_auth_object(auth.clone(), session).await?;
+    let current_id = auth.user.as_ref().unwrap().id;
     debug!("Getting relevant person");
 
     let person = sqlx::query_as!(
         DbPerson,
         r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
 FROM people WHERE id = $1
         "#,
         current_id
     )
-        .fetch_one(&mut *state.get_connection().await?)
-        .await.context(SqlxSnafu { action: SqlxAction::FindingPerson(current_id.into()) })?;
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(current_id.into()),
+    })?;
 
     let person = SmolPerson {
         id: person.id,
-        permissions: person.permissions,
+        role_id: person.role_id,
         first_name: person.first_name,
         surname: person.surname,
         username: person.username,
@@ -173,16 +197,20 @@ ON pe.event_id = e.id AND pe.participant_id = $1 AND pe.is_verified"#,
     let pts = event_pts + bonus_pts;
     let rewards = sqlx::query_as!(Reward, "select name, first_entry_pts, second_entry_pts, id FROM rewards_received rr inner join rewards r on r.id = rr.reward_id and rr.person_id = $1", person.id).fetch_all(&mut *state.get_connection().await?).await.context(SqlxSnafu { action: SqlxAction::FindingPerson(person.id.into()) })?;
 
+    let two_factor = get_two_factor_object(state, current_id).await?;
+    let sessions = get_sessions(state, current_id, session).await?;
+    let passkeys = get_passkeys(state, current_id).await?;
+    let api_tokens = get_api_tokens(state, current_id).await?;
+
     debug!("Compiling");
 
-    state.compile("www/edit_self.liquid", liquid::object!({ "person": person, "supervised": events_supervised, "participated": events_participated, "pts": pts, "event_pts": event_pts, "bonus_points": bonus_points, "bonus_pts": bonus_pts, "rewards": rewards, "auth": aa, "imgs": photos, "n_imgs": photos.len() }), Some(format!("Edit {} {}", person.first_name, person.surname))).await
+    state.compile("www/edit_self.liquid", liquid::object!({ "person": person, "supervised": events_supervised, "participated": events_participated, "pts": pts, "event_pts": event_pts, "bonus_points": bonus_points, "bonus_pts": bonus_pts, "rewards": rewards, "auth": aa, "imgs": photos, "n_imgs": photos.len(), "two_factor": two_factor, "sessions": sessions, "passkeys": passkeys, "api_tokens": api_tokens, "password_problems": password_problems }), Some(format!("Edit {} {}", person.first_name, person.surname))).await
 }
 
 #[derive(Deserialize)]
 pub struct LoginDetails {
     pub first_name: String,
     pub surname: String,
-    pub unhashed_password: String,
 }
 #[axum::debug_handler]
 pub async fn post_edit_user(
@@ -191,26 +219,20 @@ pub async fn post_edit_user(
     Form(LoginDetails {
         first_name,
         surname,
-        unhashed_password,
     }): Form<LoginDetails>,
 ) -> Result<impl IntoResponse, VentError> {
     let current_id = auth.user.unwrap().id;
 
-    debug!(%current_id, "Hashing password");
-
-    let hashed = hash(&unhashed_password, DEFAULT_COST)?;
-
-    debug!("Updating in DB");
+    debug!(%current_id, "Updating in DB");
 
     sqlx::query!(
         r#"
 UPDATE people
-SET first_name=$1, surname = $2, hashed_password=$3
-WHERE id=$4;
+SET first_name=$1, surname = $2
+WHERE id=$3;
         "#,
         first_name,
         surname,
-        hashed,
         current_id
     )
     .execute(&mut *state.get_connection().await?)
@@ -222,8 +244,117 @@ WHERE id=$4;
     Ok(Redirect::to("/"))
 }
 
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+///`POST` method for someone to change their own password, which logs out all of their other sessions.
+#[axum::debug_handler]
+pub async fn post_change_password(
+    mut auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Form(ChangePassword {
+        current_password,
+        new_password,
+    }): Form<ChangePassword>,
+) -> Result<impl IntoResponse, VentError> {
+    let current_id = auth.user.as_ref().unwrap().id;
+
+    let person = sqlx::query_as!(
+        DbPerson,
+        r#"
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
+FROM people WHERE id = $1
+        "#,
+        current_id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPerson(current_id.into()),
+    })?;
+
+    let attempter = Attempter::username(&person.username);
+    if is_locked_out(&state, std::slice::from_ref(&attempter)).await? {
+        return Ok(Redirect::to("/login_failure/locked_out").into_response());
+    }
+
+    //people who've only ever used SSO or login links don't have a password to check, so they have to go through add password like everyone else
+    let Some(hashed_password) = &person.hashed_password else {
+        return Ok(render_edit_user(
+            auth,
+            &session,
+            &state,
+            vec!["You don't have a password yet - log out, and then log in with your username to get a link to set one.".to_string()],
+        )
+        .await?
+        .into_response());
+    };
+
+    let settings = &state.settings.password_hashing;
+    if verify_password(settings, &current_password, hashed_password).await?
+        == PasswordCheck::Incorrect
+    {
+        warn!(%current_id, "Wrong current password when changing password");
+        record_failure(&state, &[attempter]).await?;
+        return Ok(render_edit_user(
+            auth,
+            &session,
+            &state,
+            vec!["Your current password wasn't right.".to_string()],
+        )
+        .await?
+        .into_response());
+    }
+
+    let problems = password_problems(
+        &state.settings.password_policy,
+        &new_password,
+        &[&person.first_name, &person.surname, &person.username],
+    );
+    if !problems.is_empty() {
+        return Ok(render_edit_user(auth, &session, &state, problems)
+            .await?
+            .into_response());
+    }
+
+    debug!(%current_id, "Hashing new password");
+    let hashed = hash_password(settings, &new_password).await?;
+
+    let person = sqlx::query_as!(
+        DbPerson,
+        r#"
+UPDATE people
+SET hashed_password = $1
+WHERE id = $2
+RETURNING id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
+        "#,
+        hashed,
+        current_id
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingPerson(current_id.into()),
+    })?;
+
+    info!(%current_id, "Changed password");
+
+    //the session auth hash comes from the password hash, so every other session stops working - logging in again here keeps this one going
+    let mut user: AuthorisationBackendPerson = person.into();
+    user.two_factor = get_two_factor_state(state.get_connection().await?, current_id).await?;
+    auth.login(&user).await?;
+    remove_other_sessions(&state, current_id, &session).await?;
+
+    Ok(Redirect::to("/edit_user").into_response())
+}
+
 pub fn router() -> Router<VentState> {
     Router::new()
         .route("/edit_user", get(get_edit_user).post(post_edit_user))
+        .route("/change_password", post(post_change_password))
         .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
 }
@@ -16,10 +16,12 @@ use axum_login::permission_required;
 use itertools::Itertools;
 use serde::Deserialize;
 use snafu::ResultExt;
+use tower_sessions::Session;
 
 #[axum::debug_handler]
 async fn get_eoy_migration(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
     debug!("Getting all forms");
@@ -37,7 +39,7 @@ async fn get_eoy_migration(
 
     debug!("Compiling");
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     state
         .compile(
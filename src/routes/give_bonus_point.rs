@@ -18,17 +18,19 @@ use axum_login::permission_required;
 use chrono::Utc;
 use dotenvy::var;
 use snafu::ResultExt;
+use tower_sessions::Session;
 
 #[allow(clippy::too_many_lines)]
 #[axum::debug_handler]
 async fn get_give_bonus_points_form(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<Response, VentError> {
     if var("HIDE_BONUS_POINTS").is_ok() {
         return Ok(Redirect::to("/").into_response());
     }
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     let page = state
         .compile(
@@ -41,6 +43,15 @@ async fn get_give_bonus_points_form(
     Ok(page.into_response())
 }
 
+///`POST` method to give a bonus point from the form. Redirects to the new bonus point's page.
+#[utoipa::path(
+    post,
+    path = "/give_bonus_point",
+    tag = "forms",
+    request_body(content = FormBonusPoint, content_type = "application/x-www-form-urlencoded"),
+    responses((status = 303, description = "Redirects to the new bonus point's page")),
+    security(("session" = []))
+)]
 #[axum::debug_handler]
 async fn post_give_bonus_points_form(
     State(state): State<VentState>,
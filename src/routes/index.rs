@@ -8,11 +8,13 @@ use crate::{
     liquid_utils::CustomFormat,
     state::{db_objects::DbEvent, VentState},
 };
+use tower_sessions::Session;
 
 #[allow(clippy::too_many_lines)]
 #[axum::debug_handler]
 pub async fn get_index(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
     #[derive(Serialize, Debug)]
@@ -35,6 +37,16 @@ pub async fn get_index(
                     zip_file: _,
                     is_locked: _,
                     extra_points: _,
+                    end_date: _,
+                    is_all_day: _,
+                    series_id: _,
+                    series_occurrence: _,
+                    is_series_override: _,
+                    capacity: _,
+                    sign_ups_open_at: _,
+                    sign_ups_close_at: _,
+                    locks_at: _,
+                    auto_locked_at: _,
                 },
                 fmt,
             ): (DbEvent, &'a str),
@@ -206,7 +218,7 @@ INNER JOIN participant_events pe ON p.id = pe.participant_id and pe.event_id = $
         });
     }
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     state.compile("www/index.liquid", liquid::object!({ "events_to_happen": events_to_happen, "happened_events": happened_events, "auth": aa }), None).await
 }
@@ -1,5 +1,5 @@
 use crate::{
-    auth::{backend::VentAuthBackend, cloudflare_turnstile::CommonHeaders, PermissionsTarget},
+    auth::{backend::VentAuthBackend, captcha::CommonHeaders, PermissionsTarget},
     error::{
         FileIdentifier, HeadersSnafu, HttpAction, HttpSnafu, IOAction, IOSnafu, SerdeJsonAction,
         SerdeJsonSnafu, UnknownMIMESnafu, VentError,
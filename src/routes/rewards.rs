@@ -9,17 +9,20 @@ use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use std::collections::HashMap;
+use utoipa::ToSchema;
 
 use crate::{
+    api::rewards::ApiRewardReceived,
     auth::{
         backend::{Auth, VentAuthBackend},
         get_auth_object, PermissionsTarget,
     },
     error::{SqlxAction, SqlxSnafu, VentError},
-    state::VentState,
+    state::{webhooks::WebhookEventKind, VentState},
 };
+use tower_sessions::Session;
 
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
 pub struct Reward {
     pub name: String,
     pub first_entry_pts: i32,
@@ -30,6 +33,7 @@ pub struct Reward {
 #[axum::debug_handler]
 pub async fn get_rewards(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
     ///NB: these are rewards TO BE RECEIVED
@@ -163,7 +167,7 @@ pub async fn get_rewards(
     already_awarded.sort_by_cached_key(|x| x.form.clone());
     already_awarded.sort_by_cached_key(|x| x.awards.clone());
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     state
         .compile(
@@ -199,6 +203,16 @@ pub async fn post_add_reward(
         action: SqlxAction::AddingReward,
     })?;
 
+    state
+        .fire_webhook(
+            WebhookEventKind::RewardAwarded,
+            ApiRewardReceived {
+                reward_id,
+                person_id,
+            },
+        )
+        .await;
+
     Ok(Redirect::to("/add_reward"))
 }
 
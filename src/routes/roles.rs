@@ -0,0 +1,243 @@
+//! Module for devs to manage the roles in the `roles` table, and which [`PermissionsTarget`]s each of them has.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        get_auth_object, get_role_permissions, get_roles, PermissionsTarget,
+    },
+    error::{SqlxAction, SqlxSnafu, VentError},
+    state::VentState,
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_login::permission_required;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use strum::IntoEnumIterator;
+use tower_sessions::Session;
+
+///`GET` method that shows the permission matrix for all of the roles
+#[axum::debug_handler]
+async fn get_roles_matrix(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    #[derive(Serialize)]
+    struct RolePermission {
+        name: &'static str,
+        has: bool,
+    }
+
+    #[derive(Serialize)]
+    struct Role {
+        id: i32,
+        name: String,
+        requires_2fa: bool,
+        n_people: i64,
+        permissions: Vec<RolePermission>,
+    }
+
+    debug!("Getting roles");
+
+    let mut roles = vec![];
+    for role in get_roles(state.get_connection().await?).await? {
+        let has = get_role_permissions(state.get_connection().await?, role.id).await?;
+        let n_people = sqlx::query!("SELECT COUNT(*) FROM people WHERE role_id = $1", role.id)
+            .fetch_one(&mut *state.get_connection().await?)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::FindingPeople,
+            })?
+            .count
+            .unwrap_or_default();
+
+        roles.push(Role {
+            id: role.id,
+            name: role.name,
+            requires_2fa: role.requires_2fa,
+            n_people,
+            permissions: PermissionsTarget::iter()
+                .map(|target| RolePermission {
+                    name: target.into(),
+                    has: has.contains(&target),
+                })
+                .collect(),
+        });
+    }
+
+    let targets = PermissionsTarget::iter()
+        .map(<&'static str>::from)
+        .collect::<Vec<_>>();
+
+    let aa = get_auth_object(auth, &session).await?;
+
+    state
+        .compile(
+            "www/roles.liquid",
+            liquid::object!({ "auth": aa, "roles": roles, "targets": targets }),
+            Some("Roles".into()),
+        )
+        .await
+}
+
+///`POST` method that replaces the whole permission matrix.
+///
+/// Every role has a `name_{id}` field and a `requires_2fa_{id}` checkbox, and every permission a role has is sent as a `perm_{id}_{PermissionsTarget}` checkbox. To avoid locking everyone out, at least one role must keep [`PermissionsTarget::DevAccess`].
+#[axum::debug_handler]
+async fn post_roles_matrix(
+    State(state): State<VentState>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, VentError> {
+    let roles = get_roles(state.get_connection().await?).await?;
+
+    if !roles
+        .iter()
+        .any(|role| form.contains_key(&format!("perm_{}_DevAccess", role.id)))
+    {
+        warn!("Tried to remove DevAccess from every role");
+        return Ok(Redirect::to("/roles"));
+    }
+
+    //all or nothing, so a failure part way through can't leave a role without its permissions
+    let mut transaction = state.begin_transaction().await?;
+
+    for role in roles {
+        if let Some(name) = form
+            .get(&format!("name_{}", role.id))
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty() && *name != role.name)
+        {
+            debug!(?role.id, ?name, "Renaming role");
+
+            sqlx::query!("UPDATE roles SET name = $1 WHERE id = $2", name, role.id)
+                .execute(&mut *transaction)
+                .await
+                .context(SqlxSnafu {
+                    action: SqlxAction::UpdatingRole(role.id),
+                })?;
+        }
+
+        let requires_2fa = form.contains_key(&format!("requires_2fa_{}", role.id));
+        if requires_2fa != role.requires_2fa {
+            debug!(?role.id, ?requires_2fa, "Changing 2FA requirement");
+
+            sqlx::query!(
+                "UPDATE roles SET requires_2fa = $1 WHERE id = $2",
+                requires_2fa,
+                role.id
+            )
+            .execute(&mut *transaction)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::UpdatingRole(role.id),
+            })?;
+        }
+
+        sqlx::query!("DELETE FROM role_permissions WHERE role_id = $1", role.id)
+            .execute(&mut *transaction)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::UpdatingRole(role.id),
+            })?;
+
+        for target in PermissionsTarget::iter() {
+            let target_name: &'static str = target.into();
+            if !form.contains_key(&format!("perm_{}_{target_name}", role.id)) {
+                continue;
+            }
+
+            sqlx::query!(
+                "INSERT INTO role_permissions (role_id, permission) VALUES ($1, $2)",
+                role.id,
+                target_name
+            )
+            .execute(&mut *transaction)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::UpdatingRole(role.id),
+            })?;
+        }
+    }
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(Redirect::to("/roles"))
+}
+
+#[derive(Deserialize)]
+struct NewRole {
+    name: String,
+}
+
+///`POST` method to add a new role, with no permissions
+#[axum::debug_handler]
+async fn post_add_role(
+    State(state): State<VentState>,
+    Form(NewRole { name }): Form<NewRole>,
+) -> Result<impl IntoResponse, VentError> {
+    debug!(?name, "Adding role");
+
+    sqlx::query!("INSERT INTO roles (name) VALUES ($1)", name.trim())
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::AddingRole,
+        })?;
+
+    Ok(Redirect::to("/roles"))
+}
+
+#[derive(Deserialize)]
+struct RemoveRole {
+    id: i32,
+}
+
+///`POST` method to remove a role - only works if nobody currently has that role
+#[axum::debug_handler]
+async fn post_remove_role(
+    State(state): State<VentState>,
+    Form(RemoveRole { id }): Form<RemoveRole>,
+) -> Result<impl IntoResponse, VentError> {
+    let n_people = sqlx::query!("SELECT COUNT(*) FROM people WHERE role_id = $1", id)
+        .fetch_one(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingPeople,
+        })?
+        .count
+        .unwrap_or_default();
+
+    if n_people > 0 {
+        warn!(%id, %n_people, "Tried to remove role that people still have");
+        return Ok(Redirect::to("/roles"));
+    }
+
+    sqlx::query!("DELETE FROM roles WHERE id = $1", id)
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::RemovingRole(id),
+        })?;
+
+    Ok(Redirect::to("/roles"))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/roles", get(get_roles_matrix).post(post_roles_matrix))
+        .route("/add_role", post(post_add_role))
+        .route("/remove_role", post(post_remove_role))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::DevAccess
+        ))
+}
@@ -16,16 +16,18 @@ use axum_login::permission_required;
 use dotenvy::var;
 use serde::Serialize;
 use snafu::ResultExt;
+use tower_sessions::Session;
 
 async fn get_show_bonus_points(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<Response, VentError> {
     if var("HIDE_BONUS_POINTS").is_ok() {
         return Ok(Redirect::to("/").into_response());
     }
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     #[derive(Serialize)]
     struct BonusPoint {
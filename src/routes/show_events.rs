@@ -5,7 +5,7 @@ use crate::{
     },
     error::{SqlxAction, SqlxSnafu, VentError},
     liquid_utils::CustomFormat,
-    state::VentState,
+    state::{event_series::add_exceptions, VentState},
 };
 use axum::{
     extract::State,
@@ -18,6 +18,7 @@ use axum_login::permission_required;
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
+use tower_sessions::Session;
 
 #[derive(Deserialize)]
 struct SmolDbEvent {
@@ -52,7 +53,11 @@ impl<'a> From<(SmolDbEvent, &'a str)> for SmolFormattedDbEvent {
 }
 
 #[axum::debug_handler]
-async fn get_(auth: Auth, State(state): State<VentState>) -> Result<impl IntoResponse, VentError> {
+async fn get_(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
     trace!("Getting events");
 
     let events: Vec<SmolFormattedDbEvent> = sqlx::query_as!(
@@ -76,7 +81,7 @@ ORDER BY e.date DESC
 
     trace!("Compiling");
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     state
         .compile(
@@ -129,20 +134,35 @@ async fn post_remove_event(
 ) -> Result<impl IntoResponse, VentError> {
     for event_id in event_id {
         trace!(?event_id, "Removing");
-        sqlx::query!(
+        let mut transaction = state.begin_transaction().await?;
+        let removed = sqlx::query!(
             r#"
     DELETE FROM public.events
     WHERE id=$1
+    RETURNING series_id, series_occurrence
             "#,
             event_id
         )
-        .execute(&mut *state.get_connection().await?)
+        .fetch_optional(&mut *transaction)
         .await
         .context(SqlxSnafu {
             action: SqlxAction::RemovingEvent(event_id),
         })?;
+
+        //so that it doesn't come back the next time the series changes
+        if let Some((series_id, occurrence)) =
+            removed.and_then(|removed| removed.series_id.zip(removed.series_occurrence))
+        {
+            add_exceptions(&mut transaction, series_id, &[occurrence.date()]).await?;
+        }
+
+        transaction.commit().await.context(SqlxSnafu {
+            action: SqlxAction::CommittingTransaction,
+        })?;
     }
 
+    state.update_events()?;
+
     Ok(Redirect::to("/show_events"))
 }
 
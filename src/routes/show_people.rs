@@ -17,10 +17,12 @@ use axum_login::permission_required;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use std::collections::HashMap;
+use tower_sessions::Session;
 
 #[axum::debug_handler]
 async fn get_show_people(
     auth: Auth,
+    session: Session,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
     let event_victory_points: i32 = sqlx::query!("SELECT extra_points FROM public.events")
@@ -94,7 +96,7 @@ FROM people p
 
     trace!("Compiling");
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     state
         .compile(
@@ -1,4 +1,5 @@
 use crate::{
+    api::bonus_points::ApiBonusPoint,
     auth::{
         backend::{Auth, VentAuthBackend},
         get_auth_object, PermissionsTarget,
@@ -7,6 +8,7 @@ use crate::{
     routes::FormBonusPoint,
     state::{
         db_objects::{DbBonusPoint, DbPerson},
+        webhooks::WebhookEventKind,
         VentState,
     },
 };
@@ -22,6 +24,7 @@ use dotenvy::var;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use std::collections::HashMap;
+use tower_sessions::Session;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct PersonPlusRelID {
@@ -34,6 +37,7 @@ struct PersonPlusRelID {
 
 async fn get_update_bonus_point(
     auth: Auth,
+    session: Session,
     Path(bonus_point_id): Path<i32>,
     State(state): State<VentState>,
 ) -> Result<Response, VentError> {
@@ -114,19 +118,22 @@ WHERE pbp.bonus_point_id = $1
     for person in sqlx::query_as!(
         DbPerson,
         r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
 FROM people p
 WHERE p.form != 'Gone'
 "#
     )
-        .fetch_all(&mut *state.get_connection().await?)
-        .await.context(SqlxSnafu { action: SqlxAction::FindingPeople })?
-        .into_iter()
-        .filter(|p| {
-            !existing_participants
-                .iter()
-                .any(|g| g.people.iter().any(|e| e.id == p.id))
-        }) {
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingPeople,
+    })?
+    .into_iter()
+    .filter(|p| {
+        !existing_participants
+            .iter()
+            .any(|g| g.people.iter().any(|e| e.id == p.id))
+    }) {
         possible_participants
             .entry(person.form.clone())
             .or_insert(DbFormGroup {
@@ -159,7 +166,7 @@ SELECT username FROM people WHERE id = $1
     .username;
 
     debug!("Compiling");
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     let page = state
         .compile(
@@ -249,6 +256,8 @@ async fn post_add_people_to_bonus_point(
         bonus_point_id,
     }): Form<AddPeopleToBonusPoint>,
 ) -> Result<impl IntoResponse, VentError> {
+    let mut added = vec![];
+
     for participant_id in person_ids {
         if sqlx::query!(
             r#"
@@ -286,11 +295,39 @@ async fn post_add_people_to_bonus_point(
                     bonus_point_id,
                 },
             })?;
+            added.push(participant_id);
         } else {
             warn!(%participant_id, %bonus_point_id, "Person already received this bonus point.");
         }
     }
 
+    if !added.is_empty() {
+        let bonus_point = sqlx::query_as!(
+            DbBonusPoint,
+            "SELECT * FROM bonus_points WHERE id = $1",
+            bonus_point_id
+        )
+        .fetch_one(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingBonusPoint(bonus_point_id),
+        })?;
+
+        state
+            .fire_webhook(
+                WebhookEventKind::BonusPointGiven,
+                ApiBonusPoint {
+                    id: bonus_point.id,
+                    point_date: bonus_point.point_date,
+                    staff_member_id: bonus_point.staff_member_id,
+                    num_points: bonus_point.num_points,
+                    reason: bonus_point.reason,
+                    participant_ids: added,
+                },
+            )
+            .await;
+    }
+
     Ok(Redirect::to(&format!(
         "/update_bonus_point/{bonus_point_id}"
     ))) //then back to the update event page
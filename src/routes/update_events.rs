@@ -1,12 +1,24 @@
 use crate::{
     auth::{
         backend::{Auth, VentAuthBackend},
-        get_auth_object, PermissionsRole, PermissionsTarget,
+        get_auth_object, get_people_with_permission, PermissionsTarget,
+    },
+    api::events::{ApiEvent, ApiParticipation},
+    error::{EncodeStep, SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
+    routes::{
+        parse_event_repeats, parse_event_times, parse_sign_up_times, EventRepeats, FormEvent,
+        SignUpTimes,
     },
-    error::{EncodeStep, ParseTimeSnafu, SqlxAction, SqlxSnafu, VentError},
-    routes::FormEvent,
     state::{
         db_objects::{DbEvent, DbPerson},
+        event_series::{
+            create_series, end_series_at, get_exceptions, get_series, split_series, SeriesTemplate,
+        },
+        sign_ups::{default_locks_at, SignUpDefaults, SignUpStatus, SignUpWindow},
+        waitlist::get_waitlist,
+        webhooks::WebhookEventKind,
+        withdrawals::{get_withdrawals, remove_participant},
         VentState,
     },
 };
@@ -17,16 +29,19 @@ use axum::{
     Router,
 };
 use axum_extra::extract::Form;
-use axum_login::{login_required, permission_required};
-use chrono::{NaiveDateTime, Utc};
+use axum_login::{login_required, permission_required, AuthzBackend};
+use chrono::NaiveDateTime;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use std::collections::HashMap;
+use tower_sessions::Session;
 
 #[allow(clippy::too_many_lines)]
 #[axum::debug_handler]
 async fn get_update_event(
     auth: Auth,
+    session: Session,
     Path(event_id): Path<i32>,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
@@ -41,6 +56,16 @@ async fn get_update_event(
         zip_file: _,
         is_locked,
         extra_points,
+        end_date,
+        is_all_day,
+        series_id,
+        series_occurrence: _,
+        is_series_override,
+        capacity,
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at,
+        auto_locked_at: _,
     } = sqlx::query_as!(
         DbEvent,
         r#"
@@ -55,6 +80,59 @@ SELECT * FROM events WHERE id = $1
     })?;
     let date = naive_date.to_string();
 
+    #[derive(Serialize)]
+    struct SeriesOccurrence {
+        pub id: i32,
+        pub date: String,
+    }
+    #[derive(Serialize)]
+    struct SeriesDetails {
+        pub frequency: &'static str,
+        pub description: &'static str,
+        pub repeat_until: String,
+        pub exceptions: String,
+        pub is_override: bool,
+        pub occurrences: Vec<SeriesOccurrence>,
+    }
+
+    let series = match series_id {
+        Some(series_id) => {
+            let mut conn = state.get_connection().await?;
+            let series = get_series(&mut conn, series_id).await?;
+            let exceptions = get_exceptions(&mut conn, series_id).await?;
+            let occurrences = sqlx::query!(
+                "SELECT id, date FROM events WHERE series_id = $1 ORDER BY date",
+                series_id
+            )
+            .fetch_all(&mut *conn)
+            .await
+            .context(SqlxSnafu {
+                action: SqlxAction::FindingEventSeries(series_id),
+            })?
+            .into_iter()
+            .map(|rec| SeriesOccurrence {
+                id: rec.id,
+                date: rec
+                    .date
+                    .to_env_string(&state.settings.niche.date_time_format),
+            })
+            .collect();
+
+            Some(SeriesDetails {
+                frequency: series.frequency().into(),
+                description: series.frequency().description(),
+                repeat_until: series.repeat_until.format("%Y-%m-%d").to_string(),
+                exceptions: exceptions
+                    .iter()
+                    .map(|date| date.format("%Y-%m-%d"))
+                    .join(","),
+                is_override: is_series_override,
+                occurrences,
+            })
+        }
+        None => None,
+    };
+
     #[derive(Deserialize, Serialize, Debug, Clone)]
     struct PersonPlusRelID {
         pub id: i32,
@@ -156,16 +234,106 @@ INNER JOIN participant_events pe ON pe.event_id = $1 AND pe.participant_id = p.i
         .collect::<Vec<_>>();
     existing_participants.sort_by_key(|rfg| rfg.form.clone());
 
+    debug!("Getting places and waitlist");
+
+    #[derive(Serialize)]
+    struct Places {
+        pub capacity: i32,
+        pub taken: usize,
+        pub remaining: usize,
+    }
+    #[derive(Serialize)]
+    struct WaitingPerson {
+        pub id: i32,
+        pub first_name: String,
+        pub surname: String,
+        pub form: String,
+        pub position: i64,
+    }
+
+    let taken = existing_participants
+        .iter()
+        .map(|rfg| rfg.people.len())
+        .sum::<usize>();
+    let places = capacity.map(|capacity| Places {
+        capacity,
+        taken,
+        remaining: usize::try_from(capacity)
+            .unwrap_or_default()
+            .saturating_sub(taken),
+    });
+    let waitlist = get_waitlist(&mut *state.get_connection().await?, event_id)
+        .await?
+        .into_iter()
+        .map(|entry| WaitingPerson {
+            id: entry.person_id,
+            first_name: entry.first_name,
+            surname: entry.surname,
+            form: entry.form,
+            position: entry.position,
+        })
+        .collect_vec();
+    let waitlist_position = auth.user.as_ref().and_then(|user| {
+        waitlist
+            .iter()
+            .find(|waiting| waiting.id == user.id)
+            .map(|waiting| waiting.position)
+    });
+    let is_full =
+        places.as_ref().is_some_and(|places| places.remaining == 0) || !waitlist.is_empty();
+
+    debug!("Getting withdrawals");
+
+    #[derive(Serialize)]
+    struct WithdrawnPerson {
+        pub first_name: String,
+        pub surname: String,
+        pub form: String,
+        pub removed_by: String,
+        pub was_verified: bool,
+        pub withdrawn_at: String,
+    }
+
+    let withdrawals = get_withdrawals(&mut *state.get_connection().await?, event_id)
+        .await?
+        .into_iter()
+        .map(|withdrawal| WithdrawnPerson {
+            first_name: withdrawal.first_name,
+            surname: withdrawal.surname,
+            form: withdrawal.form,
+            removed_by: if withdrawal.was_self {
+                "Themselves".to_string()
+            } else {
+                withdrawal
+                    .removed_by
+                    .unwrap_or_else(|| "Someone who has since been removed".to_string())
+            },
+            was_verified: withdrawal.was_verified,
+            withdrawn_at: withdrawal
+                .withdrawn_at
+                .to_env_string(&state.settings.niche.date_time_format),
+        })
+        .collect_vec();
+
     debug!("Getting possible prefects");
 
+    let prefect_ids = get_people_with_permission(
+        &mut *state.get_connection().await?,
+        PermissionsTarget::EditParticipantsOnEvents,
+    )
+    .await?
+    .into_iter()
+    .collect_vec();
+
     let mut possible_prefects = HashMap::new();
     for person in sqlx::query_as!(
         DbPerson,
         r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
 FROM people p
-WHERE p.permissions != 'participant' AND p.form != 'Gone'
-"#
+WHERE p.id = ANY($1) AND p.form != 'Gone'
+"#,
+        &prefect_ids
     )
     .fetch_all(&mut *state.get_connection().await?)
     .await.context(SqlxSnafu { action: SqlxAction::FindingPeople })?
@@ -199,7 +367,7 @@ WHERE p.permissions != 'participant' AND p.form != 'Gone'
     for person in sqlx::query_as!(
         DbPerson,
         r#"
-SELECT id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry
+SELECT id, first_name, surname, username, form, hashed_password, role_id, was_first_entry
 FROM people p
 WHERE p.form != 'Gone'
 "#
@@ -281,42 +449,51 @@ WHERE event_id = $1
     #[derive(Serialize)]
     pub struct AlreadyIn {
         pub is_in: bool,
-        pub past_date: bool,
-        pub rel_id: i32,
     }
 
-    let already_in = auth.user.clone().map_or(
-        AlreadyIn {
-            is_in: false,
-            past_date: naive_date < Utc::now().naive_local(),
-            rel_id: -1,
-        },
-        |target_id| {
-            if let Some(relation) = existing_participants
+    let already_in = AlreadyIn {
+        is_in: auth.user.as_ref().is_some_and(|user| {
+            existing_participants
                 .iter()
-                .find(|rel_id| rel_id.people.iter().any(|rel_id| rel_id.id == target_id.id))
-            {
-                AlreadyIn {
-                    is_in: true,
-                    past_date: naive_date < Utc::now().naive_local(),
-                    rel_id: relation
-                        .people
-                        .iter()
-                        .find(|rel_id| rel_id.id == target_id.id)
-                        .expect("but I just found one :(")
-                        .relation_id,
-                }
-            } else {
-                AlreadyIn {
-                    is_in: false,
-                    past_date: naive_date < Utc::now().naive_local(),
-                    rel_id: -1,
-                }
-            }
-        },
+                .any(|rfg| rfg.people.iter().any(|person| person.id == user.id))
+        }),
+    };
+
+    #[derive(Serialize)]
+    struct SignUps {
+        pub status: SignUpStatus,
+        pub opens_at: String,
+        pub closes_at: String,
+        pub locks_at: String,
+    }
+
+    let date_time_format = &state.settings.niche.date_time_format;
+    let window = SignUpWindow::new(
+        &state.settings.sign_ups,
+        naive_date,
+        sign_ups_open_at,
+        sign_ups_close_at,
     );
+    let sign_ups = SignUps {
+        status: window.status(),
+        opens_at: window
+            .opens_at
+            .map(|opens_at| opens_at.to_env_string(date_time_format))
+            .unwrap_or_default(),
+        closes_at: window.closes_at.to_env_string(date_time_format),
+        locks_at: locks_at
+            .or_else(|| {
+                default_locks_at(&state.settings.sign_ups, naive_date, end_date, is_all_day)
+            })
+            .map(|locks_at| locks_at.to_env_string(date_time_format))
+            .unwrap_or_default(),
+    };
+    let form_time = |time: Option<NaiveDateTime>| {
+        time.map(|time| time.format("%Y-%m-%dT%H:%M").to_string())
+            .unwrap_or_default()
+    };
 
-    let aa = get_auth_object(auth).await?;
+    let aa = get_auth_object(auth, &session).await?;
 
     state
         .compile(
@@ -330,7 +507,15 @@ WHERE event_id = $1
                 "teacher": teacher,
                 "other_info": other_info.unwrap_or_default(),
                 "is_locked": is_locked,
-                "victory_points": extra_points
+                "victory_points": extra_points,
+                "end_date": end_date
+                    .map(|end_date| end_date.format("%Y-%m-%dT%H:%M").to_string())
+                    .unwrap_or_default(),
+                "is_all_day": is_all_day,
+                "capacity": capacity.map(|capacity| capacity.to_string()).unwrap_or_default(),
+                "sign_ups_open_at": form_time(sign_ups_open_at),
+                "sign_ups_close_at": form_time(sign_ups_close_at),
+                "locks_at": form_time(locks_at)
             }),
         "existing_prefects": existing_prefects,
         "existing_participants": existing_participants,
@@ -338,6 +523,19 @@ WHERE event_id = $1
         "participants": possible_participants,
         "n_imgs": photos.len(),
         "imgs": photos,
+        "repeats": liquid::object!({
+            "frequency": series.as_ref().map_or("", |series| series.frequency),
+            "repeat_until": series.as_ref().map(|series| series.repeat_until.clone()).unwrap_or_default(),
+            "exceptions": series.as_ref().map(|series| series.exceptions.clone()).unwrap_or_default(),
+        }),
+        "series": series,
+        "places": places,
+        "is_full": is_full,
+        "waitlist": waitlist,
+        "waitlist_position": waitlist_position,
+        "withdrawals": withdrawals,
+        "sign_ups": sign_ups,
+        "sign_up_defaults": SignUpDefaults::from(&state.settings.sign_ups),
         "auth": aa, "already_in": already_in }),
             Some(event_name),
         )
@@ -355,18 +553,51 @@ async fn post_update_event(
         info,
         is_locked,
         victory_points,
+        end_date,
+        is_all_day,
+        repeats,
+        repeat_until,
+        repeat_except,
+        apply_to_future,
+        capacity,
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at,
     }): Form<FormEvent>,
 ) -> Result<impl IntoResponse, VentError> {
-    let date = NaiveDateTime::parse_from_str(&date, "%Y-%m-%dT%H:%M").context(ParseTimeSnafu {
-        original: date.clone(),
-        how_got_in: EncodeStep::Decode,
-    })?;
-
-    sqlx::query!(
+    let (date, end_date) = parse_event_times(date, end_date, is_all_day, EncodeStep::Decode)?;
+    let capacity = capacity.map(i32::from);
+    let SignUpTimes {
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at,
+    } = parse_sign_up_times(
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at,
+        EncodeStep::Decode,
+    )?;
+    let repeats = parse_event_repeats(
+        date,
+        repeats,
+        repeat_until.as_deref(),
+        repeat_except.as_deref(),
+        EncodeStep::Decode,
+    )?;
+    //changing a series takes lots of steps, and any of them could fail
+    let mut transaction = state.begin_transaction().await?;
+
+    //if it's part of a series, this makes it an override until it gets changed along with the rest of the series
+    //if it has been moved, it can get locked automatically again
+    let series_id = sqlx::query!(
         r#"
 UPDATE public.events
-SET event_name=$2, date=$3, location=$4, teacher=$5, other_info=$6, is_locked=$7, extra_points=$8
+SET event_name=$2, date=$3, location=$4, teacher=$5, other_info=$6, is_locked=$7, extra_points=$8, end_date=$9, is_all_day=$10,
+    capacity=$11, sign_ups_open_at=$12, sign_ups_close_at=$13, locks_at=$14,
+    auto_locked_at=(CASE WHEN date = $3 AND end_date IS NOT DISTINCT FROM $9 AND locks_at IS NOT DISTINCT FROM $14 THEN auto_locked_at END),
+    is_series_override=(series_id IS NOT NULL)
 WHERE id=$1
+RETURNING series_id
         "#,
         event_id,
         name,
@@ -375,15 +606,78 @@ WHERE id=$1
         teacher,
         info,
         is_locked,
-        victory_points
+        victory_points,
+        end_date,
+        is_all_day,
+        capacity,
+        sign_ups_open_at,
+        sign_ups_close_at,
+        locks_at
     )
-    .execute(&mut *state.get_connection().await?)
+    .fetch_one(&mut *transaction)
     .await
     .context(SqlxSnafu {
         action: SqlxAction::UpdatingEvent(event_id),
+    })?
+    .series_id;
+
+    let make_template = |repeats: &EventRepeats| SeriesTemplate {
+        frequency: repeats.frequency,
+        repeat_until: repeats.repeat_until,
+        event_name: name,
+        location,
+        teacher,
+        other_info: Some(info),
+        starts_at: date,
+        ends_at: end_date,
+        is_all_day,
+        is_locked,
+        extra_points: victory_points,
+        capacity,
+    };
+    let added = match (series_id, repeats) {
+        (Some(_), Some(repeats)) if apply_to_future => {
+            let template = make_template(&repeats);
+            split_series(&mut transaction, event_id, &template, &repeats.exceptions).await?
+        }
+        (Some(_), None) if apply_to_future => {
+            end_series_at(&mut transaction, event_id).await?;
+            vec![]
+        }
+        (None, Some(repeats)) => {
+            let template = make_template(&repeats);
+            create_series(&mut transaction, &template, &repeats.exceptions, Some(event_id)).await?
+        }
+        _ => vec![],
+    };
+
+    //the capacity might have gone up, for this occurrence and any others that just changed with it
+    let to_fill = sqlx::query!(
+        r#"
+SELECT id FROM events
+WHERE id = $1 OR (series_id = (SELECT series_id FROM events WHERE id = $1) AND date >= $2)"#,
+        event_id,
+        date
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingEvent(event_id),
     })?;
 
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+    for rec in to_fill {
+        state.fill_places(rec.id).await?;
+    }
+
     state.update_events()?;
+    for event in added {
+        state
+            .fire_webhook(WebhookEventKind::EventCreated, ApiEvent::from(event))
+            .await;
+    }
 
     Ok(Redirect::to(&format!("/update_event/{event_id}")))
 }
@@ -417,12 +711,16 @@ RETURNING event_id
     Ok(Redirect::to(&format!("/update_event/{id}")))
 }
 #[axum::debug_handler]
-async fn get_remove_participant_from_event(
+async fn post_remove_participant_from_event(
     auth: Auth,
     State(state): State<VentState>,
     Form(Removal { relation_id }): Form<Removal>,
 ) -> Result<impl IntoResponse, VentError> {
     let current_user = auth.user.expect("need to be logged in to add participants");
+    let can_edit_others = auth
+        .backend
+        .has_perm(&current_user, PermissionsTarget::EditParticipantsOnEvents)
+        .await?;
 
     let event_details = sqlx::query!(
         "SELECT * FROM participant_events WHERE relation_id = $1",
@@ -434,22 +732,20 @@ async fn get_remove_participant_from_event(
         action: SqlxAction::FindingParticipantOrPrefectByRI { relation_id },
     })?;
 
-    if current_user.permissions < PermissionsRole::Prefect
-        && current_user.id != event_details.participant_id
-    {
-        warn!(participant_id=?event_details.participant_id, perp=?current_user.id, "Participant did POST magic to get other participant, but failed.");
-    } else {
-        sqlx::query!(
-            r#"
-    DELETE FROM participant_events WHERE relation_id = $1 
-    "#,
-            relation_id
+    //people taking themselves off go through `/withdraw_from_event`, which checks the lock and sign-up window
+    if can_edit_others {
+        remove_participant(
+            &mut *state.get_connection().await?,
+            event_details.event_id,
+            event_details.participant_id,
+            current_user.id,
         )
-        .execute(&mut *state.get_connection().await?)
-        .await
-        .context(SqlxSnafu {
-            action: SqlxAction::RemovingPrefectOrPrefectFromEventByRI { relation_id },
-        })?;
+        .await?;
+
+        state.fill_places(event_details.event_id).await?;
+        state.update_events()?;
+    } else {
+        warn!(participant_id=?event_details.participant_id, perp=?current_user.id, "Participant did POST magic to remove a participant, but failed.");
     }
     Ok(Redirect::to(&format!(
         "/update_event/{}",
@@ -458,7 +754,7 @@ async fn get_remove_participant_from_event(
 }
 
 #[axum::debug_handler]
-async fn delete_image(
+async fn post_delete_image(
     Path(img_id): Path<i32>,
     State(state): State<VentState>,
 ) -> Result<impl IntoResponse, VentError> {
@@ -524,7 +820,20 @@ async fn post_verify_person(
         person_id,
     }): Form<VerifyPerson>,
 ) -> Result<impl IntoResponse, VentError> {
-    sqlx::query!("UPDATE participant_events SET is_verified = true WHERE event_id = $1 AND participant_id = $2", event_id, person_id).execute(&mut *state.get_connection().await?).await.context(SqlxSnafu { action: SqlxAction::UpdatingParticipantOrPrefect {person: person_id.into(), event_id} })?;
+    let newly_verified = sqlx::query!("UPDATE participant_events SET is_verified = true WHERE event_id = $1 AND participant_id = $2 AND NOT is_verified", event_id, person_id).execute(&mut *state.get_connection().await?).await.context(SqlxSnafu { action: SqlxAction::UpdatingParticipantOrPrefect {person: person_id.into(), event_id} })?.rows_affected() > 0;
+
+    if newly_verified {
+        state
+            .fire_webhook(
+                WebhookEventKind::ParticipantVerified,
+                ApiParticipation {
+                    event_id,
+                    person_id,
+                    is_verified: true,
+                },
+            )
+            .await;
+    }
 
     Ok(Redirect::to(&format!("/update_event/{event_id}")))
 }
@@ -552,15 +861,29 @@ async fn post_verify_everyone(
     State(state): State<VentState>,
     Form(VerifyEveryone { event_id }): Form<VerifyEveryone>,
 ) -> Result<impl IntoResponse, VentError> {
-    sqlx::query!(
-        "UPDATE participant_events SET is_verified = true WHERE event_id = $1",
+    let newly_verified = sqlx::query!(
+        "UPDATE participant_events SET is_verified = true WHERE event_id = $1 AND NOT is_verified RETURNING participant_id",
         event_id
     )
-    .execute(&mut *state.get_connection().await?)
+    .fetch_all(&mut *state.get_connection().await?)
     .await
     .context(SqlxSnafu {
         action: SqlxAction::MassVerifying { event_id },
     })?;
+
+    for rec in newly_verified {
+        state
+            .fire_webhook(
+                WebhookEventKind::ParticipantVerified,
+                ApiParticipation {
+                    event_id,
+                    person_id: rec.participant_id,
+                    is_verified: true,
+                },
+            )
+            .await;
+    }
+
     Ok(Redirect::to(&format!("/update_event/{event_id}")))
 }
 
@@ -591,9 +914,9 @@ pub fn router() -> Router<VentState> {
         ))
         .route(
             "/remove_participant_from_event",
-            post(get_remove_participant_from_event),
+            post(post_remove_participant_from_event),
         )
-        .route("/remove_img/:id", get(delete_image))
+        .route("/remove_img/:id", post(post_delete_image))
         .route_layer(login_required!(VentAuthBackend, login_url = "/login"))
         .route("/update_event/:id", get(get_update_event))
 }
@@ -0,0 +1,421 @@
+//! Pages for devs to register webhook endpoints, choose what each one gets sent, and see how deliveries went - see [`crate::state::webhooks`] for how they get sent.
+
+use crate::{
+    auth::{
+        backend::{Auth, VentAuthBackend},
+        get_auth_object, PermissionsTarget,
+    },
+    error::{SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
+    state::{webhooks::WebhookEventKind, VentState},
+};
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_login::permission_required;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use snafu::ResultExt;
+use std::collections::{HashMap, HashSet};
+use strum::IntoEnumIterator;
+use tower_sessions::Session;
+use url::Url;
+
+///How many deliveries get shown for each endpoint
+const MAX_DELIVERIES_SHOWN: i64 = 100;
+
+#[derive(Serialize)]
+struct Subscription {
+    name: &'static str,
+    description: &'static str,
+    is_subscribed: bool,
+}
+
+fn get_subscriptions(subscribed: &HashSet<String>) -> Vec<Subscription> {
+    WebhookEventKind::iter()
+        .map(|kind| {
+            let name: &'static str = kind.into();
+            Subscription {
+                name,
+                description: kind.description(),
+                is_subscribed: subscribed.contains(name),
+            }
+        })
+        .collect()
+}
+
+///Gets the URL from a form, as long as it's a valid `http` or `https` URL
+fn get_url(form: &HashMap<String, String>) -> Option<String> {
+    let url = Url::parse(form.get("url")?.trim()).ok()?;
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+///Replaces what an endpoint is subscribed to with the event types checked in a form
+async fn set_subscriptions(
+    state: &VentState,
+    webhook_id: i32,
+    form: &HashMap<String, String>,
+) -> Result<(), VentError> {
+    sqlx::query!(
+        "DELETE FROM webhook_subscriptions WHERE webhook_id = $1",
+        webhook_id
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingWebhook(webhook_id),
+    })?;
+
+    for kind in WebhookEventKind::iter() {
+        let name: &'static str = kind.into();
+        if !form.contains_key(name) {
+            continue;
+        }
+
+        sqlx::query!(
+            "INSERT INTO webhook_subscriptions (webhook_id, event_type) VALUES ($1, $2)",
+            webhook_id,
+            name
+        )
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingWebhook(webhook_id),
+        })?;
+    }
+
+    Ok(())
+}
+
+async fn render_webhooks(
+    auth: Auth,
+    session: &Session,
+    state: &VentState,
+    problem: Option<String>,
+) -> Result<Response, VentError> {
+    #[derive(Serialize)]
+    struct Webhook {
+        id: i32,
+        url: String,
+        description: String,
+        is_active: bool,
+        event_types: Vec<String>,
+        n_pending: i64,
+        n_failed: i64,
+    }
+
+    let webhooks: Vec<Webhook> = sqlx::query!(
+        r#"
+SELECT w.id, w.url, w.description, w.is_active,
+       COALESCE(array_agg(DISTINCT ws.event_type) FILTER (WHERE ws.event_type IS NOT NULL), '{}') AS "event_types!",
+       (SELECT COUNT(*) FROM webhook_deliveries d WHERE d.webhook_id = w.id AND d.status = 'pending') AS "n_pending!",
+       (SELECT COUNT(*) FROM webhook_deliveries d WHERE d.webhook_id = w.id AND d.status = 'failed') AS "n_failed!"
+FROM webhooks w
+LEFT JOIN webhook_subscriptions ws ON ws.webhook_id = w.id
+GROUP BY w.id
+ORDER BY w.id"#
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingWebhooks,
+    })?
+    .into_iter()
+    .map(|rec| Webhook {
+        id: rec.id,
+        url: rec.url,
+        description: rec.description,
+        is_active: rec.is_active,
+        event_types: rec.event_types,
+        n_pending: rec.n_pending,
+        n_failed: rec.n_failed,
+    })
+    .collect();
+
+    let aa = get_auth_object(auth, session).await?;
+
+    Ok(state
+        .compile(
+            "www/webhooks.liquid",
+            liquid::object!({
+                "auth": aa,
+                "webhooks": webhooks,
+                "subscriptions": get_subscriptions(&HashSet::new()),
+                "problem": problem,
+            }),
+            Some("Webhooks".to_string()),
+        )
+        .await?
+        .into_response())
+}
+
+///`GET` method for every endpoint, with a form to add another
+#[axum::debug_handler]
+async fn get_webhooks(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+) -> Result<impl IntoResponse, VentError> {
+    render_webhooks(auth, &session, &state, None).await
+}
+
+///`POST` method to add an endpoint, which gets a new random secret
+#[axum::debug_handler]
+async fn post_add_webhook(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(url) = get_url(&form) else {
+        return render_webhooks(
+            auth,
+            &session,
+            &state,
+            Some("Webhook URLs need to start with http:// or https://.".to_string()),
+        )
+        .await;
+    };
+    let description = form
+        .get("description")
+        .map(|description| description.trim())
+        .unwrap_or_default();
+    let secret = URL_SAFE_NO_PAD.encode(thread_rng().gen::<[u8; 32]>());
+
+    let id = sqlx::query!(
+        "INSERT INTO webhooks (url, description, secret) VALUES ($1, $2, $3) RETURNING id",
+        url,
+        description,
+        secret
+    )
+    .fetch_one(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingWebhook,
+    })?
+    .id;
+
+    info!(%id, %url, "Added webhook");
+
+    set_subscriptions(&state, id, &form).await?;
+
+    Ok(Redirect::to(&format!("/webhooks/{id}")).into_response())
+}
+
+///`GET` method for one endpoint, with its secret and most recent deliveries
+#[axum::debug_handler]
+async fn get_webhook(
+    auth: Auth,
+    session: Session,
+    State(state): State<VentState>,
+    Path(id): Path<i32>,
+) -> Result<impl IntoResponse, VentError> {
+    #[derive(Serialize)]
+    struct Webhook {
+        id: i32,
+        url: String,
+        description: String,
+        secret: String,
+        is_active: bool,
+        created_at: String,
+    }
+
+    #[derive(Serialize)]
+    struct Delivery {
+        id: i32,
+        event_type: String,
+        payload: String,
+        status: String,
+        attempts: i32,
+        created_at: String,
+        last_attempt_at: Option<String>,
+        next_attempt_at: Option<String>,
+        response_status: Option<i32>,
+        last_error: Option<String>,
+    }
+
+    let Some(rec) = sqlx::query!(
+        "SELECT id, url, description, secret, is_active, created_at FROM webhooks WHERE id = $1",
+        id
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingWebhook(id),
+    })?
+    else {
+        return Ok(Redirect::to("/webhooks").into_response());
+    };
+
+    let format = &state.settings.niche.date_time_format;
+    let webhook = Webhook {
+        id: rec.id,
+        url: rec.url,
+        description: rec.description,
+        secret: rec.secret,
+        is_active: rec.is_active,
+        created_at: rec.created_at.to_env_string(format),
+    };
+
+    let subscribed: HashSet<String> = sqlx::query!(
+        "SELECT event_type FROM webhook_subscriptions WHERE webhook_id = $1",
+        id
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingWebhook(id),
+    })?
+    .into_iter()
+    .map(|rec| rec.event_type)
+    .collect();
+
+    let deliveries: Vec<Delivery> = sqlx::query!(
+        r#"
+SELECT id, event_type, payload, status, attempts, created_at, last_attempt_at, next_attempt_at, response_status, last_error
+FROM webhook_deliveries
+WHERE webhook_id = $1
+ORDER BY created_at DESC
+LIMIT $2"#,
+        id,
+        MAX_DELIVERIES_SHOWN
+    )
+    .fetch_all(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingWebhookDeliveries(Some(id)),
+    })?
+    .into_iter()
+    .map(|rec| Delivery {
+        id: rec.id,
+        event_type: rec.event_type,
+        payload: rec.payload,
+        status: rec.status,
+        attempts: rec.attempts,
+        created_at: rec.created_at.to_env_string(format),
+        last_attempt_at: rec.last_attempt_at.map(|at| at.to_env_string(format)),
+        next_attempt_at: rec.next_attempt_at.map(|at| at.to_env_string(format)),
+        response_status: rec.response_status,
+        last_error: rec.last_error,
+    })
+    .collect();
+
+    let aa = get_auth_object(auth, &session).await?;
+
+    Ok(state
+        .compile(
+            "www/webhook.liquid",
+            liquid::object!({
+                "auth": aa,
+                "webhook": webhook,
+                "subscriptions": get_subscriptions(&subscribed),
+                "deliveries": deliveries,
+            }),
+            Some("Webhook".to_string()),
+        )
+        .await?
+        .into_response())
+}
+
+///`POST` method to change an endpoint's URL, description, subscriptions or whether it's active
+#[axum::debug_handler]
+async fn post_update_webhook(
+    State(state): State<VentState>,
+    Path(id): Path<i32>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(url) = get_url(&form) else {
+        warn!(%id, "Tried to give a webhook an invalid URL");
+        return Ok(Redirect::to(&format!("/webhooks/{id}")));
+    };
+    let description = form
+        .get("description")
+        .map(|description| description.trim())
+        .unwrap_or_default();
+    let is_active = form.contains_key("is_active");
+
+    sqlx::query!(
+        "UPDATE webhooks SET url = $2, description = $3, is_active = $4 WHERE id = $1",
+        id,
+        url,
+        description,
+        is_active
+    )
+    .execute(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingWebhook(id),
+    })?;
+
+    set_subscriptions(&state, id, &form).await?;
+
+    if is_active {
+        //there might be deliveries from when it was inactive
+        state.wake_webhook_sender();
+    }
+
+    Ok(Redirect::to(&format!("/webhooks/{id}")))
+}
+
+///`POST` method to remove an endpoint, along with its deliveries
+#[axum::debug_handler]
+async fn post_remove_webhook(
+    State(state): State<VentState>,
+    Path(id): Path<i32>,
+) -> Result<impl IntoResponse, VentError> {
+    info!(%id, "Removing webhook");
+
+    sqlx::query!("DELETE FROM webhooks WHERE id = $1", id)
+        .execute(&mut *state.get_connection().await?)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::RemovingWebhook(id),
+        })?;
+
+    Ok(Redirect::to("/webhooks"))
+}
+
+///`POST` method to try a delivery again straight away, with all of its attempts back
+#[axum::debug_handler]
+async fn post_retry_delivery(
+    State(state): State<VentState>,
+    Path(id): Path<i32>,
+) -> Result<impl IntoResponse, VentError> {
+    let Some(rec) = sqlx::query!(
+        r#"
+UPDATE webhook_deliveries
+SET status = 'pending', attempts = 0, next_attempt_at = now()
+WHERE id = $1
+RETURNING webhook_id"#,
+        id
+    )
+    .fetch_optional(&mut *state.get_connection().await?)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingWebhookDelivery(id),
+    })?
+    else {
+        return Ok(Redirect::to("/webhooks"));
+    };
+
+    state.wake_webhook_sender();
+
+    Ok(Redirect::to(&format!("/webhooks/{}", rec.webhook_id)))
+}
+
+pub fn router() -> Router<VentState> {
+    Router::new()
+        .route("/webhooks", get(get_webhooks).post(post_add_webhook))
+        .route("/webhooks/:id", get(get_webhook).post(post_update_webhook))
+        .route("/webhooks/:id/remove", post(post_remove_webhook))
+        .route("/webhooks/deliveries/:id/retry", post(post_retry_delivery))
+        .route_layer(permission_required!(
+            VentAuthBackend,
+            login_url = "/login",
+            PermissionsTarget::DevAccess
+        ))
+}
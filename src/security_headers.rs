@@ -0,0 +1,190 @@
+//! Security headers that get added to every response, configured in the `security_headers` section of the config.
+//!
+//! The `Content-Security-Policy` allows the CDN that Bootstrap comes from, plus Google Analytics and the captcha provider if they're being used. Inline scripts are only allowed if they have the nonce made for that response, which [`VentState::compile`](crate::state::VentState::compile) puts into every page as `csp_nonce`.
+
+use crate::{auth::captcha::CaptchaProvider, cfg::Settings};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::{
+    header::{
+        CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+        X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+    HeaderName, HeaderValue,
+};
+use rand::{thread_rng, Rng};
+use std::sync::Arc;
+
+///Where Bootstrap gets loaded from
+const CDN_ORIGIN: &str = "https://cdn.jsdelivr.net";
+///Where the service worker gets workbox from
+const WORKBOX_ORIGIN: &str = "https://storage.googleapis.com";
+///Where the cats on the error pages come from
+const ERROR_IMAGE_ORIGIN: &str = "https://http.cat";
+const ANALYTICS_SCRIPT_ORIGINS: &[&str] = &["https://www.googletagmanager.com"];
+const ANALYTICS_CONNECT_ORIGINS: &[&str] = &[
+    "https://*.google-analytics.com",
+    "https://*.analytics.google.com",
+    "https://*.googletagmanager.com",
+];
+
+///Stands in for the nonce in the `Content-Security-Policy`, and gets swapped for a new one on each request
+const NONCE_PLACEHOLDER: &str = "{csp_nonce}";
+
+tokio::task_local! {
+    ///The nonce for inline scripts in the response currently being made
+    static CURRENT_NONCE: String;
+}
+
+///Gets the nonce that inline scripts need in the response currently being made.
+///
+/// Returns `None` outside of [`add_security_headers`], eg. in background tasks.
+pub fn current_csp_nonce() -> Option<String> {
+    CURRENT_NONCE.try_with(Clone::clone).ok()
+}
+
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    ///The `Content-Security-Policy`, which still has the [`NONCE_PLACEHOLDER`] in it
+    csp: Arc<str>,
+    others: Arc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl SecurityHeaders {
+    ///Works out all of the headers - panics if any of the configured values can't be used as headers.
+    pub fn new(settings: &Settings, captcha: &dyn CaptchaProvider) -> Self {
+        let config = &settings.security_headers;
+        let csp = config
+            .content_security_policy
+            .clone()
+            .unwrap_or_else(|| content_security_policy(settings, captcha));
+
+        assert!(
+            HeaderValue::from_str(&csp.replace(NONCE_PLACEHOLDER, "")).is_ok(),
+            "invalid value for {CONTENT_SECURITY_POLICY} in config"
+        );
+
+        let mut headers = vec![
+            (X_FRAME_OPTIONS, config.frame_options.clone()),
+            (REFERRER_POLICY, config.referrer_policy.clone()),
+            (
+                HeaderName::from_static("permissions-policy"),
+                config.permissions_policy.clone(),
+            ),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ];
+        if settings.brand.is_https() {
+            headers.push((
+                STRICT_TRANSPORT_SECURITY,
+                format!("max-age={}", config.hsts_max_age_secs),
+            ));
+        }
+
+        Self {
+            csp: csp.into(),
+            others: Arc::new(
+                headers
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let value = HeaderValue::from_str(&value)
+                            .unwrap_or_else(|_| panic!("invalid value for {name} in config"));
+                        (name, value)
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn content_security_policy(settings: &Settings, captcha: &dyn CaptchaProvider) -> String {
+    let uses_analytics = settings.brand.google_analytics.is_some();
+    let captcha_origins = captcha.content_security_origins();
+    let extra = &settings.security_headers.extra_csp_sources;
+
+    let sources = |base: &[&str], analytics: &[&str], captcha: bool| {
+        let mut sources: Vec<&str> = base.to_vec();
+        if uses_analytics {
+            sources.extend(analytics);
+        }
+        if captcha {
+            sources.extend(captcha_origins);
+        }
+        sources.extend(extra.iter().map(String::as_str));
+        if sources.is_empty() {
+            sources.push("'none'");
+        }
+        sources.join(" ")
+    };
+
+    [
+        ("default-src", sources(&["'self'"], &[], false)),
+        (
+            "script-src",
+            sources(
+                &[
+                    "'self'",
+                    &format!("'nonce-{NONCE_PLACEHOLDER}'"),
+                    CDN_ORIGIN,
+                    WORKBOX_ORIGIN,
+                ],
+                ANALYTICS_SCRIPT_ORIGINS,
+                true,
+            ),
+        ),
+        (
+            "style-src",
+            sources(&["'self'", "'unsafe-inline'", CDN_ORIGIN], &[], true),
+        ),
+        (
+            "img-src",
+            sources(
+                &["'self'", "data:", ERROR_IMAGE_ORIGIN],
+                ANALYTICS_CONNECT_ORIGINS,
+                false,
+            ),
+        ),
+        (
+            "connect-src",
+            sources(&["'self'", CDN_ORIGIN], ANALYTICS_CONNECT_ORIGINS, true),
+        ),
+        ("frame-src", sources(&[], &[], true)),
+        ("worker-src", "'self'".to_string()),
+        ("object-src", "'none'".to_string()),
+        ("base-uri", "'self'".to_string()),
+        ("form-action", "'self'".to_string()),
+        ("frame-ancestors", "'none'".to_string()),
+    ]
+    .into_iter()
+    .map(|(directive, sources)| format!("{directive} {sources}"))
+    .collect::<Vec<_>>()
+    .join("; ")
+}
+
+///Middleware that adds the [`SecurityHeaders`] with a new nonce, unless a handler has already set one of them
+pub async fn add_security_headers(
+    State(headers): State<SecurityHeaders>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let nonce = URL_SAFE_NO_PAD.encode(thread_rng().gen::<[u8; 16]>());
+    let csp = HeaderValue::from_str(&headers.csp.replace(NONCE_PLACEHOLDER, &nonce))
+        .expect("policy already checked in SecurityHeaders::new");
+
+    let mut response = CURRENT_NONCE.scope(nonce, next.run(request)).await;
+
+    let response_headers = response.headers_mut();
+    response_headers
+        .entry(CONTENT_SECURITY_POLICY)
+        .or_insert(csp);
+    for (name, value) in &*headers.others {
+        response_headers
+            .entry(name.clone())
+            .or_insert_with(|| value.clone());
+    }
+
+    response
+}
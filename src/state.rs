@@ -4,24 +4,41 @@ pub mod mail;
 mod cache;
 mod compiler;
 pub mod db_objects;
+pub mod event_series;
+pub mod maintenance;
+pub mod sign_ups;
 pub mod storage;
+pub mod waitlist;
+pub mod webhooks;
+pub mod withdrawals;
 
 use crate::{
+    api::events::ApiParticipation,
     auth::{
-        add_password::get_email_to_be_sent_for_reset_password, backend::VentAuthBackend,
+        add_password::get_email_to_be_sent_for_reset_password,
+        backend::VentAuthBackend,
+        captcha::{captcha_provider, CaptchaProvider},
+        csrf::current_csrf_token,
+        passkeys::build_webauthn,
         PermissionsTarget,
     },
     cfg::Settings,
     error::{ChannelReason, SendSnafu, SqlxAction, SqlxSnafu, VentError},
+    liquid_utils::CustomFormat,
     routes::{
         calendar::{get_events, update_calendar_thread},
         public::serve_bytes_with_mime,
     },
+    security_headers::current_csp_nonce,
     state::{
         cache::VentCache,
         compiler::VentCompiler,
         db::VentDatabase,
-        mail::{email_sender_thread, EmailToSend},
+        mail::{email_sender_thread, EmailKind, EmailToSend},
+        maintenance::maintenance_thread,
+        sign_ups::auto_lock_thread,
+        waitlist::promote_from_waitlist,
+        webhooks::{queue_webhook_deliveries, webhook_delivery_thread, WebhookEventKind},
     },
 };
 use axum::{
@@ -32,10 +49,12 @@ use axum::{
 };
 use axum_login::permission_required;
 use icalendar::Calendar;
-use liquid::Object;
+use liquid::{model::Value, Object};
+use serde::Serialize;
 use snafu::ResultExt;
-use sqlx::{pool::PoolConnection, Pool, Postgres};
+use sqlx::{pool::PoolConnection, Pool, Postgres, Transaction};
 use std::{fmt::Debug, path::Path, sync::Arc};
+use webauthn_rs::Webauthn;
 use tokio::sync::{
     broadcast::{channel as broadcast_channel, Sender as BroadcastSender},
     mpsc::UnboundedSender,
@@ -47,6 +66,7 @@ use crate::state::storage::VentStorage;
 pub struct VentState {
     mail_sender: UnboundedSender<EmailToSend>,
     update_calendar_sender: UnboundedSender<()>,
+    webhook_sender: UnboundedSender<()>,
     calendar: Arc<RwLock<Calendar>>,
     stop_senders: BroadcastSender<()>,
     pub settings: Settings,
@@ -54,6 +74,8 @@ pub struct VentState {
     compiler: VentCompiler,
     cache: VentCache,
     pub storage: VentStorage,
+    pub captcha: Arc<dyn CaptchaProvider>,
+    pub webauthn: Arc<Webauthn>,
 }
 
 impl VentState {
@@ -71,6 +93,7 @@ impl VentState {
                 conn,
                 settings.timezone_id.clone(),
                 &settings.brand.instance_name,
+                &settings.brand.domain,
             )
             .await
             .expect("unable to create calendar")
@@ -82,24 +105,41 @@ impl VentState {
             stop_senders_tx.subscribe(),
             settings.timezone_id.clone(),
             &settings.brand.instance_name,
+            settings.brand.domain.clone(),
             calendar.clone(),
         );
+        let webhook_sender = webhook_delivery_thread(
+            postgres.clone(),
+            stop_senders_tx.subscribe(),
+            settings.webhooks.clone(),
+        );
+        maintenance_thread(postgres.clone(), stop_senders_tx.subscribe(), &settings);
+        auto_lock_thread(
+            postgres.clone(),
+            stop_senders_tx.subscribe(),
+            settings.sign_ups.clone(),
+        );
 
         let database = VentDatabase::new(postgres);
         let compiler = VentCompiler;
         let cache = VentCache::new();
         cache.pre_populate().await;
+        let captcha = captcha_provider(&settings.captcha);
+        let webauthn = Arc::new(build_webauthn(&settings));
 
         Self {
             database,
             mail_sender,
             update_calendar_sender,
+            webhook_sender,
             stop_senders: stop_senders_tx,
             calendar,
             settings,
             compiler,
             cache,
-            storage: bucket
+            storage: bucket,
+            captcha,
+            webauthn,
         }
     }
 
@@ -109,21 +149,121 @@ impl VentState {
         })
     }
 
-    pub async fn reset_password(&self, user_id: i32) -> Result<(), VentError> {
-        let email =
-            get_email_to_be_sent_for_reset_password(self.get_connection().await?, user_id).await?;
+    ///Starts a transaction, for when several statements need to all happen or not at all. Nothing gets saved unless it's committed.
+    pub async fn begin_transaction(&self) -> Result<Transaction<'static, Postgres>, VentError> {
+        self.database.pool.begin().await.context(SqlxSnafu {
+            action: SqlxAction::StartingTransaction,
+        })
+    }
 
-        self.mail_sender.send(email).expect("error sending email");
+    pub async fn reset_password(
+        &self,
+        user_id: i32,
+        requested_by: Option<i32>,
+    ) -> Result<(), VentError> {
+        let email = get_email_to_be_sent_for_reset_password(
+            self.get_connection().await?,
+            user_id,
+            requested_by,
+        )
+        .await?;
+
+        self.send_email(email);
 
         Ok(())
     }
 
+    pub fn send_email(&self, email: EmailToSend) {
+        self.mail_sender.send(email).expect("error sending email");
+    }
+
     pub fn update_events(&self) -> Result<(), VentError> {
         self.update_calendar_sender.send(()).context(SendSnafu {
             reason: ChannelReason::SendUpdateCalMessage,
         })
     }
 
+    ///Queues webhooks for something that just happened. Problems only get logged, as they shouldn't stop the change that caused them.
+    pub async fn fire_webhook(&self, kind: WebhookEventKind, data: impl Serialize) {
+        let queued = match self.get_connection().await {
+            Ok(conn) => queue_webhook_deliveries(conn, kind, &data).await,
+            Err(e) => Err(e),
+        };
+
+        match queued {
+            Ok(0) => {}
+            Ok(queued) => {
+                debug!(?kind, %queued, "Queued webhooks");
+                self.wake_webhook_sender();
+            }
+            Err(e) => error!(?e, ?kind, "Error queueing webhooks"),
+        }
+    }
+
+    ///Gives any free places on an event to people on its waitlist, and lets them know by email
+    pub async fn fill_places(&self, event_id: i32) -> Result<(), VentError> {
+        let mut conn = self.get_connection().await?;
+        let promoted = promote_from_waitlist(&mut conn, event_id, &self.settings.sign_ups).await?;
+        if promoted.is_empty() {
+            return Ok(());
+        }
+
+        let event = sqlx::query!(
+            "SELECT event_name, date FROM events WHERE id = $1",
+            event_id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::GettingEvent(event_id),
+        })?;
+        let people = sqlx::query!(
+            "SELECT id, username, first_name, surname FROM people WHERE id = ANY($1)",
+            &promoted
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::PromotingFromWaitlist(event_id),
+        })?;
+        drop(conn);
+
+        for person in people {
+            info!(person_id=%person.id, %event_id, "Promoted from waitlist");
+
+            self.send_email(EmailToSend {
+                to_username: person.username,
+                to_id: person.id,
+                to_fullname: format!("{} {}", person.first_name, person.surname),
+                kind: EmailKind::PromotedFromWaitlist {
+                    event_id,
+                    event_name: event.event_name.clone(),
+                    event_date: event
+                        .date
+                        .to_env_string(&self.settings.niche.date_time_format),
+                },
+            });
+            self.fire_webhook(
+                WebhookEventKind::ParticipantAdded,
+                ApiParticipation {
+                    event_id,
+                    person_id: person.id,
+                    is_verified: false,
+                },
+            )
+            .await;
+        }
+
+        self.update_events()
+    }
+
+    ///Gets the webhook thread to check for deliveries that are due, eg. after one gets retried
+    pub fn wake_webhook_sender(&self) {
+        if self.webhook_sender.send(()).is_err() {
+            warn!("Webhook delivery thread has stopped");
+        }
+    }
+
     pub fn send_stop_notices(&self) {
         self.stop_senders
             .send(())
@@ -133,9 +273,17 @@ impl VentState {
     pub async fn compile(
         &self,
         path: impl AsRef<Path> + Debug,
-        globals: Object,
+        mut globals: Object,
         title_additional_info: Option<String>,
     ) -> Result<Html<String>, VentError> {
+        globals.insert("captcha".into(), Value::Object(self.captcha.widget()));
+        if let Some(csrf_token) = current_csrf_token() {
+            globals.insert("csrf_token".into(), Value::scalar(csrf_token));
+        }
+        if let Some(csp_nonce) = current_csp_nonce() {
+            globals.insert("csp_nonce".into(), Value::scalar(csp_nonce));
+        }
+
         self.compiler
             .compile_with_newtitle(
                 path,
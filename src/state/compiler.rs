@@ -10,8 +10,6 @@ use once_cell::sync::Lazy;
 use snafu::ResultExt;
 use std::{env::var, fmt::Debug, path::Path};
 
-pub static CFT_SITEKEY: Lazy<String> =
-    Lazy::new(|| var("CFT_SITEKEY").expect("missing environment variable `CFT_SITEKEY`"));
 pub static DOMAIN: Lazy<(bool, String)> = Lazy::new(|| {
     if let Ok(dom) = var("DOMAIN") {
         (true, dom)
@@ -53,7 +51,6 @@ impl VentCompiler {
             None => liquid::object!({"uses_ga": false})
         };
 
-        globals.insert("cft_sitekey".into(), Value::scalar(CFT_SITEKEY.as_str()));
         globals.insert(
             "siteinfo".into(),
             Value::Object(liquid::object!({
@@ -1,10 +1,10 @@
-use crate::auth::PermissionsRole;
+use crate::auth::two_factor::TwoFactorState;
 use axum_login::AuthUser;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
-//get everything `id, first_name, surname, username, form, hashed_password, permissions as "permissions: _", was_first_entry `
+//get everything `id, first_name, surname, username, form, hashed_password, role_id, was_first_entry `
 //https://github.com/launchbadge/sqlx/issues/1004
 #[derive(Deserialize, Serialize, Clone, FromRow, Debug)]
 pub struct DbPerson {
@@ -15,7 +15,7 @@ pub struct DbPerson {
     pub was_first_entry: bool,
     pub form: String,
     pub hashed_password: Option<String>,
-    pub permissions: PermissionsRole,
+    pub role_id: i32,
 }
 
 //necessary due to unability to make breaking changes with old versions - in future implentations I might store the bytes
@@ -28,7 +28,8 @@ pub struct AuthorisationBackendPerson {
     pub was_first_entry: bool,
     pub form: String,
     hashed_password_bytes: Vec<u8>,
-    pub permissions: PermissionsRole,
+    pub role_id: i32,
+    pub two_factor: TwoFactorState,
 }
 
 impl From<DbPerson> for AuthorisationBackendPerson {
@@ -41,7 +42,7 @@ impl From<DbPerson> for AuthorisationBackendPerson {
             was_first_entry,
             form,
             hashed_password,
-            permissions,
+            role_id,
         }: DbPerson,
     ) -> Self {
         Self {
@@ -52,7 +53,8 @@ impl From<DbPerson> for AuthorisationBackendPerson {
             was_first_entry,
             form,
             hashed_password_bytes: hashed_password.unwrap_or_default().as_bytes().to_vec(),
-            permissions,
+            role_id,
+            two_factor: TwoFactorState::default(),
         }
     }
 }
@@ -81,6 +83,44 @@ pub struct DbEvent {
     pub zip_file: Option<String>,
     pub is_locked: bool,
     pub extra_points: i32,
+    ///When the event finishes - for all-day events, this is the last day of it
+    pub end_date: Option<NaiveDateTime>,
+    pub is_all_day: bool,
+    pub series_id: Option<i32>,
+    ///When the series said this occurrence should start, even if it has been moved since
+    pub series_occurrence: Option<NaiveDateTime>,
+    ///Whether this occurrence has been edited on its own, so changes to the series don't apply to it
+    pub is_series_override: bool,
+    ///How many people can sign up before everyone else goes on the waitlist, or `None` for no limit
+    pub capacity: Option<i32>,
+    ///When people can start signing up, or `None` to use the default
+    pub sign_ups_open_at: Option<NaiveDateTime>,
+    ///When people can't sign up any more, or `None` to use the default
+    pub sign_ups_close_at: Option<NaiveDateTime>,
+    ///When the event gets locked automatically, or `None` to use the default
+    pub locks_at: Option<NaiveDateTime>,
+    ///When the event got locked automatically, so that it doesn't get locked again after someone unlocks it
+    #[allow(dead_code)]
+    pub auto_locked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct DbEventSeries {
+    pub id: i32,
+    pub frequency: String,
+    pub repeat_until: NaiveDate,
+    pub event_name: String,
+    pub location: String,
+    pub teacher: String,
+    pub other_info: Option<String>,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: Option<NaiveDateTime>,
+    pub is_all_day: bool,
+    pub is_locked: bool,
+    pub extra_points: i32,
+    #[allow(dead_code)]
+    pub created_at: NaiveDateTime,
+    pub capacity: Option<i32>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -0,0 +1,631 @@
+//! Recurring series of events, like weekly clubs.
+//!
+//! A series is a template in `event_series`, and each occurrence of it is a normal row in `events` with a `series_id`, so that people can still sign up to each one. [`sync_series`] keeps those rows in step with the template - adding occurrences that are missing, removing ones that have been cancelled, and updating the rest. Occurrences that have been edited on their own have `is_series_override` set, and get left alone.
+//!
+//! Cancelled occurrences only get deleted if nothing is attached to them. Ones that have been overridden, or have participants, prefects, photos, a waitlist or withdrawals, get taken out of the series and kept as standalone events instead, so that changing a series can't lose anyone's points.
+//!
+//! Editing "this and all future occurrences" splits the series in two with [`split_series`], so that the calendar feed can still give each half as one `RRULE`.
+
+use crate::{
+    error::{SqlxAction, SqlxSnafu, TooManyOccurrencesSnafu, VentError},
+    state::db_objects::{DbEvent, DbEventSeries},
+};
+use chrono::{Datelike, Days, Months, NaiveDate, NaiveDateTime};
+use itertools::Itertools;
+use serde::Deserialize;
+use snafu::{ensure, ResultExt};
+use sqlx::PgConnection;
+use std::collections::HashSet;
+use utoipa::ToSchema;
+
+///How many occurrences a series can have, so that a typo in the end date can't make thousands of events
+pub const MAX_OCCURRENCES: usize = 500;
+
+#[derive(
+    strum::EnumIter,
+    strum::IntoStaticStr,
+    strum::EnumString,
+    Deserialize,
+    ToSchema,
+    Copy,
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SeriesFrequency {
+    Daily,
+    Weekly,
+    Fortnightly,
+    ///Every four months, so once a term for schools with three terms a year
+    Termly,
+}
+
+impl SeriesFrequency {
+    ///Gets the `n`th occurrence after `starts_at`. This always works from the start, so that termly series don't drift at the ends of months.
+    ///
+    /// Termly series can't start after the 28th (see [`Self::can_start_on`]), so adding months never has to clamp to a shorter month and these always match [`Self::rrule`].
+    pub fn nth_after(self, starts_at: NaiveDateTime, n: u32) -> Option<NaiveDateTime> {
+        match self {
+            Self::Daily => starts_at.checked_add_days(Days::new(n.into())),
+            Self::Weekly => starts_at.checked_add_days(Days::new(u64::from(n) * 7)),
+            Self::Fortnightly => starts_at.checked_add_days(Days::new(u64::from(n) * 14)),
+            Self::Termly => starts_at.checked_add_months(Months::new(n.checked_mul(4)?)),
+        }
+    }
+
+    ///Whether a series can start on `date`. Calendar apps skip months that don't have the day that a monthly `RRULE` is on, rather than moving to the end of the month, so termly series have to be on a day that every month has.
+    pub fn can_start_on(self, date: NaiveDate) -> bool {
+        self != Self::Termly || date.day() <= 28
+    }
+
+    ///The `RRULE` for the calendar feed, without when it stops
+    pub fn rrule(self) -> &'static str {
+        match self {
+            Self::Daily => "FREQ=DAILY",
+            Self::Weekly => "FREQ=WEEKLY",
+            Self::Fortnightly => "FREQ=WEEKLY;INTERVAL=2",
+            Self::Termly => "FREQ=MONTHLY;INTERVAL=4",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Daily => "every day",
+            Self::Weekly => "every week",
+            Self::Fortnightly => "every fortnight",
+            Self::Termly => "every term",
+        }
+    }
+}
+
+impl DbEventSeries {
+    pub fn frequency(&self) -> SeriesFrequency {
+        self.frequency
+            .parse()
+            .expect("the database only allows known frequencies")
+    }
+}
+
+///Everything needed to make a series, from the add or update event forms
+#[derive(Debug, Clone)]
+pub struct SeriesTemplate {
+    pub frequency: SeriesFrequency,
+    pub repeat_until: NaiveDate,
+    pub event_name: String,
+    pub location: String,
+    pub teacher: String,
+    pub other_info: Option<String>,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: Option<NaiveDateTime>,
+    pub is_all_day: bool,
+    pub is_locked: bool,
+    pub extra_points: i32,
+    pub capacity: Option<i32>,
+}
+
+///Gets how far apart two times are, in the seconds that `make_interval` wants
+#[allow(clippy::cast_precision_loss)] //events aren't anywhere near 2^52 seconds long
+fn seconds_between(from: NaiveDateTime, to: NaiveDateTime) -> f64 {
+    (to - from).num_seconds() as f64
+}
+
+///Works out when every occurrence of a series starts, including any that are exceptions
+pub fn occurrence_slots(
+    frequency: SeriesFrequency,
+    starts_at: NaiveDateTime,
+    repeat_until: NaiveDate,
+) -> Result<Vec<NaiveDateTime>, VentError> {
+    let mut slots = vec![];
+
+    for n in 0_u32.. {
+        let Some(slot) = frequency.nth_after(starts_at, n) else {
+            break;
+        };
+        if slot.date() > repeat_until {
+            break;
+        }
+
+        ensure!(
+            slots.len() < MAX_OCCURRENCES,
+            TooManyOccurrencesSnafu {
+                max: MAX_OCCURRENCES
+            }
+        );
+        slots.push(slot);
+    }
+
+    Ok(slots)
+}
+
+///Gets the last day that the first half of a series split at `split_at` repeats until, so that it stops just before the second half starts
+fn repeat_until_before(split_at: NaiveDateTime) -> NaiveDate {
+    split_at.date().pred_opt().unwrap_or(split_at.date())
+}
+
+pub async fn get_series(
+    conn: &mut PgConnection,
+    series_id: i32,
+) -> Result<DbEventSeries, VentError> {
+    sqlx::query_as!(
+        DbEventSeries,
+        "SELECT * FROM event_series WHERE id = $1",
+        series_id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingEventSeries(series_id),
+    })
+}
+
+pub async fn get_exceptions(
+    conn: &mut PgConnection,
+    series_id: i32,
+) -> Result<Vec<NaiveDate>, VentError> {
+    Ok(sqlx::query!(
+        "SELECT exception_date FROM event_series_exceptions WHERE series_id = $1 ORDER BY exception_date",
+        series_id
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingEventSeries(series_id),
+    })?
+    .into_iter()
+    .map(|rec| rec.exception_date)
+    .collect())
+}
+
+///Cancels occurrences of a series on some days, so that syncing it doesn't bring them back
+pub async fn add_exceptions(
+    conn: &mut PgConnection,
+    series_id: i32,
+    dates: &[NaiveDate],
+) -> Result<(), VentError> {
+    if dates.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        r#"
+INSERT INTO event_series_exceptions (series_id, exception_date)
+SELECT $1, exception_date FROM UNNEST($2::date[]) AS exception_date
+ON CONFLICT DO NOTHING"#,
+        series_id,
+        dates
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingEventSeries(series_id),
+    })?;
+
+    Ok(())
+}
+
+async fn insert_series(
+    conn: &mut PgConnection,
+    template: &SeriesTemplate,
+) -> Result<i32, VentError> {
+    let frequency: &'static str = template.frequency.into();
+
+    Ok(sqlx::query!(
+        r#"
+INSERT INTO event_series
+(frequency, repeat_until, event_name, location, teacher, other_info, starts_at, ends_at, is_all_day, is_locked, extra_points, capacity)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+RETURNING id"#,
+        frequency,
+        template.repeat_until,
+        template.event_name,
+        template.location,
+        template.teacher,
+        template.other_info,
+        template.starts_at,
+        template.ends_at,
+        template.is_all_day,
+        template.is_locked,
+        template.extra_points,
+        template.capacity
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingEventSeries,
+    })?
+    .id)
+}
+
+///Makes the occurrences in `events` match their series, returning any that are new.
+///
+/// This does several things in a row, so it should be given a transaction.
+pub async fn sync_series(
+    conn: &mut PgConnection,
+    series_id: i32,
+) -> Result<Vec<DbEvent>, VentError> {
+    let series = get_series(conn, series_id).await?;
+    let exceptions: HashSet<NaiveDate> =
+        get_exceptions(conn, series_id).await?.into_iter().collect();
+    let slots = occurrence_slots(series.frequency(), series.starts_at, series.repeat_until)?
+        .into_iter()
+        .filter(|slot| !exceptions.contains(&slot.date()))
+        .collect_vec();
+    let duration_secs = series
+        .ends_at
+        .map(|ends_at| seconds_between(series.starts_at, ends_at));
+
+    let detached = sqlx::query!(
+        r#"
+UPDATE events e
+SET series_id = NULL, series_occurrence = NULL, is_series_override = false
+WHERE e.series_id = $1 AND NOT (e.series_occurrence = ANY($2)) AND (
+    e.is_series_override
+    OR EXISTS (SELECT 1 FROM participant_events WHERE event_id = e.id)
+    OR EXISTS (SELECT 1 FROM prefect_events WHERE event_id = e.id)
+    OR EXISTS (SELECT 1 FROM photos WHERE event_id = e.id)
+    OR EXISTS (SELECT 1 FROM event_waitlist WHERE event_id = e.id)
+    OR EXISTS (SELECT 1 FROM participant_withdrawals WHERE event_id = e.id)
+)"#,
+        series_id,
+        &slots
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingEventSeries(series_id),
+    })?
+    .rows_affected();
+    if detached > 0 {
+        info!(%series_id, %detached, "Kept cancelled occurrences with things attached as standalone events");
+    }
+
+    sqlx::query!(
+        "DELETE FROM events WHERE series_id = $1 AND NOT (series_occurrence = ANY($2))",
+        series_id,
+        &slots
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingEventSeries(series_id),
+    })?;
+
+    //is_locked only gets set when occurrences are made, so that locking one after it happens sticks
+    sqlx::query!(
+        r#"
+UPDATE events
+SET event_name = $2, location = $3, teacher = $4, other_info = $5, extra_points = $6, is_all_day = $7,
+    date = series_occurrence, end_date = series_occurrence + make_interval(secs => $8), capacity = $9
+WHERE series_id = $1 AND NOT is_series_override"#,
+        series_id,
+        series.event_name,
+        series.location,
+        series.teacher,
+        series.other_info,
+        series.extra_points,
+        series.is_all_day,
+        duration_secs,
+        series.capacity
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingEventSeries(series_id),
+    })?;
+
+    let existing: HashSet<NaiveDateTime> = sqlx::query!(
+        "SELECT series_occurrence FROM events WHERE series_id = $1",
+        series_id
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingEventSeries(series_id),
+    })?
+    .into_iter()
+    .filter_map(|rec| rec.series_occurrence)
+    .collect();
+    let missing = slots
+        .into_iter()
+        .filter(|slot| !existing.contains(slot))
+        .collect_vec();
+
+    if missing.is_empty() {
+        return Ok(vec![]);
+    }
+
+    sqlx::query_as!(
+        DbEvent,
+        r#"
+INSERT INTO events
+(event_name, location, teacher, other_info, is_locked, extra_points, is_all_day, date, end_date, series_id, series_occurrence, capacity)
+SELECT $2, $3, $4, $5, $6, $7, $8, slot, slot + make_interval(secs => $9), $1, slot, $11
+FROM UNNEST($10::timestamp[]) AS slot
+RETURNING *"#,
+        series_id,
+        series.event_name,
+        series.location,
+        series.teacher,
+        series.other_info,
+        series.is_locked,
+        series.extra_points,
+        series.is_all_day,
+        duration_secs,
+        &missing,
+        series.capacity
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingEvent,
+    })
+}
+
+///Makes a new series, returning the occurrences that got added. If `first_event` is given, it becomes the first occurrence rather than adding a new one, so it should start at the same time as the template.
+pub async fn create_series(
+    conn: &mut PgConnection,
+    template: &SeriesTemplate,
+    exceptions: &[NaiveDate],
+    first_event: Option<i32>,
+) -> Result<Vec<DbEvent>, VentError> {
+    //check that it isn't too long before adding anything
+    occurrence_slots(
+        template.frequency,
+        template.starts_at,
+        template.repeat_until,
+    )?;
+
+    let series_id = insert_series(conn, template).await?;
+    add_exceptions(conn, series_id, exceptions).await?;
+
+    if let Some(event_id) = first_event {
+        sqlx::query!(
+            r#"
+UPDATE events
+SET series_id = $2, series_occurrence = date, is_series_override = false
+WHERE id = $1"#,
+            event_id,
+            series_id
+        )
+        .execute(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::UpdatingEvent(event_id),
+        })?;
+    }
+
+    sync_series(conn, series_id).await
+}
+
+///Splits a series at an occurrence, so that it and everything after it follow `template` instead, returning any occurrences that got added. Sign-ups stay with the occurrences that they were for.
+pub async fn split_series(
+    conn: &mut PgConnection,
+    event_id: i32,
+    template: &SeriesTemplate,
+    extra_exceptions: &[NaiveDate],
+) -> Result<Vec<DbEvent>, VentError> {
+    let event = sqlx::query!(
+        "SELECT series_id, series_occurrence FROM events WHERE id = $1",
+        event_id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingEvent(event_id),
+    })?;
+    let (Some(old_series_id), Some(split_at)) = (event.series_id, event.series_occurrence) else {
+        return create_series(conn, template, extra_exceptions, Some(event_id)).await;
+    };
+
+    occurrence_slots(
+        template.frequency,
+        template.starts_at,
+        template.repeat_until,
+    )?;
+
+    let new_series_id = insert_series(conn, template).await?;
+    //everything after the split moves along with the occurrence that was edited
+    let shift_secs = seconds_between(split_at, template.starts_at);
+
+    sqlx::query!(
+        r#"
+INSERT INTO event_series_exceptions (series_id, exception_date)
+SELECT $1, (exception_date + make_interval(secs => $4))::date
+FROM event_series_exceptions
+WHERE series_id = $2 AND exception_date >= $3
+ON CONFLICT DO NOTHING"#,
+        new_series_id,
+        old_series_id,
+        split_at.date(),
+        shift_secs
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingEventSeries(new_series_id),
+    })?;
+    add_exceptions(conn, new_series_id, extra_exceptions).await?;
+
+    sqlx::query!(
+        r#"
+UPDATE events
+SET series_id = $1, series_occurrence = series_occurrence + make_interval(secs => $4),
+    is_series_override = is_series_override AND id <> $5
+WHERE series_id = $2 AND series_occurrence >= $3"#,
+        new_series_id,
+        old_series_id,
+        split_at,
+        shift_secs,
+        event_id
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingEventSeries(new_series_id),
+    })?;
+
+    sqlx::query!(
+        "UPDATE event_series SET repeat_until = $2 WHERE id = $1",
+        old_series_id,
+        repeat_until_before(split_at)
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingEventSeries(old_series_id),
+    })?;
+    sqlx::query!(
+        "DELETE FROM event_series WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM events WHERE series_id = $1)",
+        old_series_id
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingEventSeries(old_series_id),
+    })?;
+
+    sync_series(conn, new_series_id).await
+}
+
+///Stops a series after an occurrence, removing any that come after it (unless they have something attached)
+pub async fn end_series_at(conn: &mut PgConnection, event_id: i32) -> Result<(), VentError> {
+    let event = sqlx::query!(
+        "SELECT series_id, series_occurrence FROM events WHERE id = $1",
+        event_id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingEvent(event_id),
+    })?;
+    let (Some(series_id), Some(last)) = (event.series_id, event.series_occurrence) else {
+        return Ok(());
+    };
+
+    sqlx::query!(
+        "UPDATE event_series SET repeat_until = $2 WHERE id = $1",
+        series_id,
+        last.date()
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingEventSeries(series_id),
+    })?;
+
+    sync_series(conn, series_id).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    fn at(year: i32, month: u32, day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(18, 30, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn occurrences_stop_at_the_end_date() {
+        let slots = occurrence_slots(
+            SeriesFrequency::Weekly,
+            at(2025, 1, 6),
+            at(2025, 1, 27).date(),
+        )
+        .unwrap();
+        assert_eq!(
+            slots,
+            [
+                at(2025, 1, 6),
+                at(2025, 1, 13),
+                at(2025, 1, 20),
+                at(2025, 1, 27)
+            ]
+        );
+
+        let slots = occurrence_slots(
+            SeriesFrequency::Fortnightly,
+            at(2025, 1, 6),
+            at(2025, 1, 19).date(),
+        )
+        .unwrap();
+        assert_eq!(slots, [at(2025, 1, 6)]);
+    }
+
+    #[test]
+    fn termly_occurrences_keep_their_day() {
+        let slots = occurrence_slots(
+            SeriesFrequency::Termly,
+            at(2025, 1, 28),
+            at(2026, 1, 31).date(),
+        )
+        .unwrap();
+        assert_eq!(
+            slots,
+            [
+                at(2025, 1, 28),
+                at(2025, 5, 28),
+                at(2025, 9, 28),
+                at(2026, 1, 28)
+            ]
+        );
+
+        //starting in October goes through February, the shortest month
+        for day in 1..=28 {
+            let starts_at = at(2023, 10, day);
+            for n in 0..12 {
+                let occurrence = SeriesFrequency::Termly.nth_after(starts_at, n).unwrap();
+                assert_eq!(occurrence.day(), day);
+            }
+        }
+    }
+
+    #[test]
+    fn termly_series_cant_start_after_the_28th() {
+        for day in 29..=31 {
+            assert!(!SeriesFrequency::Termly.can_start_on(at(2025, 1, day).date()));
+        }
+        assert!(SeriesFrequency::Termly.can_start_on(at(2025, 1, 28).date()));
+        for frequency in SeriesFrequency::iter().filter(|f| *f != SeriesFrequency::Termly) {
+            assert!(frequency.can_start_on(at(2025, 1, 31).date()));
+        }
+    }
+
+    #[test]
+    fn long_series_are_refused() {
+        let error = occurrence_slots(
+            SeriesFrequency::Daily,
+            at(2025, 1, 1),
+            at(2027, 1, 1).date(),
+        )
+        .unwrap_err();
+        assert!(matches!(error, VentError::TooManyOccurrences { .. }));
+    }
+
+    #[test]
+    fn splitting_keeps_every_occurrence() {
+        let starts_at = at(2025, 1, 6);
+        let repeat_until = at(2027, 1, 6).date();
+
+        for frequency in SeriesFrequency::iter().filter(|f| *f != SeriesFrequency::Daily) {
+            let all = occurrence_slots(frequency, starts_at, repeat_until).unwrap();
+
+            for (i, &split_at) in all.iter().enumerate().skip(1) {
+                let before =
+                    occurrence_slots(frequency, starts_at, repeat_until_before(split_at)).unwrap();
+                let after = occurrence_slots(frequency, split_at, repeat_until).unwrap();
+
+                assert_eq!(before, all[..i], "{frequency:?} split at {split_at}");
+                assert_eq!(after, all[i..], "{frequency:?} split at {split_at}");
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{
+    auth::add_password::RESET_TOKEN_LIFETIME_HOURS,
     cfg::Settings,
     error::{LettreAction, LettreEmailSnafu, VentError},
 };
@@ -12,12 +13,30 @@ use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedSender},
 };
 
+///Which email to send, and anything specific to it
+#[derive(Debug)]
+pub enum EmailKind {
+    AddPassword {
+        token: String,
+    },
+    MagicLink {
+        token: String,
+        lifetime_minutes: i32,
+    },
+    ///They were on the waitlist for an event, and a place came up
+    PromotedFromWaitlist {
+        event_id: i32,
+        event_name: String,
+        event_date: String,
+    },
+}
+
 #[derive(Debug)]
 pub struct EmailToSend {
     pub to_username: String,
     pub to_id: i32,
     pub to_fullname: String,
-    pub unique_id: i32,
+    pub kind: EmailKind,
 }
 
 pub fn email_sender_thread(
@@ -32,7 +51,7 @@ pub fn email_sender_thread(
             to_username,
             to_id,
             to_fullname,
-            unique_id,
+            kind,
         }: EmailToSend,
         mailer: &AsyncSmtpTransport<Tokio1Executor>,
         from_username: &str,
@@ -40,24 +59,64 @@ pub fn email_sender_thread(
         project_name: &str,
         project_domain: &str,
     ) -> Result<(), VentError> {
-        let m = Message::builder()
-            .from(format!("{project_name} noreply <{from_username}>").parse()?)
-            .to(format!("{to_fullname} <{to_username}@{username_domain}>").parse()?)
-            .subject(format!("{project_name} - Add Password"))
-            .body(format!(
-                r#"Dear {to_fullname},
+        let (subject, body) = match kind {
+            EmailKind::AddPassword { token } => (
+                "Add Password",
+                format!(
+                    r"Dear {to_fullname},
 
 You've just tried to login to {project_name}, but you don't have a password set yet.
 
-To set one, go to {project_domain}/add_password/{to_id}?code={unique_id}.
+To set one, go to {project_domain}/add_password/{to_id}?code={token}. This link will only work once, and only for the next {RESET_TOKEN_LIFETIME_HOURS} hours.
 
-Have a nice day!"#
-            ))
+Have a nice day!"
+                ),
+            ),
+            EmailKind::MagicLink {
+                token,
+                lifetime_minutes,
+            } => (
+                "Login Link",
+                format!(
+                    r"Dear {to_fullname},
+
+Someone (hopefully you) asked for a link to login to {project_name}.
+
+To login, go to {project_domain}/magic_link/login?token={token}. This link will only work once, and only for the next {lifetime_minutes} minutes.
+
+If it wasn't you, you can ignore this email.
+
+Have a nice day!"
+                ),
+            ),
+            EmailKind::PromotedFromWaitlist {
+                event_id,
+                event_name,
+                event_date,
+            } => (
+                "Waitlist Place",
+                format!(
+                    r"Dear {to_fullname},
+
+A place has come up on {event_name} ({event_date}), so you've been moved off the waitlist and signed up to it.
+
+If you can't go any more, you can take yourself off it at {project_domain}/update_event/{event_id} so that someone else can have the place.
+
+Have a nice day!"
+                ),
+            ),
+        };
+
+        let m = Message::builder()
+            .from(format!("{project_name} noreply <{from_username}>").parse()?)
+            .to(format!("{to_fullname} <{to_username}@{username_domain}>").parse()?)
+            .subject(format!("{project_name} - {subject}"))
+            .body(body)
             .context(LettreEmailSnafu {
                 trying_to: LettreAction::BuildMessage,
             })?;
 
-        info!(%to_fullname, %to_id, numbers=%unique_id, "Sending email.");
+        info!(%to_fullname, %to_id, "Sending email.");
 
         mailer.send(m).await?;
 
@@ -0,0 +1,66 @@
+//! Deleting rows that aren't needed any more, so that tables which get added to on every login or event don't grow forever.
+//!
+//! Each table has its own function to delete its old rows (like [`PostgresStore::delete_expired_sessions`]), and [`maintenance_thread`] runs all of them every [`Settings::cleanup_interval_secs`].
+
+use crate::{
+    auth::{pg_session::PostgresStore, rate_limit::delete_stale_throttles},
+    cfg::Settings,
+    error::VentError,
+    state::webhooks::delete_old_deliveries,
+};
+use sqlx::{Pool, Postgres};
+use std::{future::Future, time::Duration};
+use tokio::{
+    sync::broadcast::Receiver as BroadcastReceiver,
+    time::{interval, MissedTickBehavior},
+};
+
+///Runs one cleanup in its own task, so that if it fails or panics the others still happen. `what` is the plural of what gets deleted, for the logs.
+async fn clean_up(
+    what: &'static str,
+    cleanup: impl Future<Output = Result<u64, VentError>> + Send + 'static,
+) {
+    match tokio::spawn(cleanup).await {
+        Ok(Ok(deleted)) => info!(%deleted, "Deleted {what}"),
+        Ok(Err(e)) => error!(?e, "Error deleting {what}"),
+        Err(e) => error!(?e, "Task deleting {what} panicked"),
+    }
+}
+
+///Starts a task which deletes expired sessions, stale login throttles and old webhook deliveries every [`Settings::cleanup_interval_secs`], until it gets a stop notice.
+pub fn maintenance_thread(
+    pool: Pool<Postgres>,
+    mut stop_rx: BroadcastReceiver<()>,
+    settings: &Settings,
+) {
+    let period = Duration::from_secs(settings.cleanup_interval_secs.max(1));
+    let webhook_retention_days = settings.webhooks.retention_days;
+    let store = PostgresStore::new(pool.clone());
+
+    tokio::spawn(async move {
+        let mut ticker = interval(period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _stop = stop_rx.recv() => {
+                    info!("Maintenance thread stopping");
+                    return;
+                }
+                _tick = ticker.tick() => {
+                    let store = store.clone();
+                    clean_up("expired sessions", async move { store.delete_expired_sessions().await }).await;
+
+                    let throttle_pool = pool.clone();
+                    clean_up("stale login throttles", async move { delete_stale_throttles(&throttle_pool).await }).await;
+
+                    let webhook_pool = pool.clone();
+                    clean_up("old webhook deliveries", async move {
+                        delete_old_deliveries(&webhook_pool, webhook_retention_days).await
+                    })
+                    .await;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,194 @@
+//! When people can sign up to events, and locking events once they're over.
+//!
+//! Each event can have its own `sign_ups_open_at`, `sign_ups_close_at` and `locks_at`, and any that aren't set come from [`SignUpSettings`] instead, so changing the config changes every event that hasn't been given its own times.
+
+use crate::{
+    cfg::SignUpSettings,
+    error::{SqlxAction, SqlxSnafu, VentError},
+    state::db_objects::DbEvent,
+};
+use chrono::{Days, Duration as ChronoDuration, NaiveDateTime, Utc};
+use serde::Serialize;
+use snafu::ResultExt;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+use tokio::{
+    sync::broadcast::Receiver as BroadcastReceiver,
+    time::{interval, MissedTickBehavior},
+};
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignUpStatus {
+    NotOpenYet,
+    Open,
+    Closed,
+}
+
+///When sign-ups for one event open and close, after filling in the defaults
+#[derive(Copy, Clone, Debug)]
+pub struct SignUpWindow {
+    pub opens_at: Option<NaiveDateTime>,
+    pub closes_at: NaiveDateTime,
+}
+
+impl SignUpWindow {
+    pub fn new(
+        settings: &SignUpSettings,
+        date: NaiveDateTime,
+        opens_at: Option<NaiveDateTime>,
+        closes_at: Option<NaiveDateTime>,
+    ) -> Self {
+        Self {
+            opens_at: opens_at.or_else(|| default_opens_at(settings, date)),
+            closes_at: closes_at.unwrap_or_else(|| default_closes_at(settings, date)),
+        }
+    }
+
+    pub fn for_event(settings: &SignUpSettings, event: &DbEvent) -> Self {
+        Self::new(
+            settings,
+            event.date,
+            event.sign_ups_open_at,
+            event.sign_ups_close_at,
+        )
+    }
+
+    pub fn status_at(&self, now: NaiveDateTime) -> SignUpStatus {
+        if self.opens_at.is_some_and(|opens_at| now < opens_at) {
+            SignUpStatus::NotOpenYet
+        } else if now >= self.closes_at {
+            SignUpStatus::Closed
+        } else {
+            SignUpStatus::Open
+        }
+    }
+
+    pub fn status(&self) -> SignUpStatus {
+        self.status_at(Utc::now().naive_local())
+    }
+}
+
+pub fn default_opens_at(settings: &SignUpSettings, date: NaiveDateTime) -> Option<NaiveDateTime> {
+    settings
+        .opens_hours_before
+        .map(|hours| date - ChronoDuration::hours(hours.into()))
+}
+
+pub fn default_closes_at(settings: &SignUpSettings, date: NaiveDateTime) -> NaiveDateTime {
+    date - ChronoDuration::minutes(settings.closes_minutes_before.into())
+}
+
+///When an event gets locked if it doesn't have its own `locks_at` - all-day events finish at the end of their last day
+pub fn default_locks_at(
+    settings: &SignUpSettings,
+    date: NaiveDateTime,
+    end_date: Option<NaiveDateTime>,
+    is_all_day: bool,
+) -> Option<NaiveDateTime> {
+    if !settings.auto_lock {
+        return None;
+    }
+
+    let finishes = end_date.unwrap_or(date);
+    let finishes = if is_all_day {
+        finishes.checked_add_days(Days::new(1))?
+    } else {
+        finishes
+    };
+
+    Some(finishes + ChronoDuration::hours(settings.lock_hours_after.into()))
+}
+
+///The defaults in words, for the add and update event forms
+#[derive(Serialize, Clone, Debug)]
+pub struct SignUpDefaults {
+    pub opens: String,
+    pub closes: String,
+    pub locks: String,
+}
+
+impl From<&SignUpSettings> for SignUpDefaults {
+    fn from(settings: &SignUpSettings) -> Self {
+        Self {
+            opens: settings.opens_hours_before.map_or_else(
+                || "as soon as the event is added".to_string(),
+                |hours| format!("{hours} hours before the event starts"),
+            ),
+            closes: format!(
+                "{} minutes before the event starts",
+                settings.closes_minutes_before
+            ),
+            locks: if settings.auto_lock {
+                format!(
+                    "{} hours after the event finishes",
+                    settings.lock_hours_after
+                )
+            } else {
+                "never".to_string()
+            },
+        }
+    }
+}
+
+///Locks every event that is due to be locked and hasn't been locked by this before, returning their IDs
+pub async fn lock_due_events(
+    pool: &Pool<Postgres>,
+    settings: &SignUpSettings,
+) -> Result<Vec<i32>, VentError> {
+    //this has to match `default_locks_at`
+    let lock_hours_after = settings
+        .auto_lock
+        .then(|| i32::try_from(settings.lock_hours_after).unwrap_or(i32::MAX));
+
+    Ok(sqlx::query!(
+        r#"
+UPDATE events
+SET is_locked = true, auto_locked_at = $1
+WHERE auto_locked_at IS NULL
+AND COALESCE(
+    locks_at,
+    COALESCE(end_date, date) + CASE WHEN is_all_day THEN interval '1 day' ELSE interval '0' END + make_interval(hours => $2)
+) <= $1
+RETURNING id"#,
+        Utc::now().naive_local(),
+        lock_hours_after
+    )
+    .fetch_all(pool)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::LockingDueEvents,
+    })?
+    .into_iter()
+    .map(|rec| rec.id)
+    .collect())
+}
+
+pub fn auto_lock_thread(
+    pool: Pool<Postgres>,
+    mut stop_rx: BroadcastReceiver<()>,
+    settings: SignUpSettings,
+) {
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(
+            settings.lock_check_interval_secs.max(1),
+        ));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _stop = stop_rx.recv() => {
+                    info!("Event locking thread stopping");
+                    return;
+                }
+                _tick = ticker.tick() => {
+                    match lock_due_events(&pool, &settings).await {
+                        Ok(locked) if locked.is_empty() => {}
+                        Ok(locked) => info!(?locked, "Locked finished events"),
+                        Err(e) => error!(?e, "Error locking finished events"),
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,285 @@
+//! Places on events, and the waitlist for when they run out.
+//!
+//! Events with a `capacity` only let that many people sign up - after that, people go into `event_waitlist` and get added in the order that they joined it whenever a place frees up. Events without one never have a waitlist.
+//!
+//! Anything that changes who has a place locks the event's row first, in its own transaction, so that the counts it makes afterwards include anyone who signed up at the same time.
+
+use crate::{
+    cfg::SignUpSettings,
+    error::{SqlxAction, SqlxSnafu, VentError},
+    state::sign_ups::{SignUpStatus, SignUpWindow},
+};
+use snafu::ResultExt;
+use sqlx::{Connection, PgConnection};
+
+///What happened when someone tried to sign up to an event
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SignUpOutcome {
+    Added,
+    ///The event was full, so they're on the waitlist - `position` starts at 1
+    Waitlisted {
+        position: i64,
+    },
+    AlreadyIn,
+}
+
+#[derive(Debug, Clone)]
+pub struct WaitlistEntry {
+    pub person_id: i32,
+    pub first_name: String,
+    pub surname: String,
+    pub form: String,
+    pub position: i64,
+}
+
+///Locks an event's row until the end of the transaction, waiting for anything else that has it locked to finish first
+async fn lock_event(conn: &mut PgConnection, event_id: i32) -> Result<(), VentError> {
+    sqlx::query!("SELECT id FROM events WHERE id = $1 FOR UPDATE", event_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::GettingEvent(event_id),
+        })?;
+
+    Ok(())
+}
+
+///Gets where someone is on an event's waitlist, if they're on it
+pub async fn waitlist_position(
+    conn: &mut PgConnection,
+    event_id: i32,
+    person_id: i32,
+) -> Result<Option<i64>, VentError> {
+    let position = sqlx::query!(
+        r#"
+SELECT COUNT(*) AS "position!"
+FROM event_waitlist w
+INNER JOIN event_waitlist me ON me.event_id = w.event_id AND me.person_id = $2
+WHERE w.event_id = $1 AND (w.joined_at, w.id) <= (me.joined_at, me.id)"#,
+        event_id,
+        person_id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingWaitlist(event_id),
+    })?
+    .position;
+
+    //nobody is 0th, so it means that they weren't on it
+    Ok(Some(position).filter(|position| *position > 0))
+}
+
+///Signs someone up to an event, or puts them on the waitlist if it is full. Anyone already waiting always goes first, so people can't skip the queue by signing up just after a place frees up.
+pub async fn sign_up(
+    conn: &mut PgConnection,
+    event_id: i32,
+    person_id: i32,
+) -> Result<SignUpOutcome, VentError> {
+    let mut transaction = conn.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+    //each statement in a transaction sees everything committed before it started, so this has to be on its own before counting anything
+    lock_event(&mut transaction, event_id).await?;
+
+    if sqlx::query!(
+        "SELECT relation_id FROM participant_events WHERE participant_id = $1 AND event_id = $2",
+        person_id,
+        event_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingParticipantOrPrefect {
+            person: person_id.into(),
+            event_id,
+        },
+    })?
+    .is_some()
+    {
+        return Ok(SignUpOutcome::AlreadyIn);
+    }
+
+    let added = sqlx::query!(
+        r#"
+INSERT INTO participant_events (participant_id, event_id, is_verified)
+SELECT $1, $2, false FROM events e
+WHERE e.id = $2
+AND NOT EXISTS (SELECT 1 FROM event_waitlist WHERE event_id = $2)
+AND (e.capacity IS NULL OR (SELECT COUNT(*) FROM participant_events WHERE event_id = $2) < e.capacity)
+RETURNING relation_id"#,
+        person_id,
+        event_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingParticipantOrPrefect {
+            person: person_id.into(),
+            event_id,
+        },
+    })?
+    .is_some();
+
+    let outcome = if added {
+        SignUpOutcome::Added
+    } else {
+        add_to_waitlist(&mut transaction, event_id, person_id).await?
+    };
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(outcome)
+}
+
+async fn add_to_waitlist(
+    conn: &mut PgConnection,
+    event_id: i32,
+    person_id: i32,
+) -> Result<SignUpOutcome, VentError> {
+    sqlx::query!(
+        "INSERT INTO event_waitlist (event_id, person_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        event_id,
+        person_id
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::AddingToWaitlist {
+            person: person_id.into(),
+            event_id,
+        },
+    })?;
+
+    let position = waitlist_position(conn, event_id, person_id)
+        .await?
+        .unwrap_or_default();
+    Ok(SignUpOutcome::Waitlisted { position })
+}
+
+///Takes someone off an event's waitlist, returning whether they were on it
+pub async fn leave_waitlist(
+    conn: &mut PgConnection,
+    event_id: i32,
+    person_id: i32,
+) -> Result<bool, VentError> {
+    Ok(sqlx::query!(
+        "DELETE FROM event_waitlist WHERE event_id = $1 AND person_id = $2",
+        event_id,
+        person_id
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingFromWaitlist {
+            person: person_id.into(),
+            event_id,
+        },
+    })?
+    .rows_affected()
+        > 0)
+}
+
+///Gets everyone on an event's waitlist, in the order they'll get places
+pub async fn get_waitlist(
+    conn: &mut PgConnection,
+    event_id: i32,
+) -> Result<Vec<WaitlistEntry>, VentError> {
+    Ok(sqlx::query!(
+        r#"
+SELECT p.id, p.first_name, p.surname, p.form
+FROM event_waitlist w
+INNER JOIN people p ON p.id = w.person_id
+WHERE w.event_id = $1
+ORDER BY w.joined_at, w.id"#,
+        event_id
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingWaitlist(event_id),
+    })?
+    .into_iter()
+    .zip(1..)
+    .map(|(rec, position)| WaitlistEntry {
+        person_id: rec.id,
+        first_name: rec.first_name,
+        surname: rec.surname,
+        form: rec.form,
+        position,
+    })
+    .collect())
+}
+
+///Moves people from the front of the waitlist onto the event until it is full again, returning who got places.
+///
+/// Nobody gets moved if the event is locked or sign-ups aren't open, as they couldn't have signed up themselves then.
+pub async fn promote_from_waitlist(
+    conn: &mut PgConnection,
+    event_id: i32,
+    settings: &SignUpSettings,
+) -> Result<Vec<i32>, VentError> {
+    let mut transaction = conn.begin().await.context(SqlxSnafu {
+        action: SqlxAction::StartingTransaction,
+    })?;
+    lock_event(&mut transaction, event_id).await?;
+
+    let event = sqlx::query!(
+        "SELECT date, is_locked, sign_ups_open_at, sign_ups_close_at FROM events WHERE id = $1",
+        event_id
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::GettingEvent(event_id),
+    })?;
+    let window = SignUpWindow::new(
+        settings,
+        event.date,
+        event.sign_ups_open_at,
+        event.sign_ups_close_at,
+    );
+    if event.is_locked || window.status() != SignUpStatus::Open {
+        return Ok(vec![]);
+    }
+
+    //`LIMIT NULL` is no limit, so everyone gets a place if the capacity has been taken away
+    let promoted = sqlx::query!(
+        r#"
+WITH event AS (SELECT capacity FROM events WHERE id = $1),
+places AS (
+    SELECT CASE WHEN event.capacity IS NOT NULL
+        THEN GREATEST(event.capacity - (SELECT COUNT(*) FROM participant_events WHERE event_id = $1), 0)
+    END AS free
+    FROM event
+),
+promoted AS (
+    DELETE FROM event_waitlist WHERE id IN (
+        SELECT id FROM event_waitlist WHERE event_id = $1
+        ORDER BY joined_at, id
+        LIMIT (SELECT free FROM places)
+    )
+    RETURNING person_id
+)
+INSERT INTO participant_events (participant_id, event_id, is_verified)
+SELECT person_id, $1, false FROM promoted
+RETURNING participant_id"#,
+        event_id
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::PromotingFromWaitlist(event_id),
+    })?
+    .into_iter()
+    .map(|rec| rec.participant_id)
+    .collect();
+
+    transaction.commit().await.context(SqlxSnafu {
+        action: SqlxAction::CommittingTransaction,
+    })?;
+
+    Ok(promoted)
+}
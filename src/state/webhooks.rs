@@ -0,0 +1,383 @@
+//! Outgoing webhooks, so that other systems can react to changes without polling the API.
+//!
+//! Devs register endpoints on `/webhooks` (see [`crate::routes::webhooks`]), each subscribed to some [`WebhookEventKind`]s. [`VentState::fire_webhook`](crate::state::VentState::fire_webhook) queues a delivery in `webhook_deliveries` for every active endpoint that's subscribed, and [`webhook_delivery_thread`] sends them - retrying failures with exponential backoff, up to [`WebhookSettings::max_attempts`] times.
+//!
+//! Deliveries to different endpoints get sent at the same time, but each endpoint gets its deliveries in order. Redirects aren't followed, so an endpoint can't send the signed payload on to somewhere else. Finished deliveries get deleted after [`WebhookSettings::retention_days`] by the [maintenance thread](crate::state::maintenance::maintenance_thread).
+//!
+//! Each delivery is a `POST` of `{"type": ..., "occurred_at": ..., "data": ...}`, where `data` is the same as what the API would give back. It gets signed with the endpoint's secret in the `X-Vent-Signature` header, as `t=<unix time>,v1=<hex HMAC-SHA256 of "<t>.<body>">`.
+
+use crate::{
+    cfg::WebhookSettings,
+    error::{SerdeJsonAction, SerdeJsonSnafu, SqlxAction, SqlxSnafu, VentError},
+};
+use chrono::{NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
+use reqwest::{
+    header::{CONTENT_TYPE, USER_AGENT},
+    redirect::Policy,
+    Client,
+};
+use serde::Serialize;
+use sha2::Sha256;
+use snafu::ResultExt;
+use sqlx::{pool::PoolConnection, Pool, Postgres};
+use std::time::Duration;
+use tokio::{
+    sync::{
+        broadcast::Receiver as BroadcastReceiver,
+        mpsc::{unbounded_channel, UnboundedSender},
+    },
+    task::JoinSet,
+    time::{interval, MissedTickBehavior},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+///Header with the type of the delivery, eg. `event_created`
+pub const EVENT_HEADER: &str = "X-Vent-Event";
+///Header with the ID of the delivery, which stays the same between retries
+pub const DELIVERY_HEADER: &str = "X-Vent-Delivery";
+///Header with the timestamp and signature
+pub const SIGNATURE_HEADER: &str = "X-Vent-Signature";
+///How many due deliveries get sent at once
+const BATCH_SIZE: i64 = 50;
+
+#[derive(
+    strum::EnumIter, strum::IntoStaticStr, strum::EnumString, Copy, Clone, Debug, Eq, PartialEq,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum WebhookEventKind {
+    EventCreated,
+    ParticipantAdded,
+    ParticipantVerified,
+    BonusPointGiven,
+    RewardAwarded,
+}
+
+impl WebhookEventKind {
+    ///What happened, and what `data` is, for the webhooks page
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::EventCreated => "An event was created - the data is an ApiEvent.",
+            Self::ParticipantAdded => "Someone joined an event - the data is an ApiParticipation.",
+            Self::ParticipantVerified => {
+                "Someone's participation was verified - the data is an ApiParticipation."
+            }
+            Self::BonusPointGiven => {
+                "People were given a bonus point - the data is an ApiBonusPoint, with just the people who were given it this time."
+            }
+            Self::RewardAwarded => "Someone was given a reward - the data is an ApiRewardReceived.",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a, T: Serialize> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    occurred_at: NaiveDateTime,
+    data: &'a T,
+}
+
+///Makes the value for the [`SIGNATURE_HEADER`]
+pub fn sign_payload(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMACs can use keys of any size");
+    mac.update(format!("{timestamp}.{payload}").as_bytes());
+    format!("t={timestamp},v1={:x}", mac.finalize().into_bytes())
+}
+
+///Queues a delivery for every active endpoint that's subscribed to `kind`, returning how many there were
+pub async fn queue_webhook_deliveries(
+    mut conn: PoolConnection<Postgres>,
+    kind: WebhookEventKind,
+    data: &impl Serialize,
+) -> Result<u64, VentError> {
+    let kind_name: &'static str = kind.into();
+    let payload = serde_json::to_string(&WebhookPayload {
+        kind: kind_name,
+        occurred_at: Utc::now().naive_utc(),
+        data,
+    })
+    .context(SerdeJsonSnafu {
+        action: SerdeJsonAction::WebhookPayload,
+    })?;
+
+    Ok(sqlx::query!(
+        r#"
+INSERT INTO webhook_deliveries (webhook_id, event_type, payload)
+SELECT w.id, ws.event_type, $2
+FROM webhooks w
+INNER JOIN webhook_subscriptions ws ON ws.webhook_id = w.id
+WHERE w.is_active AND ws.event_type = $1"#,
+        kind_name,
+        payload
+    )
+    .execute(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::QueueingWebhookDeliveries,
+    })?
+    .rows_affected())
+}
+
+///Sends one delivery, returning the status code it got back and what went wrong (if anything)
+async fn send_delivery(
+    client: &Client,
+    url: &str,
+    secret: &str,
+    delivery_id: i32,
+    event_type: &str,
+    payload: String,
+) -> (Option<i32>, Option<String>) {
+    let signature = sign_payload(secret, Utc::now().timestamp(), &payload);
+
+    match client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(USER_AGENT, "Vent-Webhooks")
+        .header(EVENT_HEADER, event_type)
+        .header(DELIVERY_HEADER, delivery_id.to_string())
+        .header(SIGNATURE_HEADER, signature)
+        .body(payload)
+        .send()
+        .await
+    {
+        Ok(response) => {
+            let status = response.status();
+            let error = (!status.is_success()).then(|| format!("Got {status} back"));
+            (Some(status.as_u16().into()), error)
+        }
+        Err(e) => (
+            e.status().map(|status| status.as_u16().into()),
+            Some(e.to_string()),
+        ),
+    }
+}
+
+struct DueDelivery {
+    id: i32,
+    webhook_id: i32,
+    event_type: String,
+    payload: String,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+///Gets how long to wait before trying a delivery again, after it has failed `attempts` times
+fn backoff_secs(settings: &WebhookSettings, attempts: i32) -> u32 {
+    let doublings = (attempts - 1).clamp(0, 20) as u32;
+    settings
+        .initial_backoff_secs
+        .saturating_mul(2_u32.saturating_pow(doublings))
+        .min(settings.max_backoff_secs)
+}
+
+///Sends one delivery and records how it went, scheduling a retry if it failed
+async fn attempt_delivery(
+    pool: &Pool<Postgres>,
+    client: &Client,
+    settings: &WebhookSettings,
+    delivery: DueDelivery,
+) -> Result<(), VentError> {
+    let attempts = delivery.attempts + 1;
+    let (response_status, error) = send_delivery(
+        client,
+        &delivery.url,
+        &delivery.secret,
+        delivery.id,
+        &delivery.event_type,
+        delivery.payload,
+    )
+    .await;
+
+    let (status, backoff_secs) = match &error {
+        None => ("succeeded", None),
+        Some(_) if attempts >= settings.max_attempts => ("failed", None),
+        Some(_) => ("pending", Some(f64::from(backoff_secs(settings, attempts)))),
+    };
+
+    match &error {
+        None => debug!(id = %delivery.id, "Delivered webhook"),
+        Some(error) => {
+            warn!(id = %delivery.id, %attempts, ?response_status, %error, %status, "Webhook delivery failed");
+        }
+    }
+
+    sqlx::query!(
+        r#"
+UPDATE webhook_deliveries
+SET status = $2, attempts = $3, last_attempt_at = now(), response_status = $4, last_error = $5,
+    next_attempt_at = now() + make_interval(secs => $6)
+WHERE id = $1"#,
+        delivery.id,
+        status,
+        attempts,
+        response_status,
+        error,
+        backoff_secs
+    )
+    .execute(pool)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::UpdatingWebhookDelivery(delivery.id),
+    })?;
+
+    Ok(())
+}
+
+///Sends every delivery that's due, until there aren't any left. Each endpoint gets its own task, so a slow one doesn't hold up the rest.
+async fn send_due_deliveries(
+    pool: &Pool<Postgres>,
+    client: &Client,
+    settings: &WebhookSettings,
+) -> Result<(), VentError> {
+    loop {
+        let due = sqlx::query_as!(
+            DueDelivery,
+            r#"
+SELECT d.id, d.webhook_id, d.event_type, d.payload, d.attempts, w.url, w.secret
+FROM webhook_deliveries d
+INNER JOIN webhooks w ON w.id = d.webhook_id
+WHERE d.status = 'pending' AND d.next_attempt_at <= now() AND w.is_active
+ORDER BY d.next_attempt_at
+LIMIT $1"#,
+            BATCH_SIZE
+        )
+        .fetch_all(pool)
+        .await
+        .context(SqlxSnafu {
+            action: SqlxAction::FindingWebhookDeliveries(None),
+        })?;
+
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let mut endpoints = JoinSet::new();
+        for deliveries in due
+            .into_iter()
+            .into_group_map_by(|delivery| delivery.webhook_id)
+            .into_values()
+        {
+            let (pool, client, settings) = (pool.clone(), client.clone(), settings.clone());
+            endpoints.spawn(async move {
+                for delivery in deliveries {
+                    attempt_delivery(&pool, &client, &settings, delivery).await?;
+                }
+                Ok::<_, VentError>(())
+            });
+        }
+
+        //let every endpoint finish before giving up, so nothing gets sent twice
+        let mut first_error = None;
+        while let Some(result) = endpoints.join_next().await {
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    first_error.get_or_insert(e);
+                }
+                Err(e) => error!(?e, "Webhook endpoint task panicked"),
+            }
+        }
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+    }
+}
+
+///Deletes deliveries that finished (whether they worked or not) more than `retention_days` ago, returning how many there were
+pub async fn delete_old_deliveries(
+    pool: &Pool<Postgres>,
+    retention_days: i32,
+) -> Result<u64, VentError> {
+    Ok(sqlx::query!(
+        r#"
+DELETE FROM webhook_deliveries
+WHERE status <> 'pending' AND created_at < now() - make_interval(days => $1)"#,
+        retention_days
+    )
+    .execute(pool)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::DeletingOldWebhookDeliveries,
+    })?
+    .rows_affected())
+}
+
+///Starts the thread that sends webhooks. It checks for due deliveries every [`WebhookSettings::poll_interval_secs`], and whenever something gets sent on the returned channel.
+pub fn webhook_delivery_thread(
+    pool: Pool<Postgres>,
+    mut stop_rx: BroadcastReceiver<()>,
+    settings: WebhookSettings,
+) -> UnboundedSender<()> {
+    let (wake_tx, mut wake_rx) = unbounded_channel();
+    let client = Client::builder()
+        .timeout(Duration::from_secs(settings.timeout_secs.max(1)))
+        .redirect(Policy::none())
+        .build()
+        .expect("unable to build webhook HTTP client");
+
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(settings.poll_interval_secs.max(1)));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _stop = stop_rx.recv() => {
+                    info!("Webhook delivery thread stopping");
+                    return;
+                }
+                woken = wake_rx.recv() => {
+                    if woken.is_none() {
+                        info!("Webhook delivery thread stopping");
+                        return;
+                    }
+                    //lots can get queued at once, but one check sends them all
+                    while wake_rx.try_recv().is_ok() {}
+                }
+                _tick = ticker.tick() => {}
+            }
+
+            if let Err(e) = send_due_deliveries(&pool, &client, &settings).await {
+                error!(?e, "Error sending webhooks");
+            }
+        }
+    });
+
+    wake_tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_match_a_known_hmac() {
+        assert_eq!(
+            sign_payload("whsec_test", 1_729_189_800, r#"{"type":"event_created"}"#),
+            "t=1729189800,v1=eb58dcb37c8d4f71aba9d9bee889ca90fdbf39c4fceaa86575d131797ca5c4f1"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let settings = WebhookSettings::default();
+        let backoffs = (1..=12)
+            .map(|attempts| backoff_secs(&settings, attempts))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            backoffs,
+            [30, 60, 120, 240, 480, 960, 1920, 3840, 7680, 15360, 21600, 21600]
+        );
+
+        let settings = WebhookSettings {
+            max_backoff_secs: u32::MAX,
+            ..WebhookSettings::default()
+        };
+        assert_eq!(backoff_secs(&settings, 0), 30);
+        assert_eq!(backoff_secs(&settings, 1000), 30 << 20);
+    }
+}
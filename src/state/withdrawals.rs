@@ -0,0 +1,89 @@
+//! Taking people off events, and keeping a history of who was taken off.
+//!
+//! Rows in `participant_events` only ever get deleted through [`remove_participant`], which moves them into `participant_withdrawals` so that prefects can still see who was signed up and when they left.
+
+use crate::error::{SqlxAction, SqlxSnafu, VentError};
+use chrono::NaiveDateTime;
+use snafu::ResultExt;
+use sqlx::PgConnection;
+
+#[derive(Debug, Clone)]
+pub struct Withdrawal {
+    pub first_name: String,
+    pub surname: String,
+    pub form: String,
+    ///`None` if whoever removed them has since been deleted
+    pub removed_by: Option<String>,
+    pub was_self: bool,
+    pub was_verified: bool,
+    pub withdrawn_at: NaiveDateTime,
+}
+
+///Takes someone off an event and records it, returning whether they were on it. `removed_by` is whoever did it, which is the same as `person_id` if they withdrew themselves.
+pub async fn remove_participant(
+    conn: &mut PgConnection,
+    event_id: i32,
+    person_id: i32,
+    removed_by: i32,
+) -> Result<bool, VentError> {
+    Ok(sqlx::query!(
+        r#"
+WITH removed AS (
+    DELETE FROM participant_events
+    WHERE event_id = $1 AND participant_id = $2
+    RETURNING event_id, participant_id, is_verified
+)
+INSERT INTO participant_withdrawals (event_id, person_id, removed_by, was_verified)
+SELECT event_id, participant_id, $3, is_verified FROM removed
+RETURNING id"#,
+        event_id,
+        person_id,
+        removed_by
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::RemovingParticipantOrPrefect {
+            person: person_id.into(),
+            event_id,
+        },
+    })?
+    .is_some())
+}
+
+///Gets everyone who has been taken off an event, most recent first
+pub async fn get_withdrawals(
+    conn: &mut PgConnection,
+    event_id: i32,
+) -> Result<Vec<Withdrawal>, VentError> {
+    Ok(sqlx::query!(
+        r#"
+SELECT p.id, p.first_name, p.surname, p.form, w.was_verified, w.withdrawn_at, w.removed_by,
+    r.first_name AS "removed_by_first_name?", r.surname AS "removed_by_surname?"
+FROM participant_withdrawals w
+INNER JOIN people p ON p.id = w.person_id
+LEFT JOIN people r ON r.id = w.removed_by
+WHERE w.event_id = $1
+ORDER BY w.withdrawn_at DESC, w.id DESC"#,
+        event_id
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .context(SqlxSnafu {
+        action: SqlxAction::FindingWithdrawals(event_id),
+    })?
+    .into_iter()
+    .map(|rec| Withdrawal {
+        first_name: rec.first_name,
+        surname: rec.surname,
+        form: rec.form,
+        removed_by: rec
+            .removed_by_first_name
+            .zip(rec.removed_by_surname)
+            .map(|(first_name, surname)| format!("{first_name} {surname}")),
+        was_self: rec.removed_by == Some(rec.id),
+        was_verified: rec.was_verified,
+        withdrawn_at: rec.withdrawn_at,
+    })
+    .collect())
+}